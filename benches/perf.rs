@@ -129,7 +129,7 @@ fn bench_gpu(
     let put = start.elapsed();
 
     let start = Instant::now();
-    let values = map.bulk_get(keys);
+    let values = map.bulk_get(keys)?;
     std::hint::black_box(values);
     Ok((put, start.elapsed()))
 }
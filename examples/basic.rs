@@ -18,11 +18,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         },
     ])?;
 
-    assert_eq!(map.get(Key::new(1)), Some(Value::new(10)));
-    assert_eq!(map.get(Key::new(2)), Some(Value::new(20)));
+    assert_eq!(map.get(Key::new(1))?, Some(Value::new(10)));
+    assert_eq!(map.get(Key::new(2))?, Some(Value::new(20)));
 
-    map.delete(Key::new(2));
-    assert_eq!(map.get(Key::new(2)), None);
+    map.delete(Key::new(2))?;
+    assert_eq!(map.get(Key::new(2))?, None);
 
     Ok(())
 }
@@ -19,7 +19,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     ])?;
 
     let keys = [Key::new(20), Key::new(10), Key::new(99)];
-    let values = map.bulk_get(&keys);
+    let values = map.bulk_get(&keys)?;
     assert_eq!(
         values,
         vec![Some(Value::new(200)), Some(Value::new(100)), None]
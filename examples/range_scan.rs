@@ -22,7 +22,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         },
     ])?;
 
-    let entries = map.range(Key::new(10), Key::new(30));
+    let entries = map.range(Key::new(10), Key::new(30))?;
     let keys: Vec<Key> = entries.iter().map(|entry| entry.key).collect();
     assert_eq!(keys, vec![Key::new(10), Key::new(20)]);
 
@@ -0,0 +1,334 @@
+//! Python bindings for `gpusorted_map`.
+//!
+//! Batch operations take and return contiguous NumPy `uint32` arrays so large
+//! batches cross the Python boundary without per-element Python objects. The
+//! GIL is released while the GPU work runs.
+
+use std::borrow::Cow;
+
+use gpusorted_map::{Backend, Capacity, GpuMapError, Key, KvEntry, Value};
+use numpy::{IntoPyArray, PyArray1, PyReadonlyArray1};
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+
+pyo3::create_exception!(
+    gpusorted_map,
+    GpuSortedMapError,
+    PyException,
+    "Base class for errors raised by GpuSortedMap."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    CapacityExceededError,
+    GpuSortedMapError,
+    "The batch would exceed the slab capacity."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    TombstoneValueReservedError,
+    GpuSortedMapError,
+    "A value equal to the reserved tombstone 0xFFFFFFFF was supplied."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    DuplicateKeysError,
+    GpuSortedMapError,
+    "The batch contains the same key more than once."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    GpuInitializationError,
+    GpuSortedMapError,
+    "No usable GPU adapter or device could be created."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    DeviceLimitsInsufficientError,
+    GpuSortedMapError,
+    "The GPU device's limits cannot hold the requested map."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    InvalidShaderError,
+    GpuSortedMapError,
+    "User-supplied WGSL failed to compile."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    BackendUnsupportedError,
+    GpuSortedMapError,
+    "The operation is not available on the map's backend."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    BufferMapError,
+    GpuSortedMapError,
+    "A GPU readback buffer could not be mapped."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    GpuValidationError,
+    GpuSortedMapError,
+    "The GPU rejected a command submitted by the map."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    GpuOutOfMemoryError,
+    GpuSortedMapError,
+    "The GPU ran out of memory."
+);
+pyo3::create_exception!(
+    gpusorted_map,
+    DeviceLostError,
+    GpuSortedMapError,
+    "The map's GPU device was lost."
+);
+
+fn to_py_err(err: GpuMapError) -> PyErr {
+    let message = err.to_string();
+    match err {
+        GpuMapError::CapacityExceeded { .. } => CapacityExceededError::new_err(message),
+        GpuMapError::TombstoneValueReserved { .. } => TombstoneValueReservedError::new_err(message),
+        GpuMapError::DuplicateKeys { .. } => DuplicateKeysError::new_err(message),
+        GpuMapError::GpuInitializationFailed { .. } => GpuInitializationError::new_err(message),
+        GpuMapError::DeviceLimitsInsufficient { .. } => {
+            DeviceLimitsInsufficientError::new_err(message)
+        }
+        GpuMapError::InvalidShader { .. } => InvalidShaderError::new_err(message),
+        GpuMapError::BackendUnsupported { .. } => BackendUnsupportedError::new_err(message),
+        GpuMapError::BufferMapFailed { .. } => BufferMapError::new_err(message),
+        GpuMapError::ValidationFailed { .. } => GpuValidationError::new_err(message),
+        GpuMapError::OutOfMemory => GpuOutOfMemoryError::new_err(message),
+        GpuMapError::DeviceLost => DeviceLostError::new_err(message),
+        GpuMapError::InvalidTuningProfile { .. } => PyValueError::new_err(message),
+        GpuMapError::CoalescerStopped => GpuSortedMapError::new_err(message),
+    }
+}
+
+/// Borrow a NumPy array as a slice, copying only if it is not contiguous.
+fn as_u32_slice<'a>(array: &'a PyReadonlyArray1<'_, u32>) -> Cow<'a, [u32]> {
+    match array.as_slice() {
+        Ok(slice) => Cow::Borrowed(slice),
+        Err(_) => Cow::Owned(array.as_array().iter().copied().collect()),
+    }
+}
+
+fn zip_entries(keys: &[u32], values: &[u32]) -> Result<Vec<KvEntry>, String> {
+    if keys.len() != values.len() {
+        return Err(format!(
+            "keys and values must have the same length (got {} and {})",
+            keys.len(),
+            values.len()
+        ));
+    }
+    Ok(keys
+        .iter()
+        .zip(values)
+        .map(|(&key, &value)| KvEntry {
+            key: Key::new(key),
+            value: Value::new(value),
+        })
+        .collect())
+}
+
+fn parse_backend(name: &str) -> Result<Backend, String> {
+    match name {
+        "gpu" => Ok(Backend::Gpu),
+        "cpu" => Ok(Backend::Cpu),
+        other => Err(format!(
+            "backend must be \"gpu\" or \"cpu\" (got {other:?})"
+        )),
+    }
+}
+
+fn split_results(results: &[Option<Value>]) -> (Vec<u32>, Vec<bool>) {
+    results
+        .iter()
+        .map(|result| match result {
+            Some(value) => (value.0, true),
+            None => (0, false),
+        })
+        .unzip()
+}
+
+fn split_entries(entries: &[KvEntry]) -> (Vec<u32>, Vec<u32>) {
+    entries
+        .iter()
+        .map(|entry| (entry.key.0, entry.value.0))
+        .unzip()
+}
+
+/// A `uint32` array returned together with a second array of `T`.
+type ArrayPair<'py, T> = (Bound<'py, PyArray1<u32>>, Bound<'py, PyArray1<T>>);
+
+/// GPU-backed sorted map from `uint32` keys to `uint32` values.
+#[pyclass(name = "GpuSortedMap", module = "gpusorted_map")]
+pub struct PyGpuSortedMap {
+    inner: gpusorted_map::GpuSortedMap,
+}
+
+#[pymethods]
+impl PyGpuSortedMap {
+    #[new]
+    #[pyo3(signature = (capacity, backend = "gpu"))]
+    fn new(py: Python<'_>, capacity: u32, backend: &str) -> PyResult<Self> {
+        let backend = parse_backend(backend).map_err(PyValueError::new_err)?;
+        let inner = py
+            .detach(|| {
+                pollster::block_on(gpusorted_map::GpuSortedMap::with_backend(
+                    Capacity::new(capacity),
+                    backend,
+                ))
+            })
+            .map_err(to_py_err)?;
+        Ok(Self { inner })
+    }
+
+    /// Insert or update `keys[i] -> values[i]` for every `i`.
+    fn bulk_put(
+        &mut self,
+        py: Python<'_>,
+        keys: PyReadonlyArray1<'_, u32>,
+        values: PyReadonlyArray1<'_, u32>,
+    ) -> PyResult<()> {
+        let entries = zip_entries(&as_u32_slice(&keys), &as_u32_slice(&values))
+            .map_err(PyValueError::new_err)?;
+        let inner = &mut self.inner;
+        py.detach(|| inner.bulk_put(&entries)).map_err(to_py_err)
+    }
+
+    /// Look up `keys`, returning `(values, found_mask)`.
+    ///
+    /// `values[i]` is 0 wherever `found_mask[i]` is false.
+    fn bulk_get<'py>(
+        &self,
+        py: Python<'py>,
+        keys: PyReadonlyArray1<'py, u32>,
+    ) -> PyResult<ArrayPair<'py, bool>> {
+        let keys = as_u32_slice(&keys);
+        let keys: &[Key] = bytemuck::cast_slice(&keys);
+        let inner = &self.inner;
+        let (values, found) = py
+            .detach(|| inner.bulk_get(keys).map(|results| split_results(&results)))
+            .map_err(to_py_err)?;
+        Ok((values.into_pyarray(py), found.into_pyarray(py)))
+    }
+
+    /// Delete `keys`; missing keys are ignored.
+    fn bulk_delete(&mut self, py: Python<'_>, keys: PyReadonlyArray1<'_, u32>) -> PyResult<()> {
+        let keys = as_u32_slice(&keys);
+        let keys: &[Key] = bytemuck::cast_slice(&keys);
+        let inner = &mut self.inner;
+        py.detach(|| inner.bulk_delete(keys)).map_err(to_py_err)
+    }
+
+    /// Entries with keys in the half-open interval `[lo, hi)`, as `(keys, values)`.
+    fn range<'py>(&self, py: Python<'py>, lo: u32, hi: u32) -> PyResult<ArrayPair<'py, u32>> {
+        let inner = &self.inner;
+        let (keys, values) = py
+            .detach(|| {
+                inner
+                    .range(Key::new(lo), Key::new(hi))
+                    .map(|entries| split_entries(&entries))
+            })
+            .map_err(to_py_err)?;
+        Ok((keys.into_pyarray(py), values.into_pyarray(py)))
+    }
+
+    #[getter]
+    fn capacity(&self) -> u32 {
+        self.inner.capacity().0
+    }
+
+    fn __len__(&self) -> usize {
+        self.inner.len().0 as usize
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "gpusorted_map")]
+fn gpusorted_map_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<PyGpuSortedMap>()?;
+    m.add("GpuSortedMapError", py.get_type::<GpuSortedMapError>())?;
+    m.add(
+        "CapacityExceededError",
+        py.get_type::<CapacityExceededError>(),
+    )?;
+    m.add(
+        "TombstoneValueReservedError",
+        py.get_type::<TombstoneValueReservedError>(),
+    )?;
+    m.add("DuplicateKeysError", py.get_type::<DuplicateKeysError>())?;
+    m.add(
+        "GpuInitializationError",
+        py.get_type::<GpuInitializationError>(),
+    )?;
+    m.add(
+        "DeviceLimitsInsufficientError",
+        py.get_type::<DeviceLimitsInsufficientError>(),
+    )?;
+    m.add("InvalidShaderError", py.get_type::<InvalidShaderError>())?;
+    m.add(
+        "BackendUnsupportedError",
+        py.get_type::<BackendUnsupportedError>(),
+    )?;
+    m.add("BufferMapError", py.get_type::<BufferMapError>())?;
+    m.add("GpuValidationError", py.get_type::<GpuValidationError>())?;
+    m.add("GpuOutOfMemoryError", py.get_type::<GpuOutOfMemoryError>())?;
+    m.add("DeviceLostError", py.get_type::<DeviceLostError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_backend, split_entries, split_results, zip_entries};
+    use gpusorted_map::{Backend, Key, KvEntry, Value};
+
+    #[test]
+    fn zip_entries_pairs_keys_with_values() {
+        let entries = zip_entries(&[1, 2], &[10, 20]).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                KvEntry {
+                    key: Key::new(1),
+                    value: Value::new(10),
+                },
+                KvEntry {
+                    key: Key::new(2),
+                    value: Value::new(20),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn zip_entries_rejects_length_mismatch() {
+        assert!(zip_entries(&[1, 2], &[10]).is_err());
+    }
+
+    #[test]
+    fn parse_backend_accepts_gpu_and_cpu() {
+        assert_eq!(parse_backend("gpu"), Ok(Backend::Gpu));
+        assert_eq!(parse_backend("cpu"), Ok(Backend::Cpu));
+        assert!(parse_backend("tpu").is_err());
+    }
+
+    #[test]
+    fn split_results_builds_found_mask() {
+        let (values, found) = split_results(&[Some(Value::new(7)), None]);
+        assert_eq!(values, vec![7, 0]);
+        assert_eq!(found, vec![true, false]);
+    }
+
+    #[test]
+    fn split_entries_separates_columns() {
+        let (keys, values) = split_entries(&[KvEntry {
+            key: Key::new(3),
+            value: Value::new(30),
+        }]);
+        assert_eq!(keys, vec![3]);
+        assert_eq!(values, vec![30]);
+    }
+}
@@ -0,0 +1,55 @@
+import numpy as np
+import pytest
+
+import gpusorted_map
+
+
+def make_map(capacity):
+    try:
+        return gpusorted_map.GpuSortedMap(capacity)
+    except gpusorted_map.GpuInitializationError:
+        pytest.skip("GPU not available in this environment")
+
+
+def test_bulk_put_then_bulk_get():
+    m = make_map(16)
+    m.bulk_put(np.array([3, 1, 2], dtype=np.uint32), np.array([30, 10, 20], dtype=np.uint32))
+
+    values, found = m.bulk_get(np.array([1, 2, 9], dtype=np.uint32))
+    assert values.dtype == np.uint32
+    assert found.dtype == np.bool_
+    assert values.tolist() == [10, 20, 0]
+    assert found.tolist() == [True, True, False]
+    assert len(m) == 3
+
+
+def test_range_returns_key_and_value_arrays():
+    m = make_map(16)
+    m.bulk_put(np.arange(10, dtype=np.uint32), np.arange(10, dtype=np.uint32) * 2)
+    m.bulk_delete(np.array([4], dtype=np.uint32))
+
+    keys, values = m.range(2, 6)
+    assert keys.tolist() == [2, 3, 5]
+    assert values.tolist() == [4, 6, 10]
+
+
+def test_non_contiguous_input_is_accepted():
+    m = make_map(16)
+    keys = np.arange(8, dtype=np.uint32)[::2]
+    m.bulk_put(keys, keys + 100)
+    values, found = m.bulk_get(keys)
+    assert found.all()
+    assert values.tolist() == [100, 102, 104, 106]
+
+
+def test_errors_map_to_python_exceptions():
+    m = make_map(4)
+    with pytest.raises(gpusorted_map.TombstoneValueReservedError):
+        m.bulk_put(np.array([1], dtype=np.uint32), np.array([0xFFFFFFFF], dtype=np.uint32))
+    with pytest.raises(gpusorted_map.DuplicateKeysError):
+        m.bulk_put(np.array([1, 1], dtype=np.uint32), np.array([1, 2], dtype=np.uint32))
+    with pytest.raises(gpusorted_map.CapacityExceededError):
+        m.bulk_put(np.arange(8, dtype=np.uint32), np.arange(8, dtype=np.uint32))
+    with pytest.raises(ValueError):
+        m.bulk_put(np.array([1, 2], dtype=np.uint32), np.array([1], dtype=np.uint32))
+    assert issubclass(gpusorted_map.CapacityExceededError, gpusorted_map.GpuSortedMapError)
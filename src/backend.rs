@@ -0,0 +1,889 @@
+//! Storage backends.
+//!
+//! A map keeps its slab either on a wgpu device, where the compute pipelines
+//! operate on it, or in host memory as a [`CpuSlab`] that follows the same
+//! steps. [`Store`] is one slab version together with what is needed to read
+//! and write it. Cloning a store shares the version; writing to a shared
+//! version leaves the other holders on the old one, as with snapshots.
+//!
+//! A GPU slab larger than one storage binding is split into segments by
+//! [`ShardedSlab`], and a slab in leveled mode is kept as [`LeveledRuns`];
+//! the store hides both from the map.
+//!
+//! Once a GPU store's device is lost, every operation on it fails with
+//! [`GpuMapError::DeviceLost`] instead of submitting to the dead device.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+
+use crate::cpu_slab::CpuSlab;
+use crate::gpu_array::{GpuArray, GpuStorage};
+use crate::leveled::{LeveledConfig, LeveledRuns};
+use crate::pipelines::utils::block_on_device;
+use crate::pipelines::{
+    BufferPool, BulkDeletePipeline, BulkGetPipeline, BulkPutPipeline, FilterScanPipeline,
+    MapValuesPipeline, MergeMeta, RangeScanPipeline, SearchIndex, SearchIndexPipeline,
+    SortedGetPipeline,
+};
+use crate::sharded::{segment_capacity, ShardedSlab};
+use crate::slab::{self, SlabSpares, SlabVersion};
+use crate::{Capacity, GpuMapError, Key, KvEntry, Length, Value, ValuePredicate};
+
+/// Where a map keeps its slab and runs its operations, chosen with
+/// [`GpuSortedMap::with_backend`](crate::GpuSortedMap::with_backend).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Backend {
+    /// Compute shaders on a wgpu device.
+    #[default]
+    Gpu,
+    /// Plain host memory with the same semantics as [`Backend::Gpu`]. Needs
+    /// no adapter, so it runs anywhere and can serve as a reference for the
+    /// GPU path. Operations that expose GPU resources are not available.
+    Cpu,
+}
+
+/// Device, scratch buffers and pipelines of a GPU-backed map, shared with
+/// its expiry map and its snapshots.
+pub(crate) struct GpuBackend {
+    pub(crate) device: Arc<wgpu::Device>,
+    pub(crate) queue: Arc<wgpu::Queue>,
+    input: GpuArray<KvEntry>,
+    merge_meta: GpuStorage<MergeMeta>,
+    pub(crate) bulk_get: BulkGetPipeline,
+    pub(crate) bulk_delete: BulkDeletePipeline,
+    pub(crate) bulk_put: BulkPutPipeline,
+    pub(crate) range_scan: RangeScanPipeline,
+    pub(crate) filter_scan: FilterScanPipeline,
+    pub(crate) map_values: MapValuesPipeline,
+    search_index: SearchIndexPipeline,
+    sorted_get: SortedGetPipeline,
+    pub(crate) pool: Arc<BufferPool>,
+    /// Held from push to pop of every error scope on the device.
+    pub(crate) scopes: Arc<Mutex<()>>,
+    /// Keys per lookup or delete dispatch. A lookup's results take 16 bytes
+    /// per key, twice a slab entry, so half a segment fits one binding.
+    pub(crate) batch_limit: u32,
+    /// Threads per workgroup the per-key and per-slot kernels were built with.
+    pub(crate) workgroup_size: u32,
+    /// Whether slab versions get a search index for lookups and deletes.
+    pub(crate) indexed: AtomicBool,
+    /// Index with no fences, bound when `indexed` is off.
+    no_index: Arc<SearchIndex>,
+    /// Set by the device-lost callback.
+    lost: Arc<AtomicBool>,
+}
+
+impl GpuBackend {
+    fn new(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        capacity: Capacity,
+        pool: Arc<BufferPool>,
+        workgroup_size: u32,
+    ) -> Self {
+        let batch_limit = (segment_capacity(&device.limits()).0 / 2).max(1);
+        let input = GpuArray::new(
+            &device,
+            capacity,
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            "input-buffer",
+        );
+
+        let merge_meta = GpuStorage::new(
+            &device,
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            "merge-meta-buffer",
+        );
+
+        let scopes = Arc::new(Mutex::new(()));
+        let bulk_get = BulkGetPipeline::new(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            Arc::clone(&pool),
+            Arc::clone(&scopes),
+            workgroup_size,
+        );
+        let bulk_delete = BulkDeletePipeline::new(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            Arc::clone(&pool),
+            Arc::clone(&scopes),
+            workgroup_size,
+        );
+        let bulk_put = BulkPutPipeline::new(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            Arc::clone(&pool),
+            Arc::clone(&scopes),
+            workgroup_size,
+        );
+        let range_scan = RangeScanPipeline::new(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            Arc::clone(&pool),
+            Arc::clone(&scopes),
+        );
+        let filter_scan = FilterScanPipeline::new(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            Arc::clone(&pool),
+            Arc::clone(&scopes),
+            workgroup_size,
+        );
+        let map_values = MapValuesPipeline::new(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            Arc::clone(&pool),
+            Arc::clone(&scopes),
+            workgroup_size,
+        );
+        let search_index = SearchIndexPipeline::new(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            Arc::clone(&pool),
+            Arc::clone(&scopes),
+            workgroup_size,
+        );
+        let sorted_get = SortedGetPipeline::new(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            Arc::clone(&pool),
+            Arc::clone(&scopes),
+            workgroup_size,
+        );
+        let no_index = Arc::new(SearchIndex::empty(&pool, &queue));
+        let lost = watch_device_lost(&device);
+
+        Self {
+            device,
+            queue,
+            input,
+            merge_meta,
+            bulk_get,
+            bulk_delete,
+            bulk_put,
+            range_scan,
+            filter_scan,
+            map_values,
+            search_index,
+            sorted_get,
+            pool,
+            scopes,
+            batch_limit,
+            workgroup_size,
+            indexed: AtomicBool::new(false),
+            no_index,
+            lost,
+        }
+    }
+
+    /// Returns true once the device has been lost.
+    pub(crate) fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Acquire)
+    }
+
+    /// Fails with [`GpuMapError::DeviceLost`] if the device has been lost.
+    pub(crate) fn check(&self) -> Result<(), GpuMapError> {
+        if self.is_lost() {
+            Err(GpuMapError::DeviceLost)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// `err`, or [`GpuMapError::DeviceLost`] if the device was lost while
+    /// the operation ran.
+    pub(crate) fn lost_or(&self, err: GpuMapError) -> GpuMapError {
+        if self.is_lost() {
+            GpuMapError::DeviceLost
+        } else {
+            err
+        }
+    }
+
+    /// An empty slab with its own spares.
+    pub(crate) fn empty_slab(&self, capacity: Capacity) -> Arc<SlabVersion> {
+        let spares = SlabSpares::new(Arc::clone(&self.device), Arc::clone(&self.pool), capacity);
+        let slab = SlabVersion::new(spares.take(), Arc::clone(&spares));
+        // One spare for the first put to merge into.
+        spares.reserve();
+        slab
+    }
+
+    /// Merge `entries` into a new version of `slab` and publish it. On error
+    /// the current version stays published.
+    pub(crate) async fn merge_into(
+        &self,
+        slab: &mut Arc<SlabVersion>,
+        entries: &[KvEntry],
+    ) -> Result<(), GpuMapError> {
+        self.input.write(&self.queue, entries);
+        self.merge_input(slab, entries.len() as u32).await
+    }
+
+    /// Merge the occupied slots of `run` into a new version of `slab`, the
+    /// run's entries replacing older ones. Tombstones in the run are
+    /// dropped. `run` must pad to no more than the input buffer.
+    pub(crate) async fn merge_run(
+        &self,
+        slab: &mut Arc<SlabVersion>,
+        run: &GpuArray<KvEntry>,
+    ) -> Result<(), GpuMapError> {
+        let len = run.len().0;
+        if len == 0 {
+            return Ok(());
+        }
+        // The sort works in place, so the run is copied rather than bound.
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("run-copy-encoder"),
+            });
+        encoder.copy_buffer_to_buffer(
+            run.buffer(),
+            0,
+            self.input.buffer(),
+            0,
+            u64::from(len) * std::mem::size_of::<KvEntry>() as u64,
+        );
+        self.queue.submit(Some(encoder.finish()));
+        self.merge_input(slab, len).await
+    }
+
+    /// Merge the first `len` entries of the input buffer into a new version
+    /// of `slab` and publish it.
+    async fn merge_input(&self, slab: &mut Arc<SlabVersion>, len: u32) -> Result<(), GpuMapError> {
+        let mut merge = slab::take_spare(slab);
+        let merged = self
+            .bulk_put
+            .execute(slab, &self.input, &merge, &self.merge_meta, len)
+            .await;
+        let indexed = match merged {
+            Ok(merge_len) => {
+                merge.update_len(&self.queue, Length::new(merge_len));
+                self.build_index(&merge).await
+            }
+            Err(err) => Err(err),
+        };
+        match indexed {
+            Ok(index) => {
+                slab::publish(slab, merge);
+                if let Some(index) = index {
+                    slab.set_index(index);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                slab::return_spare(slab, merge);
+                Err(err)
+            }
+        }
+    }
+
+    /// A search index over `slab`'s keys, if this backend indexes slabs.
+    async fn build_index(
+        &self,
+        slab: &GpuArray<KvEntry>,
+    ) -> Result<Option<Arc<SearchIndex>>, GpuMapError> {
+        if !self.indexed.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let index = self.search_index.execute(slab).await?;
+        Ok(Some(Arc::new(index)))
+    }
+
+    /// The index lookups and deletes in `slab` bind: its search index,
+    /// built now if a write dropped it, or one without fences if this
+    /// backend does not index slabs.
+    pub(crate) async fn index_for(
+        &self,
+        slab: &SlabVersion,
+    ) -> Result<Arc<SearchIndex>, GpuMapError> {
+        if let Some(index) = slab.index() {
+            return Ok(Arc::clone(index));
+        }
+        match self.build_index(slab).await? {
+            Some(index) => {
+                slab.set_index(Arc::clone(&index));
+                Ok(index)
+            }
+            None => Ok(Arc::clone(&self.no_index)),
+        }
+    }
+
+    /// Values of live entries in `slab` for `keys`, looked up in batches
+    /// of at most [`batch_limit`](Self::batch_limit) keys.
+    pub(crate) async fn lookup(
+        &self,
+        slab: &SlabVersion,
+        keys: &[Key],
+    ) -> Result<Vec<Option<Value>>, GpuMapError> {
+        let index = self.index_for(slab).await?;
+        if keys.len() <= self.batch_limit as usize {
+            return self.bulk_get.execute(slab, &index, keys).await;
+        }
+        let mut values = Vec::with_capacity(keys.len());
+        for chunk in keys.chunks(self.batch_limit as usize) {
+            values.extend(self.bulk_get.execute(slab, &index, chunk).await?);
+        }
+        Ok(values)
+    }
+
+    /// [`lookup`](Self::lookup) that sorts each batch of keys and merge-joins
+    /// it with `slab`.
+    pub(crate) async fn lookup_sorted(
+        &self,
+        slab: &GpuArray<KvEntry>,
+        keys: &[Key],
+    ) -> Result<Vec<Option<Value>>, GpuMapError> {
+        let mut values = Vec::with_capacity(keys.len());
+        for chunk in keys.chunks(self.batch_limit as usize) {
+            let found = self.sorted_get.execute(&self.bulk_put, slab, chunk).await?;
+            values.extend(found);
+        }
+        Ok(values)
+    }
+
+    /// Live entries of `slab` with keys from `from_key` up to `to_key`, or
+    /// to the end without one, whose value matches `predicate`.
+    pub(crate) async fn filter_slab(
+        &self,
+        slab: &GpuArray<KvEntry>,
+        from_key: Key,
+        to_key: Option<Key>,
+        predicate: &ValuePredicate,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        let (start, end) = match to_key {
+            Some(to_key) => match self.range_scan.bounds(slab, from_key, to_key).await? {
+                Some(bounds) => bounds,
+                None => return Ok(Vec::new()),
+            },
+            None => (0, slab.len().0),
+        };
+        self.filter_scan.execute(slab, start, end, predicate).await
+    }
+
+    /// Tombstone `keys` in `slab`, copying it first if it is shared.
+    pub(crate) async fn delete_from(
+        &self,
+        slab: &mut Arc<SlabVersion>,
+        keys: &[Key],
+    ) -> Result<(), GpuMapError> {
+        let index = self.index_for(slab).await?;
+        let slab = slab::make_values_unique(slab, &self.queue, &self.device);
+        for chunk in keys.chunks(self.batch_limit as usize) {
+            self.bulk_delete.execute(slab, &index, chunk).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Lost flags of the devices maps have been created on. wgpu keeps a single
+/// lost callback per device, so maps sharing a device share its flag.
+static DEVICE_LOST: Mutex<Vec<(Weak<wgpu::Device>, Arc<AtomicBool>)>> = Mutex::new(Vec::new());
+
+/// The lost flag for `device`, registering its lost callback on first use.
+///
+/// The callback also runs when the device is dropped or the callback is
+/// replaced; neither means the device is gone for the maps still using it.
+fn watch_device_lost(device: &Arc<wgpu::Device>) -> Arc<AtomicBool> {
+    let mut watched = DEVICE_LOST.lock().unwrap_or_else(PoisonError::into_inner);
+    watched.retain(|(watched_device, _)| watched_device.strong_count() > 0);
+    if let Some((_, lost)) = watched
+        .iter()
+        .find(|(watched_device, _)| std::ptr::eq(watched_device.as_ptr(), Arc::as_ptr(device)))
+    {
+        return Arc::clone(lost);
+    }
+
+    let lost = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&lost);
+    device.set_device_lost_callback(move |reason, _message| {
+        if !matches!(
+            reason,
+            wgpu::DeviceLostReason::Dropped | wgpu::DeviceLostReason::ReplacedCallback
+        ) {
+            flag.store(true, Ordering::Release);
+        }
+    });
+    watched.push((Arc::downgrade(device), Arc::clone(&lost)));
+    lost
+}
+
+/// One slab version and the backend that reads and writes it.
+#[derive(Clone)]
+pub(crate) enum Store {
+    Gpu {
+        gpu: Arc<GpuBackend>,
+        slab: Arc<SlabVersion>,
+    },
+    /// A GPU slab split into segments, for capacities beyond one binding.
+    Sharded {
+        gpu: Arc<GpuBackend>,
+        shards: ShardedSlab,
+    },
+    /// A GPU slab in leveled mode.
+    Leveled {
+        gpu: Arc<GpuBackend>,
+        runs: LeveledRuns,
+    },
+    Cpu(Arc<CpuSlab>),
+}
+
+impl Store {
+    pub(crate) fn gpu(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        capacity: Capacity,
+        pool: Arc<BufferPool>,
+        workgroup_size: u32,
+    ) -> Self {
+        let segment_capacity = segment_capacity(&device.limits());
+        let gpu = Arc::new(GpuBackend::new(
+            device,
+            queue,
+            Capacity::new(capacity.0.min(segment_capacity.0)),
+            pool,
+            workgroup_size,
+        ));
+        if capacity.0 <= segment_capacity.0 {
+            let slab = gpu.empty_slab(capacity);
+            Store::Gpu { gpu, slab }
+        } else {
+            let shards = ShardedSlab::new(&gpu, capacity, segment_capacity);
+            Store::Sharded { gpu, shards }
+        }
+    }
+
+    pub(crate) fn cpu(capacity: Capacity) -> Self {
+        Store::Cpu(Arc::new(CpuSlab::new(capacity)))
+    }
+
+    /// An empty store with the same backend and capacity. A GPU store shares
+    /// the device, pipelines and scratch buffers.
+    pub(crate) fn sibling(&self) -> Self {
+        match self {
+            Store::Gpu { gpu, slab } => Store::Gpu {
+                gpu: Arc::clone(gpu),
+                slab: gpu.empty_slab(slab.capacity()),
+            },
+            Store::Sharded { gpu, shards } => Store::Sharded {
+                gpu: Arc::clone(gpu),
+                shards: shards.sibling(gpu),
+            },
+            Store::Leveled { gpu, runs } => Store::Leveled {
+                gpu: Arc::clone(gpu),
+                runs: runs.sibling(gpu),
+            },
+            Store::Cpu(slab) => Store::cpu(slab.capacity()),
+        }
+    }
+
+    pub(crate) fn backend(&self) -> Backend {
+        match self {
+            Store::Gpu { .. } | Store::Sharded { .. } | Store::Leveled { .. } => Backend::Gpu,
+            Store::Cpu(_) => Backend::Cpu,
+        }
+    }
+
+    /// GPU backend, if this store lives on the GPU.
+    pub(crate) fn gpu_backend(&self) -> Option<&Arc<GpuBackend>> {
+        match self {
+            Store::Gpu { gpu, .. } | Store::Sharded { gpu, .. } | Store::Leveled { gpu, .. } => {
+                Some(gpu)
+            }
+            Store::Cpu(_) => None,
+        }
+    }
+
+    /// GPU backend and slab, if this store is a single GPU slab.
+    pub(crate) fn gpu_slab(&self) -> Option<(&Arc<GpuBackend>, &SlabVersion)> {
+        match self {
+            Store::Gpu { gpu, slab } => Some((gpu, slab)),
+            Store::Sharded { .. } | Store::Leveled { .. } | Store::Cpu(_) => None,
+        }
+    }
+
+    /// Number of slab segments: more than one only for sharded stores.
+    pub(crate) fn segment_count(&self) -> usize {
+        match self {
+            Store::Sharded { shards, .. } => shards.segment_count(),
+            Store::Gpu { .. } | Store::Leveled { .. } | Store::Cpu(_) => 1,
+        }
+    }
+
+    /// Runs holding entries: one or none outside leveled mode.
+    pub(crate) fn run_count(&self) -> usize {
+        match self {
+            Store::Leveled { runs, .. } => runs.run_count(),
+            _ => usize::from(self.len().0 > 0),
+        }
+    }
+
+    /// Run sizes, if this store is in leveled mode.
+    pub(crate) fn leveled(&self) -> Option<LeveledConfig> {
+        match self {
+            Store::Leveled { runs, .. } => Some(runs.config()),
+            Store::Gpu { .. } | Store::Sharded { .. } | Store::Cpu(_) => None,
+        }
+    }
+
+    /// Switch a GPU store to leveled runs with `config`, or back to a single
+    /// slab with `None`. Leaving leveled mode, or changing its run sizes,
+    /// merges the runs into one. CPU stores are left as they are; sharded
+    /// stores cannot be leveled.
+    pub(crate) async fn set_leveled(
+        &mut self,
+        config: Option<LeveledConfig>,
+    ) -> Result<(), GpuMapError> {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                if let Some(config) = config {
+                    let runs = LeveledRuns::over(gpu, Arc::clone(slab), config);
+                    *self = Store::Leveled {
+                        gpu: Arc::clone(gpu),
+                        runs,
+                    };
+                }
+                Ok(())
+            }
+            Store::Leveled { gpu, runs } => {
+                if config == Some(runs.config()) {
+                    return Ok(());
+                }
+                gpu.check()?;
+                let flattened = runs.flatten(gpu).await;
+                let slab = flattened.map_err(|err| gpu.lost_or(err))?;
+                let gpu = Arc::clone(gpu);
+                *self = match config {
+                    Some(config) => Store::Leveled {
+                        runs: LeveledRuns::over(&gpu, slab, config),
+                        gpu,
+                    },
+                    None => Store::Gpu { gpu, slab },
+                };
+                Ok(())
+            }
+            Store::Sharded { .. } if config.is_some() => Err(GpuMapError::BackendUnsupported {
+                operation: "set_leveled",
+                mode: "maps split into segments",
+            }),
+            Store::Sharded { .. } | Store::Cpu(_) => Ok(()),
+        }
+    }
+
+    /// Largest batch one pipeline call handles; bigger batches are split.
+    pub(crate) fn batch_limit(&self) -> u32 {
+        match self {
+            Store::Gpu { gpu, .. } | Store::Sharded { gpu, .. } | Store::Leveled { gpu, .. } => {
+                gpu.batch_limit
+            }
+            Store::Cpu(_) => u32::MAX,
+        }
+    }
+
+    /// Returns true if this store's device has been lost.
+    pub(crate) fn is_lost(&self) -> bool {
+        match self {
+            Store::Gpu { gpu, .. } | Store::Sharded { gpu, .. } | Store::Leveled { gpu, .. } => {
+                gpu.is_lost()
+            }
+            Store::Cpu(_) => false,
+        }
+    }
+
+    /// Runs this store's futures to completion. Detached from the store so
+    /// the future may borrow it mutably.
+    pub(crate) fn blocker(&self) -> Blocker {
+        match self {
+            Store::Gpu { gpu, .. } | Store::Sharded { gpu, .. } | Store::Leveled { gpu, .. } => {
+                Blocker(Some(Arc::clone(&gpu.device)))
+            }
+            Store::Cpu(_) => Blocker(None),
+        }
+    }
+
+    /// Occupied slab slots, including tombstones.
+    pub(crate) fn len(&self) -> Length {
+        match self {
+            Store::Gpu { slab, .. } => slab.len(),
+            Store::Sharded { shards, .. } => shards.len(),
+            Store::Leveled { runs, .. } => runs.len(),
+            Store::Cpu(slab) => slab.len(),
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> Capacity {
+        match self {
+            Store::Gpu { slab, .. } => slab.capacity(),
+            Store::Sharded { shards, .. } => shards.capacity(),
+            Store::Leveled { runs, .. } => runs.capacity(),
+            Store::Cpu(slab) => slab.capacity(),
+        }
+    }
+
+    /// Values of live entries for `keys`, ignoring TTLs.
+    pub(crate) async fn get(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, GpuMapError> {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                gpu.check()?;
+                let values = gpu.lookup(slab, keys).await;
+                values.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Sharded { gpu, shards } => {
+                gpu.check()?;
+                let values = shards.get(gpu, keys).await;
+                values.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Leveled { gpu, runs } => {
+                gpu.check()?;
+                let values = runs.get(gpu, keys).await;
+                values.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Cpu(slab) => Ok(slab.get(keys)),
+        }
+    }
+
+    /// [`get`](Self::get) by merge-joining the sorted keys with the slab.
+    /// Segmented and leveled slabs look the keys up one by one.
+    pub(crate) async fn get_sorted(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, GpuMapError> {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                gpu.check()?;
+                let values = gpu.lookup_sorted(slab, keys).await;
+                values.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Sharded { .. } | Store::Leveled { .. } | Store::Cpu(_) => self.get(keys).await,
+        }
+    }
+
+    /// Occupied slots with keys in `[from_key, to_key)`, tombstones included.
+    pub(crate) async fn range(
+        &self,
+        from_key: Key,
+        to_key: Key,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                gpu.check()?;
+                let entries = gpu.range_scan.execute(slab, from_key, to_key).await;
+                entries.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Sharded { gpu, shards } => {
+                gpu.check()?;
+                let entries = shards.range(gpu, from_key, to_key).await;
+                entries.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Leveled { gpu, runs } => {
+                gpu.check()?;
+                let entries = runs.range(gpu, from_key, to_key).await;
+                entries.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Cpu(slab) => Ok(slab.range(from_key, to_key)),
+        }
+    }
+
+    /// Live entries with keys from `from_key` up to `to_key`, or to the end
+    /// without one, whose value matches `predicate`.
+    pub(crate) async fn filter(
+        &self,
+        from_key: Key,
+        to_key: Option<Key>,
+        predicate: &ValuePredicate,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                gpu.check()?;
+                let entries = gpu.filter_slab(slab, from_key, to_key, predicate).await;
+                entries.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Sharded { gpu, shards } => {
+                gpu.check()?;
+                let entries = shards.filter(gpu, from_key, to_key, predicate).await;
+                entries.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Leveled { gpu, runs } => {
+                gpu.check()?;
+                let entries = runs.filter(gpu, from_key, to_key, predicate).await;
+                entries.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Cpu(slab) => {
+                let bounds = match to_key {
+                    Some(to_key) => slab.bounds(from_key, to_key),
+                    None => Some((0, slab.len().0)),
+                };
+                Ok(bounds.map_or_else(Vec::new, |(start, end)| slab.filter(start, end, predicate)))
+            }
+        }
+    }
+
+    /// Merge `entries` into a new slab version and publish it. On error the
+    /// current version stays published.
+    ///
+    /// The caller checks reserved values, duplicates and capacity; the
+    /// padded batch must fit in the slab.
+    pub(crate) async fn merge(&mut self, entries: &[KvEntry]) -> Result<(), GpuMapError> {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                gpu.check()?;
+                let merged = gpu.merge_into(slab, entries).await;
+                merged.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Sharded { gpu, shards } => {
+                gpu.check()?;
+                let merged = shards.merge(gpu, entries).await;
+                merged.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Leveled { gpu, runs } => {
+                gpu.check()?;
+                let merged = runs.merge(gpu, entries).await;
+                merged.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Cpu(slab) => {
+                *slab = Arc::new(slab.merged(entries));
+                Ok(())
+            }
+        }
+    }
+
+    /// Tombstone `keys`, copying the slab first if it is shared.
+    pub(crate) async fn delete(&mut self, keys: &[Key]) -> Result<(), GpuMapError> {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                gpu.check()?;
+                let deleted = gpu.delete_from(slab, keys).await;
+                deleted.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Sharded { gpu, shards } => {
+                gpu.check()?;
+                let deleted = shards.delete(gpu, keys).await;
+                deleted.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Leveled { gpu, runs } => {
+                gpu.check()?;
+                let deleted = runs.delete(gpu, keys).await;
+                deleted.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Cpu(slab) => {
+                Arc::make_mut(slab).delete(keys);
+                Ok(())
+            }
+        }
+    }
+
+    /// Rewrite values of live entries with keys in `[lo, hi]`; GPU only.
+    pub(crate) fn map_values(
+        &mut self,
+        wgsl_fn: &str,
+        lo: u32,
+        hi: u32,
+    ) -> Result<(), GpuMapError> {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                gpu.check()?;
+                let slab = slab::make_values_unique(slab, &gpu.queue, &gpu.device);
+                let mapped = gpu.map_values.execute(slab, wgsl_fn, lo, hi);
+                mapped.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Sharded { gpu, shards } => {
+                gpu.check()?;
+                let mapped = shards.map_values(gpu, wgsl_fn, lo, hi);
+                mapped.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Leveled { gpu, runs } => {
+                gpu.check()?;
+                let mapped = runs.map_values(gpu, wgsl_fn, lo, hi);
+                mapped.map_err(|err| gpu.lost_or(err))
+            }
+            Store::Cpu(_) => Err(GpuMapError::BackendUnsupported {
+                operation: "map_values",
+                mode: "the CPU backend",
+            }),
+        }
+    }
+
+    /// Set the stored slab length, copying the slab first if it is shared.
+    /// Leveled runs are merged into one first.
+    pub(crate) fn update_len(&mut self, len: Length) {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                slab::make_unique(slab, &gpu.queue, &gpu.device).update_len(&gpu.queue, len)
+            }
+            Store::Sharded { gpu, shards } => shards.update_len(gpu, len),
+            Store::Leveled { gpu, runs } => runs.update_len(gpu, len),
+            Store::Cpu(slab) => Arc::make_mut(slab).set_len(len),
+        }
+    }
+
+    /// Host copy of every occupied slot, tombstones included.
+    pub(crate) async fn read_all(&self) -> Result<CpuSlab, GpuMapError> {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                gpu.check()?;
+                let entries = gpu.range_scan.read_slots(slab, 0, slab.len().0).await;
+                let entries = entries.map_err(|err| gpu.lost_or(err))?;
+                Ok(CpuSlab::from_entries(entries, slab.capacity()))
+            }
+            Store::Sharded { gpu, shards } => {
+                gpu.check()?;
+                let entries = shards.read_all(gpu).await;
+                let entries = entries.map_err(|err| gpu.lost_or(err))?;
+                Ok(CpuSlab::from_entries(entries, shards.capacity()))
+            }
+            Store::Leveled { gpu, runs } => {
+                gpu.check()?;
+                let entries = runs.read_all(gpu).await;
+                let entries = entries.map_err(|err| gpu.lost_or(err))?;
+                Ok(CpuSlab::from_entries(entries, runs.capacity()))
+            }
+            Store::Cpu(slab) => Ok(CpuSlab::clone(slab)),
+        }
+    }
+
+    /// Replace the slab contents with `contents`, as read by
+    /// [`read_all`](Self::read_all).
+    pub(crate) fn load(&mut self, contents: &CpuSlab) {
+        match self {
+            Store::Gpu { gpu, slab } => {
+                let slab = slab::make_unique(slab, &gpu.queue, &gpu.device);
+                slab.write(&gpu.queue, contents.entries());
+                slab.update_len(&gpu.queue, contents.len());
+            }
+            Store::Sharded { gpu, shards } => shards.load(gpu, contents.entries()),
+            Store::Leveled { gpu, runs } => runs.load(gpu, contents.entries()),
+            Store::Cpu(slab) => *slab = Arc::new(contents.clone()),
+        }
+    }
+}
+
+/// Blocks on futures from a [`Store`], polling its device if it has one.
+pub(crate) struct Blocker(Option<Arc<wgpu::Device>>);
+
+impl Blocker {
+    pub(crate) fn block_on<F: Future>(&self, future: F) -> F::Output {
+        match &self.0 {
+            Some(device) => block_on_device(device, future),
+            None => pollster::block_on(future),
+        }
+    }
+}
+
+/// Map for tests: the GPU backend when an adapter is available, otherwise
+/// the CPU backend. Set `GPUSORTED_MAP_TEST_BACKEND=cpu` to force the CPU
+/// backend.
+#[cfg(test)]
+pub(crate) fn test_map(capacity: Capacity) -> crate::GpuSortedMap {
+    use crate::GpuSortedMap;
+
+    let force_cpu = std::env::var("GPUSORTED_MAP_TEST_BACKEND")
+        .is_ok_and(|backend| backend.eq_ignore_ascii_case("cpu"));
+    if !force_cpu {
+        match pollster::block_on(GpuSortedMap::new(capacity)) {
+            Ok(map) => return map,
+            Err(_) => eprintln!("GPU not available in this environment; using the CPU backend"),
+        }
+    }
+    pollster::block_on(GpuSortedMap::with_backend(capacity, Backend::Cpu))
+        .expect("CPU backend is always available")
+}
@@ -0,0 +1,522 @@
+//! Configuration for creating a [`GpuSortedMap`].
+//!
+//! [`GpuSortedMap::new`] picks the best adapter on any wgpu backend and asks
+//! for no features. A [`GpuSortedMapBuilder`] lets callers pin a wgpu
+//! backend, choose an adapter by name or index, force the software adapter,
+//! request features or limits, and set the map's own options before the map
+//! is created. The device options are kept with the map, so
+//! [`recover`](GpuSortedMap::recover) requests the replacement device the
+//! same way.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::pipelines::wgsl::DEFAULT_WORKGROUP_SIZE;
+use crate::pipelines::DEFAULT_MAX_RETAINED_BYTES;
+use crate::tuning;
+use crate::{
+    validate_device_limits, Backend, Capacity, DispatchPolicy, GpuMapError, GpuSortedMap,
+    LeveledConfig, TuningProfile,
+};
+
+/// Options for creating a [`GpuSortedMap`], started with
+/// [`GpuSortedMap::builder`].
+///
+/// ```no_run
+/// # use gpusorted_map::{Capacity, GpuSortedMap};
+/// # async fn run() -> Result<(), gpusorted_map::GpuMapError> {
+/// let map = GpuSortedMap::builder(Capacity::new(1 << 20))
+///     .wgpu_backends(wgpu::Backends::VULKAN)
+///     .adapter_name("NVIDIA")
+///     .build()
+///     .await?;
+/// println!("running on {}", map.adapter_info().unwrap().name);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct GpuSortedMapBuilder {
+    capacity: Capacity,
+    backend: Backend,
+    device: DeviceRequest,
+    max_retained_bytes: u64,
+    leveled: Option<LeveledConfig>,
+    host_shadow: bool,
+    dispatch: DispatchPolicy,
+    tuning: Tuning,
+    search_index: bool,
+}
+
+/// How a map asks wgpu for its adapter and device.
+#[derive(Debug, Clone)]
+pub(crate) struct DeviceRequest {
+    backends: wgpu::Backends,
+    power_preference: wgpu::PowerPreference,
+    force_fallback_adapter: bool,
+    adapter: Option<AdapterChoice>,
+    features: wgpu::Features,
+    limits: Option<wgpu::Limits>,
+}
+
+/// Where the builder gets the kernels' workgroup size from.
+#[derive(Debug, Clone)]
+enum Tuning {
+    Default,
+    Profile(TuningProfile),
+    Measure,
+    Cache(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+enum AdapterChoice {
+    Name(String),
+    Index(usize),
+}
+
+impl GpuSortedMapBuilder {
+    /// Options matching [`GpuSortedMap::new`] for a map of `capacity` entries.
+    pub fn new(capacity: Capacity) -> Self {
+        Self {
+            capacity,
+            backend: Backend::Gpu,
+            device: DeviceRequest::default(),
+            max_retained_bytes: DEFAULT_MAX_RETAINED_BYTES,
+            leveled: None,
+            host_shadow: false,
+            dispatch: DispatchPolicy::Gpu,
+            tuning: Tuning::Default,
+            search_index: false,
+        }
+    }
+
+    /// Slab capacity of the map.
+    pub fn capacity(&mut self, capacity: Capacity) -> &mut Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Where the map keeps its slab. With [`Backend::Cpu`] the device
+    /// options are ignored.
+    pub fn backend(&mut self, backend: Backend) -> &mut Self {
+        self.backend = backend;
+        self
+    }
+
+    /// wgpu backends to look for adapters on, for example
+    /// `wgpu::Backends::VULKAN`. Defaults to all of them.
+    pub fn wgpu_backends(&mut self, backends: wgpu::Backends) -> &mut Self {
+        self.device.backends = backends;
+        self
+    }
+
+    /// Preference used when no adapter is chosen by name or index.
+    /// Defaults to `HighPerformance`.
+    pub fn power_preference(&mut self, preference: wgpu::PowerPreference) -> &mut Self {
+        self.device.power_preference = preference;
+        self
+    }
+
+    /// Use only the software adapter, for tests that must not depend on
+    /// the host's GPU. Without this, the software adapter is used only when
+    /// no other adapter is found.
+    pub fn force_fallback_adapter(&mut self, force: bool) -> &mut Self {
+        self.device.force_fallback_adapter = force;
+        self
+    }
+
+    /// Use the first adapter whose name contains `name`, ignoring case.
+    /// Replaces an earlier [`adapter_index`](Self::adapter_index).
+    pub fn adapter_name(&mut self, name: impl Into<String>) -> &mut Self {
+        self.device.adapter = Some(AdapterChoice::Name(name.into()));
+        self
+    }
+
+    /// Use the adapter at `index` in
+    /// [`available_adapters`](Self::available_adapters). Replaces an earlier
+    /// [`adapter_name`](Self::adapter_name).
+    pub fn adapter_index(&mut self, index: usize) -> &mut Self {
+        self.device.adapter = Some(AdapterChoice::Index(index));
+        self
+    }
+
+    /// Features to enable on the device, for shaders sharing it through
+    /// [`slab_binding`](GpuSortedMap::slab_binding). The map fails to build
+    /// if the adapter lacks any of them.
+    pub fn features(&mut self, features: wgpu::Features) -> &mut Self {
+        self.device.features = features;
+        self
+    }
+
+    /// Limits to request instead of the highest the adapter supports. They
+    /// must still be enough for the map, as for
+    /// [`with_device`](GpuSortedMap::with_device).
+    pub fn limits(&mut self, limits: wgpu::Limits) -> &mut Self {
+        self.device.limits = Some(limits);
+        self
+    }
+
+    /// Bytes of idle scratch buffers the map's [`BufferPool`](crate::BufferPool)
+    /// keeps for reuse. Defaults to 64 MiB.
+    pub fn max_retained_bytes(&mut self, bytes: u64) -> &mut Self {
+        self.max_retained_bytes = bytes;
+        self
+    }
+
+    /// Start in [leveled mode](GpuSortedMap::set_leveled): run sizes grow by
+    /// `size_ratio` from `l0_capacity`, and a run is compacted into the next
+    /// when it fills.
+    pub fn leveled(&mut self, config: LeveledConfig) -> &mut Self {
+        self.leveled = Some(config);
+        self
+    }
+
+    /// Start with a [search index](GpuSortedMap::set_search_index) for
+    /// lookups and deletes.
+    pub fn search_index(&mut self, enabled: bool) -> &mut Self {
+        self.search_index = enabled;
+        self
+    }
+
+    /// Start with a [host shadow](GpuSortedMap::set_host_shadow).
+    pub fn host_shadow(&mut self, enabled: bool) -> &mut Self {
+        self.host_shadow = enabled;
+        self
+    }
+
+    /// Serve small reads from a host copy, as
+    /// [`set_dispatch`](GpuSortedMap::set_dispatch) does.
+    /// [`DispatchPolicy::Calibrated`] takes the crossover from the tuning
+    /// profile, if there is one, and otherwise measures the new, empty map.
+    pub fn dispatch(&mut self, policy: DispatchPolicy) -> &mut Self {
+        self.dispatch = policy;
+        self
+    }
+
+    /// Measure workgroup sizes and the CPU/GPU crossover on the chosen
+    /// adapter while building, and build the kernels with the fastest size.
+    /// Takes a fraction of a second on a discrete GPU, longer on software
+    /// adapters. [`GpuSortedMap::tuning_profile`] reports the result, and a
+    /// [`DispatchPolicy::Calibrated`] dispatch uses its crossover.
+    pub fn auto_tune(&mut self, enabled: bool) -> &mut Self {
+        self.tuning = if enabled {
+            Tuning::Measure
+        } else {
+            Tuning::Default
+        };
+        self
+    }
+
+    /// Build with a profile from an earlier [`auto_tune`](Self::auto_tune),
+    /// without measuring. Fails to build if the device's limits do not allow
+    /// its workgroup size.
+    pub fn tuning(&mut self, profile: TuningProfile) -> &mut Self {
+        self.tuning = Tuning::Profile(profile);
+        self
+    }
+
+    /// [`auto_tune`](Self::auto_tune), keeping the profile in the file at
+    /// `path`: a profile there that was measured on the chosen adapter is
+    /// used as is, and otherwise the new measurement replaces the file.
+    pub fn tuning_cache(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.tuning = Tuning::Cache(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adapters on the chosen wgpu backends, in the order
+    /// [`adapter_index`](Self::adapter_index) counts them.
+    pub fn available_adapters(&self) -> Vec<wgpu::AdapterInfo> {
+        self.device
+            .instance()
+            .enumerate_adapters(self.device.backends)
+            .iter()
+            .map(wgpu::Adapter::get_info)
+            .collect()
+    }
+
+    /// Create the map.
+    ///
+    /// Returns [`GpuMapError::GpuInitializationFailed`] if no adapter
+    /// matches or it lacks a requested feature, and
+    /// [`GpuMapError::DeviceLimitsInsufficient`] if the device's limits
+    /// cannot run the map. [`GpuSortedMap::adapter_info`] reports the
+    /// adapter that was chosen.
+    pub async fn build(&self) -> Result<GpuSortedMap, GpuMapError> {
+        let mut map = match self.backend {
+            Backend::Gpu => {
+                let (device, queue, adapter) = self.device.request(self.capacity).await?;
+                let (device, queue) = (Arc::new(device), Arc::new(queue));
+                let tuning = match &self.tuning {
+                    Tuning::Default => None,
+                    Tuning::Profile(profile) => {
+                        profile.validate(&device.limits())?;
+                        Some(profile.clone())
+                    }
+                    Tuning::Measure => Some(tuning::measure(&device, &queue, &adapter)?),
+                    Tuning::Cache(path) => {
+                        Some(tuning::cached_or_measure(path, &device, &queue, &adapter)?)
+                    }
+                };
+                let workgroup_size = tuning
+                    .as_ref()
+                    .map_or(DEFAULT_WORKGROUP_SIZE, |profile| profile.workgroup_size);
+                let mut map = GpuSortedMap::from_device(
+                    device,
+                    queue,
+                    self.capacity,
+                    self.max_retained_bytes,
+                    workgroup_size,
+                )?;
+                map.adapter = Some(adapter);
+                map.device_request = self.device.clone();
+                map.tuning = tuning;
+                map
+            }
+            Backend::Cpu => GpuSortedMap::cpu(self.capacity),
+        };
+        if self.leveled.is_some() {
+            map.set_leveled(self.leveled)?;
+        }
+        if self.host_shadow {
+            map.set_host_shadow(true)?;
+        }
+        map.set_search_index(self.search_index);
+        let dispatch = match (self.dispatch, &map.tuning) {
+            (DispatchPolicy::Calibrated, Some(profile)) => {
+                DispatchPolicy::Threshold(profile.dispatch_threshold)
+            }
+            (policy, _) => policy,
+        };
+        map.set_dispatch(dispatch)?;
+        Ok(map)
+    }
+}
+
+impl Default for DeviceRequest {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::Backends::all(),
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            adapter: None,
+            features: wgpu::Features::empty(),
+            limits: None,
+        }
+    }
+}
+
+impl DeviceRequest {
+    fn instance(&self) -> wgpu::Instance {
+        wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: self.backends,
+            ..Default::default()
+        })
+    }
+
+    /// Device on the chosen adapter, with the requested limits or else the
+    /// highest the adapter supports. Fails with
+    /// [`GpuMapError::DeviceLimitsInsufficient`] if those fall short of what
+    /// a map of `capacity` entries needs.
+    pub(crate) async fn request(
+        &self,
+        capacity: Capacity,
+    ) -> Result<(wgpu::Device, wgpu::Queue, wgpu::AdapterInfo), GpuMapError> {
+        let instance = self.instance();
+        let adapter = match &self.adapter {
+            Some(choice) => self.choose(&instance, choice)?,
+            None => self.best(&instance).await?,
+        };
+        let info = adapter.get_info();
+
+        let missing = self.features - adapter.features();
+        if !missing.is_empty() {
+            return Err(GpuMapError::GpuInitializationFailed {
+                message: format!("adapter {} lacks features {:?}", info.name, missing),
+            });
+        }
+        let limits = self.limits.clone().unwrap_or_else(|| adapter.limits());
+        validate_device_limits(&limits, capacity)?;
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("gpu-sorted-map-device"),
+                    required_features: self.features,
+                    required_limits: limits,
+                },
+                None,
+            )
+            .await
+            .map_err(|e| GpuMapError::GpuInitializationFailed {
+                message: format!("failed to request device: {}", e),
+            })?;
+        Ok((device, queue, info))
+    }
+
+    /// Adapter picked by wgpu, falling back to the software adapter.
+    async fn best(&self, instance: &wgpu::Instance) -> Result<wgpu::Adapter, GpuMapError> {
+        if !self.force_fallback_adapter {
+            let adapter = instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: self.power_preference,
+                    compatible_surface: None,
+                    force_fallback_adapter: false,
+                })
+                .await;
+            if let Some(adapter) = adapter {
+                return Ok(adapter);
+            }
+        }
+        instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::LowPower,
+                compatible_surface: None,
+                force_fallback_adapter: true,
+            })
+            .await
+            .ok_or_else(|| GpuMapError::GpuInitializationFailed {
+                message: if self.force_fallback_adapter {
+                    "no fallback adapter found".to_string()
+                } else {
+                    "no suitable GPU adapters found (including fallback)".to_string()
+                },
+            })
+    }
+
+    fn choose(
+        &self,
+        instance: &wgpu::Instance,
+        choice: &AdapterChoice,
+    ) -> Result<wgpu::Adapter, GpuMapError> {
+        let mut adapters = instance.enumerate_adapters(self.backends);
+        let found = match choice {
+            AdapterChoice::Name(name) => {
+                let name = name.to_lowercase();
+                adapters
+                    .iter()
+                    .position(|adapter| adapter.get_info().name.to_lowercase().contains(&name))
+            }
+            AdapterChoice::Index(index) => (*index < adapters.len()).then_some(*index),
+        };
+        match found {
+            Some(index) => Ok(adapters.swap_remove(index)),
+            None => {
+                let names: Vec<String> = adapters
+                    .iter()
+                    .map(|adapter| adapter.get_info().name)
+                    .collect();
+                Err(GpuMapError::GpuInitializationFailed {
+                    message: format!("no adapter matches {:?} among {:?}", choice, names),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GpuSortedMapBuilder;
+    use crate::{Backend, Capacity, GpuMapError, GpuSortedMap, Key, LeveledConfig, Value};
+
+    fn build(builder: &GpuSortedMapBuilder) -> Result<GpuSortedMap, GpuMapError> {
+        pollster::block_on(builder.build())
+    }
+
+    #[test]
+    fn default_builder_reports_the_chosen_adapter() {
+        let Ok(mut map) = build(&GpuSortedMap::builder(Capacity::new(64))) else {
+            eprintln!("Skipping test: GPU not available in this environment");
+            return;
+        };
+        let adapter = map.adapter_info().expect("GPU maps report their adapter");
+        assert!(!adapter.name.is_empty());
+        map.put(Key::new(1), Value::new(10)).unwrap();
+        assert_eq!(map.get(Key::new(1)).unwrap(), Some(Value::new(10)));
+    }
+
+    #[test]
+    fn adapter_can_be_chosen_by_index_or_name() {
+        let mut builder = GpuSortedMap::builder(Capacity::new(64));
+        let adapters = builder.available_adapters();
+        let Some(last) = adapters.last() else {
+            eprintln!("Skipping test: GPU not available in this environment");
+            return;
+        };
+
+        builder.adapter_index(adapters.len() - 1);
+        let map = build(&builder).unwrap();
+        let chosen = map.adapter_info().unwrap();
+        assert_eq!((&chosen.name, chosen.backend), (&last.name, last.backend));
+
+        builder.adapter_name(last.name.to_uppercase());
+        let map = build(&builder).unwrap();
+        assert!(map
+            .adapter_info()
+            .unwrap()
+            .name
+            .eq_ignore_ascii_case(&last.name));
+    }
+
+    #[test]
+    fn unmatched_adapter_or_missing_features_fail_to_build() {
+        let mut builder = GpuSortedMap::builder(Capacity::new(64));
+        builder.adapter_name("no adapter has this name");
+        assert!(matches!(
+            build(&builder),
+            Err(GpuMapError::GpuInitializationFailed { .. })
+        ));
+
+        let mut builder = GpuSortedMap::builder(Capacity::new(64));
+        builder.adapter_index(usize::MAX);
+        assert!(matches!(
+            build(&builder),
+            Err(GpuMapError::GpuInitializationFailed { .. })
+        ));
+
+        let mut builder = GpuSortedMap::builder(Capacity::new(64));
+        builder.features(wgpu::Features::all());
+        assert!(matches!(
+            build(&builder),
+            Err(GpuMapError::GpuInitializationFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn requested_limits_are_validated() {
+        let mut builder = GpuSortedMap::builder(Capacity::new(64));
+        builder.limits(wgpu::Limits {
+            max_storage_buffer_binding_size: 256,
+            ..wgpu::Limits::default()
+        });
+        match build(&builder) {
+            Err(GpuMapError::DeviceLimitsInsufficient { limit, .. }) => {
+                assert_eq!(limit, "max_storage_buffer_binding_size");
+            }
+            Err(GpuMapError::GpuInitializationFailed { .. }) => {
+                eprintln!("Skipping test: GPU not available in this environment");
+            }
+            other => panic!("expected insufficient limits, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn map_options_are_applied() {
+        let config = LeveledConfig {
+            l0_capacity: Capacity::new(8),
+            size_ratio: 2,
+        };
+        let mut builder = GpuSortedMap::builder(Capacity::new(32));
+        builder
+            .capacity(Capacity::new(64))
+            .leveled(config)
+            .host_shadow(true);
+        let map = match build(&builder) {
+            Ok(map) => map,
+            Err(_) => build(builder.backend(Backend::Cpu)).unwrap(),
+        };
+        assert_eq!(map.capacity(), Capacity::new(64));
+        match map.backend() {
+            Backend::Gpu => assert_eq!(map.leveled(), Some(config)),
+            Backend::Cpu => assert!(map.adapter_info().is_none()),
+        }
+        assert!(map.host.as_ref().is_some_and(|host| host.is_shadow()));
+    }
+}
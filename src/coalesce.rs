@@ -0,0 +1,531 @@
+//! Request coalescing for single-key operations.
+//!
+//! Single-key `get`/`put`/`delete` calls each pay a full GPU round trip. A
+//! [`Coalescer`] queues them from any number of threads or tasks and hands
+//! them to a worker thread. The worker collects requests until the batch
+//! window closes or the batch is full, then issues one [`WriteBatch`] for the
+//! writes followed by one `bulk_get` for the reads, and sends each caller its
+//! own result.
+//!
+//! Running the writes before the reads lets a get see every write queued
+//! alongside it. A write to a key with a get queued ahead of it would
+//! overtake that get, so it starts a new batch instead: a caller that queues
+//! a get and then a put of one key reads the value from before its put.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::{GpuMapError, Key, SharedGpuSortedMap, Value, WriteBatch, TOMBSTONE_VALUE};
+
+/// When a [`Coalescer`] flushes its pending requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoalesceConfig {
+    /// How long to wait for more requests after the first one arrives.
+    pub window: Duration,
+    /// Flush as soon as this many requests are pending.
+    pub max_batch: usize,
+}
+
+impl Default for CoalesceConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_micros(200),
+            max_batch: 4096,
+        }
+    }
+}
+
+/// Counters for requests served by a [`Coalescer`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CoalesceStats {
+    /// Requests answered.
+    pub requests: u64,
+    /// Batches flushed to the map. A window splits into several batches
+    /// when a write follows a get of the same key.
+    pub batches: u64,
+}
+
+enum Request {
+    Get(Key, Responder<Option<Value>>),
+    Write(Key, Option<Value>, Responder<()>),
+}
+
+/// Batching front-end for single-key operations on a shared map.
+///
+/// Blocking calls ([`get`](Self::get), [`put`](Self::put),
+/// [`delete`](Self::delete)) suit thread-per-request servers; the `*_async`
+/// variants return a [`Reply`] future for async tasks. Dropping the
+/// coalescer answers any queued requests and stops its worker thread.
+pub struct Coalescer {
+    map: Arc<SharedGpuSortedMap>,
+    sender: Option<mpsc::Sender<Request>>,
+    counters: Arc<Counters>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    batches: AtomicU64,
+}
+
+impl Coalescer {
+    /// Start a worker that batches requests against `map`.
+    pub fn new(map: Arc<SharedGpuSortedMap>, config: CoalesceConfig) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let counters = Arc::new(Counters::default());
+        let worker_map = Arc::clone(&map);
+        let worker_counters = Arc::clone(&counters);
+        let max_batch = config.max_batch.max(1);
+        let thread = std::thread::Builder::new()
+            .name("gpusorted-map-coalescer".into())
+            .spawn(move || {
+                while let Ok(first) = receiver.recv() {
+                    let deadline = Instant::now() + config.window;
+                    let mut batch = vec![first];
+                    while batch.len() < max_batch {
+                        let timeout = deadline.saturating_duration_since(Instant::now());
+                        match receiver.recv_timeout(timeout) {
+                            Ok(request) => batch.push(request),
+                            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+                                break
+                            }
+                        }
+                    }
+                    worker_counters
+                        .requests
+                        .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    flush(&worker_map, &worker_counters, batch);
+                }
+            })
+            .expect("failed to spawn coalescer thread");
+        Self {
+            map,
+            sender: Some(sender),
+            counters,
+            thread: Some(thread),
+        }
+    }
+
+    /// Look up `key`, blocking until its batch completes.
+    pub fn get(&self, key: Key) -> Result<Option<Value>, GpuMapError> {
+        self.get_async(key).wait()
+    }
+
+    /// Insert or update `key`, blocking until its batch is published.
+    pub fn put(&self, key: Key, value: Value) -> Result<(), GpuMapError> {
+        self.put_async(key, value).wait()
+    }
+
+    /// Delete `key`, blocking until its batch is published.
+    pub fn delete(&self, key: Key) -> Result<(), GpuMapError> {
+        self.delete_async(key).wait()
+    }
+
+    /// Queue a lookup of `key`.
+    pub fn get_async(&self, key: Key) -> Reply<Option<Value>> {
+        let (responder, reply) = reply_pair();
+        self.send(Request::Get(key, responder));
+        reply
+    }
+
+    /// Queue a put of `key`. A reserved tombstone value is rejected without
+    /// being queued.
+    pub fn put_async(&self, key: Key, value: Value) -> Reply<()> {
+        let (responder, reply) = reply_pair();
+        if value == TOMBSTONE_VALUE {
+            responder.send(Err(GpuMapError::TombstoneValueReserved { value }));
+        } else {
+            self.send(Request::Write(key, Some(value), responder));
+        }
+        reply
+    }
+
+    /// Queue a delete of `key`.
+    pub fn delete_async(&self, key: Key) -> Reply<()> {
+        let (responder, reply) = reply_pair();
+        self.send(Request::Write(key, None, responder));
+        reply
+    }
+
+    /// Requests answered and batches flushed so far.
+    pub fn stats(&self) -> CoalesceStats {
+        CoalesceStats {
+            requests: self.counters.requests.load(Ordering::Relaxed),
+            batches: self.counters.batches.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The map requests are applied to.
+    pub fn map(&self) -> &Arc<SharedGpuSortedMap> {
+        &self.map
+    }
+
+    fn send(&self, request: Request) {
+        // The worker only exits once the sender is dropped, so it is still
+        // receiving; a send error means it panicked, which closes the reply.
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(request);
+        }
+    }
+}
+
+impl Drop for Coalescer {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Flush `batch` in as few batches as keep each key's requests in order.
+fn flush(map: &SharedGpuSortedMap, counters: &Counters, batch: Vec<Request>) {
+    let mut writes = Vec::new();
+    let mut gets = Vec::new();
+    let mut read = HashSet::new();
+    for request in batch {
+        match request {
+            Request::Get(key, responder) => {
+                read.insert(key);
+                gets.push((key, responder));
+            }
+            Request::Write(key, value, responder) => {
+                if read.contains(&key) {
+                    counters.batches.fetch_add(1, Ordering::Relaxed);
+                    flush_batch(map, std::mem::take(&mut writes), std::mem::take(&mut gets));
+                    read.clear();
+                }
+                writes.push((key, value, responder));
+            }
+        }
+    }
+    counters.batches.fetch_add(1, Ordering::Relaxed);
+    flush_batch(map, writes, gets);
+}
+
+/// Apply `writes`, then answer `gets`.
+fn flush_batch(
+    map: &SharedGpuSortedMap,
+    writes: Vec<(Key, Option<Value>, Responder<()>)>,
+    gets: Vec<(Key, Responder<Option<Value>>)>,
+) {
+    if !writes.is_empty() {
+        let mut write_batch = WriteBatch::new();
+        for (key, value, _) in &writes {
+            match value {
+                Some(value) => write_batch.put(*key, *value),
+                None => write_batch.delete(*key),
+            };
+        }
+        match map.apply_batch(&write_batch) {
+            Ok(()) => {
+                for (_, _, responder) in writes {
+                    responder.send(Ok(()));
+                }
+            }
+            // Apply one at a time so only the writes that do not fit fail.
+            Err(GpuMapError::CapacityExceeded { .. }) => {
+                for (key, value, responder) in writes {
+                    responder.send(match value {
+                        Some(value) => map.put(key, value),
+                        None => map.delete(key),
+                    });
+                }
+            }
+            Err(err) => {
+                for (_, _, responder) in writes {
+                    responder.send(Err(err.clone()));
+                }
+            }
+        }
+    }
+
+    if !gets.is_empty() {
+        let keys: Vec<Key> = gets.iter().map(|(key, _)| *key).collect();
+        match map.bulk_get(&keys) {
+            Ok(values) => {
+                for ((_, responder), value) in gets.into_iter().zip(values) {
+                    responder.send(Ok(value));
+                }
+            }
+            Err(err) => {
+                for (_, responder) in gets {
+                    responder.send(Err(err.clone()));
+                }
+            }
+        }
+    }
+}
+
+enum SlotState<T> {
+    Pending(Option<Waker>),
+    Ready(T),
+    Taken,
+    Closed,
+}
+
+struct Slot<T> {
+    state: Mutex<SlotState<T>>,
+    ready: Condvar,
+}
+
+fn reply_pair<T>() -> (Responder<T>, Reply<T>) {
+    let slot = Arc::new(Slot {
+        state: Mutex::new(SlotState::Pending(None)),
+        ready: Condvar::new(),
+    });
+    (
+        Responder {
+            slot: Some(Arc::clone(&slot)),
+        },
+        Reply { slot },
+    )
+}
+
+/// Worker side of a reply. Dropping it unanswered closes the reply.
+struct Responder<T> {
+    slot: Option<Arc<Slot<Result<T, GpuMapError>>>>,
+}
+
+impl<T> Responder<T> {
+    fn send(mut self, value: Result<T, GpuMapError>) {
+        if let Some(slot) = self.slot.take() {
+            slot.complete(SlotState::Ready(value));
+        }
+    }
+}
+
+impl<T> Drop for Responder<T> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            slot.complete(SlotState::Closed);
+        }
+    }
+}
+
+impl<T> Slot<T> {
+    fn complete(&self, next: SlotState<T>) {
+        let previous = std::mem::replace(&mut *self.lock(), next);
+        if let SlotState::Pending(Some(waker)) = previous {
+            waker.wake();
+        }
+        self.ready.notify_all();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SlotState<T>> {
+        self.state.lock().expect("coalescer reply poisoned")
+    }
+}
+
+/// Result of a coalesced request.
+///
+/// Await it from an async task, or call [`wait`](Self::wait) to block the
+/// current thread. Resolves to [`GpuMapError::CoalescerStopped`] if the
+/// coalescer's worker stopped without answering.
+pub struct Reply<T> {
+    slot: Arc<Slot<Result<T, GpuMapError>>>,
+}
+
+impl<T> Reply<T> {
+    /// Block until the request's batch completes.
+    pub fn wait(self) -> Result<T, GpuMapError> {
+        let mut state = self.slot.lock();
+        loop {
+            match std::mem::replace(&mut *state, SlotState::Taken) {
+                SlotState::Ready(value) => return value,
+                SlotState::Closed => return Err(GpuMapError::CoalescerStopped),
+                SlotState::Taken => panic!("reply already taken"),
+                pending @ SlotState::Pending(_) => {
+                    *state = pending;
+                    state = self
+                        .slot
+                        .ready
+                        .wait(state)
+                        .expect("coalescer reply poisoned");
+                }
+            }
+        }
+    }
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T, GpuMapError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.slot.lock();
+        match std::mem::replace(&mut *state, SlotState::Taken) {
+            SlotState::Ready(value) => Poll::Ready(value),
+            SlotState::Closed => Poll::Ready(Err(GpuMapError::CoalescerStopped)),
+            SlotState::Taken => panic!("reply polled after completion"),
+            SlotState::Pending(_) => {
+                *state = SlotState::Pending(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::test_map;
+    use crate::{Capacity, KvEntry, Length};
+
+    fn create_map(capacity: Capacity) -> Arc<SharedGpuSortedMap> {
+        Arc::new(SharedGpuSortedMap::new(test_map(capacity)))
+    }
+
+    #[test]
+    fn coalesces_concurrent_single_key_calls() {
+        let map = create_map(Capacity::new(1024));
+        let entries: Vec<KvEntry> = (0..256)
+            .map(|i| KvEntry {
+                key: Key::new(i),
+                value: Value::new(i * 3),
+            })
+            .collect();
+        map.bulk_put(&entries).unwrap();
+        let coalescer = Coalescer::new(
+            Arc::clone(&map),
+            CoalesceConfig {
+                window: Duration::from_millis(5),
+                max_batch: 1024,
+            },
+        );
+
+        std::thread::scope(|scope| {
+            for t in 0..8u32 {
+                let coalescer = &coalescer;
+                scope.spawn(move || {
+                    for i in 0..32u32 {
+                        let key = Key::new(t * 32 + i);
+                        assert_eq!(coalescer.get(key).unwrap(), Some(Value::new(key.0 * 3)));
+                        coalescer
+                            .put(Key::new(1000 + key.0), Value::new(key.0))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let stats = coalescer.stats();
+        assert_eq!(stats.requests, 512);
+        assert!(stats.batches < stats.requests, "{stats:?}");
+        assert_eq!(map.len(), Length::new(512));
+        assert_eq!(
+            coalescer.get(Key::new(1255)).unwrap(),
+            Some(Value::new(255))
+        );
+    }
+
+    #[test]
+    fn async_replies_see_writes_queued_in_the_same_batch() {
+        let map = create_map(Capacity::new(64));
+        map.put(Key::new(1), Value::new(10)).unwrap();
+        let coalescer = Coalescer::new(
+            map,
+            CoalesceConfig {
+                window: Duration::from_millis(50),
+                max_batch: 3,
+            },
+        );
+
+        let put = coalescer.put_async(Key::new(2), Value::new(20));
+        let delete = coalescer.delete_async(Key::new(1));
+        let get = coalescer.get_async(Key::new(2));
+        let rejected = coalescer.put_async(Key::new(3), TOMBSTONE_VALUE);
+
+        pollster::block_on(async {
+            assert_eq!(put.await, Ok(()));
+            assert_eq!(delete.await, Ok(()));
+            assert_eq!(get.await, Ok(Some(Value::new(20))));
+            assert!(matches!(
+                rejected.await,
+                Err(GpuMapError::TombstoneValueReserved { .. })
+            ));
+        });
+        assert_eq!(coalescer.get(Key::new(1)).unwrap(), None);
+        assert_eq!(
+            coalescer.stats(),
+            CoalesceStats {
+                requests: 4,
+                batches: 2
+            }
+        );
+    }
+
+    #[test]
+    fn write_after_a_get_of_the_same_key_starts_a_new_batch() {
+        let map = create_map(Capacity::new(64));
+        map.put(Key::new(1), Value::new(10)).unwrap();
+        let coalescer = Coalescer::new(
+            map,
+            CoalesceConfig {
+                window: Duration::from_millis(50),
+                max_batch: 4,
+            },
+        );
+
+        let before = coalescer.get_async(Key::new(1));
+        let put = coalescer.put_async(Key::new(1), Value::new(11));
+        let other = coalescer.put_async(Key::new(2), Value::new(20));
+        let after = coalescer.get_async(Key::new(1));
+
+        assert_eq!(before.wait(), Ok(Some(Value::new(10))));
+        assert_eq!(put.wait(), Ok(()));
+        assert_eq!(other.wait(), Ok(()));
+        assert_eq!(after.wait(), Ok(Some(Value::new(11))));
+        assert_eq!(
+            coalescer.stats(),
+            CoalesceStats {
+                requests: 4,
+                batches: 2
+            }
+        );
+    }
+
+    #[test]
+    fn unanswered_reply_resolves_to_an_error() {
+        let (responder, reply) = reply_pair::<()>();
+        drop(responder);
+        assert_eq!(reply.wait(), Err(GpuMapError::CoalescerStopped));
+
+        let (responder, reply) = reply_pair::<()>();
+        drop(responder);
+        assert_eq!(
+            pollster::block_on(reply),
+            Err(GpuMapError::CoalescerStopped)
+        );
+    }
+
+    #[test]
+    fn capacity_overflow_fails_only_writes_that_do_not_fit() {
+        let map = create_map(Capacity::new(4));
+        let coalescer = Coalescer::new(
+            Arc::clone(&map),
+            CoalesceConfig {
+                window: Duration::from_millis(50),
+                max_batch: 6,
+            },
+        );
+
+        let replies: Vec<_> = (0..6)
+            .map(|i| coalescer.put_async(Key::new(i), Value::new(i)))
+            .collect();
+        let results: Vec<_> = replies.into_iter().map(Reply::wait).collect();
+
+        assert!(results[..4].iter().all(Result::is_ok));
+        assert!(results[4..]
+            .iter()
+            .all(|r| matches!(r, Err(GpuMapError::CapacityExceeded { .. }))));
+        assert_eq!(map.len(), Length::new(4));
+    }
+}
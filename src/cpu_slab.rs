@@ -0,0 +1,184 @@
+//! Host-memory slab for [`Backend::Cpu`](crate::Backend::Cpu).
+//!
+//! [`CpuSlab`] holds the same sorted array of entries as the GPU slab, with
+//! deleted entries left in place as tombstones until the next put compacts
+//! them away. Each method follows the shader it stands in for, so both
+//! backends return the same results for the same sequence of operations.
+
+use crate::{Capacity, Key, KvEntry, Length, Value, ValuePredicate, TOMBSTONE_VALUE};
+
+/// Sorted slab kept in host memory.
+#[derive(Clone, Debug)]
+pub(crate) struct CpuSlab {
+    /// Occupied slots, sorted by key and unique. Mirrors `slab[0..len)`.
+    entries: Vec<KvEntry>,
+    capacity: Capacity,
+}
+
+impl CpuSlab {
+    pub(crate) fn new(capacity: Capacity) -> Self {
+        Self {
+            entries: Vec::new(),
+            capacity,
+        }
+    }
+
+    /// Slab holding `entries`, which must be sorted by key and unique.
+    pub(crate) fn from_entries(mut entries: Vec<KvEntry>, capacity: Capacity) -> Self {
+        entries.truncate(capacity.0 as usize);
+        Self { entries, capacity }
+    }
+
+    /// Occupied slots in key order.
+    pub(crate) fn entries(&self) -> &[KvEntry] {
+        &self.entries
+    }
+
+    /// Occupied slots, including tombstones.
+    pub(crate) fn len(&self) -> Length {
+        Length::new(self.entries.len() as u32)
+    }
+
+    pub(crate) fn capacity(&self) -> Capacity {
+        self.capacity
+    }
+
+    /// Values of live entries for `keys`, like the bulk-get kernel.
+    pub(crate) fn get(&self, keys: &[Key]) -> Vec<Option<Value>> {
+        keys.iter()
+            .map(|&key| {
+                self.find(key)
+                    .map(|index| self.entries[index].value)
+                    .filter(|value| *value != TOMBSTONE_VALUE)
+            })
+            .collect()
+    }
+
+    /// Slot range holding keys in `[from_key, to_key)`, or `None` if it is
+    /// empty.
+    pub(crate) fn bounds(&self, from_key: Key, to_key: Key) -> Option<(u32, u32)> {
+        if from_key >= to_key || self.entries.is_empty() {
+            return None;
+        }
+        let start = self.lower_bound(from_key) as u32;
+        let end = self.lower_bound(to_key) as u32;
+        (end > start).then_some((start, end))
+    }
+
+    /// Occupied slots with keys in `[from_key, to_key)`, tombstones included.
+    pub(crate) fn range(&self, from_key: Key, to_key: Key) -> Vec<KvEntry> {
+        match self.bounds(from_key, to_key) {
+            Some((start, end)) => self.entries[start as usize..end as usize].to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Live entries in slots `[start, end)` whose value matches `predicate`.
+    pub(crate) fn filter(&self, start: u32, end: u32, predicate: &ValuePredicate) -> Vec<KvEntry> {
+        let end = (end as usize).min(self.entries.len());
+        let start = (start as usize).min(end);
+        self.entries[start..end]
+            .iter()
+            .filter(|entry| entry.value != TOMBSTONE_VALUE && predicate.matches(entry.value))
+            .copied()
+            .collect()
+    }
+
+    /// Merge `input` into a new slab, like the bulk-put pipeline.
+    ///
+    /// The input is sorted and de-duplicated with the last entry per key
+    /// winning. Input entries replace slab entries with the same key, and
+    /// tombstoned slab entries are dropped.
+    pub(crate) fn merged(&self, input: &[KvEntry]) -> Self {
+        let mut sorted = input.to_vec();
+        sorted.sort_by_key(|entry| entry.key);
+        let mut dedup: Vec<KvEntry> = Vec::with_capacity(sorted.len());
+        for entry in sorted {
+            match dedup.last_mut() {
+                Some(last) if last.key == entry.key => *last = entry,
+                _ => dedup.push(entry),
+            }
+        }
+
+        let live = |entry: &&KvEntry| entry.value != TOMBSTONE_VALUE;
+        let mut entries = Vec::with_capacity(self.entries.len() + dedup.len());
+        let (mut slab, mut input) = (self.entries.iter().peekable(), dedup.iter().peekable());
+        while let (Some(old), Some(new)) = (slab.peek(), input.peek()) {
+            if old.key < new.key {
+                entries.extend(slab.next().filter(live));
+            } else {
+                if old.key == new.key {
+                    slab.next();
+                }
+                entries.extend(input.next());
+            }
+        }
+        entries.extend(slab.filter(live));
+        entries.extend(input);
+        entries.truncate(self.capacity.0 as usize);
+
+        Self {
+            entries,
+            capacity: self.capacity,
+        }
+    }
+
+    /// Tombstone the entries for `keys`, like the bulk-delete kernel.
+    pub(crate) fn delete(&mut self, keys: &[Key]) {
+        for &key in keys {
+            if let Some(index) = self.find(key) {
+                self.entries[index].value = TOMBSTONE_VALUE;
+            }
+        }
+    }
+
+    /// Set the occupied length, clamped to the capacity. New slots read as
+    /// zeroed entries, as in a freshly allocated GPU buffer.
+    pub(crate) fn set_len(&mut self, len: Length) {
+        let len = len.0.min(self.capacity.0) as usize;
+        self.entries.resize(len, KvEntry::default());
+    }
+
+    fn lower_bound(&self, key: Key) -> usize {
+        self.entries.partition_point(|entry| entry.key < key)
+    }
+
+    fn find(&self, key: Key) -> Option<usize> {
+        let index = self.lower_bound(key);
+        (self.entries.get(index)?.key == key).then_some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: u32, value: u32) -> KvEntry {
+        KvEntry {
+            key: Key::new(key),
+            value: Value::new(value),
+        }
+    }
+
+    #[test]
+    fn merge_replaces_equal_keys_and_compacts_tombstones() {
+        let slab =
+            CpuSlab::new(Capacity::new(8)).merged(&[entry(1, 10), entry(3, 30), entry(5, 50)]);
+        let mut deleted = slab.clone();
+        deleted.delete(&[Key::new(3), Key::new(4)]);
+        assert_eq!(deleted.len(), Length::new(3));
+        assert_eq!(slab.get(&[Key::new(3)]), vec![Some(Value::new(30))]);
+
+        let merged = deleted.merged(&[entry(5, 55), entry(2, 20)]);
+        assert_eq!(
+            merged.range(Key::new(0), Key::new(10)),
+            vec![entry(1, 10), entry(2, 20), entry(5, 55)]
+        );
+        assert_eq!(merged.bounds(Key::new(2), Key::new(5)), Some((1, 2)));
+        assert_eq!(merged.bounds(Key::new(3), Key::new(5)), None);
+        assert_eq!(
+            merged.filter(0, 3, &ValuePredicate::Gt(Value::new(15))),
+            vec![entry(2, 20), entry(5, 55)]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Hybrid CPU/GPU dispatch for reads.
+//!
+//! A GPU lookup pays a fixed cost for the submission and readback, so a
+//! binary search in host memory answers small batches sooner. With a
+//! [host shadow](crate::GpuSortedMap::set_host_shadow) on, the map already
+//! keeps a sorted copy of its slab that every write is applied to. A
+//! [`HostMirror`] wraps that copy in a [`Snapshot`] over
+//! [`Store::Cpu`](crate::backend::Store), and reads smaller than the
+//! dispatch threshold are served from it instead of the device.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::backend::Store;
+use crate::cpu_slab::CpuSlab;
+use crate::{GpuMapError, Key, Length, Snapshot};
+
+/// Batch sizes past this are left to the GPU without being measured.
+const MAX_CALIBRATED_BATCH: usize = 1 << 16;
+/// Timed runs per batch size and path; the fastest one counts.
+const CALIBRATION_RUNS: usize = 3;
+
+/// Which reads [`GpuSortedMap::set_dispatch`](crate::GpuSortedMap::set_dispatch)
+/// sends to the host mirror.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DispatchPolicy {
+    /// Every read runs on the map's backend.
+    #[default]
+    Gpu,
+    /// `bulk_get` calls with fewer keys, and `range` calls spanning fewer
+    /// slab entries, than this are served from the host mirror.
+    Threshold(usize),
+    /// Measure lookups on both paths against the map's current contents
+    /// and use the smallest batch size at which the GPU wins.
+    Calibrated,
+}
+
+/// Host copy of a map's slabs, served for reads below `threshold`.
+#[derive(Clone)]
+pub(crate) struct HostMirror {
+    slab: Arc<CpuSlab>,
+    view: Box<Snapshot>,
+    threshold: usize,
+}
+
+impl HostMirror {
+    pub(crate) fn new(
+        slab: Arc<CpuSlab>,
+        live_len: Length,
+        expiries: Option<Arc<CpuSlab>>,
+        clock: u32,
+        threshold: usize,
+    ) -> Self {
+        let view = Snapshot::new(
+            Store::Cpu(Arc::clone(&slab)),
+            live_len,
+            expiries.map(Store::Cpu),
+            clock,
+        );
+        Self {
+            slab,
+            view: Box::new(view),
+            threshold,
+        }
+    }
+
+    /// The mirror, if a lookup of `len` keys should use it.
+    pub(crate) fn for_batch(&self, len: usize) -> Option<&Snapshot> {
+        (len < self.threshold).then_some(&*self.view)
+    }
+
+    /// The mirror, if a scan of `[from_key, to_key)` should use it.
+    pub(crate) fn for_range(&self, from_key: Key, to_key: Key) -> Option<&Snapshot> {
+        let slots = self
+            .slab
+            .bounds(from_key, to_key)
+            .map_or(0, |(start, end)| end - start);
+        self.for_batch(slots as usize)
+    }
+}
+
+/// Smallest power-of-four batch at which `store` answers lookups faster
+/// than `host`, or the first size past those measured if it never does.
+pub(crate) fn calibrate(store: &Store, host: &CpuSlab) -> Result<usize, GpuMapError> {
+    let largest = MAX_CALIBRATED_BATCH.min(store.batch_limit() as usize);
+    let blocker = store.blocker();
+    let mut batch = 1;
+    while batch <= largest {
+        let keys = probe_keys(batch);
+        let mut gpu = Duration::MAX;
+        let mut cpu = Duration::MAX;
+        for _ in 0..CALIBRATION_RUNS {
+            let start = Instant::now();
+            blocker.block_on(store.get(&keys))?;
+            gpu = gpu.min(start.elapsed());
+
+            let start = Instant::now();
+            std::hint::black_box(host.get(&keys));
+            cpu = cpu.min(start.elapsed());
+        }
+        if gpu < cpu {
+            return Ok(batch);
+        }
+        batch *= 4;
+    }
+    Ok(batch)
+}
+
+/// `len` keys spread over the whole key space.
+fn probe_keys(len: usize) -> Vec<Key> {
+    (0..len as u32)
+        .map(|i| Key::new(i.wrapping_mul(0x9E37_79B9)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DispatchPolicy;
+    use crate::backend::test_map;
+    use crate::{Backend, Capacity, Key, KvEntry, Value};
+
+    fn entries(keys: impl Iterator<Item = u32>) -> Vec<KvEntry> {
+        keys.map(|key| KvEntry {
+            key: Key::new(key),
+            value: Value::new(key * 10),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn small_reads_use_the_mirror_and_large_ones_the_backend() {
+        let mut map = test_map(Capacity::new(256));
+        map.bulk_put(&entries(0..100)).unwrap();
+        map.set_dispatch(DispatchPolicy::Threshold(8)).unwrap();
+        if map.backend() == Backend::Cpu {
+            assert_eq!(map.dispatch_threshold(), 0);
+            return;
+        }
+        assert_eq!(map.dispatch_threshold(), 8);
+        let mirror = map
+            .host_mirror()
+            .expect("the mirror turns the host shadow on");
+        assert!(mirror.for_batch(7).is_some());
+        assert!(mirror.for_batch(8).is_none());
+        assert!(mirror.for_range(Key::new(10), Key::new(17)).is_some());
+        assert!(mirror.for_range(Key::new(10), Key::new(18)).is_none());
+
+        // Writes, deletes and TTLs reach both copies.
+        map.bulk_delete(&[Key::new(3)]).unwrap();
+        map.put(Key::new(150), Value::new(1)).unwrap();
+        map.put_with_ttl(Key::new(151), Value::new(2), 5).unwrap();
+        let small = [Key::new(3), Key::new(4), Key::new(150), Key::new(151)];
+        let large: Vec<Key> = small.iter().copied().cycle().take(16).collect();
+        let expected = vec![
+            None,
+            Some(Value::new(40)),
+            Some(Value::new(1)),
+            Some(Value::new(2)),
+        ];
+        assert_eq!(map.bulk_get(&small).unwrap(), expected);
+        assert_eq!(map.bulk_get(&large).unwrap()[..4], expected);
+        assert_eq!(map.snapshot().bulk_get(&small).unwrap(), expected);
+        assert_eq!(
+            map.range(Key::new(148), Key::new(152)).unwrap(),
+            map.range(Key::new(0), Key::new(1000)).unwrap()[99..]
+        );
+
+        map.advance_clock(5);
+        assert_eq!(map.bulk_get(&small).unwrap()[3], None);
+        assert_eq!(map.bulk_get(&large).unwrap()[3], None);
+
+        map.set_dispatch(DispatchPolicy::Gpu).unwrap();
+        assert!(map.host_mirror().is_none());
+    }
+
+    #[test]
+    fn calibration_picks_a_threshold() {
+        let mut map = test_map(Capacity::new(1024));
+        map.bulk_put(&entries(0..512)).unwrap();
+        map.set_dispatch(DispatchPolicy::Calibrated).unwrap();
+        if map.backend() == Backend::Gpu {
+            assert!(map.dispatch_threshold() >= 1);
+            assert!(map.dispatch_threshold().is_power_of_two());
+        }
+        assert_eq!(map.get(Key::new(7)).unwrap(), Some(Value::new(70)));
+    }
+}
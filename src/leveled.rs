@@ -0,0 +1,362 @@
+//! Leveled runs that make small puts cheap.
+//!
+//! A bulk put merges its batch with the whole slab into a new version, so it
+//! costs as much as the map is large. In leveled mode the slab is a list of
+//! sorted runs, newest first, whose capacities grow by a size ratio from a
+//! small L0 up to the map's capacity. Puts merge into L0. A run that cannot
+//! take what is merged into it is first pushed down into the next one, so
+//! the last run is only rewritten once every level above it has filled.
+//!
+//! A key may have copies in several runs; the newest wins. Deletes tombstone
+//! the key in every run, so an older live copy is never hidden behind a
+//! newer tombstone: lookups take the first live copy, newest run first, and
+//! pushing a run down drops its tombstones.
+
+use std::sync::Arc;
+
+use crate::backend::GpuBackend;
+use crate::pipelines::utils::block_on_device;
+use crate::slab::{self, SlabVersion};
+use crate::{Capacity, GpuMapError, Key, KvEntry, Length, Value, ValuePredicate};
+
+/// Run sizes for [`GpuSortedMap::set_leveled`](crate::GpuSortedMap::set_leveled).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeveledConfig {
+    /// Capacity of the newest run, which takes every put. Rounded up to a
+    /// power of two.
+    pub l0_capacity: Capacity,
+    /// How much larger each run is than the one before it. At least 2.
+    pub size_ratio: u32,
+}
+
+impl Default for LeveledConfig {
+    fn default() -> Self {
+        Self {
+            l0_capacity: Capacity::new(4096),
+            size_ratio: 8,
+        }
+    }
+}
+
+impl LeveledConfig {
+    /// Capacities of the runs of a map of `capacity` entries, newest first.
+    /// All but the last are powers of two, so a full run pads to no more
+    /// than its own size when it is merged into the next.
+    fn run_capacities(&self, capacity: Capacity) -> Vec<Capacity> {
+        let ratio = u64::from(self.size_ratio.max(2));
+        let mut run = u64::from(self.l0_capacity.0.max(1).next_power_of_two());
+        let mut capacities = Vec::new();
+        while run < u64::from(capacity.0) {
+            capacities.push(Capacity::new(run as u32));
+            run = (run * ratio).next_power_of_two();
+        }
+        capacities.push(capacity);
+        capacities
+    }
+}
+
+/// A slab kept as sorted runs of growing capacity, newest first.
+#[derive(Clone)]
+pub(crate) struct LeveledRuns {
+    runs: Vec<Arc<SlabVersion>>,
+    config: LeveledConfig,
+}
+
+impl LeveledRuns {
+    /// Empty runs for a map of `capacity` entries.
+    pub(crate) fn new(gpu: &GpuBackend, capacity: Capacity, config: LeveledConfig) -> Self {
+        let runs = config
+            .run_capacities(capacity)
+            .into_iter()
+            .map(|run| gpu.empty_slab(run))
+            .collect();
+        Self { runs, config }
+    }
+
+    /// Runs over the existing `slab`, which becomes the oldest.
+    pub(crate) fn over(gpu: &GpuBackend, slab: Arc<SlabVersion>, config: LeveledConfig) -> Self {
+        let mut capacities = config.run_capacities(slab.capacity());
+        capacities.pop();
+        let mut runs: Vec<Arc<SlabVersion>> = capacities
+            .into_iter()
+            .map(|run| gpu.empty_slab(run))
+            .collect();
+        runs.push(slab);
+        Self { runs, config }
+    }
+
+    /// Empty runs with the same capacities.
+    pub(crate) fn sibling(&self, gpu: &GpuBackend) -> Self {
+        Self::new(gpu, self.capacity(), self.config)
+    }
+
+    pub(crate) fn config(&self) -> LeveledConfig {
+        self.config
+    }
+
+    /// Occupied slots across all runs, including tombstones and copies
+    /// shadowed by newer runs.
+    pub(crate) fn len(&self) -> Length {
+        Length::new(self.runs.iter().map(|run| run.len().0).sum())
+    }
+
+    pub(crate) fn capacity(&self) -> Capacity {
+        self.last().capacity()
+    }
+
+    /// Runs holding any slots.
+    pub(crate) fn run_count(&self) -> usize {
+        self.runs.iter().filter(|run| run.len().0 > 0).count()
+    }
+
+    fn last(&self) -> &Arc<SlabVersion> {
+        self.runs.last().expect("leveled slab has at least one run")
+    }
+
+    /// Values of live entries for `keys`, newest run first.
+    pub(crate) async fn get(
+        &self,
+        gpu: &GpuBackend,
+        keys: &[Key],
+    ) -> Result<Vec<Option<Value>>, GpuMapError> {
+        newest_live(gpu, &self.runs, keys).await
+    }
+
+    /// The newest copy of each key in `[from_key, to_key)`, tombstones
+    /// included, in key order.
+    pub(crate) async fn range(
+        &self,
+        gpu: &GpuBackend,
+        from_key: Key,
+        to_key: Key,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        let mut entries = Vec::new();
+        for run in self.runs.iter().filter(|run| run.len().0 > 0) {
+            entries.extend(gpu.range_scan.execute(run, from_key, to_key).await?);
+        }
+        Ok(newest_copies(entries))
+    }
+
+    /// Live entries with keys from `from_key` up to `to_key` (or the end)
+    /// whose newest value matches `predicate`.
+    pub(crate) async fn filter(
+        &self,
+        gpu: &GpuBackend,
+        from_key: Key,
+        to_key: Option<Key>,
+        predicate: &ValuePredicate,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        let mut found = Vec::new();
+        for (index, run) in self.runs.iter().enumerate() {
+            if run.len().0 == 0 {
+                continue;
+            }
+            let matches = gpu.filter_slab(run, from_key, to_key, predicate).await?;
+            if index == 0 || matches.is_empty() {
+                found.extend(matches);
+                continue;
+            }
+            // A live copy in a newer run replaces this one.
+            let keys: Vec<Key> = matches.iter().map(|entry| entry.key).collect();
+            let newer = newest_live(gpu, &self.runs[..index], &keys).await?;
+            found.extend(
+                matches
+                    .into_iter()
+                    .zip(newer)
+                    .filter(|(_, newer)| newer.is_none())
+                    .map(|(entry, _)| entry),
+            );
+        }
+        found.sort_unstable_by_key(|entry| entry.key);
+        Ok(found)
+    }
+
+    /// Merge `entries` into the smallest run that fits the padded batch,
+    /// pushing newer runs down first so it stays the newest copy. On error
+    /// the runs are left as they were.
+    pub(crate) async fn merge(
+        &mut self,
+        gpu: &GpuBackend,
+        entries: &[KvEntry],
+    ) -> Result<(), GpuMapError> {
+        let mut next = self.clone();
+        let padded = (entries.len() as u32).next_power_of_two();
+        let target = next
+            .runs
+            .iter()
+            .position(|run| run.capacity().0 >= padded)
+            .unwrap_or(next.runs.len() - 1);
+        for level in 0..target {
+            next.push_down(gpu, level).await?;
+        }
+        next.make_room(gpu, target, entries.len() as u32).await?;
+        gpu.merge_into(&mut next.runs[target], entries).await?;
+        *self = next;
+        Ok(())
+    }
+
+    /// Push runs down, starting at `level`, until `level` can take
+    /// `incoming` more slots. The last run always can: it holds the map's
+    /// capacity, which bounds the live entries of any merge into it.
+    async fn make_room(
+        &mut self,
+        gpu: &GpuBackend,
+        level: usize,
+        incoming: u32,
+    ) -> Result<(), GpuMapError> {
+        let last = self.runs.len() - 1;
+        let mut full = level;
+        let mut incoming = incoming;
+        while full < last && self.runs[full].len().0 + incoming > self.runs[full].capacity().0 {
+            incoming = self.runs[full].len().0;
+            full += 1;
+        }
+        // Runs `level..full` are full; the run below each has room once
+        // the ones below it have moved.
+        for run in (level..full).rev() {
+            self.merge_down(gpu, run).await?;
+        }
+        Ok(())
+    }
+
+    /// Move run `level` into the run below it, making room there first.
+    async fn push_down(&mut self, gpu: &GpuBackend, level: usize) -> Result<(), GpuMapError> {
+        if self.runs[level].len().0 == 0 {
+            return Ok(());
+        }
+        let incoming = self.runs[level].len().0;
+        self.make_room(gpu, level + 1, incoming).await?;
+        self.merge_down(gpu, level).await
+    }
+
+    /// Merge run `level` into the run below it and empty it. The caller has
+    /// made room.
+    async fn merge_down(&mut self, gpu: &GpuBackend, level: usize) -> Result<(), GpuMapError> {
+        let (upper, lower) = self.runs.split_at_mut(level + 1);
+        gpu.merge_run(&mut lower[0], &upper[level]).await?;
+        clear(gpu, &mut upper[level]);
+        Ok(())
+    }
+
+    /// Merge every run into the last one and return it.
+    pub(crate) async fn flatten(&self, gpu: &GpuBackend) -> Result<Arc<SlabVersion>, GpuMapError> {
+        let (last, newer) = self.runs.split_last().expect("leveled slab has runs");
+        let mut last = Arc::clone(last);
+        // Oldest first, so newer copies are merged over older ones.
+        for run in newer.iter().rev() {
+            gpu.merge_run(&mut last, run).await?;
+        }
+        Ok(last)
+    }
+
+    /// Tombstone `keys` in every run.
+    pub(crate) async fn delete(
+        &mut self,
+        gpu: &GpuBackend,
+        keys: &[Key],
+    ) -> Result<(), GpuMapError> {
+        for run in self.runs.iter_mut().filter(|run| run.len().0 > 0) {
+            gpu.delete_from(run, keys).await?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite values of live entries with keys in `[lo, hi]` in every run.
+    /// Shadowed copies change too, which no read can observe.
+    pub(crate) fn map_values(
+        &mut self,
+        gpu: &GpuBackend,
+        wgsl_fn: &str,
+        lo: u32,
+        hi: u32,
+    ) -> Result<(), GpuMapError> {
+        for run in &mut self.runs {
+            if run.len().0 == 0 {
+                // Still compiles the function, so a bad one fails on an
+                // empty map too.
+                gpu.map_values.execute(run, wgsl_fn, lo, hi)?;
+                continue;
+            }
+            let run = slab::make_values_unique(run, &gpu.queue, &gpu.device);
+            gpu.map_values.execute(run, wgsl_fn, lo, hi)?;
+        }
+        Ok(())
+    }
+
+    /// Merge the runs into the last one, then set its length. Blocks on the
+    /// merge; if it fails the runs are left as they are, and the next
+    /// operation reports the failure.
+    pub(crate) fn update_len(&mut self, gpu: &GpuBackend, len: Length) {
+        let Ok(flattened) = block_on_device(&gpu.device, self.flatten(gpu)) else {
+            return;
+        };
+        let (last, newer) = self.runs.split_last_mut().expect("leveled slab has runs");
+        for run in newer {
+            clear(gpu, run);
+        }
+        *last = flattened;
+        slab::make_unique(last, &gpu.queue, &gpu.device).update_len(&gpu.queue, len);
+    }
+
+    /// Every slot's newest copy in key order, tombstones included.
+    pub(crate) async fn read_all(&self, gpu: &GpuBackend) -> Result<Vec<KvEntry>, GpuMapError> {
+        let mut entries = Vec::with_capacity(self.len().0 as usize);
+        for run in &self.runs {
+            entries.extend(gpu.range_scan.read_slots(run, 0, run.len().0).await?);
+        }
+        Ok(newest_copies(entries))
+    }
+
+    /// Replace the contents with sorted `entries`, all in the last run.
+    pub(crate) fn load(&mut self, gpu: &GpuBackend, entries: &[KvEntry]) {
+        let (last, newer) = self.runs.split_last_mut().expect("leveled slab has runs");
+        for run in newer {
+            clear(gpu, run);
+        }
+        let last = slab::make_unique(last, &gpu.queue, &gpu.device);
+        last.write(&gpu.queue, entries);
+        last.update_len(&gpu.queue, Length::new(entries.len() as u32));
+    }
+}
+
+/// Values of the first live copy of each of `keys` in `runs`, newest first.
+/// Each run is only asked for the keys the newer ones did not hold.
+async fn newest_live(
+    gpu: &GpuBackend,
+    runs: &[Arc<SlabVersion>],
+    keys: &[Key],
+) -> Result<Vec<Option<Value>>, GpuMapError> {
+    let mut values = vec![None; keys.len()];
+    let mut missing: Vec<usize> = (0..keys.len()).collect();
+    for run in runs.iter().filter(|run| run.len().0 > 0) {
+        if missing.is_empty() {
+            break;
+        }
+        let run_keys: Vec<Key> = missing.iter().map(|&position| keys[position]).collect();
+        let found = gpu.lookup(run, &run_keys).await?;
+        let mut still_missing = Vec::new();
+        for (position, value) in missing.into_iter().zip(found) {
+            match value {
+                Some(value) => values[position] = Some(value),
+                None => still_missing.push(position),
+            }
+        }
+        missing = still_missing;
+    }
+    Ok(values)
+}
+
+/// Sort `entries`, gathered newest run first, by key and keep the newest
+/// copy of each.
+fn newest_copies(mut entries: Vec<KvEntry>) -> Vec<KvEntry> {
+    entries.sort_by_key(|entry| entry.key);
+    entries.dedup_by_key(|entry| entry.key);
+    entries
+}
+
+/// Publish an empty version of `run`.
+fn clear(gpu: &GpuBackend, run: &mut Arc<SlabVersion>) {
+    let mut empty = slab::take_spare(run);
+    empty.update_len(&gpu.queue, Length::new(0));
+    slab::publish(run, empty);
+}
@@ -20,7 +20,8 @@
 //! - Scenarios where PCIe transfer latency can be amortized over many operations
 //!
 //! It may not be optimal for:
-//! - Single-key operations (use a CPU-based map instead)
+//! - Single-key operations (use a CPU-based map, batch concurrent calls with a [`Coalescer`], or
+//!   serve small reads from a host mirror with [`GpuSortedMap::set_dispatch`])
 //! - Very small datasets (<1000 items)
 //! - Workloads requiring frequent updates with small batches
 //!
@@ -35,7 +36,7 @@
 //!     KvEntry { key: Key::new(1), value: Value::new(10) },
 //!     KvEntry { key: Key::new(2), value: Value::new(20) },
 //! ])?;
-//! assert_eq!(map.get(Key::new(1)), Some(Value::new(10)));
+//! assert_eq!(map.get(Key::new(1))?, Some(Value::new(10)));
 //! # Ok(())
 //! # }
 //! ```
@@ -61,7 +62,7 @@
 //!
 //! // Efficient batch lookup
 //! let keys: Vec<Key> = vec![Key::new(0), Key::new(100), Key::new(1000)];
-//! let values = map.bulk_get(&keys);
+//! let values = map.bulk_get(&keys)?;
 //! # Ok(())
 //! # }
 //! ```
@@ -80,7 +81,7 @@
 //! ])?;
 //!
 //! // Half-open range [10, 30) - includes 10 and 20, but not 30
-//! let entries = map.range(Key::new(10), Key::new(30));
+//! let entries = map.range(Key::new(10), Key::new(30))?;
 //! assert_eq!(entries.len(), 2);
 //! # Ok(())
 //! # }
@@ -88,7 +89,11 @@
 //!
 //! # Error Handling
 //!
-//! Operations that can fail return [`Result<T, GpuMapError>`](GpuMapError):
+//! Operations that can fail return [`Result<T, GpuMapError>`](GpuMapError).
+//! Reads fail too: if the GPU rejects a command, runs out of memory, or a
+//! readback buffer cannot be mapped, the call returns an error instead of
+//! panicking.
+//!
 //!
 //! ```rust,no_run
 //! use gpusorted_map::{Capacity, GpuSortedMap, Key, KvEntry, Value, GpuMapError};
@@ -125,18 +130,57 @@
 //!
 //! If no GPU is available, wgpu will attempt to use a CPU-based software adapter
 //! (if available in your environment).
-
+//! Without any adapter, [`GpuSortedMap::with_backend`] with [`Backend::Cpu`]
+//! keeps the slab in host memory and gives the same results.
+
+mod backend;
+mod builder;
+mod coalesce;
+mod cpu_slab;
+mod dispatch;
 mod gpu_array;
+mod leveled;
 mod pipelines;
+mod poller;
+mod predicate;
+mod recovery;
+mod sharded;
+mod shared;
+mod slab;
+mod snapshot;
+mod submission;
+mod ttl;
+mod tuning;
+mod write_batch;
 
 use bytemuck::{Pod, Zeroable};
 use std::collections::HashSet;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-use crate::gpu_array::{GpuArray, GpuStorage};
-use crate::pipelines::{
-    BulkDeletePipeline, BulkGetPipeline, BulkPutPipeline, MergeMeta, RangeScanPipeline,
-};
+use crate::backend::Store;
+use crate::builder::DeviceRequest;
+use crate::dispatch::HostMirror;
+use crate::pipelines::search_index::INDEX_FENCES;
+use crate::pipelines::wgsl::DEFAULT_WORKGROUP_SIZE;
+use crate::pipelines::DEFAULT_MAX_RETAINED_BYTES;
+use crate::recovery::HostCopy;
+use crate::ttl::ExpiryView;
+
+pub use crate::backend::Backend;
+pub use crate::builder::GpuSortedMapBuilder;
+pub use crate::coalesce::{CoalesceConfig, CoalesceStats, Coalescer, Reply};
+pub use crate::dispatch::DispatchPolicy;
+pub use crate::leveled::LeveledConfig;
+pub use crate::pipelines::{BufferPool, PoolStats, PooledBuffer, SLAB_WGSL};
+pub use crate::poller::DevicePoller;
+pub use crate::predicate::ValuePredicate;
+pub use crate::shared::SharedGpuSortedMap;
+pub use crate::snapshot::Snapshot;
+pub use crate::submission::{BatchId, BatchResult, SubmissionQueue};
+pub use crate::tuning::TuningProfile;
+pub use crate::write_batch::WriteBatch;
 
 /// Key wrapper to distinguish keys from other `u32` values.
 #[repr(transparent)]
@@ -248,123 +292,310 @@ pub struct KvEntry {
 
 const TOMBSTONE_VALUE: Value = Value(0xFFFF_FFFF);
 
+/// Layout of the slab exposed by [`GpuSortedMap::slab_binding`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlabLayout {
+    /// Bytes between consecutive entries (`size_of::<KvEntry>()`).
+    pub entry_stride: u64,
+    /// Byte offset of `key` within an entry.
+    pub key_offset: u64,
+    /// Byte offset of `value` within an entry.
+    pub value_offset: u64,
+    /// Occupied slab slots, sorted by key. Includes tombstoned slots, so it can
+    /// exceed [`GpuSortedMap::len`]. Mirrors `SlabMeta.len` on the GPU.
+    pub len: Length,
+    /// Number of entry slots allocated in the slab buffer.
+    pub capacity: Capacity,
+    /// Value marking a deleted slot. Slots holding it must be skipped.
+    pub tombstone: Value,
+}
+
+/// Borrowed view of the slab buffers for binding in user compute shaders.
+///
+/// Entries in `slab[0..layout.len)` are sorted by key and unique. Bind the
+/// slab as `var<storage, read>`: writing to it can break the ordering the map
+/// relies on. `meta` is a uniform buffer holding `SlabMeta`. See
+/// [`SLAB_WGSL`] for matching WGSL declarations.
+#[derive(Clone, Copy, Debug)]
+pub struct SlabBinding<'a> {
+    pub slab: &'a wgpu::Buffer,
+    pub meta: &'a wgpu::Buffer,
+    pub layout: SlabLayout,
+}
+
 /// GPU-backed sorted map with batched operations.
 pub struct GpuSortedMap {
-    queue: Arc<wgpu::Queue>,
-    slab: GpuArray<KvEntry>,
-    input: GpuArray<KvEntry>,
-    merge: GpuArray<KvEntry>,
-    merge_meta: GpuStorage<MergeMeta>,
-    bulk_get: BulkGetPipeline,
-    bulk_delete: BulkDeletePipeline,
-    bulk_put: BulkPutPipeline,
-    range_scan: RangeScanPipeline,
+    store: Store,
     live_len: Length,
+    /// Expiry times of entries written with a TTL, created on first use.
+    expiries: Option<Box<GpuSortedMap>>,
+    clock: u32,
+    /// Copy of the slab for [`recover`](Self::recover), if one was taken.
+    host: Option<HostCopy>,
+    /// How [`recover`](Self::recover) requests a new device.
+    device_request: DeviceRequest,
+    /// Adapter the map's device was requested from, if the map requested it.
+    adapter: Option<wgpu::AdapterInfo>,
+    /// Reads smaller than this are served from the host shadow.
+    dispatch_threshold: usize,
+    /// Profile the map's kernels were built with, if it was tuned.
+    tuning: Option<TuningProfile>,
 }
 
 impl GpuSortedMap {
     /// Create a new map with the given slab capacity.
+    ///
+    /// The device is requested with the highest limits the adapter
+    /// supports. A slab larger than one storage binding is split into
+    /// [segments](Self::segment_count). Returns
+    /// [`GpuMapError::DeviceLimitsInsufficient`] if the limits cannot run
+    /// the map's shaders.
     pub async fn new(capacity: Capacity) -> Result<Self, GpuMapError> {
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::all(),
-            ..Default::default()
-        });
-        let adapter = match instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                compatible_surface: None,
-                force_fallback_adapter: false,
-            })
-            .await
-        {
-            Some(adapter) => adapter,
-            None => instance
-                .request_adapter(&wgpu::RequestAdapterOptions {
-                    power_preference: wgpu::PowerPreference::LowPower,
-                    compatible_surface: None,
-                    force_fallback_adapter: true,
-                })
-                .await
-                .ok_or_else(|| GpuMapError::GpuInitializationFailed {
-                    message: "no suitable GPU adapters found (including fallback)".to_string(),
-                })?,
-        };
+        Self::with_backend(capacity, Backend::Gpu).await
+    }
 
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: Some("gpu-sorted-map-device"),
-                    required_features: wgpu::Features::empty(),
-                    required_limits: wgpu::Limits::default(),
-                },
-                None,
-            )
-            .await
-            .map_err(|e| GpuMapError::GpuInitializationFailed {
-                message: format!("failed to request device: {}", e),
-            })?;
-        let device = Arc::new(device);
-        let queue = Arc::new(queue);
-
-        let slab = GpuArray::new(
-            &device,
-            capacity,
-            wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::COPY_DST
-                | wgpu::BufferUsages::COPY_SRC,
-            "slab-buffer",
-        );
+    /// Create a new map with the given slab capacity on `backend`.
+    ///
+    /// [`Backend::Cpu`] never fails; [`Backend::Gpu`] behaves like
+    /// [`new`](Self::new).
+    pub async fn with_backend(capacity: Capacity, backend: Backend) -> Result<Self, GpuMapError> {
+        Self::builder(capacity).backend(backend).build().await
+    }
 
-        let input = GpuArray::new(
-            &device,
-            capacity,
-            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            "input-buffer",
-        );
+    /// Options for a map of `capacity` entries, for choosing the adapter and
+    /// device or starting with non-default settings.
+    pub fn builder(capacity: Capacity) -> GpuSortedMapBuilder {
+        GpuSortedMapBuilder::new(capacity)
+    }
 
-        let merge = GpuArray::new(
-            &device,
+    /// Create a new map on an existing device and queue.
+    ///
+    /// The map's pipelines and buffers are created on `device`, so they can be
+    /// shared with the rest of an application's GPU work. Returns
+    /// [`GpuMapError::DeviceLimitsInsufficient`] if the device's limits cannot
+    /// run the map's shaders or bind a slab segment of 64 entries (or the
+    /// whole slab, if smaller).
+    ///
+    /// The map sets the device's lost callback to detect
+    /// [device loss](Self::is_device_lost); setting another callback on the
+    /// device afterwards turns that detection off.
+    pub fn with_device(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        capacity: Capacity,
+    ) -> Result<Self, GpuMapError> {
+        Self::from_device(
+            device,
+            queue,
             capacity,
-            wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::COPY_DST
-                | wgpu::BufferUsages::COPY_SRC,
-            "merge-buffer",
-        );
+            DEFAULT_MAX_RETAINED_BYTES,
+            DEFAULT_WORKGROUP_SIZE,
+        )
+    }
 
-        let merge_meta = GpuStorage::new(
-            &device,
-            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-            "merge-meta-buffer",
-        );
+    fn from_device(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        capacity: Capacity,
+        max_retained_bytes: u64,
+        workgroup_size: u32,
+    ) -> Result<Self, GpuMapError> {
+        validate_device_limits(&device.limits(), capacity)?;
+        let pool = Arc::new(BufferPool::new(Arc::clone(&device), max_retained_bytes));
+        Ok(Self::with_store(Store::gpu(
+            device,
+            queue,
+            capacity,
+            pool,
+            workgroup_size,
+        )))
+    }
 
-        let bulk_get = BulkGetPipeline::new(Arc::clone(&device), Arc::clone(&queue));
-        let bulk_delete = BulkDeletePipeline::new(Arc::clone(&device), Arc::clone(&queue));
-        let bulk_put = BulkPutPipeline::new(Arc::clone(&device), Arc::clone(&queue));
-        let range_scan = RangeScanPipeline::new(Arc::clone(&device), Arc::clone(&queue));
+    fn cpu(capacity: Capacity) -> Self {
+        Self::with_store(Store::cpu(capacity))
+    }
 
-        Ok(Self {
-            queue,
-            slab,
-            input,
-            merge,
-            merge_meta,
-            bulk_get,
-            bulk_delete,
-            bulk_put,
-            range_scan,
+    fn with_store(store: Store) -> Self {
+        Self {
+            store,
             live_len: Length::new(0),
-        })
+            expiries: None,
+            clock: 0,
+            host: None,
+            device_request: DeviceRequest::default(),
+            adapter: None,
+            dispatch_threshold: 0,
+            tuning: None,
+        }
+    }
+
+    /// Backend the map was created on.
+    pub fn backend(&self) -> Backend {
+        self.store.backend()
+    }
+
+    /// Adapter the map's device came from, when the map requested the
+    /// device itself. `None` on [`Backend::Cpu`] and for devices passed to
+    /// [`with_device`](Self::with_device) or
+    /// [`recover_with_device`](Self::recover_with_device).
+    pub fn adapter_info(&self) -> Option<&wgpu::AdapterInfo> {
+        self.adapter.as_ref()
+    }
+
+    /// Workgroup size and dispatch crossover the map was built with, for
+    /// maps [tuned](GpuSortedMapBuilder::auto_tune) at startup. Store it
+    /// with `to_string()` and pass it to
+    /// [`GpuSortedMapBuilder::tuning`] to skip measuring next time.
+    pub fn tuning_profile(&self) -> Option<&TuningProfile> {
+        self.tuning.as_ref()
     }
 
     /// Batch lookup of keys.
-    pub fn bulk_get(&self, keys: &[Key]) -> Vec<Option<Value>> {
-        self.bulk_get.execute(&self.slab, keys)
+    ///
+    /// Returns [`GpuMapError::BufferMapFailed`], [`GpuMapError::ValidationFailed`]
+    /// or [`GpuMapError::OutOfMemory`] if the GPU cannot run the lookup or
+    /// return its results.
+    pub fn bulk_get(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, GpuMapError> {
+        self.store.blocker().block_on(self.bulk_get_async(keys))
+    }
+
+    /// Non-blocking [`bulk_get`](Self::bulk_get).
+    ///
+    /// The lookup is submitted when the future is first polled and resolves
+    /// once its readback buffer is mapped. Mapping only progresses while the
+    /// device is polled, so pair this with [`poll`](Self::poll) or a
+    /// [`DevicePoller`] thread. On [`Backend::Cpu`] the future is ready as
+    /// soon as it is polled.
+    pub async fn bulk_get_async(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, GpuMapError> {
+        if let Some(mirror) = self.host_mirror() {
+            if let Some(view) = mirror.for_batch(keys.len()) {
+                return view.bulk_get_async(keys).await;
+            }
+        }
+        let mut values = self.store.get(keys).await?;
+        if let Some(expiries) = self.expiry_view() {
+            expiries.hide_values(keys, &mut values).await?;
+        }
+        Ok(values)
+    }
+
+    /// Batch lookup that merge-joins the keys with the slab instead of
+    /// searching for each key separately.
+    ///
+    /// The keys are sorted on the GPU, each remembering its position, and
+    /// then walked together with the slab in one linear pass, split across
+    /// threads by merge path; values come back in the order of `keys`.
+    /// Since the pass reads the whole slab, it pays off for batches that
+    /// are large next to the slab or hit it densely, as in joins; small
+    /// batches are faster with [`bulk_get`](Self::bulk_get). Segmented and
+    /// leveled slabs fall back to `bulk_get`.
+    pub fn bulk_get_sorted(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, GpuMapError> {
+        self.store
+            .blocker()
+            .block_on(self.bulk_get_sorted_async(keys))
+    }
+
+    /// Non-blocking [`bulk_get_sorted`](Self::bulk_get_sorted), with the
+    /// polling requirements of [`bulk_get_async`](Self::bulk_get_async).
+    pub async fn bulk_get_sorted_async(
+        &self,
+        keys: &[Key],
+    ) -> Result<Vec<Option<Value>>, GpuMapError> {
+        if let Some(mirror) = self.host_mirror() {
+            if let Some(view) = mirror.for_batch(keys.len()) {
+                return view.bulk_get_async(keys).await;
+            }
+        }
+        let mut values = self.store.get_sorted(keys).await?;
+        if let Some(expiries) = self.expiry_view() {
+            expiries.hide_values(keys, &mut values).await?;
+        }
+        Ok(values)
     }
 
     /// Batch insert/update of entries.
     pub fn bulk_put(&mut self, entries: &[KvEntry]) -> Result<(), GpuMapError> {
+        self.store.blocker().block_on(self.bulk_put_async(entries))
+    }
+
+    /// Non-blocking [`bulk_put`](Self::bulk_put).
+    ///
+    /// Resolves once the merged slab length has been read back. Polling
+    /// requirements are the same as for [`bulk_get_async`](Self::bulk_get_async).
+    pub async fn bulk_put_async(&mut self, entries: &[KvEntry]) -> Result<(), GpuMapError> {
+        let keys = self.merge_entries(entries).await?;
+        // A plain put clears any TTL the keys had.
+        if let Some(expiries) = self.live_expiries_mut() {
+            expiries.delete_keys(&keys).await?;
+        }
+        Ok(())
+    }
+
+    /// Batch insert/update of entries that expire `ttl` ticks after the
+    /// current [`clock`](Self::clock).
+    ///
+    /// Once the clock reaches an entry's expiry time, reads treat it as
+    /// absent; [`expire`](Self::expire) removes it. Putting the key again,
+    /// with or without a TTL, replaces its expiry. If the expiry times
+    /// cannot be written, the entries are not written either.
+    pub fn bulk_put_with_ttl(&mut self, entries: &[KvEntry], ttl: u32) -> Result<(), GpuMapError> {
+        self.store.blocker().block_on(async {
+            // Merges publish new versions, so holding the old ones lets a
+            // failed expiry write undo the data merge.
+            let saved = (self.store.clone(), self.live_len, self.host.clone());
+            let keys = self.merge_entries(entries).await?;
+            if keys.is_empty() {
+                return Ok(());
+            }
+            let at = ttl::expiry_at(self.clock, ttl);
+            let expiries: Vec<KvEntry> =
+                keys.iter().map(|&key| KvEntry { key, value: at }).collect();
+            let written = self.expiries_mut().bulk_put_async(&expiries).await;
+            if written.is_err() {
+                (self.store, self.live_len, self.host) = saved;
+            }
+            written
+        })
+    }
+
+    /// Single-key convenience wrapper over
+    /// [`bulk_put_with_ttl`](Self::bulk_put_with_ttl).
+    pub fn put_with_ttl(&mut self, key: Key, value: Value, ttl: u32) -> Result<(), GpuMapError> {
+        self.bulk_put_with_ttl(&[KvEntry { key, value }], ttl)
+    }
+
+    /// Current logical time used for TTLs.
+    pub fn clock(&self) -> u32 {
+        self.clock
+    }
+
+    /// Move the logical clock forward to `now`. Entries whose expiry time is
+    /// at or before the clock read as absent. The clock never moves back.
+    pub fn advance_clock(&mut self, now: u32) {
+        self.clock = self.clock.max(now);
+    }
+
+    /// Advance the clock to `now`, then tombstone every expired entry on the
+    /// GPU and drop it from [`len`](Self::len). Returns how many entries
+    /// expired.
+    pub fn expire(&mut self, now: u32) -> Result<usize, GpuMapError> {
+        self.advance_clock(now);
+        let Some(expiries) = self.expiries.as_deref() else {
+            return Ok(0);
+        };
+        let expired: Vec<Key> = expiries
+            .filter(&ValuePredicate::Le(Value::new(self.clock)))?
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+        self.bulk_delete(&expired)?;
+        Ok(expired.len())
+    }
+
+    /// Merge `entries` into a new slab version, returning their keys.
+    async fn merge_entries(&mut self, entries: &[KvEntry]) -> Result<Vec<Key>, GpuMapError> {
         if entries.is_empty() {
-            return Ok(());
+            return Ok(Vec::new());
         }
 
         if entries.iter().any(|entry| entry.value == TOMBSTONE_VALUE) {
@@ -375,44 +606,115 @@ impl GpuSortedMap {
 
         let unique_keys =
             unique_keys_from_entries(entries).map_err(|key| GpuMapError::DuplicateKeys { key })?;
-        let existing = self.count_existing_keys(&unique_keys);
+        let existing = self.count_existing_keys(&unique_keys).await?;
         let net_new = unique_keys.len().saturating_sub(existing) as u32;
+        let capacity = self.capacity();
         let requested = Length::new(self.live_len.0 + net_new);
-        if requested.0 > self.slab.capacity().0 {
+        if requested.0 > capacity.0 {
             return Err(GpuMapError::CapacityExceeded {
-                capacity: self.slab.capacity(),
+                capacity,
                 requested,
             });
         }
+        self.check_padded_batch(entries.len())?;
 
-        self.input.write(&self.queue, entries);
-        let len = Length::new(entries.len() as u32);
-        let merge_len = self.bulk_put.execute(
-            &self.slab,
-            &self.input,
-            &self.merge,
-            &self.merge_meta,
-            len.0,
-        )?;
-        self.update_len(Length::new(merge_len));
+        self.store.merge(entries).await?;
         self.live_len = Length::new(self.live_len.0 + net_new);
+        let live_len = self.live_len;
+        if let Some(shadow) = self.shadow_mut() {
+            shadow.merge(entries, live_len);
+        }
+        Ok(unique_keys)
+    }
+
+    /// The GPU sort pads a batch of `len` entries to a power of two inside
+    /// the input buffer. Both backends reject batches whose padding does not
+    /// fit.
+    fn check_padded_batch(&self, len: usize) -> Result<(), GpuMapError> {
+        let capacity = self.capacity();
+        let padded_len = (len as u32).next_power_of_two();
+        if padded_len > capacity.0 {
+            return Err(GpuMapError::CapacityExceeded {
+                capacity,
+                requested: Length::new(self.store.len().0.saturating_add(padded_len)),
+            });
+        }
         Ok(())
     }
 
     /// Batch delete of keys.
-    pub fn bulk_delete(&mut self, keys: &[Key]) {
+    ///
+    /// Fails like [`bulk_get`](Self::bulk_get) if the GPU cannot run the
+    /// delete.
+    pub fn bulk_delete(&mut self, keys: &[Key]) -> Result<(), GpuMapError> {
+        self.store.blocker().block_on(async {
+            self.delete_keys(keys).await?;
+            if let Some(expiries) = self.live_expiries_mut() {
+                expiries.delete_keys(keys).await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Tombstone `keys` in this map's slab only.
+    async fn delete_keys(&mut self, keys: &[Key]) -> Result<(), GpuMapError> {
         if keys.is_empty() {
-            return;
+            return Ok(());
         }
         let unique_keys = unique_keys(keys);
-        let existing = self.count_existing_keys(&unique_keys);
-        self.bulk_delete.execute(&self.slab, &unique_keys);
+        let existing = self.count_existing_keys(&unique_keys).await?;
+        self.store.delete(&unique_keys).await?;
         self.live_len = Length::new(self.live_len.0.saturating_sub(existing as u32));
+        let live_len = self.live_len;
+        if let Some(shadow) = self.shadow_mut() {
+            shadow.delete(&unique_keys, live_len);
+        }
+        Ok(())
+    }
+
+    /// Apply a [`WriteBatch`] of puts and deletes.
+    ///
+    /// Later operations on a key replace earlier ones. Reserved values and
+    /// capacity are checked before anything is written, so an error leaves
+    /// the map unchanged.
+    pub fn apply_batch(&mut self, batch: &WriteBatch) -> Result<(), GpuMapError> {
+        if let Some(value) = batch.reserved_value() {
+            return Err(GpuMapError::TombstoneValueReserved { value });
+        }
+        let (puts, deletes) = batch.resolve();
+        if !puts.is_empty() {
+            let keys: Vec<Key> = puts
+                .iter()
+                .map(|entry| entry.key)
+                .chain(deletes.iter().copied())
+                .collect();
+            let found = self.store.blocker().block_on(self.store.get(&keys))?;
+            let (put_found, delete_found) = found.split_at(puts.len());
+            let existing = put_found.iter().filter(|v| v.is_some()).count() as u32;
+            let removed = delete_found.iter().filter(|v| v.is_some()).count() as u32;
+            let requested = Length::new(
+                self.live_len
+                    .0
+                    .saturating_sub(removed)
+                    .saturating_add(puts.len() as u32 - existing),
+            );
+            if requested.0 > self.capacity().0 {
+                return Err(GpuMapError::CapacityExceeded {
+                    capacity: self.capacity(),
+                    requested,
+                });
+            }
+            // The put below runs the same check; failing it there would
+            // leave the deletes applied.
+            self.check_padded_batch(puts.len())?;
+        }
+        self.bulk_delete(&deletes)?;
+        self.bulk_put(&puts)
     }
 
     /// Single-key lookup convenience wrapper over `bulk_get`.
-    pub fn get(&self, key: Key) -> Option<Value> {
-        self.bulk_get(&[key]).into_iter().next().unwrap_or(None)
+    pub fn get(&self, key: Key) -> Result<Option<Value>, GpuMapError> {
+        Ok(self.bulk_get(&[key])?.into_iter().next().unwrap_or(None))
     }
 
     /// Single-key insert/update convenience wrapper over `bulk_put`.
@@ -422,30 +724,279 @@ impl GpuSortedMap {
     }
 
     /// Single-key delete convenience wrapper over `bulk_delete`.
-    pub fn delete(&mut self, key: Key) {
-        self.bulk_delete(std::slice::from_ref(&key));
+    pub fn delete(&mut self, key: Key) -> Result<(), GpuMapError> {
+        self.bulk_delete(std::slice::from_ref(&key))
     }
 
     /// Returns entries with keys in `[from_key, to_key)`.
-    pub fn range(&self, from_key: Key, to_key: Key) -> Vec<KvEntry> {
-        self.range_scan
-            .execute(&self.slab, from_key, to_key)
+    ///
+    /// Fails like [`bulk_get`](Self::bulk_get) if the GPU cannot run the scan.
+    pub fn range(&self, from_key: Key, to_key: Key) -> Result<Vec<KvEntry>, GpuMapError> {
+        self.store
+            .blocker()
+            .block_on(self.range_async(from_key, to_key))
+    }
+
+    /// Non-blocking [`range`](Self::range).
+    ///
+    /// Polling requirements are the same as for
+    /// [`bulk_get_async`](Self::bulk_get_async).
+    pub async fn range_async(
+        &self,
+        from_key: Key,
+        to_key: Key,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        if let Some(mirror) = self.host_mirror() {
+            if let Some(view) = mirror.for_range(from_key, to_key) {
+                return view.range_async(from_key, to_key).await;
+            }
+        }
+        let entries = self
+            .store
+            .range(from_key, to_key)
+            .await?
             .into_iter()
             .filter(|entry| entry.value != TOMBSTONE_VALUE)
-            .collect()
+            .collect();
+        self.hide_expired(entries).await
+    }
+
+    /// Read-only view of the map as it is now.
+    ///
+    /// The snapshot keeps the current slab version alive, so its `bulk_get`
+    /// and `range` results do not change while the map is written to.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(
+            self.store.clone(),
+            self.live_len,
+            self.expiries
+                .as_deref()
+                .filter(|expiries| !expiries.is_empty())
+                .map(|expiries| expiries.store.clone()),
+            self.clock,
+        )
+        .with_mirror(self.host_mirror())
     }
 
     /// Iterator over entries with keys in `[from_key, to_key)`.
-    pub fn range_iter(&self, from_key: Key, to_key: Key) -> std::vec::IntoIter<KvEntry> {
-        self.range(from_key, to_key).into_iter()
+    pub fn range_iter(
+        &self,
+        from_key: Key,
+        to_key: Key,
+    ) -> Result<std::vec::IntoIter<KvEntry>, GpuMapError> {
+        Ok(self.range(from_key, to_key)?.into_iter())
+    }
+
+    /// Rewrite values in place with a user-supplied WGSL function.
+    ///
+    /// `wgsl_fn` must define `fn f(key: u32, value: u32) -> u32` (plus any
+    /// helpers it needs). It runs on the GPU once per live entry whose key is in
+    /// `range`, and its result replaces the entry's value. A result equal to the
+    /// reserved tombstone `0xFFFF_FFFF` leaves the entry unchanged. Compiled
+    /// pipelines for the 32 most recently used source strings are cached, so
+    /// repeated calls with the same source only pay for the dispatch; keep
+    /// per-call constants out of the source.
+    ///
+    /// Returns [`GpuMapError::InvalidShader`] if the source fails to compile,
+    /// and [`GpuMapError::BackendUnsupported`] on [`Backend::Cpu`]. With a
+    /// [host shadow](Self::set_host_shadow), the shadow is refreshed by
+    /// reading the slab back, since the function only runs on the GPU.
+    pub fn map_values(
+        &mut self,
+        range: impl RangeBounds<Key>,
+        wgsl_fn: &str,
+    ) -> Result<(), GpuMapError> {
+        let (lo, hi) = inclusive_key_bounds(&range).unwrap_or((1, 0));
+        self.store.map_values(wgsl_fn, lo, hi)?;
+        if self.shadow_mut().is_some() {
+            self.host = Some(self.read_host_copy(true)?);
+        }
+        Ok(())
+    }
+
+    /// Slab and metadata buffers for binding in user compute shaders.
+    ///
+    /// The borrow keeps the map from being modified while the buffers are in
+    /// use. Submit any work that reads them before the next write.
+    /// Entries past their TTL stay in the slab until [`expire`](Self::expire)
+    /// tombstones them.
+    ///
+    /// Returns [`GpuMapError::BackendUnsupported`] on [`Backend::Cpu`], which
+    /// has no GPU buffers, and on maps whose slab is split into
+    /// [segments](Self::segment_count) or kept in
+    /// [leveled runs](Self::set_leveled), which have no single buffer to
+    /// bind.
+    pub fn slab_binding(&self) -> Result<SlabBinding<'_>, GpuMapError> {
+        let Some((_, slab)) = self.store.gpu_slab() else {
+            let mode = match self.backend() {
+                Backend::Cpu => "the CPU backend",
+                Backend::Gpu if self.leveled().is_some() => "leveled mode",
+                Backend::Gpu => "maps split into segments",
+            };
+            return Err(GpuMapError::BackendUnsupported {
+                operation: "slab_binding",
+                mode,
+            });
+        };
+        Ok(SlabBinding {
+            slab: slab.buffer(),
+            meta: slab.meta_buffer(),
+            layout: SlabLayout {
+                entry_stride: std::mem::size_of::<KvEntry>() as u64,
+                key_offset: std::mem::offset_of!(KvEntry, key) as u64,
+                value_offset: std::mem::offset_of!(KvEntry, value) as u64,
+                len: slab.len(),
+                capacity: slab.capacity(),
+                tombstone: TOMBSTONE_VALUE,
+            },
+        })
+    }
+
+    /// Device the map's buffers and pipelines live on. `None` on
+    /// [`Backend::Cpu`].
+    pub fn device(&self) -> Option<&Arc<wgpu::Device>> {
+        self.store.gpu_backend().map(|gpu| &gpu.device)
+    }
+
+    /// Queue the map submits its work to. `None` on [`Backend::Cpu`].
+    pub fn queue(&self) -> Option<&Arc<wgpu::Queue>> {
+        self.store.gpu_backend().map(|gpu| &gpu.queue)
+    }
+
+    /// Returns entries with keys in `[from_key, to_key)` whose value matches
+    /// `predicate`.
+    ///
+    /// The predicate runs in a compacting GPU pass, so only matching entries
+    /// are read back.
+    pub fn range_filtered(
+        &self,
+        from_key: Key,
+        to_key: Key,
+        predicate: &ValuePredicate,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        self.store.blocker().block_on(async {
+            let entries = self.store.filter(from_key, Some(to_key), predicate).await?;
+            self.hide_expired(entries).await
+        })
+    }
+
+    /// Returns all entries whose value matches `predicate`, in key order.
+    pub fn filter(&self, predicate: &ValuePredicate) -> Result<Vec<KvEntry>, GpuMapError> {
+        self.store.blocker().block_on(async {
+            let entries = self.store.filter(Key::new(0), None, predicate).await?;
+            self.hide_expired(entries).await
+        })
+    }
+
+    /// Buffer pool and bind-group cache shared by the map's pipelines.
+    ///
+    /// Idle buffers are kept for reuse up to
+    /// [`BufferPool::max_retained_bytes`] (64 MiB by default). `None` on
+    /// [`Backend::Cpu`], which keeps no GPU buffers.
+    pub fn buffer_pool(&self) -> Option<&Arc<BufferPool>> {
+        self.store.gpu_backend().map(|gpu| &gpu.pool)
+    }
+
+    /// Allocate scratch buffers for get, put, delete and range batches of up
+    /// to `max_batch` keys or entries ahead of time, so the first calls of
+    /// that size do not pay for allocation. Does nothing on [`Backend::Cpu`].
+    pub fn prewarm(&self, max_batch: usize) {
+        let Some(gpu) = self.store.gpu_backend() else {
+            return;
+        };
+        let batch = max_batch.min(self.store.batch_limit() as usize) as u32;
+        gpu.bulk_get.prewarm(batch);
+        gpu.bulk_delete.prewarm(batch);
+        gpu.bulk_put.prewarm(batch);
+        gpu.range_scan.prewarm(batch);
+    }
+
+    /// Poll the device once without blocking, advancing pending `*_async`
+    /// futures. Returns `true` when all submitted GPU work has completed,
+    /// which on [`Backend::Cpu`] is always.
+    pub fn poll(&self) -> bool {
+        match self.store.gpu_backend() {
+            Some(gpu) => gpu.device.poll(wgpu::Maintain::Poll).is_queue_empty(),
+            None => true,
+        }
+    }
+
+    /// Streaming queue that keeps up to `depth` get/range batches in flight.
+    ///
+    /// A depth of [`SubmissionQueue::DEFAULT_DEPTH`] double-buffers staging
+    /// so one batch computes while the previous one reads back. On
+    /// [`Backend::Cpu`], on maps split into [segments](Self::segment_count)
+    /// and in [leveled mode](Self::set_leveled), each batch completes as it
+    /// is enqueued.
+    pub fn submission_queue(&self, depth: usize) -> SubmissionQueue<'_> {
+        SubmissionQueue::new(self, depth)
+    }
+
+    /// Spawn a background thread that polls this map's device so `*_async`
+    /// futures complete without explicit [`poll`](Self::poll) calls. `None`
+    /// on [`Backend::Cpu`], whose futures need no polling.
+    pub fn spawn_poller(&self) -> Option<DevicePoller> {
+        let gpu = self.store.gpu_backend()?;
+        Some(DevicePoller::spawn(Arc::clone(&gpu.device)))
     }
 
     /// Total slab capacity.
     pub fn capacity(&self) -> Capacity {
-        self.slab.capacity()
+        self.store.capacity()
+    }
+
+    /// Number of buffers the slab is split into.
+    ///
+    /// A slab larger than the device's `max_storage_buffer_binding_size`
+    /// is kept in segments with disjoint key ranges, and more segments are
+    /// added as it fills. Always 1 for smaller slabs and on
+    /// [`Backend::Cpu`].
+    pub fn segment_count(&self) -> usize {
+        self.store.segment_count()
+    }
+
+    /// Switch leveled mode on with `config`, or off with `None`.
+    ///
+    /// A bulk put normally merges its batch with the whole slab, so a small
+    /// put into a large map rewrites all of it. In leveled mode the slab is
+    /// kept as sorted runs whose capacities grow by `size_ratio` from
+    /// `l0_capacity` up to the map's capacity. Puts merge into the small
+    /// newest run, and a run is merged into the next only when it cannot
+    /// take more. Lookups and scans consult the runs newest first, and
+    /// deletes tombstone every run, so reads cost more as runs accumulate.
+    ///
+    /// The current slab becomes the oldest run without being copied.
+    /// Turning leveled mode off, or changing its run sizes, merges the runs
+    /// into one. The mode carries over to the expiry map and through
+    /// [`recover`](Self::recover). Does nothing on [`Backend::Cpu`], and
+    /// returns [`GpuMapError::BackendUnsupported`] for maps split into
+    /// [segments](Self::segment_count).
+    pub fn set_leveled(&mut self, config: Option<LeveledConfig>) -> Result<(), GpuMapError> {
+        self.store
+            .blocker()
+            .block_on(self.store.set_leveled(config))?;
+        if let Some(expiries) = self.expiries.as_deref_mut() {
+            expiries.set_leveled(config)?;
+        }
+        Ok(())
+    }
+
+    /// Run sizes if the map is in [leveled mode](Self::set_leveled).
+    pub fn leveled(&self) -> Option<LeveledConfig> {
+        self.store.leveled()
+    }
+
+    /// Number of sorted runs holding entries: up to one per level in
+    /// [leveled mode](Self::set_leveled), otherwise 1, or 0 while the slab
+    /// is empty.
+    pub fn run_count(&self) -> usize {
+        self.store.run_count()
     }
 
     /// Current number of live entries (tombstones are excluded).
+    ///
+    /// Entries past their TTL are counted until [`expire`](Self::expire)
+    /// removes them.
     pub fn len(&self) -> Length {
         self.live_len
     }
@@ -460,15 +1011,371 @@ impl GpuSortedMap {
     ///
     /// This does not change the live entry count returned by `len()`.
     pub fn update_len(&mut self, new_len: Length) {
-        self.slab.update_len(&self.queue, new_len);
+        self.store.update_len(new_len);
+        if let Some(shadow) = self.shadow_mut() {
+            shadow.set_len(new_len);
+        }
+    }
+
+    /// Returns true once the map's device has been lost. Every operation
+    /// then fails with [`GpuMapError::DeviceLost`] until
+    /// [`recover`](Self::recover) moves the map to a new device. Always
+    /// false on [`Backend::Cpu`].
+    ///
+    /// wgpu reports a loss while the device is polled, which every blocking
+    /// operation does.
+    pub fn is_device_lost(&self) -> bool {
+        self.store.is_lost()
+    }
+
+    /// Copy the map to host memory, replacing any earlier checkpoint, so
+    /// [`recover`](Self::recover) can restore it after a device loss.
+    /// Writes made after the checkpoint are not restored.
+    ///
+    /// Does nothing while a [host shadow](Self::set_host_shadow) is on,
+    /// since the shadow is already current.
+    pub fn checkpoint(&mut self) -> Result<(), GpuMapError> {
+        if self.shadow_mut().is_some() {
+            return Ok(());
+        }
+        self.copy_to_host(false)
+    }
+
+    /// Keep a host copy of the map up to date on every write, so
+    /// [`recover`](Self::recover) restores everything written before a
+    /// device loss.
+    ///
+    /// Each write is also applied to the copy on the CPU, which costs about
+    /// as much as the same write on [`Backend::Cpu`]. Enabling reads the slab
+    /// back once; disabling drops the copy, including any checkpoint.
+    pub fn set_host_shadow(&mut self, enabled: bool) -> Result<(), GpuMapError> {
+        if enabled {
+            self.copy_to_host(true)
+        } else {
+            self.drop_host_copy();
+            Ok(())
+        }
+    }
+
+    /// Serve small reads from a host copy of the slab, as `policy` decides.
+    ///
+    /// A GPU lookup pays a fixed cost for its submission and readback, so
+    /// below a few thousand keys a binary search in host memory is faster.
+    /// Any policy other than [`DispatchPolicy::Gpu`] turns the
+    /// [host shadow](Self::set_host_shadow) on, since every write already
+    /// keeps it equal to the slab. `bulk_get` calls with fewer keys than the
+    /// threshold, and `range` calls spanning fewer slab entries, then read
+    /// the shadow; larger ones, and all other operations, run on the GPU.
+    /// [Snapshots](Self::snapshot) taken afterwards dispatch the same way.
+    ///
+    /// [`DispatchPolicy::Calibrated`] times lookups on both paths against
+    /// the current contents, so call it once the map holds representative
+    /// data. Switching back to [`DispatchPolicy::Gpu`] leaves the shadow on.
+    /// Does nothing on [`Backend::Cpu`].
+    pub fn set_dispatch(&mut self, policy: DispatchPolicy) -> Result<(), GpuMapError> {
+        if self.backend() == Backend::Cpu {
+            return Ok(());
+        }
+        if policy != DispatchPolicy::Gpu && self.shadow_mut().is_none() {
+            self.set_host_shadow(true)?;
+        }
+        self.dispatch_threshold = match policy {
+            DispatchPolicy::Gpu => 0,
+            DispatchPolicy::Threshold(threshold) => threshold,
+            DispatchPolicy::Calibrated => {
+                let host = self.host.as_ref().expect("host shadow was just enabled");
+                dispatch::calibrate(&self.store, host.slab())?
+            }
+        };
+        Ok(())
+    }
+
+    /// Batch size below which reads are served from the host shadow, or 0
+    /// if they all run on the GPU; see [`set_dispatch`](Self::set_dispatch).
+    pub fn dispatch_threshold(&self) -> usize {
+        self.dispatch_threshold
+    }
+
+    /// Keep a search index of fence keys next to the slab.
+    ///
+    /// The index holds up to 2048 keys spread evenly over the slab and is
+    /// rebuilt by each merge. `bulk_get` and `bulk_delete` copy it into
+    /// workgroup memory, search it there, and then search only the slab
+    /// slots between two fences, so large slabs see fewer scattered reads.
+    /// The slab current when the index is enabled gets one on its first
+    /// lookup. Does nothing on [`Backend::Cpu`].
+    pub fn set_search_index(&mut self, enabled: bool) {
+        if let Some(gpu) = self.store.gpu_backend() {
+            gpu.indexed.store(enabled, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns true if lookups and deletes go through a search index; see
+    /// [`set_search_index`](Self::set_search_index).
+    pub fn search_index(&self) -> bool {
+        self.store
+            .gpu_backend()
+            .is_some_and(|gpu| gpu.indexed.load(Ordering::Relaxed))
+    }
+
+    /// Rebuild the map on a new device after its device was lost.
+    ///
+    /// Requests a device with the options the map was
+    /// [built](Self::builder) with, or the way [`new`](Self::new) does,
+    /// recreates the pipelines and buffers on it, and restores the contents from the
+    /// [host shadow](Self::set_host_shadow) or the last
+    /// [`checkpoint`](Self::checkpoint). Without either, the map comes back
+    /// empty. The clock and the TTLs in the copy are kept. Snapshots,
+    /// pollers and user bindings taken earlier still refer to the old
+    /// device. Does nothing on [`Backend::Cpu`].
+    pub fn recover(&mut self) -> Result<(), GpuMapError> {
+        if self.backend() == Backend::Cpu {
+            return Ok(());
+        }
+        let (device, queue, adapter) =
+            pollster::block_on(self.device_request.request(self.capacity()))?;
+        self.recover_with_device(Arc::new(device), Arc::new(queue))?;
+        self.adapter = Some(adapter);
+        Ok(())
+    }
+
+    /// [`recover`](Self::recover) onto an existing device and queue, for
+    /// maps that share a device with the rest of an application.
+    ///
+    /// Returns [`GpuMapError::DeviceLimitsInsufficient`] like
+    /// [`with_device`](Self::with_device), and
+    /// [`GpuMapError::BackendUnsupported`] on [`Backend::Cpu`].
+    pub fn recover_with_device(
+        &mut self,
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+    ) -> Result<(), GpuMapError> {
+        let Some(gpu) = self.store.gpu_backend() else {
+            return Err(GpuMapError::BackendUnsupported {
+                operation: "recover_with_device",
+                mode: "the CPU backend",
+            });
+        };
+        let max_retained_bytes = gpu.pool.max_retained_bytes();
+        let workgroup_size = gpu.workgroup_size;
+        let search_index = self.search_index();
+        validate_device_limits(&device.limits(), self.capacity())?;
+        if let Some(tuning) = &self.tuning {
+            tuning.validate(&device.limits())?;
+        }
+        let pool = Arc::new(BufferPool::new(Arc::clone(&device), max_retained_bytes));
+        let capacity = self.capacity();
+        let mut store = Store::gpu(device, queue, capacity, pool, workgroup_size);
+        // A single slab becomes leveled without running anything.
+        pollster::block_on(store.set_leveled(self.leveled()))?;
+        self.restore(store);
+        self.set_search_index(search_index);
+        self.adapter = None;
+        Ok(())
+    }
+
+    /// Move the map onto `store`, loading the host copies into it.
+    fn restore(&mut self, mut store: Store) {
+        self.live_len = match &self.host {
+            Some(host) => {
+                store.load(host.slab());
+                host.live_len()
+            }
+            None => Length::new(0),
+        };
+        if let Some(expiries) = self.expiries.as_deref_mut() {
+            expiries.restore(store.sibling());
+        }
+        self.store = store;
+    }
+
+    /// Replace the host copies of this map and its expiry map.
+    fn copy_to_host(&mut self, shadow: bool) -> Result<(), GpuMapError> {
+        let host = self.read_host_copy(shadow)?;
+        if let Some(expiries) = self.expiries.as_deref_mut() {
+            expiries.copy_to_host(shadow)?;
+        }
+        self.host = Some(host);
+        Ok(())
     }
 
-    fn count_existing_keys(&self, keys: &[Key]) -> usize {
+    fn read_host_copy(&self, shadow: bool) -> Result<HostCopy, GpuMapError> {
+        let slab = self.store.blocker().block_on(self.store.read_all())?;
+        Ok(HostCopy::new(slab, self.live_len, shadow))
+    }
+
+    fn drop_host_copy(&mut self) {
+        self.host = None;
+        if let Some(expiries) = self.expiries.as_deref_mut() {
+            expiries.drop_host_copy();
+        }
+    }
+
+    /// Host view of the shadows for [`set_dispatch`](Self::set_dispatch), if
+    /// small reads should use it. A lost device fails reads, as it does
+    /// without the mirror.
+    fn host_mirror(&self) -> Option<HostMirror> {
+        if self.dispatch_threshold == 0 || self.store.is_lost() {
+            return None;
+        }
+        let host = self.host.as_ref().filter(|host| host.is_shadow())?;
+        let expiries = match self.expiries.as_deref().filter(|e| !e.is_empty()) {
+            Some(expiries) => Some(expiries.host.as_ref()?.shared_slab()),
+            None => None,
+        };
+        Some(HostMirror::new(
+            host.shared_slab(),
+            self.live_len,
+            expiries,
+            self.clock,
+            self.dispatch_threshold,
+        ))
+    }
+
+    /// The host copy, if writes are being applied to it.
+    fn shadow_mut(&mut self) -> Option<&mut HostCopy> {
+        self.host.as_mut().filter(|host| host.is_shadow())
+    }
+
+    async fn count_existing_keys(&self, keys: &[Key]) -> Result<usize, GpuMapError> {
         if keys.is_empty() {
-            return 0;
+            return Ok(0);
+        }
+        // Expired entries still occupy the slab, so look past their TTLs.
+        Ok(self
+            .store
+            .get(keys)
+            .await?
+            .iter()
+            .filter(|v| v.is_some())
+            .count())
+    }
+
+    /// The expiry map, created on first use.
+    fn expiries_mut(&mut self) -> &mut GpuSortedMap {
+        let store = &self.store;
+        let shadowed = self.host.as_ref().is_some_and(HostCopy::is_shadow);
+        self.expiries.get_or_insert_with(|| {
+            let mut expiries = GpuSortedMap::with_store(store.sibling());
+            if shadowed {
+                expiries.host = Some(HostCopy::empty_shadow(store.capacity()));
+            }
+            Box::new(expiries)
+        })
+    }
+
+    /// The expiry map, if any entry currently has a TTL.
+    fn live_expiries_mut(&mut self) -> Option<&mut GpuSortedMap> {
+        self.expiries
+            .as_deref_mut()
+            .filter(|expiries| !expiries.is_empty())
+    }
+
+    /// Expiry lookups at the current clock, if any entry has a TTL.
+    fn expiry_view(&self) -> Option<ExpiryView<'_>> {
+        let expiries = self.expiries.as_deref()?;
+        (!expiries.is_empty()).then_some(ExpiryView {
+            store: &expiries.store,
+            now: self.clock,
+        })
+    }
+
+    async fn hide_expired(&self, entries: Vec<KvEntry>) -> Result<Vec<KvEntry>, GpuMapError> {
+        match self.expiry_view() {
+            Some(expiries) if !entries.is_empty() => expiries.hide_entries(entries).await,
+            _ => Ok(entries),
+        }
+    }
+}
+
+/// Storage buffers bound by the widest pipeline (the filter scan).
+const REQUIRED_STORAGE_BUFFERS_PER_STAGE: u32 = 5;
+/// Uniform buffers bound by the widest pipeline (the indexed lookup).
+const REQUIRED_UNIFORM_BUFFERS_PER_STAGE: u32 = 3;
+/// Largest `@workgroup_size` used by the map's shaders (the bitonic sort tiles).
+const REQUIRED_WORKGROUP_SIZE: u32 = 256;
+/// Workgroup memory used by the bitonic sort tiles: 512 entries.
+const SORT_TILE_BYTES: u32 = 512 * std::mem::size_of::<KvEntry>() as u32;
+/// Workgroup memory used by the fence cache of the lookup and delete
+/// kernels, which declare it whether or not the map keeps an index.
+const INDEX_CACHE_BYTES: u32 = INDEX_FENCES * std::mem::size_of::<u32>() as u32;
+/// Workgroup memory used by the hungriest shader.
+const REQUIRED_WORKGROUP_STORAGE_BYTES: u32 = if SORT_TILE_BYTES > INDEX_CACHE_BYTES {
+    SORT_TILE_BYTES
+} else {
+    INDEX_CACHE_BYTES
+};
+/// Sort stage parameters are selected with one dynamic uniform offset.
+const REQUIRED_DYNAMIC_UNIFORM_BUFFERS: u32 = 1;
+
+fn validate_device_limits(limits: &wgpu::Limits, capacity: Capacity) -> Result<(), GpuMapError> {
+    // Larger slabs are split into segments, so one binding need only hold
+    // the smallest segment.
+    let segment = capacity.0.min(sharded::MIN_SEGMENT_CAPACITY);
+    let slab_bytes = (segment as u64) * std::mem::size_of::<KvEntry>() as u64;
+    let checks: [(&'static str, u64, u64); 8] = [
+        (
+            "max_storage_buffer_binding_size",
+            slab_bytes,
+            limits.max_storage_buffer_binding_size as u64,
+        ),
+        ("max_buffer_size", slab_bytes, limits.max_buffer_size),
+        (
+            "max_storage_buffers_per_shader_stage",
+            REQUIRED_STORAGE_BUFFERS_PER_STAGE as u64,
+            limits.max_storage_buffers_per_shader_stage as u64,
+        ),
+        (
+            "max_uniform_buffers_per_shader_stage",
+            REQUIRED_UNIFORM_BUFFERS_PER_STAGE as u64,
+            limits.max_uniform_buffers_per_shader_stage as u64,
+        ),
+        (
+            "max_compute_workgroup_size_x",
+            REQUIRED_WORKGROUP_SIZE as u64,
+            limits.max_compute_workgroup_size_x as u64,
+        ),
+        (
+            "max_compute_invocations_per_workgroup",
+            REQUIRED_WORKGROUP_SIZE as u64,
+            limits.max_compute_invocations_per_workgroup as u64,
+        ),
+        (
+            "max_compute_workgroup_storage_size",
+            REQUIRED_WORKGROUP_STORAGE_BYTES as u64,
+            limits.max_compute_workgroup_storage_size as u64,
+        ),
+        (
+            "max_dynamic_uniform_buffers_per_pipeline_layout",
+            REQUIRED_DYNAMIC_UNIFORM_BUFFERS as u64,
+            limits.max_dynamic_uniform_buffers_per_pipeline_layout as u64,
+        ),
+    ];
+    for (limit, required, available) in checks {
+        if required > available {
+            return Err(GpuMapError::DeviceLimitsInsufficient {
+                limit,
+                required,
+                available,
+            });
         }
-        self.bulk_get(keys).iter().filter(|v| v.is_some()).count()
     }
+    Ok(())
+}
+
+/// Converts `range` to inclusive `(lo, hi)` key bounds, or `None` if it is empty.
+fn inclusive_key_bounds(range: &impl RangeBounds<Key>) -> Option<(u32, u32)> {
+    let lo = match range.start_bound() {
+        Bound::Included(key) => key.0,
+        Bound::Excluded(key) => key.0.checked_add(1)?,
+        Bound::Unbounded => 0,
+    };
+    let hi = match range.end_bound() {
+        Bound::Included(key) => key.0,
+        Bound::Excluded(key) => key.0.checked_sub(1)?,
+        Bound::Unbounded => u32::MAX,
+    };
+    (lo <= hi).then_some((lo, hi))
 }
 
 fn unique_keys_from_entries(entries: &[KvEntry]) -> Result<Vec<Key>, Key> {
@@ -510,9 +1417,43 @@ pub enum GpuMapError {
     GpuInitializationFailed {
         message: String,
     },
-}
-
-impl std::fmt::Display for GpuMapError {
+    DeviceLimitsInsufficient {
+        limit: &'static str,
+        required: u64,
+        available: u64,
+    },
+    InvalidShader {
+        message: String,
+    },
+    /// `operation` cannot run on this map; `mode` names what rules it out,
+    /// such as "the CPU backend".
+    BackendUnsupported {
+        operation: &'static str,
+        mode: &'static str,
+    },
+    /// A readback buffer could not be mapped, for example because the
+    /// device was lost.
+    BufferMapFailed {
+        message: String,
+    },
+    /// wgpu rejected a command submitted for the operation.
+    ValidationFailed {
+        message: String,
+    },
+    /// The device ran out of memory while running the operation.
+    OutOfMemory,
+    /// The map's device was lost. Every operation fails with this until
+    /// [`GpuSortedMap::recover`] moves the map to a new device.
+    DeviceLost,
+    /// Text passed to [`TuningProfile`]'s `parse` is not a profile.
+    InvalidTuningProfile {
+        message: String,
+    },
+    /// A [`Coalescer`]'s worker stopped before answering the request.
+    CoalescerStopped,
+}
+
+impl std::fmt::Display for GpuMapError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             GpuMapError::CapacityExceeded {
@@ -538,6 +1479,35 @@ impl std::fmt::Display for GpuMapError {
             GpuMapError::GpuInitializationFailed { message } => {
                 write!(f, "GPU initialization failed: {}", message)
             }
+            GpuMapError::DeviceLimitsInsufficient {
+                limit,
+                required,
+                available,
+            } => {
+                write!(
+                    f,
+                    "Device limit {} is {} but the map requires {}",
+                    limit, available, required
+                )
+            }
+            GpuMapError::InvalidShader { message } => {
+                write!(f, "Invalid shader: {}", message)
+            }
+            GpuMapError::BackendUnsupported { operation, mode } => {
+                write!(f, "{} is not supported by {}", operation, mode)
+            }
+            GpuMapError::BufferMapFailed { message } => {
+                write!(f, "Failed to map readback buffer: {}", message)
+            }
+            GpuMapError::ValidationFailed { message } => {
+                write!(f, "GPU validation failed: {}", message)
+            }
+            GpuMapError::OutOfMemory => write!(f, "GPU out of memory"),
+            GpuMapError::DeviceLost => write!(f, "GPU device lost"),
+            GpuMapError::InvalidTuningProfile { message } => {
+                write!(f, "Invalid tuning profile: {}", message)
+            }
+            GpuMapError::CoalescerStopped => write!(f, "Coalescer worker stopped"),
         }
     }
 }
@@ -546,7 +1516,12 @@ impl std::error::Error for GpuMapError {}
 
 #[cfg(test)]
 mod tests {
-    use super::{Capacity, GpuSortedMap, Key, KvEntry, Length, Value};
+    use super::{
+        Backend, BatchResult, Capacity, GpuMapError, GpuSortedMap, Key, KvEntry, Length,
+        SubmissionQueue, Value, WriteBatch,
+    };
+    use crate::backend::test_map;
+    use std::sync::Arc;
 
     fn k(value: u32) -> Key {
         Key::new(value)
@@ -581,6 +1556,34 @@ mod tests {
         };
     }
 
+    fn try_create_device_queue() -> Option<(Arc<wgpu::Device>, Arc<wgpu::Queue>)> {
+        try_create_device_queue_with_limits(wgpu::Limits::default())
+    }
+
+    fn try_create_device_queue_with_limits(
+        limits: wgpu::Limits,
+    ) -> Option<(Arc<wgpu::Device>, Arc<wgpu::Queue>)> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            ..Default::default()
+        });
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::LowPower,
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        }))?;
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("lib-test-device"),
+                required_features: wgpu::Features::empty(),
+                required_limits: limits,
+            },
+            None,
+        ))
+        .ok()?;
+        Some((Arc::new(device), Arc::new(queue)))
+    }
+
     #[test]
     fn creates_gpu_sorted_map() {
         // Test that GPU map creation either succeeds with a GPU or fails gracefully without one
@@ -595,9 +1598,197 @@ mod tests {
         }
     }
 
+    #[test]
+    fn cpu_backend_needs_no_adapter_and_rejects_gpu_only_operations() {
+        let mut map =
+            pollster::block_on(GpuSortedMap::with_backend(Capacity::new(8), Backend::Cpu)).unwrap();
+        assert_eq!(map.backend(), Backend::Cpu);
+        map.put(k(1), v(10)).unwrap();
+        map.prewarm(64);
+        assert!(map.poll());
+        assert!(map.device().is_none() && map.queue().is_none());
+        assert!(map.buffer_pool().is_none());
+        assert!(map.spawn_poller().is_none());
+        assert_eq!(
+            map.map_values(.., "fn f(key: u32, value: u32) -> u32 { return value; }"),
+            Err(GpuMapError::BackendUnsupported {
+                operation: "map_values",
+                mode: "the CPU backend",
+            })
+        );
+        let mut queue = map.submission_queue(SubmissionQueue::DEFAULT_DEPTH);
+        let id = queue.enqueue_get(&[k(1), k(2)]);
+        assert_eq!(
+            queue.drain(),
+            vec![(id, BatchResult::Get(vec![Some(v(10)), None]))]
+        );
+        drop(queue);
+        assert!(super::SharedGpuSortedMap::new(map).device().is_none());
+    }
+
+    #[test]
+    fn cpu_backend_matches_gpu_backend() {
+        use rand::rngs::StdRng;
+        use rand::seq::SliceRandom;
+        use rand::{Rng, SeedableRng};
+
+        skip_if_no_gpu!(mut gpu, Capacity::new(128));
+        let mut cpu =
+            pollster::block_on(GpuSortedMap::with_backend(Capacity::new(128), Backend::Cpu))
+                .unwrap();
+        let mut rng = StdRng::seed_from_u64(39);
+        let key_space: Vec<u32> = (0..256).collect();
+        for step in 0..60 {
+            let batch_len = rng.gen_range(0..48);
+            let batch: Vec<u32> = key_space
+                .choose_multiple(&mut rng, batch_len)
+                .copied()
+                .collect();
+            if rng.gen_bool(0.7) {
+                let entries: Vec<KvEntry> = batch
+                    .iter()
+                    .map(|&key| KvEntry {
+                        key: k(key),
+                        value: v(rng.gen_range(0..1000)),
+                    })
+                    .collect();
+                assert_eq!(
+                    gpu.bulk_put(&entries),
+                    cpu.bulk_put(&entries),
+                    "step {step}"
+                );
+            } else {
+                let keys: Vec<Key> = batch.iter().map(|&key| k(key)).collect();
+                gpu.bulk_delete(&keys).unwrap();
+                cpu.bulk_delete(&keys).unwrap();
+            }
+            assert_eq!(gpu.len(), cpu.len(), "step {step}");
+            let (from, to) = (k(rng.gen_range(0..256)), k(rng.gen_range(0..256)));
+            assert_eq!(
+                gpu.range(from, to).unwrap(),
+                cpu.range(from, to).unwrap(),
+                "step {step}"
+            );
+            let probe: Vec<Key> = (0..32).map(|_| k(rng.gen_range(0..256))).collect();
+            assert_eq!(
+                gpu.bulk_get(&probe).unwrap(),
+                cpu.bulk_get(&probe).unwrap(),
+                "step {step}"
+            );
+            let predicate = super::ValuePredicate::Lt(v(500));
+            assert_eq!(
+                gpu.filter(&predicate).unwrap(),
+                cpu.filter(&predicate).unwrap(),
+                "step {step}"
+            );
+        }
+    }
+
+    #[test]
+    fn with_device_shares_application_device() {
+        let Some((device, queue)) = try_create_device_queue() else {
+            eprintln!("Skipping test: GPU not available in this environment");
+            return;
+        };
+        let mut first =
+            GpuSortedMap::with_device(Arc::clone(&device), Arc::clone(&queue), Capacity::new(8))
+                .unwrap();
+        let second =
+            GpuSortedMap::with_device(Arc::clone(&device), Arc::clone(&queue), Capacity::new(8))
+                .unwrap();
+        assert!(Arc::ptr_eq(
+            first.device().unwrap(),
+            second.device().unwrap()
+        ));
+
+        first.put(k(1), v(10)).unwrap();
+        assert_eq!(first.get(k(1)).unwrap(), Some(v(10)));
+        assert_eq!(second.get(k(1)).unwrap(), None);
+    }
+
+    #[test]
+    fn with_device_rejects_bindings_smaller_than_a_segment() {
+        let Some((device, queue)) = try_create_device_queue_with_limits(wgpu::Limits {
+            max_storage_buffer_binding_size: 256,
+            ..wgpu::Limits::default()
+        }) else {
+            eprintln!("Skipping test: GPU not available in this environment");
+            return;
+        };
+        // 32 entries fit one binding; a larger slab would need segments
+        // smaller than the minimum of 64 entries.
+        assert!(GpuSortedMap::with_device(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            Capacity::new(32)
+        )
+        .is_ok());
+        let res = GpuSortedMap::with_device(device, queue, Capacity::new(1000));
+        assert!(matches!(
+            res,
+            Err(super::GpuMapError::DeviceLimitsInsufficient { .. })
+        ));
+    }
+
+    #[test]
+    fn device_limits_cover_the_filter_scan_bindings() {
+        let limits = wgpu::Limits {
+            max_storage_buffers_per_shader_stage: 4,
+            ..wgpu::Limits::default()
+        };
+        assert!(matches!(
+            super::validate_device_limits(&limits, Capacity::new(64)),
+            Err(super::GpuMapError::DeviceLimitsInsufficient {
+                limit: "max_storage_buffers_per_shader_stage",
+                required: 5,
+                available: 4,
+            })
+        ));
+    }
+
+    #[test]
+    fn device_limits_cover_the_search_index_cache() {
+        let limits = wgpu::Limits {
+            max_compute_workgroup_storage_size: 4096,
+            ..wgpu::Limits::default()
+        };
+        assert!(matches!(
+            super::validate_device_limits(&limits, Capacity::new(64)),
+            Err(super::GpuMapError::DeviceLimitsInsufficient {
+                limit: "max_compute_workgroup_storage_size",
+                required: 8192,
+                available: 4096,
+            })
+        ));
+        let limits = wgpu::Limits {
+            max_uniform_buffers_per_shader_stage: 2,
+            ..wgpu::Limits::default()
+        };
+        assert!(matches!(
+            super::validate_device_limits(&limits, Capacity::new(64)),
+            Err(super::GpuMapError::DeviceLimitsInsufficient {
+                limit: "max_uniform_buffers_per_shader_stage",
+                ..
+            })
+        ));
+
+        // A device at the limit builds every pipeline, index included.
+        let Some((device, queue)) = try_create_device_queue_with_limits(wgpu::Limits {
+            max_compute_workgroup_storage_size: 8192,
+            ..wgpu::Limits::default()
+        }) else {
+            eprintln!("Skipping test: GPU not available in this environment");
+            return;
+        };
+        let mut map = GpuSortedMap::with_device(device, queue, Capacity::new(64)).unwrap();
+        map.set_search_index(true);
+        map.put(k(1), v(10)).unwrap();
+        assert_eq!(map.get(k(1)).unwrap(), Some(v(10)));
+    }
+
     #[test]
     fn put_then_get() {
-        skip_if_no_gpu!(mut map, Capacity::new(8));
+        let mut map = test_map(Capacity::new(8));
         let entries = [
             KvEntry {
                 key: k(42),
@@ -614,26 +1805,26 @@ mod tests {
         ];
         map.bulk_put(&entries).unwrap();
 
-        let results = map.bulk_get(&[k(7), k(13), k(42), k(99)]);
+        let results = map.bulk_get(&[k(7), k(13), k(42), k(99)]).unwrap();
         assert_eq!(results, vec![Some(v(9)), Some(v(1)), Some(v(7)), None]);
     }
 
     #[test]
     fn single_put_then_get() {
-        skip_if_no_gpu!(mut map, Capacity::new(4));
+        let mut map = test_map(Capacity::new(4));
         map.put(k(5), v(11)).unwrap();
-        assert_eq!(map.get(k(5)), Some(v(11)));
+        assert_eq!(map.get(k(5)).unwrap(), Some(v(11)));
     }
 
     #[test]
     fn single_get_missing_key() {
-        skip_if_no_gpu!(map, Capacity::new(4));
-        assert_eq!(map.get(k(9)), None);
+        let map = test_map(Capacity::new(4));
+        assert_eq!(map.get(k(9)).unwrap(), None);
     }
 
     #[test]
     fn bulk_delete_clears_values() {
-        skip_if_no_gpu!(mut map, Capacity::new(8));
+        let mut map = test_map(Capacity::new(8));
         let entries = [
             KvEntry {
                 key: k(1),
@@ -649,22 +1840,22 @@ mod tests {
             },
         ];
         map.bulk_put(&entries).unwrap();
-        map.bulk_delete(&[k(1), k(3)]);
-        let results = map.bulk_get(&[k(1), k(2), k(3)]);
+        map.bulk_delete(&[k(1), k(3)]).unwrap();
+        let results = map.bulk_get(&[k(1), k(2), k(3)]).unwrap();
         assert_eq!(results, vec![None, Some(v(20)), None]);
     }
 
     #[test]
     fn delete_single_key() {
-        skip_if_no_gpu!(mut map, Capacity::new(4));
+        let mut map = test_map(Capacity::new(4));
         map.put(k(9), v(99)).unwrap();
-        map.delete(k(9));
-        assert_eq!(map.get(k(9)), None);
+        map.delete(k(9)).unwrap();
+        assert_eq!(map.get(k(9)).unwrap(), None);
     }
 
     #[test]
     fn range_returns_half_open_interval() {
-        skip_if_no_gpu!(mut map, Capacity::new(16));
+        let mut map = test_map(Capacity::new(16));
         map.bulk_put(&[
             KvEntry {
                 key: k(1),
@@ -685,13 +1876,179 @@ mod tests {
         ])
         .unwrap();
 
-        let entries = map.range(k(2), k(4));
+        let entries = map.range(k(2), k(4)).unwrap();
         let keys: Vec<Key> = entries.iter().map(|entry| entry.key).collect();
         assert_eq!(keys, vec![k(2), k(3)]);
     }
 
     #[test]
     fn range_empty_when_from_equals_to() {
+        let mut map = test_map(Capacity::new(16));
+        map.bulk_put(&[
+            KvEntry {
+                key: k(1),
+                value: v(10),
+            },
+            KvEntry {
+                key: k(2),
+                value: v(20),
+            },
+        ])
+        .unwrap();
+        assert!(map.range(k(2), k(2)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn range_empty_when_from_greater_than_to() {
+        let mut map = test_map(Capacity::new(16));
+        map.bulk_put(&[
+            KvEntry {
+                key: k(1),
+                value: v(10),
+            },
+            KvEntry {
+                key: k(2),
+                value: v(20),
+            },
+        ])
+        .unwrap();
+        assert!(map.range(k(3), k(1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn range_empty_on_empty_map() {
+        let map = test_map(Capacity::new(16));
+        assert!(map.range(k(0), k(10)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn range_outside_bounds_is_empty() {
+        let mut map = test_map(Capacity::new(16));
+        map.bulk_put(&[
+            KvEntry {
+                key: k(10),
+                value: v(10),
+            },
+            KvEntry {
+                key: k(20),
+                value: v(20),
+            },
+        ])
+        .unwrap();
+        assert!(map.range(k(0), k(5)).unwrap().is_empty());
+        assert!(map.range(k(30), k(40)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn range_clamps_to_existing_keys() {
+        let mut map = test_map(Capacity::new(16));
+        map.bulk_put(&[
+            KvEntry {
+                key: k(5),
+                value: v(50),
+            },
+            KvEntry {
+                key: k(10),
+                value: v(100),
+            },
+            KvEntry {
+                key: k(15),
+                value: v(150),
+            },
+        ])
+        .unwrap();
+        let keys: Vec<Key> = map
+            .range(k(0), k(100))
+            .unwrap()
+            .iter()
+            .map(|e| e.key)
+            .collect();
+        assert_eq!(keys, vec![k(5), k(10), k(15)]);
+    }
+
+    #[test]
+    fn range_starts_between_keys() {
+        let mut map = test_map(Capacity::new(16));
+        map.bulk_put(&[
+            KvEntry {
+                key: k(10),
+                value: v(100),
+            },
+            KvEntry {
+                key: k(20),
+                value: v(200),
+            },
+            KvEntry {
+                key: k(30),
+                value: v(300),
+            },
+        ])
+        .unwrap();
+        let keys: Vec<Key> = map
+            .range(k(15), k(30))
+            .unwrap()
+            .iter()
+            .map(|e| e.key)
+            .collect();
+        assert_eq!(keys, vec![k(20)]);
+    }
+
+    #[test]
+    fn range_excludes_tombstones() {
+        let mut map = test_map(Capacity::new(16));
+        map.bulk_put(&[
+            KvEntry {
+                key: k(1),
+                value: v(10),
+            },
+            KvEntry {
+                key: k(2),
+                value: v(20),
+            },
+            KvEntry {
+                key: k(3),
+                value: v(30),
+            },
+        ])
+        .unwrap();
+        map.delete(k(2)).unwrap();
+        let keys: Vec<Key> = map
+            .range(k(1), k(4))
+            .unwrap()
+            .iter()
+            .map(|e| e.key)
+            .collect();
+        assert_eq!(keys, vec![k(1), k(3)]);
+    }
+
+    #[test]
+    fn slab_binding_reports_maps_without_a_single_slab() {
+        let unsupported = |mode| {
+            Err(GpuMapError::BackendUnsupported {
+                operation: "slab_binding",
+                mode,
+            })
+        };
+        let cpu =
+            pollster::block_on(GpuSortedMap::with_backend(Capacity::new(8), Backend::Cpu)).unwrap();
+        assert_eq!(
+            cpu.slab_binding().map(|_| ()),
+            unsupported("the CPU backend")
+        );
+
+        skip_if_no_gpu!(mut map, Capacity::new(64));
+        map.put(k(1), v(10)).unwrap();
+        assert!(map.slab_binding().is_ok());
+        map.set_leveled(Some(super::LeveledConfig::default()))
+            .unwrap();
+        assert_eq!(map.slab_binding().map(|_| ()), unsupported("leveled mode"));
+    }
+
+    #[test]
+    fn slab_binding_supports_user_lookup_join_kernel() {
+        use crate::pipelines::core::ComputeStep;
+        use crate::pipelines::utils::{create_buffer_with_data, readback_vec};
+
         skip_if_no_gpu!(mut map, Capacity::new(16));
         map.bulk_put(&[
             KvEntry {
@@ -702,99 +2059,427 @@ mod tests {
                 key: k(2),
                 value: v(20),
             },
-        ])
-        .unwrap();
-        assert!(map.range(k(2), k(2)).is_empty());
-    }
-
-    #[test]
-    fn range_empty_when_from_greater_than_to() {
-        skip_if_no_gpu!(mut map, Capacity::new(16));
-        map.bulk_put(&[
             KvEntry {
-                key: k(1),
-                value: v(10),
+                key: k(3),
+                value: v(30),
+            },
+        ])
+        .unwrap();
+        map.delete(k(2)).unwrap();
+
+        let binding = map.slab_binding().unwrap();
+        assert_eq!(binding.layout.entry_stride, 8);
+        assert_eq!(binding.layout.value_offset, 4);
+        assert_eq!(binding.layout.len, Length::new(3));
+        assert_eq!(binding.layout.tombstone, v(0xFFFF_FFFF));
+
+        let source = format!(
+            "{}{}",
+            super::SLAB_WGSL,
+            r#"
+@group(0) @binding(0) var<storage, read> slab: array<KvEntry>;
+@group(0) @binding(1) var<uniform> slab_meta: SlabMeta;
+@group(0) @binding(2) var<storage, read> probes: array<u32>;
+@group(0) @binding(3) var<storage, read_write> joined: array<u32>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+    let idx = gid.x;
+    if (idx >= arrayLength(&probes)) {
+        return;
+    }
+    let lo = slab_lower_bound(probes[idx], slab_meta.len);
+    joined[idx] = 0u;
+    if (lo < slab_meta.len && slab[lo].key == probes[idx] && slab_is_live(slab[lo])) {
+        joined[idx] = slab[lo].value;
+    }
+}
+"#
+        );
+        let device = Arc::clone(map.device().unwrap());
+        let storage = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let step = ComputeStep::new(
+            Arc::clone(&device),
+            &source,
+            "main",
+            &[
+                storage(0, true),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage(2, true),
+                storage(3, false),
+            ],
+        );
+
+        let probes = [3_u32, 2, 1, 9];
+        let probes_buffer =
+            create_buffer_with_data(&device, "probes", wgpu::BufferUsages::STORAGE, &probes);
+        let joined_buffer = create_buffer_with_data(
+            &device,
+            "joined",
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            &[0_u32; 4],
+        );
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("joined-readback"),
+            size: 16,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = step.create_bind_group(
+            "join-bind-group",
+            &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: binding.slab.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: binding.meta.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: probes_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: joined_buffer.as_entire_binding(),
+                },
+            ],
+        );
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("join-encoder"),
+        });
+        step.dispatch(&mut encoder, "join-pass", &bind_group, 1);
+        encoder.copy_buffer_to_buffer(&joined_buffer, 0, &readback, 0, 16);
+        map.queue().unwrap().submit(Some(encoder.finish()));
+
+        assert_eq!(readback_vec::<u32>(&device, &readback), vec![30, 0, 10, 0]);
+    }
+
+    #[test]
+    fn map_values_rewrites_live_entries() {
+        skip_if_no_gpu!(mut map, Capacity::new(16));
+        map.bulk_put(&[
+            KvEntry {
+                key: k(1),
+                value: v(10),
+            },
+            KvEntry {
+                key: k(2),
+                value: v(20),
+            },
+            KvEntry {
+                key: k(3),
+                value: v(30),
+            },
+        ])
+        .unwrap();
+        map.delete(k(2)).unwrap();
+
+        let halve = "fn f(key: u32, value: u32) -> u32 { return value / 2u; }";
+        map.map_values(.., halve).unwrap();
+        assert_eq!(
+            map.bulk_get(&[k(1), k(2), k(3)]).unwrap(),
+            vec![Some(v(5)), None, Some(v(15))]
+        );
+        assert_eq!(map.len(), Length::new(2));
+
+        map.map_values(.., halve).unwrap();
+        assert_eq!(
+            map.bulk_get(&[k(1), k(3)]).unwrap(),
+            vec![Some(v(2)), Some(v(7))]
+        );
+    }
+
+    #[test]
+    fn map_values_respects_key_range() {
+        skip_if_no_gpu!(mut map, Capacity::new(16));
+        let entries: Vec<KvEntry> = (0..8)
+            .map(|i| KvEntry {
+                key: k(i),
+                value: v(0b1111),
+            })
+            .collect();
+        map.bulk_put(&entries).unwrap();
+
+        let clear_bit_3 = "fn f(key: u32, value: u32) -> u32 { return value & ~(1u << 3u); }";
+        map.map_values(k(2)..k(5), clear_bit_3).unwrap();
+        map.map_values(
+            k(7)..=k(7),
+            "fn f(key: u32, value: u32) -> u32 { return key; }",
+        )
+        .unwrap();
+
+        let values: Vec<u32> = map
+            .range(k(0), k(8))
+            .unwrap()
+            .iter()
+            .map(|entry| entry.value.0)
+            .collect();
+        assert_eq!(values, vec![15, 15, 7, 7, 7, 15, 15, 7]);
+    }
+
+    #[test]
+    fn map_values_ignores_tombstone_results_and_rejects_invalid_source() {
+        skip_if_no_gpu!(mut map, Capacity::new(8));
+        map.put(k(1), v(10)).unwrap();
+
+        map.map_values(
+            ..,
+            "fn f(key: u32, value: u32) -> u32 { return 0xffffffffu; }",
+        )
+        .unwrap();
+        assert_eq!(map.get(k(1)).unwrap(), Some(v(10)));
+
+        let err = map
+            .map_values(.., "fn f(key: u32) -> u32 { return nope; }")
+            .unwrap_err();
+        assert!(matches!(err, super::GpuMapError::InvalidShader { .. }));
+        assert_eq!(map.get(k(1)).unwrap(), Some(v(10)));
+    }
+
+    #[test]
+    fn range_filtered_applies_value_predicates() {
+        use super::ValuePredicate;
+
+        let mut map = test_map(Capacity::new(256));
+        let entries: Vec<KvEntry> = (0..200)
+            .map(|i| KvEntry {
+                key: k(i * 2),
+                value: v(i),
+            })
+            .collect();
+        map.bulk_put(&entries).unwrap();
+        map.bulk_delete(&[k(20), k(22)]).unwrap();
+
+        let predicates = [
+            ValuePredicate::Eq(v(7)),
+            ValuePredicate::Ne(v(7)),
+            ValuePredicate::Lt(v(30)),
+            ValuePredicate::Le(v(30)),
+            ValuePredicate::Gt(v(150)),
+            ValuePredicate::Ge(v(150)),
+            ValuePredicate::MaskAny(0b100),
+            ValuePredicate::MaskAll(0b101),
+            ValuePredicate::Between(v(5), v(15)),
+            ValuePredicate::OneOf(vec![v(150), v(3), v(11), v(3), v(999)]),
+        ];
+        for predicate in &predicates {
+            let expected: Vec<KvEntry> = map
+                .range(k(10), k(350))
+                .unwrap()
+                .into_iter()
+                .filter(|entry| predicate.matches(entry.value))
+                .collect();
+            assert_eq!(
+                map.range_filtered(k(10), k(350), predicate).unwrap(),
+                expected,
+                "{predicate:?}"
+            );
+        }
+        assert!(map
+            .range_filtered(k(10), k(10), &ValuePredicate::Ge(v(0)))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn filter_scans_whole_table_and_skips_tombstones() {
+        use super::ValuePredicate;
+
+        let mut map = test_map(Capacity::new(16));
+        map.bulk_put(&[
+            KvEntry {
+                key: k(1),
+                value: v(10),
+            },
+            KvEntry {
+                key: k(2),
+                value: v(20),
+            },
+            KvEntry {
+                key: k(3),
+                value: v(30),
             },
             KvEntry {
-                key: k(2),
-                value: v(20),
+                key: k(u32::MAX),
+                value: v(40),
             },
         ])
         .unwrap();
-        assert!(map.range(k(3), k(1)).is_empty());
+        map.delete(k(2)).unwrap();
+
+        let keys: Vec<Key> = map
+            .filter(&ValuePredicate::Gt(v(5)))
+            .unwrap()
+            .iter()
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(keys, vec![k(1), k(3), k(u32::MAX)]);
+        assert!(map.filter(&ValuePredicate::Eq(v(20))).unwrap().is_empty());
     }
 
     #[test]
-    fn range_empty_on_empty_map() {
-        skip_if_no_gpu!(map, Capacity::new(16));
-        assert!(map.range(k(0), k(10)).is_empty());
+    fn put_rejects_tombstone_value() {
+        let mut map = test_map(Capacity::new(4));
+        let err = map.put(k(1), v(0xFFFF_FFFF)).unwrap_err();
+        assert!(matches!(
+            err,
+            super::GpuMapError::TombstoneValueReserved { .. }
+        ));
     }
 
     #[test]
-    fn range_outside_bounds_is_empty() {
+    fn async_ops_complete_with_explicit_poll() {
+        use std::future::Future;
+        use std::task::{Context, Poll, Wake, Waker};
+
+        struct NoopWaker;
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        fn drive<F: Future>(map_poll: impl Fn() -> bool, future: F) -> F::Output {
+            let mut future = std::pin::pin!(future);
+            let waker = Waker::from(Arc::new(NoopWaker));
+            let mut cx = Context::from_waker(&waker);
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+                map_poll();
+            }
+        }
+
         skip_if_no_gpu!(mut map, Capacity::new(16));
-        map.bulk_put(&[
-            KvEntry {
-                key: k(10),
-                value: v(10),
-            },
-            KvEntry {
-                key: k(20),
-                value: v(20),
-            },
-        ])
+        let device = Arc::clone(map.device().unwrap());
+        let poll = || device.poll(wgpu::Maintain::Poll).is_queue_empty();
+        drive(
+            poll,
+            map.bulk_put_async(&[
+                KvEntry {
+                    key: k(1),
+                    value: v(10),
+                },
+                KvEntry {
+                    key: k(2),
+                    value: v(20),
+                },
+            ]),
+        )
         .unwrap();
-        assert!(map.range(k(0), k(5)).is_empty());
-        assert!(map.range(k(30), k(40)).is_empty());
+        assert_eq!(map.len(), Length::new(2));
+
+        let values = drive(|| map.poll(), map.bulk_get_async(&[k(2), k(3)])).unwrap();
+        assert_eq!(values, vec![Some(v(20)), None]);
+        let entries = drive(|| map.poll(), map.range_async(k(0), k(10))).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(map.poll());
     }
 
     #[test]
-    fn range_clamps_to_existing_keys() {
+    fn async_ops_complete_with_background_poller() {
         skip_if_no_gpu!(mut map, Capacity::new(16));
-        map.bulk_put(&[
+        let poller = map.spawn_poller().unwrap();
+        pollster::block_on(map.bulk_put_async(&[
             KvEntry {
                 key: k(5),
                 value: v(50),
             },
             KvEntry {
-                key: k(10),
-                value: v(100),
-            },
-            KvEntry {
-                key: k(15),
-                value: v(150),
+                key: k(7),
+                value: v(70),
             },
-        ])
+        ]))
         .unwrap();
-        let keys: Vec<Key> = map.range(k(0), k(100)).iter().map(|e| e.key).collect();
-        assert_eq!(keys, vec![k(5), k(10), k(15)]);
+
+        let values = pollster::block_on(map.bulk_get_async(&[k(5), k(6), k(7)])).unwrap();
+        assert_eq!(values, vec![Some(v(50)), None, Some(v(70))]);
+        let entries = pollster::block_on(map.range_async(k(6), k(8))).unwrap();
+        assert_eq!(
+            entries,
+            vec![KvEntry {
+                key: k(7),
+                value: v(70),
+            }]
+        );
+        drop(poller);
+        assert_eq!(map.get(k(5)).unwrap(), Some(v(50)));
     }
 
     #[test]
-    fn range_starts_between_keys() {
-        skip_if_no_gpu!(mut map, Capacity::new(16));
-        map.bulk_put(&[
-            KvEntry {
-                key: k(10),
-                value: v(100),
-            },
-            KvEntry {
-                key: k(20),
-                value: v(200),
-            },
-            KvEntry {
-                key: k(30),
-                value: v(300),
-            },
-        ])
-        .unwrap();
-        let keys: Vec<Key> = map.range(k(15), k(30)).iter().map(|e| e.key).collect();
-        assert_eq!(keys, vec![k(20)]);
+    fn max_key_is_not_confused_with_sort_padding() {
+        let mut map = test_map(Capacity::new(16));
+        // Six entries pad to eight with `u32::MAX` keys.
+        let entries: Vec<KvEntry> = [1, 2, 3, 4, 5, u32::MAX]
+            .iter()
+            .map(|&key| KvEntry {
+                key: k(key),
+                value: v(key % 100 + 1),
+            })
+            .collect();
+        map.bulk_put(&entries).unwrap();
+        assert_eq!(map.get(k(u32::MAX)).unwrap(), Some(v(u32::MAX % 100 + 1)));
+        assert_eq!(map.len(), Length::new(6));
+        assert_eq!(map.range(k(0), k(u32::MAX)).unwrap().len(), 5);
     }
 
     #[test]
-    fn range_excludes_tombstones() {
-        skip_if_no_gpu!(mut map, Capacity::new(16));
+    fn bulk_put_sorts_batches_spanning_many_workgroups() {
+        let mut map = test_map(Capacity::new(8192));
+        // Distinct pseudo-random keys: multiplying by an odd constant permutes u32.
+        let scramble = |i: u32| i.wrapping_mul(0x9E37_79B9) >> 8;
+        let first: Vec<KvEntry> = (0..5000)
+            .map(|i| KvEntry {
+                key: k(scramble(i)),
+                value: v(i),
+            })
+            .collect();
+        map.bulk_put(&first).unwrap();
+        let second: Vec<KvEntry> = (2500..3100)
+            .map(|i| KvEntry {
+                key: k(scramble(i)),
+                value: v(i + 1_000_000),
+            })
+            .chain((5000..5700).map(|i| KvEntry {
+                key: k(scramble(i)),
+                value: v(i),
+            }))
+            .collect();
+        map.bulk_put(&second).unwrap();
+
+        let mut expected: Vec<KvEntry> = first
+            .iter()
+            .filter(|entry| !second.iter().any(|other| other.key == entry.key))
+            .chain(second.iter())
+            .copied()
+            .collect();
+        expected.sort_by_key(|entry| entry.key);
+        assert_eq!(map.len(), Length::new(5700));
+        assert_eq!(map.range(k(0), k(u32::MAX)).unwrap(), expected);
+    }
+
+    #[test]
+    fn apply_batch_collapses_ops_and_checks_capacity_first() {
+        let mut map = test_map(Capacity::new(4));
         map.bulk_put(&[
             KvEntry {
                 key: k(1),
@@ -804,40 +2489,597 @@ mod tests {
                 key: k(2),
                 value: v(20),
             },
-            KvEntry {
-                key: k(3),
-                value: v(30),
-            },
         ])
         .unwrap();
-        map.delete(k(2));
-        let keys: Vec<Key> = map.range(k(1), k(4)).iter().map(|e| e.key).collect();
-        assert_eq!(keys, vec![k(1), k(3)]);
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(k(3), v(30))
+            .delete(k(1))
+            .put(k(4), v(40))
+            .put(k(3), v(31))
+            .delete(k(4))
+            .put(k(5), v(50));
+        map.apply_batch(&batch).unwrap();
+        assert_eq!(
+            map.bulk_get(&[k(1), k(2), k(3), k(4), k(5)]).unwrap(),
+            vec![None, Some(v(20)), Some(v(31)), None, Some(v(50))]
+        );
+        assert_eq!(map.len(), Length::new(3));
+
+        let mut overflow = WriteBatch::new();
+        overflow
+            .delete(k(2))
+            .put(k(6), v(60))
+            .put(k(7), v(70))
+            .put(k(8), v(80));
+        assert!(matches!(
+            map.apply_batch(&overflow),
+            Err(GpuMapError::CapacityExceeded { .. })
+        ));
+        assert_eq!(map.get(k(2)).unwrap(), Some(v(20)));
+        assert_eq!(map.len(), Length::new(3));
+
+        let mut reserved = WriteBatch::new();
+        reserved.delete(k(2)).put(k(6), Value::new(0xFFFF_FFFF));
+        assert!(matches!(
+            map.apply_batch(&reserved),
+            Err(GpuMapError::TombstoneValueReserved { .. })
+        ));
+        assert_eq!(map.get(k(2)).unwrap(), Some(v(20)));
     }
 
     #[test]
-    fn put_rejects_tombstone_value() {
-        skip_if_no_gpu!(mut map, Capacity::new(4));
-        let err = map.put(k(1), v(0xFFFF_FFFF)).unwrap_err();
+    fn apply_batch_failing_the_padding_check_leaves_the_map_unchanged() {
+        let cpu = pollster::block_on(GpuSortedMap::with_backend(Capacity::new(6), Backend::Cpu));
+        for mut map in [test_map(Capacity::new(6)), cpu.unwrap()] {
+            let backend = map.backend();
+            map.put(k(100), v(1)).unwrap();
+
+            // Five puts fit once 100 is gone, but pad to 8 slots.
+            let mut batch = WriteBatch::new();
+            batch.delete(k(100));
+            for key in 1..=5 {
+                batch.put(k(key), v(key));
+            }
+            assert!(matches!(
+                map.apply_batch(&batch),
+                Err(GpuMapError::CapacityExceeded { .. })
+            ));
+            assert_eq!(map.get(k(100)).unwrap(), Some(v(1)), "{:?}", backend);
+            assert_eq!(map.len(), Length::new(1), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn failed_ttl_write_rolls_back_the_data_merge() {
+        let cpu = pollster::block_on(GpuSortedMap::with_backend(Capacity::new(8), Backend::Cpu));
+        for mut map in [test_map(Capacity::new(8)), cpu.unwrap()] {
+            let backend = map.backend();
+            map.put(k(1), v(10)).unwrap();
+            // An expiry map with no room left makes the expiry write fail
+            // after the data merge succeeded.
+            let full =
+                pollster::block_on(GpuSortedMap::with_backend(Capacity::new(1), Backend::Cpu));
+            let mut full = full.unwrap();
+            full.put(k(9), v(1)).unwrap();
+            map.expiries = Some(Box::new(full));
+
+            let entries = [
+                KvEntry {
+                    key: k(1),
+                    value: v(11),
+                },
+                KvEntry {
+                    key: k(2),
+                    value: v(20),
+                },
+            ];
+            assert!(
+                matches!(
+                    map.bulk_put_with_ttl(&entries, 5),
+                    Err(GpuMapError::CapacityExceeded { .. })
+                ),
+                "{:?}",
+                backend
+            );
+            assert_eq!(
+                map.bulk_get(&[k(1), k(2)]).unwrap(),
+                vec![Some(v(10)), None],
+                "{:?}",
+                backend
+            );
+            assert_eq!(map.len(), Length::new(1), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn ttl_entries_read_as_absent_once_expired() {
+        use super::ValuePredicate;
+
+        let mut map = test_map(Capacity::new(64));
+        map.put(k(1), v(10)).unwrap();
+        map.put_with_ttl(k(2), v(20), 5).unwrap();
+        map.bulk_put_with_ttl(
+            &[
+                KvEntry {
+                    key: k(3),
+                    value: v(30),
+                },
+                KvEntry {
+                    key: k(4),
+                    value: v(40),
+                },
+            ],
+            10,
+        )
+        .unwrap();
+        // A plain put clears the key's TTL.
+        map.put(k(4), v(41)).unwrap();
+
+        map.advance_clock(4);
+        assert_eq!(map.get(k(2)).unwrap(), Some(v(20)));
+        map.advance_clock(5);
+        let before = map.snapshot();
+        assert_eq!(
+            map.bulk_get(&[k(1), k(2), k(3), k(4)]).unwrap(),
+            vec![Some(v(10)), None, Some(v(30)), Some(v(41))]
+        );
+        assert_eq!(map.range(k(0), k(10)).unwrap().len(), 3);
+        assert_eq!(map.filter(&ValuePredicate::Ge(v(0))).unwrap().len(), 3);
+        assert_eq!(map.len(), Length::new(4));
+
+        map.advance_clock(3);
+        assert_eq!(map.clock(), 5, "the clock never moves back");
+        assert_eq!(map.expire(12).unwrap(), 2);
+        assert_eq!(map.len(), Length::new(2));
+        assert_eq!(map.range(k(0), k(10)).unwrap().len(), 2);
+        assert_eq!(map.expire(100).unwrap(), 0);
+
+        // Snapshots judge TTLs by the clock they were taken at.
+        assert_eq!(before.clock(), 5);
+        assert_eq!(before.get(k(3)).unwrap(), Some(v(30)));
+        assert_eq!(before.range(k(0), k(10)).unwrap().len(), 3);
+
+        map.put_with_ttl(k(2), v(21), u32::MAX).unwrap();
+        assert_eq!(map.expire(u32::MAX - 2).unwrap(), 0);
+        assert_eq!(map.get(k(2)).unwrap(), Some(v(21)));
+    }
+
+    #[test]
+    fn submission_queue_hides_expired_entries() {
+        let mut map = test_map(Capacity::new(64));
+        map.put(k(1), v(10)).unwrap();
+        map.put_with_ttl(k(2), v(20), 1).unwrap();
+        map.advance_clock(1);
+
+        let mut queue = map.submission_queue(SubmissionQueue::DEFAULT_DEPTH);
+        let get = queue.enqueue_get(&[k(1), k(2)]);
+        let range = queue.enqueue_range(k(0), k(10));
+        let mut results = queue.drain();
+        results.sort_by_key(|(id, _)| *id);
+        assert_eq!(
+            results,
+            vec![
+                (get, BatchResult::Get(vec![Some(v(10)), None])),
+                (
+                    range,
+                    BatchResult::Range(vec![KvEntry {
+                        key: k(1),
+                        value: v(10)
+                    }])
+                ),
+            ]
+        );
+    }
+
+    /// Destroy the map's device and poll it, so wgpu reports the loss.
+    fn lose_device(map: &GpuSortedMap) {
+        map.device().unwrap().destroy();
+        map.device().unwrap().poll(wgpu::Maintain::Poll);
+    }
+
+    #[test]
+    fn lost_device_fails_every_operation_until_recovered() {
+        use super::ValuePredicate;
+
+        skip_if_no_gpu!(mut map, Capacity::new(64));
+        map.set_host_shadow(true).unwrap();
+        let entries: Vec<KvEntry> = (0..10)
+            .map(|i| KvEntry {
+                key: k(i),
+                value: v(i * 10),
+            })
+            .collect();
+        map.bulk_put(&entries).unwrap();
+        map.bulk_delete(&[k(3), k(4)]).unwrap();
+        map.put_with_ttl(k(20), v(200), 5).unwrap();
+        map.map_values(
+            k(0)..k(2),
+            "fn f(key: u32, value: u32) -> u32 { return value + 1u; }",
+        )
+        .unwrap();
+        let snapshot = map.snapshot();
+        let expected = map.range(k(0), k(100)).unwrap();
+
+        lose_device(&map);
+        assert!(map.is_device_lost());
+        assert_eq!(map.get(k(1)), Err(GpuMapError::DeviceLost));
+        assert_eq!(map.put(k(50), v(1)), Err(GpuMapError::DeviceLost));
+        assert_eq!(map.delete(k(1)), Err(GpuMapError::DeviceLost));
+        assert_eq!(map.range(k(0), k(100)), Err(GpuMapError::DeviceLost));
+        assert_eq!(
+            map.filter(&ValuePredicate::Ge(v(0))),
+            Err(GpuMapError::DeviceLost)
+        );
+        assert_eq!(snapshot.get(k(1)), Err(GpuMapError::DeviceLost));
+        {
+            let mut queue = map.submission_queue(SubmissionQueue::DEFAULT_DEPTH);
+            let id = queue.enqueue_get(&[k(1)]);
+            assert_eq!(
+                queue.drain(),
+                vec![(id, BatchResult::Failed(GpuMapError::DeviceLost))]
+            );
+        }
+
+        map.recover().unwrap();
+        assert!(!map.is_device_lost());
+        assert_eq!(map.range(k(0), k(100)).unwrap(), expected);
+        assert_eq!(map.len(), Length::new(9));
+        assert_eq!(map.get(k(1)).unwrap(), Some(v(11)));
+        assert_eq!(map.expire(5).unwrap(), 1);
+        assert_eq!(map.get(k(20)).unwrap(), None);
+
+        map.put(k(50), v(500)).unwrap();
+        assert_eq!(map.get(k(50)).unwrap(), Some(v(500)));
+    }
+
+    #[test]
+    fn recover_restores_the_last_checkpoint() {
+        skip_if_no_gpu!(mut map, Capacity::new(16));
+        map.put(k(1), v(10)).unwrap();
+        map.put(k(2), v(20)).unwrap();
+        map.checkpoint().unwrap();
+        map.put(k(3), v(30)).unwrap();
+        map.delete(k(1)).unwrap();
+
+        lose_device(&map);
+        map.recover().unwrap();
+        assert_eq!(
+            map.range(k(0), k(10)).unwrap(),
+            vec![
+                KvEntry {
+                    key: k(1),
+                    value: v(10)
+                },
+                KvEntry {
+                    key: k(2),
+                    value: v(20)
+                },
+            ]
+        );
+        assert_eq!(map.len(), Length::new(2));
+    }
+
+    #[test]
+    fn recover_keeps_cpu_maps_unchanged() {
+        let mut map =
+            pollster::block_on(GpuSortedMap::with_backend(Capacity::new(8), Backend::Cpu)).unwrap();
+        map.put(k(1), v(10)).unwrap();
+        assert!(!map.is_device_lost());
+        map.recover().unwrap();
+        assert_eq!(map.get(k(1)).unwrap(), Some(v(10)));
+        if let Ok(gpu) = pollster::block_on(GpuSortedMap::new(Capacity::new(8))) {
+            assert_eq!(
+                map.recover_with_device(
+                    Arc::clone(gpu.device().unwrap()),
+                    Arc::clone(gpu.queue().unwrap())
+                ),
+                Err(GpuMapError::BackendUnsupported {
+                    operation: "recover_with_device",
+                    mode: "the CPU backend",
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn slab_beyond_one_binding_is_split_into_segments() {
+        use super::ValuePredicate;
+        use std::collections::BTreeMap;
+
+        // 512-byte bindings hold segments of 64 entries.
+        let Some((device, queue)) = try_create_device_queue_with_limits(wgpu::Limits {
+            max_storage_buffer_binding_size: 512,
+            ..wgpu::Limits::default()
+        }) else {
+            eprintln!("Skipping test: GPU not available in this environment");
+            return;
+        };
+        let mut map = GpuSortedMap::with_device(device, queue, Capacity::new(2048)).unwrap();
+        let mut model = BTreeMap::new();
+
+        // One batch larger than a segment, then smaller ones that fill the
+        // segments they land in.
+        let first: Vec<KvEntry> = (0..300)
+            .map(|i| KvEntry {
+                key: k(i * 3),
+                value: v(i),
+            })
+            .collect();
+        map.bulk_put(&first).unwrap();
+        model.extend(first.iter().map(|entry| (entry.key, entry.value)));
+        for round in 0..10 {
+            let batch: Vec<KvEntry> = (0..40)
+                .map(|i| KvEntry {
+                    key: k(i * 23 + round),
+                    value: v(round * 100 + i),
+                })
+                .collect();
+            map.bulk_put(&batch).unwrap();
+            model.extend(batch.iter().map(|entry| (entry.key, entry.value)));
+        }
+        assert!(map.segment_count() > 1);
+        assert_eq!(map.len(), Length::new(model.len() as u32));
         assert!(matches!(
-            err,
-            super::GpuMapError::TombstoneValueReserved { .. }
+            map.slab_binding(),
+            Err(GpuMapError::BackendUnsupported {
+                mode: "maps split into segments",
+                ..
+            })
         ));
+
+        let keys: Vec<Key> = (0..1000).map(k).collect();
+        let expected: Vec<Option<Value>> = keys.iter().map(|key| model.get(key).copied()).collect();
+        assert_eq!(map.bulk_get(&keys).unwrap(), expected);
+
+        let deleted: Vec<Key> = (0..1000).step_by(7).map(k).collect();
+        map.bulk_delete(&deleted).unwrap();
+        for key in &deleted {
+            model.remove(key);
+        }
+        assert_eq!(map.len(), Length::new(model.len() as u32));
+
+        let in_range = |from: u32, to: u32| -> Vec<KvEntry> {
+            model
+                .range(k(from)..k(to))
+                .map(|(&key, &value)| KvEntry { key, value })
+                .collect()
+        };
+        assert_eq!(map.range(k(100), k(800)).unwrap(), in_range(100, 800));
+        assert_eq!(map.range(k(0), k(u32::MAX)).unwrap(), in_range(0, u32::MAX));
+
+        let snapshot = map.snapshot();
+        let predicate = ValuePredicate::Lt(v(150));
+        let matching: Vec<KvEntry> = in_range(200, 900)
+            .into_iter()
+            .filter(|entry| entry.value.0 < 150)
+            .collect();
+        assert_eq!(
+            map.range_filtered(k(200), k(900), &predicate).unwrap(),
+            matching
+        );
+
+        map.map_values(
+            k(500)..,
+            "fn f(key: u32, value: u32) -> u32 { return value + 1u; }",
+        )
+        .unwrap();
+        assert_eq!(
+            map.get(k(501)).unwrap(),
+            model.get(&k(501)).map(|x| v(x.0 + 1))
+        );
+        assert_eq!(snapshot.get(k(501)).unwrap(), model.get(&k(501)).copied());
+        assert_eq!(
+            snapshot.range(k(0), k(u32::MAX)).unwrap(),
+            in_range(0, u32::MAX)
+        );
+
+        map.put_with_ttl(k(5000), v(1), 5).unwrap();
+        map.advance_clock(5);
+        assert_eq!(map.get(k(5000)).unwrap(), None);
+
+        // Segments are rebuilt on the recovered device.
+        map.checkpoint().unwrap();
+        lose_device(&map);
+        let (device, queue) = try_create_device_queue_with_limits(wgpu::Limits {
+            max_storage_buffer_binding_size: 512,
+            ..wgpu::Limits::default()
+        })
+        .unwrap();
+        map.recover_with_device(device, queue).unwrap();
+        assert!(map.segment_count() > 1);
+        assert_eq!(
+            map.get(k(501)).unwrap(),
+            model.get(&k(501)).map(|x| v(x.0 + 1))
+        );
+        assert_eq!(map.range(k(0), k(500)).unwrap(), in_range(0, 500));
+    }
+
+    #[test]
+    fn segmented_maps_reject_leveled_mode() {
+        let Some((device, queue)) = try_create_device_queue_with_limits(wgpu::Limits {
+            max_storage_buffer_binding_size: 512,
+            ..wgpu::Limits::default()
+        }) else {
+            eprintln!("Skipping test: GPU not available in this environment");
+            return;
+        };
+        let mut map = GpuSortedMap::with_device(device, queue, Capacity::new(2048)).unwrap();
+        let batch: Vec<KvEntry> = (0..300)
+            .map(|i| KvEntry {
+                key: k(i),
+                value: v(i),
+            })
+            .collect();
+        map.bulk_put(&batch).unwrap();
+        assert!(map.segment_count() > 1);
+
+        let err = map
+            .set_leveled(Some(super::LeveledConfig::default()))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            GpuMapError::BackendUnsupported {
+                operation: "set_leveled",
+                mode: "maps split into segments",
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "set_leveled is not supported by maps split into segments"
+        );
+        assert_eq!(map.leveled(), None);
+        map.set_leveled(None).unwrap();
+        assert_eq!(map.get(k(299)).unwrap(), Some(v(299)));
+    }
+
+    #[test]
+    fn dispatches_fold_when_workgroup_counts_exceed_the_device_limit() {
+        use super::ValuePredicate;
+
+        let Some((device, queue)) = try_create_device_queue_with_limits(wgpu::Limits {
+            max_compute_workgroups_per_dimension: 12,
+            ..wgpu::Limits::default()
+        }) else {
+            eprintln!("Skipping test: GPU not available in this environment");
+            return;
+        };
+        let mut map = GpuSortedMap::with_device(device, queue, Capacity::new(8192)).unwrap();
+        // 5000 entries pad to 8192: sixteen sort tiles and 128-workgroup passes.
+        let entries: Vec<KvEntry> = (0..5000)
+            .rev()
+            .map(|i| KvEntry {
+                key: k(i * 2),
+                value: v(i),
+            })
+            .collect();
+        map.bulk_put(&entries).unwrap();
+
+        let keys: Vec<Key> = (0..5000).map(|i| k(i * 2)).collect();
+        let expected: Vec<Option<Value>> = (0..5000).map(|i| Some(v(i))).collect();
+        assert_eq!(map.bulk_get(&keys).unwrap(), expected);
+
+        map.map_values(
+            ..,
+            "fn f(key: u32, value: u32) -> u32 { return value + 1u; }",
+        )
+        .unwrap();
+        map.bulk_delete(&keys[..1000]).unwrap();
+        assert_eq!(map.len(), Length::new(4000));
+        let found = map.filter(&ValuePredicate::Ge(v(0))).unwrap();
+        assert_eq!(found.len(), 4000);
+        assert_eq!(
+            found[0],
+            KvEntry {
+                key: k(2000),
+                value: v(1001)
+            }
+        );
+        assert_eq!(
+            found[3999],
+            KvEntry {
+                key: k(9998),
+                value: v(5000)
+            }
+        );
+    }
+
+    #[test]
+    fn leveled_mode_matches_a_single_slab() {
+        use super::{LeveledConfig, ValuePredicate};
+        use std::collections::BTreeMap;
+
+        skip_if_no_gpu!(mut map, Capacity::new(1024));
+        map.bulk_put(&[KvEntry {
+            key: k(7),
+            value: v(70),
+        }])
+        .unwrap();
+        let config = LeveledConfig {
+            l0_capacity: Capacity::new(8),
+            size_ratio: 4,
+        };
+        map.set_leveled(Some(config)).unwrap();
+        assert_eq!(map.leveled(), Some(config));
+        let mut model = BTreeMap::from([(k(7), v(70))]);
+
+        // Small puts land in L0 and spill into larger runs as it fills.
+        for round in 0..60u32 {
+            let batch: Vec<KvEntry> = (0..5)
+                .map(|i| KvEntry {
+                    key: k((round * 37 + i * 101) % 700),
+                    value: v(round * 10 + i),
+                })
+                .collect();
+            map.bulk_put(&batch).unwrap();
+            model.extend(batch.iter().map(|entry| (entry.key, entry.value)));
+            if round % 7 == 3 {
+                let keys = [k(round * 11 % 700), k(7), k(round * 37 % 700)];
+                map.bulk_delete(&keys).unwrap();
+                for key in &keys {
+                    model.remove(key);
+                }
+            }
+            if round == 30 {
+                assert!(map.run_count() > 1);
+            }
+        }
+        assert_eq!(map.len(), Length::new(model.len() as u32));
+
+        let keys: Vec<Key> = (0..720).map(k).collect();
+        let expected: Vec<Option<Value>> = keys.iter().map(|key| model.get(key).copied()).collect();
+        assert_eq!(map.bulk_get(&keys).unwrap(), expected);
+        let all: Vec<KvEntry> = model
+            .iter()
+            .map(|(&key, &value)| KvEntry { key, value })
+            .collect();
+        assert_eq!(map.range(k(0), k(u32::MAX)).unwrap(), all);
+        let small: Vec<KvEntry> = all.iter().copied().filter(|e| e.value.0 < 200).collect();
+        assert_eq!(map.filter(&ValuePredicate::Lt(v(200))).unwrap(), small);
+
+        let snapshot = map.snapshot();
+        map.map_values(
+            ..,
+            "fn f(key: u32, value: u32) -> u32 { return value + 1u; }",
+        )
+        .unwrap();
+        map.put(k(900), v(9)).unwrap();
+        assert_eq!(snapshot.range(k(0), k(u32::MAX)).unwrap(), all);
+        let bumped: Vec<KvEntry> = all
+            .iter()
+            .map(|e| KvEntry {
+                key: e.key,
+                value: v(e.value.0 + 1),
+            })
+            .chain([KvEntry {
+                key: k(900),
+                value: v(9),
+            }])
+            .collect();
+        assert_eq!(map.range(k(0), k(u32::MAX)).unwrap(), bumped);
+
+        // Leaving leveled mode merges the runs into one slab.
+        map.set_leveled(None).unwrap();
+        assert_eq!(map.leveled(), None);
+        assert_eq!(map.run_count(), 1);
+        assert_eq!(map.range(k(0), k(u32::MAX)).unwrap(), bumped);
+        assert_eq!(map.len(), Length::new(bumped.len() as u32));
     }
 
     #[test]
     fn bulk_get_empty_keys() {
-        skip_if_no_gpu!(map, Capacity::new(10));
-        let results = map.bulk_get(&[]);
+        let map = test_map(Capacity::new(10));
+        let results = map.bulk_get(&[]).unwrap();
         assert!(results.is_empty());
     }
 
     #[test]
     fn bulk_delete_empty_keys() {
-        skip_if_no_gpu!(mut map, Capacity::new(10));
+        let mut map = test_map(Capacity::new(10));
         map.put(k(1), v(10)).unwrap();
-        map.bulk_delete(&[]);
-        assert_eq!(map.get(k(1)), Some(v(10)));
+        map.bulk_delete(&[]).unwrap();
+        assert_eq!(map.get(k(1)).unwrap(), Some(v(10)));
     }
 
     #[test]
@@ -848,7 +3090,7 @@ mod tests {
         // If I use capacity 6 and entries 5.
         // High-level: 5 <= 6. OK.
         // Internal: next_power_of_two(5) = 8. 8 > 6. FAIL.
-        skip_if_no_gpu!(mut map, Capacity::new(6));
+        let mut map = test_map(Capacity::new(6));
         let entries = [
             KvEntry {
                 key: k(1),
@@ -880,14 +3122,14 @@ mod tests {
 
     #[test]
     fn is_empty_returns_true_for_new_map() {
-        skip_if_no_gpu!(map, Capacity::new(10));
+        let map = test_map(Capacity::new(10));
         assert!(map.is_empty());
         assert_eq!(map.len(), Length::new(0));
     }
 
     #[test]
     fn is_empty_returns_false_after_insert() {
-        skip_if_no_gpu!(mut map, Capacity::new(10));
+        let mut map = test_map(Capacity::new(10));
         map.put(k(1), v(10)).unwrap();
         assert!(!map.is_empty());
         assert_eq!(map.len(), Length::new(1));
@@ -895,12 +3137,12 @@ mod tests {
 
     #[test]
     fn update_overwrites_existing_key() {
-        skip_if_no_gpu!(mut map, Capacity::new(8));
+        let mut map = test_map(Capacity::new(8));
         map.put(k(1), v(10)).unwrap();
         map.put(k(1), v(20)).unwrap();
 
-        assert_eq!(map.get(k(1)), Some(v(20)));
-        let entries = map.range(k(1), k(2));
+        assert_eq!(map.get(k(1)).unwrap(), Some(v(20)));
+        let entries = map.range(k(1), k(2)).unwrap();
         assert_eq!(entries.len(), 1);
         assert_eq!(entries[0].value, v(20));
         assert_eq!(map.len(), Length::new(1));
@@ -908,7 +3150,7 @@ mod tests {
 
     #[test]
     fn delete_reduces_live_len() {
-        skip_if_no_gpu!(mut map, Capacity::new(8));
+        let mut map = test_map(Capacity::new(8));
         map.bulk_put(&[
             KvEntry {
                 key: k(1),
@@ -920,14 +3162,14 @@ mod tests {
             },
         ])
         .unwrap();
-        map.bulk_delete(&[k(1), k(2)]);
+        map.bulk_delete(&[k(1), k(2)]).unwrap();
         assert_eq!(map.len(), Length::new(0));
         assert!(map.is_empty());
     }
 
     #[test]
     fn put_compacts_tombstones_and_preserves_live_data() {
-        skip_if_no_gpu!(mut map, Capacity::new(16));
+        let mut map = test_map(Capacity::new(16));
         map.bulk_put(&[
             KvEntry {
                 key: k(1),
@@ -952,21 +3194,21 @@ mod tests {
         ])
         .unwrap();
 
-        map.bulk_delete(&[k(2), k(4)]);
+        map.bulk_delete(&[k(2), k(4)]).unwrap();
         map.bulk_put(&[KvEntry {
             key: k(6),
             value: v(60),
         }])
         .unwrap();
 
-        assert_eq!(map.get(k(1)), Some(v(10)));
-        assert_eq!(map.get(k(2)), None);
-        assert_eq!(map.get(k(3)), Some(v(30)));
-        assert_eq!(map.get(k(4)), None);
-        assert_eq!(map.get(k(5)), Some(v(50)));
-        assert_eq!(map.get(k(6)), Some(v(60)));
+        assert_eq!(map.get(k(1)).unwrap(), Some(v(10)));
+        assert_eq!(map.get(k(2)).unwrap(), None);
+        assert_eq!(map.get(k(3)).unwrap(), Some(v(30)));
+        assert_eq!(map.get(k(4)).unwrap(), None);
+        assert_eq!(map.get(k(5)).unwrap(), Some(v(50)));
+        assert_eq!(map.get(k(6)).unwrap(), Some(v(60)));
 
-        let entries = map.range(k(1), k(7));
+        let entries = map.range(k(1), k(7)).unwrap();
         let keys: Vec<Key> = entries.iter().map(|entry| entry.key).collect();
         assert_eq!(keys, vec![k(1), k(3), k(5), k(6)]);
         assert_eq!(map.len(), Length::new(4));
@@ -974,13 +3216,13 @@ mod tests {
 
     #[test]
     fn put_revives_deleted_key_instead_of_creating_duplicate() {
-        skip_if_no_gpu!(mut map, Capacity::new(8));
+        let mut map = test_map(Capacity::new(8));
         map.put(k(42), v(1)).unwrap();
-        map.delete(k(42));
+        map.delete(k(42)).unwrap();
         map.put(k(42), v(2)).unwrap();
 
-        assert_eq!(map.get(k(42)), Some(v(2)));
-        let entries = map.range(k(42), k(43));
+        assert_eq!(map.get(k(42)).unwrap(), Some(v(2)));
+        let entries = map.range(k(42), k(43)).unwrap();
         assert_eq!(entries.len(), 1);
         assert_eq!(entries[0].key, k(42));
         assert_eq!(entries[0].value, v(2));
@@ -989,7 +3231,7 @@ mod tests {
 
     #[test]
     fn put_after_many_deletes_does_not_false_capacity_exceed() {
-        skip_if_no_gpu!(mut map, Capacity::new(8));
+        let mut map = test_map(Capacity::new(8));
         map.bulk_put(&[
             KvEntry {
                 key: k(1),
@@ -1017,20 +3259,20 @@ mod tests {
             },
         ])
         .unwrap();
-        map.bulk_delete(&[k(2), k(3), k(4), k(5), k(6)]);
+        map.bulk_delete(&[k(2), k(3), k(4), k(5), k(6)]).unwrap();
 
         let res = map.bulk_put(&[KvEntry {
             key: k(100),
             value: v(100),
         }]);
         assert!(res.is_ok());
-        assert_eq!(map.get(k(100)), Some(v(100)));
+        assert_eq!(map.get(k(100)).unwrap(), Some(v(100)));
         assert_eq!(map.len(), Length::new(2));
     }
 
     #[test]
     fn put_with_no_new_entries_still_compacts() {
-        skip_if_no_gpu!(mut map, Capacity::new(8));
+        let mut map = test_map(Capacity::new(8));
         map.bulk_put(&[
             KvEntry {
                 key: k(1),
@@ -1059,7 +3301,7 @@ mod tests {
         ])
         .unwrap();
 
-        map.bulk_delete(&[k(2), k(4), k(6)]);
+        map.bulk_delete(&[k(2), k(4), k(6)]).unwrap();
         map.bulk_put(&[
             KvEntry {
                 key: k(1),
@@ -1081,16 +3323,16 @@ mod tests {
             value: v(70),
         }]);
         assert!(insert_after_compact.is_ok());
-        assert_eq!(map.get(k(1)), Some(v(100)));
-        assert_eq!(map.get(k(3)), Some(v(300)));
-        assert_eq!(map.get(k(5)), Some(v(500)));
-        assert_eq!(map.get(k(7)), Some(v(70)));
+        assert_eq!(map.get(k(1)).unwrap(), Some(v(100)));
+        assert_eq!(map.get(k(3)).unwrap(), Some(v(300)));
+        assert_eq!(map.get(k(5)).unwrap(), Some(v(500)));
+        assert_eq!(map.get(k(7)).unwrap(), Some(v(70)));
         assert_eq!(map.len(), Length::new(4));
     }
 
     #[test]
     fn range_after_compacting_put_excludes_old_tombstoned_keys() {
-        skip_if_no_gpu!(mut map, Capacity::new(10));
+        let mut map = test_map(Capacity::new(10));
         map.bulk_put(&[
             KvEntry {
                 key: k(1),
@@ -1115,14 +3357,14 @@ mod tests {
         ])
         .unwrap();
 
-        map.bulk_delete(&[k(2), k(4)]);
+        map.bulk_delete(&[k(2), k(4)]).unwrap();
         map.bulk_put(&[KvEntry {
             key: k(6),
             value: v(60),
         }])
         .unwrap();
 
-        let entries = map.range(k(1), k(7));
+        let entries = map.range(k(1), k(7)).unwrap();
         let pairs: Vec<(Key, Value)> = entries.iter().map(|e| (e.key, e.value)).collect();
         assert_eq!(
             pairs,
@@ -1132,7 +3374,7 @@ mod tests {
 
     #[test]
     fn bulk_delete_then_bulk_put_mixed_overlap_is_consistent() {
-        skip_if_no_gpu!(mut map, Capacity::new(12));
+        let mut map = test_map(Capacity::new(12));
         map.bulk_put(&[
             KvEntry {
                 key: k(1),
@@ -1161,7 +3403,7 @@ mod tests {
         ])
         .unwrap();
 
-        map.bulk_delete(&[k(2), k(4), k(6)]);
+        map.bulk_delete(&[k(2), k(4), k(6)]).unwrap();
         map.bulk_put(&[
             KvEntry {
                 key: k(2),
@@ -1182,7 +3424,7 @@ mod tests {
         ])
         .unwrap();
 
-        let entries = map.range(k(1), k(9));
+        let entries = map.range(k(1), k(9)).unwrap();
         let pairs: Vec<(Key, Value)> = entries.iter().map(|e| (e.key, e.value)).collect();
         assert_eq!(
             pairs,
@@ -1201,7 +3443,7 @@ mod tests {
 
     #[test]
     fn duplicate_keys_in_bulk_put_is_error_and_atomic() {
-        skip_if_no_gpu!(mut map, Capacity::new(8));
+        let mut map = test_map(Capacity::new(8));
         map.put(k(1), v(10)).unwrap();
 
         let entries = [
@@ -1217,8 +3459,8 @@ mod tests {
         let err = map.bulk_put(&entries).unwrap_err();
         assert!(matches!(err, super::GpuMapError::DuplicateKeys { .. }));
 
-        assert_eq!(map.get(k(1)), Some(v(10)));
-        assert_eq!(map.get(k(2)), None);
+        assert_eq!(map.get(k(1)).unwrap(), Some(v(10)));
+        assert_eq!(map.get(k(2)).unwrap(), None);
         assert_eq!(map.len(), Length::new(1));
     }
 }
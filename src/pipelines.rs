@@ -3,11 +3,23 @@ pub mod bulk_get;
 pub mod bulk_put;
 pub mod core;
 pub mod data;
+pub mod filter_scan;
+pub mod map_values;
+pub mod pool;
 pub mod range_scan;
+pub mod search_index;
+pub mod sorted_get;
 pub mod utils;
+pub mod wgsl;
 
 pub use bulk_delete::BulkDeletePipeline;
 pub use bulk_get::BulkGetPipeline;
 pub use bulk_put::BulkPutPipeline;
 pub use data::MergeMeta;
+pub use filter_scan::FilterScanPipeline;
+pub use map_values::MapValuesPipeline;
+pub use pool::{BufferPool, PoolStats, PooledBuffer, DEFAULT_MAX_RETAINED_BYTES};
 pub use range_scan::RangeScanPipeline;
+pub use search_index::{SearchIndex, SearchIndexPipeline};
+pub use sorted_get::SortedGetPipeline;
+pub use wgsl::SLAB_WGSL;
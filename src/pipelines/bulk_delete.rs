@@ -1,32 +1,47 @@
 //! Bulk delete pipeline.
 //!
-//! One GPU thread handles one key, binary-searches the sorted slab, and marks
-//! a match as tombstoned by writing the reserved sentinel value.
+//! One GPU thread handles one key, binary-searches the sorted slab through
+//! its search index, and marks a match as tombstoned by writing the
+//! reserved sentinel value.
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::gpu_array::GpuArray;
 use crate::pipelines::core::ComputeStep;
 use crate::pipelines::data::KeysMeta;
-use crate::pipelines::utils::create_buffer_with_data;
-use crate::{Key, KvEntry};
+use crate::pipelines::pool::BufferPool;
+use crate::pipelines::search_index::SearchIndex;
+use crate::pipelines::utils::ErrorScope;
+use crate::pipelines::wgsl::{search_index_wgsl, slab_wgsl, with_workgroup_size};
+use crate::{GpuMapError, Key, KvEntry};
 
 const BULK_DELETE_BIND_SLAB: u32 = 0;
 const BULK_DELETE_BIND_SLAB_META: u32 = 1;
 const BULK_DELETE_BIND_KEYS: u32 = 2;
 const BULK_DELETE_BIND_KEYS_META: u32 = 3;
+const BULK_DELETE_BIND_FENCES: u32 = 4;
+const BULK_DELETE_BIND_INDEX_META: u32 = 5;
 
 pub struct BulkDeletePipeline {
     device: Arc<wgpu::Device>,
     queue: Arc<wgpu::Queue>,
+    pool: Arc<BufferPool>,
+    scopes: Arc<Mutex<()>>,
+    workgroup_size: u32,
     step: ComputeStep,
 }
 
 impl BulkDeletePipeline {
-    pub fn new(device: Arc<wgpu::Device>, queue: Arc<wgpu::Queue>) -> Self {
+    pub fn new(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        pool: Arc<BufferPool>,
+        scopes: Arc<Mutex<()>>,
+        workgroup_size: u32,
+    ) -> Self {
         let step = ComputeStep::new(
             Arc::clone(&device),
-            BULK_DELETE_WGSL,
+            &with_workgroup_size(BULK_DELETE_WGSL, workgroup_size),
             "main",
             &[
                 wgpu::BindGroupLayoutEntry {
@@ -69,22 +84,51 @@ impl BulkDeletePipeline {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: BULK_DELETE_BIND_FENCES,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: BULK_DELETE_BIND_INDEX_META,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         );
         Self {
             device,
             queue,
+            pool,
+            scopes,
+            workgroup_size,
             step,
         }
     }
 
-    pub fn execute(&self, slab: &GpuArray<KvEntry>, keys: &[Key]) {
+    pub async fn execute(
+        &self,
+        slab: &GpuArray<KvEntry>,
+        index: &SearchIndex,
+        keys: &[Key],
+    ) -> Result<(), GpuMapError> {
         if keys.is_empty() {
-            return;
+            return Ok(());
         }
 
-        let keys_buffer = create_buffer_with_data(
-            &self.device,
+        let scope = ErrorScope::push(&self.device, &self.scopes);
+        let keys_buffer = self.pool.acquire_with_data(
+            &self.queue,
             "delete-keys-buffer",
             wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
             keys,
@@ -93,14 +137,15 @@ impl BulkDeletePipeline {
             len: keys.len() as u32,
             _pad: [0; 3],
         };
-        let keys_meta_buffer = create_buffer_with_data(
-            &self.device,
+        let keys_meta_buffer = self.pool.acquire_with_data(
+            &self.queue,
             "delete-keys-meta-buffer",
             wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             &[keys_meta],
         );
 
-        let bind_group = self.step.create_bind_group(
+        let bind_group = self.step.cached_bind_group(
+            &self.pool,
             "bulk-delete-bind-group",
             &[
                 wgpu::BindGroupEntry {
@@ -119,6 +164,14 @@ impl BulkDeletePipeline {
                     binding: BULK_DELETE_BIND_KEYS_META,
                     resource: keys_meta_buffer.as_entire_binding(),
                 },
+                wgpu::BindGroupEntry {
+                    binding: BULK_DELETE_BIND_FENCES,
+                    resource: index.fences().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: BULK_DELETE_BIND_INDEX_META,
+                    resource: index.meta().as_entire_binding(),
+                },
             ],
         );
 
@@ -128,31 +181,33 @@ impl BulkDeletePipeline {
                 label: Some("bulk-delete-encoder"),
             });
 
-        let workgroups = (keys.len() as u32).div_ceil(64);
-        self.step.dispatch(
-            &mut encoder,
-            "bulk-delete-pass",
-            &bind_group,
-            (workgroups, 1, 1),
-        );
+        let workgroups = (keys.len() as u32).div_ceil(self.workgroup_size);
+        self.step
+            .dispatch(&mut encoder, "bulk-delete-pass", &bind_group, workgroups);
 
         self.queue.submit(Some(encoder.finish()));
+        scope.pop().await
     }
-}
-
-const BULK_DELETE_WGSL: &str = r#"
-struct KvEntry {
-    key: u32,
-    value: u32,
-};
 
-struct SlabMeta {
-    len: u32,
-    capacity: u32,
-    _pad0: u32,
-    _pad1: u32,
-};
+    /// Fill the pool with key buffers for batches of up to `batch` keys.
+    pub fn prewarm(&self, batch: u32) {
+        self.pool.prewarm(
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            (batch as u64).max(1) * std::mem::size_of::<Key>() as u64,
+            1,
+        );
+        self.pool.prewarm(
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            std::mem::size_of::<KeysMeta>() as u64,
+            1,
+        );
+    }
+}
 
+const BULK_DELETE_WGSL: &str = concat!(
+    slab_wgsl!(),
+    search_index_wgsl!(),
+    r#"
 struct KeysMeta {
     len: u32,
     _pad0: u32,
@@ -164,29 +219,27 @@ struct KeysMeta {
 @group(0) @binding(1) var<uniform> slab_meta: SlabMeta;
 @group(0) @binding(2) var<storage, read> keys: array<u32>;
 @group(0) @binding(3) var<uniform> keys_meta: KeysMeta;
-
-@compute @workgroup_size(64)
-fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
-    let idx = gid.x;
+@group(0) @binding(4) var<storage, read> fences: array<u32>;
+@group(0) @binding(5) var<uniform> index_meta: IndexMeta;
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn main(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    index_load(local_index);
+    let idx = gid.x + gid.y * groups.x * WORKGROUP_SIZE;
     if (idx >= keys_meta.len) {
         return;
     }
 
     let key = keys[idx];
-    var lo: u32 = 0u;
-    var hi: u32 = slab_meta.len;
-    while (lo < hi) {
-        let mid = (lo + hi) / 2u;
-        let mid_key = slab[mid].key;
-        if (mid_key < key) {
-            lo = mid + 1u;
-        } else {
-            hi = mid;
-        }
-    }
+    let lo = index_lower_bound(key, slab_meta.len);
 
     if (lo < slab_meta.len && slab[lo].key == key) {
-        slab[lo].value = 0xffffffffu;
+        slab[lo].value = SLAB_TOMBSTONE;
     }
 }
-"#;
+"#
+);
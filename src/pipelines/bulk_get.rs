@@ -1,16 +1,19 @@
 //! Bulk lookup pipeline.
 //!
 //! One GPU thread handles one requested key and performs a binary search over
-//! the sorted slab. Host-side post-processing maps missing keys and tombstones
+//! the sorted slab, narrowed by the slab's search index. Host-side post-processing maps missing keys and tombstones
 //! to `None`.
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::gpu_array::GpuArray;
 use crate::pipelines::core::ComputeStep;
 use crate::pipelines::data::{KeysMeta, ResultEntry};
-use crate::pipelines::utils::{create_buffer_with_data, readback_vec};
-use crate::{Key, KvEntry, Value};
+use crate::pipelines::pool::{BufferPool, PooledBuffer};
+use crate::pipelines::search_index::SearchIndex;
+use crate::pipelines::utils::{map_read, ErrorScope};
+use crate::pipelines::wgsl::{search_index_wgsl, slab_wgsl, with_workgroup_size};
+use crate::{GpuMapError, Key, KvEntry, Value};
 
 const TOMBSTONE_VALUE: Value = Value(0xFFFF_FFFF);
 const BULK_GET_BIND_SLAB: u32 = 0;
@@ -18,18 +21,29 @@ const BULK_GET_BIND_SLAB_META: u32 = 1;
 const BULK_GET_BIND_KEYS: u32 = 2;
 const BULK_GET_BIND_KEYS_META: u32 = 3;
 const BULK_GET_BIND_RESULTS: u32 = 4;
+const BULK_GET_BIND_FENCES: u32 = 5;
+const BULK_GET_BIND_INDEX_META: u32 = 6;
 
 pub struct BulkGetPipeline {
     device: Arc<wgpu::Device>,
     queue: Arc<wgpu::Queue>,
+    pool: Arc<BufferPool>,
+    scopes: Arc<Mutex<()>>,
+    workgroup_size: u32,
     step: ComputeStep,
 }
 
 impl BulkGetPipeline {
-    pub fn new(device: Arc<wgpu::Device>, queue: Arc<wgpu::Queue>) -> Self {
+    pub fn new(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        pool: Arc<BufferPool>,
+        scopes: Arc<Mutex<()>>,
+        workgroup_size: u32,
+    ) -> Self {
         let step = ComputeStep::new(
             Arc::clone(&device),
-            BULK_GET_WGSL,
+            &with_workgroup_size(BULK_GET_WGSL, workgroup_size),
             "main",
             &[
                 wgpu::BindGroupLayoutEntry {
@@ -82,50 +96,85 @@ impl BulkGetPipeline {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: BULK_GET_BIND_FENCES,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: BULK_GET_BIND_INDEX_META,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         );
         Self {
             device,
             queue,
+            pool,
+            scopes,
+            workgroup_size,
             step,
         }
     }
 
-    pub fn execute(&self, slab: &GpuArray<KvEntry>, keys: &[Key]) -> Vec<Option<Value>> {
+    pub async fn execute(
+        &self,
+        slab: &GpuArray<KvEntry>,
+        index: &SearchIndex,
+        keys: &[Key],
+    ) -> Result<Vec<Option<Value>>, GpuMapError> {
         if keys.is_empty() {
-            return Vec::new();
+            return Ok(Vec::new());
         }
 
-        let keys_buffer = create_buffer_with_data(
-            &self.device,
-            "keys-buffer",
-            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            keys,
-        );
-        let results_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("results-buffer"),
-            size: (keys.len() * std::mem::size_of::<ResultEntry>()) as u64,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-            mapped_at_creation: false,
-        });
-        let readback_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("results-readback-buffer"),
-            size: (keys.len() * std::mem::size_of::<ResultEntry>()) as u64,
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let scope = ErrorScope::push(&self.device, &self.scopes);
+        let staging = GetStaging::acquire(&self.pool, keys.len() as u32);
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("bulk-get-encoder"),
+            });
+        self.encode(&mut encoder, slab, index, &staging, keys);
+        self.queue.submit(Some(encoder.finish()));
+        scope.pop().await?;
+
+        let result_entries = map_read::<ResultEntry>(&staging.readback, keys.len()).await?;
+        Ok(decode_results(&result_entries))
+    }
+
+    /// Upload `keys` into `staging` and record the lookup plus the copy into
+    /// its readback buffer. `keys` must fit in the staging capacity.
+    pub fn encode(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        slab: &GpuArray<KvEntry>,
+        index: &SearchIndex,
+        staging: &GetStaging,
+        keys: &[Key],
+    ) {
+        assert!(keys.len() as u32 <= staging.capacity);
         let keys_meta = KeysMeta {
             len: keys.len() as u32,
             _pad: [0; 3],
         };
-        let keys_meta_buffer = create_buffer_with_data(
-            &self.device,
-            "keys-meta-buffer",
-            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            &[keys_meta],
-        );
+        self.queue
+            .write_buffer(&staging.keys, 0, bytemuck::cast_slice(keys));
+        self.queue
+            .write_buffer(&staging.keys_meta, 0, bytemuck::bytes_of(&keys_meta));
 
-        let bind_group = self.step.create_bind_group(
+        let bind_group = self.step.cached_bind_group(
+            &self.pool,
             "bulk-get-bind-group",
             &[
                 wgpu::BindGroupEntry {
@@ -138,69 +187,112 @@ impl BulkGetPipeline {
                 },
                 wgpu::BindGroupEntry {
                     binding: BULK_GET_BIND_KEYS,
-                    resource: keys_buffer.as_entire_binding(),
+                    resource: staging.keys.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: BULK_GET_BIND_KEYS_META,
-                    resource: keys_meta_buffer.as_entire_binding(),
+                    resource: staging.keys_meta.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: BULK_GET_BIND_RESULTS,
-                    resource: results_buffer.as_entire_binding(),
+                    resource: staging.results.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: BULK_GET_BIND_FENCES,
+                    resource: index.fences().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: BULK_GET_BIND_INDEX_META,
+                    resource: index.meta().as_entire_binding(),
                 },
             ],
         );
 
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("bulk-get-encoder"),
-            });
-
-        let workgroups = (keys.len() as u32).div_ceil(64);
-        self.step.dispatch(
-            &mut encoder,
-            "bulk-get-pass",
-            &bind_group,
-            (workgroups, 1, 1),
-        );
+        let workgroups = (keys.len() as u32).div_ceil(self.workgroup_size);
+        self.step
+            .dispatch(encoder, "bulk-get-pass", &bind_group, workgroups);
 
         encoder.copy_buffer_to_buffer(
-            &results_buffer,
+            &staging.results,
             0,
-            &readback_buffer,
+            &staging.readback,
             0,
-            (keys.len() * std::mem::size_of::<ResultEntry>()) as u64,
+            result_bytes(keys.len() as u32),
         );
-        self.queue.submit(Some(encoder.finish()));
+    }
 
-        let result_entries = readback_vec::<ResultEntry>(&self.device, &readback_buffer);
-        result_entries
-            .iter()
-            .map(|entry| {
-                if entry.found == 0 || entry.value == TOMBSTONE_VALUE.0 {
-                    None
-                } else {
-                    Some(Value(entry.value))
-                }
-            })
-            .collect()
+    /// Fill the pool with staging buffers for batches of up to `batch` keys.
+    pub fn prewarm(&self, batch: u32) {
+        drop(GetStaging::acquire(&self.pool, batch));
     }
 }
 
-const BULK_GET_WGSL: &str = r#"
-struct KvEntry {
-    key: u32,
-    value: u32,
-};
-
-struct SlabMeta {
-    len: u32,
+/// Key upload, result and readback buffers for one lookup batch, on loan
+/// from the buffer pool.
+pub struct GetStaging {
+    keys: PooledBuffer,
+    keys_meta: PooledBuffer,
+    results: PooledBuffer,
+    readback: PooledBuffer,
     capacity: u32,
-    _pad0: u32,
-    _pad1: u32,
-};
+}
+
+impl GetStaging {
+    pub fn acquire(pool: &Arc<BufferPool>, capacity: u32) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            keys: pool.acquire(
+                "keys-buffer",
+                wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                (capacity as usize * std::mem::size_of::<Key>()) as u64,
+            ),
+            keys_meta: pool.acquire(
+                "keys-meta-buffer",
+                wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                std::mem::size_of::<KeysMeta>() as u64,
+            ),
+            results: pool.acquire(
+                "results-buffer",
+                wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                result_bytes(capacity),
+            ),
+            readback: pool.acquire(
+                "results-readback-buffer",
+                wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                result_bytes(capacity),
+            ),
+            capacity,
+        }
+    }
 
+    pub fn readback(&self) -> &wgpu::Buffer {
+        &self.readback
+    }
+}
+
+/// Readback size for `len` lookup results.
+pub fn result_bytes(len: u32) -> u64 {
+    (len as usize * std::mem::size_of::<ResultEntry>()) as u64
+}
+
+/// Map raw lookup results to values; misses and tombstones become `None`.
+pub fn decode_results(entries: &[ResultEntry]) -> Vec<Option<Value>> {
+    entries
+        .iter()
+        .map(|entry| {
+            if entry.found == 0 || entry.value == TOMBSTONE_VALUE.0 {
+                None
+            } else {
+                Some(Value(entry.value))
+            }
+        })
+        .collect()
+}
+
+const BULK_GET_WGSL: &str = concat!(
+    slab_wgsl!(),
+    search_index_wgsl!(),
+    r#"
 struct KeysMeta {
     len: u32,
     _pad0: u32,
@@ -220,26 +312,23 @@ struct ResultEntry {
 @group(0) @binding(2) var<storage, read> keys: array<u32>;
 @group(0) @binding(3) var<uniform> keys_meta: KeysMeta;
 @group(0) @binding(4) var<storage, read_write> results: array<ResultEntry>;
+@group(0) @binding(5) var<storage, read> fences: array<u32>;
+@group(0) @binding(6) var<uniform> index_meta: IndexMeta;
 
-@compute @workgroup_size(64)
-fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
-    let idx = gid.x;
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn main(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    index_load(local_index);
+    let idx = gid.x + gid.y * groups.x * WORKGROUP_SIZE;
     if (idx >= keys_meta.len) {
         return;
     }
 
     let key = keys[idx];
-    var lo: u32 = 0u;
-    var hi: u32 = slab_meta.len;
-    while (lo < hi) {
-        let mid = (lo + hi) / 2u;
-        let mid_key = slab[mid].key;
-        if (mid_key < key) {
-            lo = mid + 1u;
-        } else {
-            hi = mid;
-        }
-    }
+    let lo = index_lower_bound(key, slab_meta.len);
 
     if (lo < slab_meta.len && slab[lo].key == key) {
         results[idx].value = slab[lo].value;
@@ -249,4 +338,5 @@ fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
         results[idx].found = 0u;
     }
 }
-"#;
+"#
+);
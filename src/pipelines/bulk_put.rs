@@ -1,19 +1,26 @@
 //! Bulk put pipeline.
 //!
 //! This pipeline performs sort -> dedup -> merge. The merge phase is also
-//! responsible for compacting away tombstoned slab entries.
+//! responsible for compacting away tombstoned slab entries. The merged slab is
+//! written to `merge`, leaving `slab` untouched; the caller publishes it.
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::gpu_array::{GpuArray, GpuStorage};
 use crate::pipelines::core::ComputeStep;
 use crate::pipelines::data::{DedupParams, InputMeta, MergeMeta, SortParams};
-use crate::pipelines::utils::{create_buffer_with_data, readback_single};
-use crate::{Key, KvEntry, Length, Value};
+use crate::pipelines::pool::{BufferPool, PooledBuffer};
+use crate::pipelines::utils::{map_read_single, ErrorScope};
+use crate::pipelines::wgsl::{slab_wgsl, with_workgroup_size};
+use crate::{GpuMapError, Key, KvEntry, TOMBSTONE_VALUE};
 
 const BULK_SORT_BIND_INPUT: u32 = 0;
 const BULK_SORT_BIND_PARAMS: u32 = 1;
 
+/// Entries sorted per workgroup in shared memory (two per invocation).
+const SORT_BLOCK: u32 = 512;
+const SORT_PARAMS_SIZE: u64 = std::mem::size_of::<SortParams>() as u64;
+
 const BULK_DEDUP_BIND_INPUT: u32 = 0;
 const BULK_DEDUP_BIND_PARAMS: u32 = 1;
 const BULK_DEDUP_BIND_META: u32 = 2;
@@ -28,39 +35,64 @@ const BULK_MERGE_BIND_MERGE_META: u32 = 5;
 pub struct BulkPutPipeline {
     device: Arc<wgpu::Device>,
     queue: Arc<wgpu::Queue>,
-    sort_step: ComputeStep,
+    pool: Arc<BufferPool>,
+    scopes: Arc<Mutex<()>>,
+    workgroup_size: u32,
+    sort_local_step: ComputeStep,
+    sort_global_step: ComputeStep,
+    sort_merge_step: ComputeStep,
     dedup_step: ComputeStep,
     merge_step: ComputeStep,
 }
 
 impl BulkPutPipeline {
-    pub fn new(device: Arc<wgpu::Device>, queue: Arc<wgpu::Queue>) -> Self {
-        let sort_step = ComputeStep::new(
-            Arc::clone(&device),
-            BULK_SORT_WGSL,
-            "main",
-            &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: BULK_SORT_BIND_INPUT,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+    pub fn new(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        pool: Arc<BufferPool>,
+        scopes: Arc<Mutex<()>>,
+        workgroup_size: u32,
+    ) -> Self {
+        let sort_layout = [
+            wgpu::BindGroupLayoutEntry {
+                binding: BULK_SORT_BIND_INPUT,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                wgpu::BindGroupLayoutEntry {
-                    binding: BULK_SORT_BIND_PARAMS,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: BULK_SORT_BIND_PARAMS,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(SORT_PARAMS_SIZE),
                 },
-            ],
+                count: None,
+            },
+        ];
+        let sort_source = with_workgroup_size(BULK_SORT_WGSL, workgroup_size);
+        let sort_local_step = ComputeStep::new(
+            Arc::clone(&device),
+            &sort_source,
+            "sort_local",
+            &sort_layout,
+        );
+        let sort_global_step = ComputeStep::new(
+            Arc::clone(&device),
+            &sort_source,
+            "sort_global",
+            &sort_layout,
+        );
+        let sort_merge_step = ComputeStep::new(
+            Arc::clone(&device),
+            &sort_source,
+            "sort_merge",
+            &sort_layout,
         );
 
         let dedup_step = ComputeStep::new(
@@ -172,35 +204,43 @@ impl BulkPutPipeline {
         Self {
             device,
             queue,
-            sort_step,
+            pool,
+            scopes,
+            workgroup_size,
+            sort_local_step,
+            sort_global_step,
+            sort_merge_step,
             dedup_step,
             merge_step,
         }
     }
 
-    pub fn execute(
+    /// Merge the first `entries_len` entries of `input` with `slab` into
+    /// `merge`, returning the merged length.
+    ///
+    /// The batch is padded to the next power of two for the sort, so the
+    /// caller must check that `entries_len.next_power_of_two()` fits in the
+    /// input buffer.
+    pub async fn execute(
         &self,
         slab: &GpuArray<KvEntry>,
         input: &GpuArray<KvEntry>,
         merge: &GpuArray<KvEntry>,
         merge_meta: &GpuStorage<MergeMeta>,
         entries_len: u32,
-    ) -> Result<u32, crate::GpuMapError> {
+    ) -> Result<u32, GpuMapError> {
         let len = entries_len;
         let padded_len = len.next_power_of_two();
-        if padded_len > slab.capacity().0 {
-            return Err(crate::GpuMapError::CapacityExceeded {
-                capacity: slab.capacity(),
-                requested: Length::new(slab.len().0 + padded_len),
-            });
-        }
+        debug_assert!(padded_len <= input.capacity().0);
 
+        // Padding sorts last, but can tie with a real `u32::MAX` key in an
+        // unstable order, so it carries the tombstone value for dedup to drop.
         if padded_len > len {
             let pad_count = (padded_len - len) as usize;
             let padding = vec![
                 KvEntry {
                     key: Key::new(u32::MAX),
-                    value: Value::new(0),
+                    value: TOMBSTONE_VALUE,
                 };
                 pad_count
             ];
@@ -209,84 +249,159 @@ impl BulkPutPipeline {
                 .write_buffer(input.buffer(), offset, bytemuck::cast_slice(&padding));
         }
 
-        if len > 1 {
-            self.run_sort_step(input, padded_len);
-        }
-
-        let dedup_len = self.run_dedup_step(input, len, merge_meta);
-
-        let merge_len = self.run_merge_step(slab, input, merge, merge_meta, dedup_len);
-        Ok(merge_len)
+        let scope = ErrorScope::push(&self.device, &self.scopes);
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("bulk-put-encoder"),
+            });
+        // Keep the stage parameters on loan until the sort has been submitted.
+        let _sort_params =
+            (len > 1).then(|| self.encode_sort(&mut encoder, input.buffer(), padded_len));
+        let dedup_len = self
+            .run_dedup_step(scope, encoder, input, padded_len, merge_meta)
+            .await?;
+
+        self.run_merge_step(slab, input, merge, merge_meta, dedup_len)
+            .await
     }
 
-    fn run_sort_step(&self, input: &GpuArray<KvEntry>, padded_len: u32) {
-        let sort_params_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("bulk-sort-params"),
-            size: std::mem::size_of::<SortParams>() as u64,
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-        let sort_bind_group = self.sort_step.create_bind_group(
-            "bulk-sort-bind-group",
-            &[
-                wgpu::BindGroupEntry {
-                    binding: BULK_SORT_BIND_INPUT,
-                    resource: input.buffer().as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: BULK_SORT_BIND_PARAMS,
-                    resource: sort_params_buffer.as_entire_binding(),
-                },
-            ],
-        );
+    /// Fill the pool with the parameter and readback buffers a put of up to
+    /// `batch` entries uses.
+    pub fn prewarm(&self, batch: u32) {
+        let padded_len = batch.max(2).next_power_of_two();
+        let sort_params_bytes = self.sort_params_stride() * self.sort_stages(padded_len).len();
+        // Held together, as during a put, so each gets its own buffer.
+        let _buffers = [
+            self.pool.acquire(
+                "bulk-sort-params",
+                wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                sort_params_bytes as u64,
+            ),
+            self.pool.acquire(
+                "bulk-dedup-params",
+                wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                std::mem::size_of::<DedupParams>() as u64,
+            ),
+            self.pool.acquire(
+                "bulk-dedup-readback",
+                wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                std::mem::size_of::<MergeMeta>() as u64,
+            ),
+        ];
+    }
 
-        let workgroups = padded_len.div_ceil(64);
-        let mut k = 2u32;
-        let max_k = padded_len;
-        while k <= max_k {
+    /// Dispatches of the bitonic sort of `padded_len` entries: the step, its
+    /// stage parameters and workgroup count.
+    fn sort_stages(&self, padded_len: u32) -> Vec<(&ComputeStep, SortParams, u32)> {
+        let blocks = padded_len.div_ceil(SORT_BLOCK);
+        let mut stages = vec![(
+            &self.sort_local_step,
+            SortParams::new(0, 0, padded_len),
+            blocks,
+        )];
+        let mut k = SORT_BLOCK * 2;
+        while k <= padded_len {
             let mut j = k / 2;
-            while j > 0 {
-                let params = SortParams {
-                    k,
-                    j,
-                    len: padded_len,
-                    _pad: 0,
-                };
-                self.queue
-                    .write_buffer(&sort_params_buffer, 0, bytemuck::bytes_of(&params));
-
-                let mut sort_encoder =
-                    self.device
-                        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                            label: Some("bulk-sort-encoder"),
-                        });
-                self.sort_step.dispatch(
-                    &mut sort_encoder,
-                    "bulk-sort-pass",
-                    &sort_bind_group,
-                    (workgroups, 1, 1),
-                );
-                self.queue.submit(Some(sort_encoder.finish()));
+            while j >= SORT_BLOCK {
+                stages.push((
+                    &self.sort_global_step,
+                    SortParams::new(k, j, padded_len),
+                    padded_len.div_ceil(self.workgroup_size),
+                ));
                 j /= 2;
             }
+            stages.push((
+                &self.sort_merge_step,
+                SortParams::new(k, j, padded_len),
+                blocks,
+            ));
             k *= 2;
         }
+        stages
+    }
+
+    /// Bytes between stage parameters in the dynamic-offset uniform buffer.
+    fn sort_params_stride(&self) -> usize {
+        SORT_PARAMS_SIZE.max(self.device.limits().min_uniform_buffer_offset_alignment as u64)
+            as usize
     }
 
-    fn run_dedup_step(
+    /// Record the whole bitonic sort of `input[0..padded_len)` by key into
+    /// `encoder`, returning the stage parameters to keep until it is
+    /// submitted. `padded_len` must be a power of two.
+    ///
+    /// Blocks of [`SORT_BLOCK`] entries are first sorted in workgroup memory.
+    /// Each larger merge size `k` then runs one global pass per distance
+    /// `j >= SORT_BLOCK` and finishes the remaining distances in workgroup
+    /// memory. Stage parameters live in one uniform buffer, selected per
+    /// dispatch with a dynamic offset.
+    pub fn encode_sort(
         &self,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &wgpu::Buffer,
+        padded_len: u32,
+    ) -> PooledBuffer {
+        let stages = self.sort_stages(padded_len);
+        let stride = self.sort_params_stride();
+        let mut params = vec![0u8; stride * stages.len()];
+        for (index, (_, stage, _)) in stages.iter().enumerate() {
+            params[index * stride..index * stride + SORT_PARAMS_SIZE as usize]
+                .copy_from_slice(bytemuck::bytes_of(stage));
+        }
+        let params_buffer = self.pool.acquire(
+            "bulk-sort-params",
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            params.len() as u64,
+        );
+        self.queue.write_buffer(&params_buffer, 0, &params);
+
+        let entries = [
+            wgpu::BindGroupEntry {
+                binding: BULK_SORT_BIND_INPUT,
+                resource: input.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: BULK_SORT_BIND_PARAMS,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &params_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(SORT_PARAMS_SIZE),
+                }),
+            },
+        ];
+        for (index, (step, _, workgroups)) in stages.iter().enumerate() {
+            let bind_group = step.cached_bind_group(&self.pool, "bulk-sort-bind-group", &entries);
+            step.dispatch_with_offsets(
+                encoder,
+                "bulk-sort-pass",
+                &bind_group,
+                &[(index * stride) as u32],
+                *workgroups,
+            );
+        }
+        params_buffer
+    }
+
+    /// Dedup and submit the sort already recorded in `encoder`, closing
+    /// `scope` once both are submitted.
+    async fn run_dedup_step(
+        &self,
+        scope: ErrorScope<'_>,
+        mut encoder: wgpu::CommandEncoder,
         input: &GpuArray<KvEntry>,
         len: u32,
         merge_meta: &GpuStorage<MergeMeta>,
-    ) -> u32 {
+    ) -> Result<u32, GpuMapError> {
         let dedup_params = DedupParams { len, _pad: [0; 3] };
-        let dedup_params_buffer = create_buffer_with_data(
-            &self.device,
+        let dedup_params_buffer = self.pool.acquire_with_data(
+            &self.queue,
             "bulk-dedup-params",
             wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             &[dedup_params],
         );
-        let dedup_bind_group = self.dedup_step.create_bind_group(
+        let dedup_bind_group = self.dedup_step.cached_bind_group(
+            &self.pool,
             "bulk-dedup-bind-group",
             &[
                 wgpu::BindGroupEntry {
@@ -304,24 +419,14 @@ impl BulkPutPipeline {
             ],
         );
 
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("bulk-put-encoder"),
-            });
-        self.dedup_step.dispatch(
-            &mut encoder,
-            "bulk-dedup-pass",
-            &dedup_bind_group,
-            (1, 1, 1),
-        );
+        self.dedup_step
+            .dispatch(&mut encoder, "bulk-dedup-pass", &dedup_bind_group, 1);
 
-        let dedup_readback = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("bulk-dedup-readback"),
-            size: std::mem::size_of::<MergeMeta>() as u64,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-            mapped_at_creation: false,
-        });
+        let dedup_readback = self.pool.acquire(
+            "bulk-dedup-readback",
+            wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            std::mem::size_of::<MergeMeta>() as u64,
+        );
 
         encoder.copy_buffer_to_buffer(
             merge_meta.buffer(),
@@ -332,30 +437,33 @@ impl BulkPutPipeline {
         );
 
         self.queue.submit(Some(encoder.finish()));
-        let dedup_meta = readback_single::<MergeMeta>(&self.device, &dedup_readback);
-        dedup_meta.len
+        scope.pop().await?;
+        let dedup_meta = map_read_single::<MergeMeta>(&dedup_readback).await?;
+        Ok(dedup_meta.len)
     }
 
-    fn run_merge_step(
+    async fn run_merge_step(
         &self,
         slab: &GpuArray<KvEntry>,
         input: &GpuArray<KvEntry>,
         merge: &GpuArray<KvEntry>,
         merge_meta: &GpuStorage<MergeMeta>,
         dedup_len: u32,
-    ) -> u32 {
+    ) -> Result<u32, GpuMapError> {
+        let scope = ErrorScope::push(&self.device, &self.scopes);
         let input_meta = InputMeta {
             len: dedup_len,
             _pad: [0; 3],
         };
-        let input_meta_buffer = create_buffer_with_data(
-            &self.device,
+        let input_meta_buffer = self.pool.acquire_with_data(
+            &self.queue,
             "input-meta-buffer",
             wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             &[input_meta],
         );
 
-        let merge_bind_group = self.merge_step.create_bind_group(
+        let merge_bind_group = self.merge_step.cached_bind_group(
+            &self.pool,
             "bulk-merge-bind-group",
             &[
                 wgpu::BindGroupEntry {
@@ -385,27 +493,20 @@ impl BulkPutPipeline {
             ],
         );
 
-        let merge_readback = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("merge-meta-readback"),
-            size: std::mem::size_of::<MergeMeta>() as u64,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-            mapped_at_creation: false,
-        });
+        let merge_readback = self.pool.acquire(
+            "merge-meta-readback",
+            wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            std::mem::size_of::<MergeMeta>() as u64,
+        );
 
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("bulk-merge-encoder"),
             });
-        self.merge_step.dispatch(
-            &mut encoder,
-            "bulk-merge-pass",
-            &merge_bind_group,
-            (1, 1, 1),
-        );
+        self.merge_step
+            .dispatch(&mut encoder, "bulk-merge-pass", &merge_bind_group, 1);
 
-        let slab_bytes = (slab.capacity().0 as u64) * std::mem::size_of::<KvEntry>() as u64;
-        encoder.copy_buffer_to_buffer(merge.buffer(), 0, slab.buffer(), 0, slab_bytes);
         encoder.copy_buffer_to_buffer(
             merge_meta.buffer(),
             0,
@@ -414,9 +515,10 @@ impl BulkPutPipeline {
             std::mem::size_of::<MergeMeta>() as u64,
         );
         self.queue.submit(Some(encoder.finish()));
+        scope.pop().await?;
 
-        let merge_meta_val = readback_single::<MergeMeta>(&self.device, &merge_readback);
-        merge_meta_val.len
+        let merge_meta_val = map_read_single::<MergeMeta>(&merge_readback).await?;
+        Ok(merge_meta_val.len)
     }
 }
 
@@ -433,12 +535,99 @@ struct SortParams {
     _pad: u32,
 };
 
+const SORT_BLOCK: u32 = 512u;
+const SORT_THREADS: u32 = 256u;
+
 @group(0) @binding(0) var<storage, read_write> data: array<KvEntry>;
 @group(0) @binding(1) var<uniform> params: SortParams;
 
-@compute @workgroup_size(64)
-fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
-    let i = gid.x;
+var<workgroup> tile: array<KvEntry, 512>;
+
+fn out_of_order(a: KvEntry, b: KvEntry, ascending: bool) -> bool {
+    if (ascending) {
+        return a.key > b.key;
+    }
+    return a.key < b.key;
+}
+
+// One compare-exchange of the (k, j) stage on the workgroup tile. Thread `t`
+// owns the pair (i, i + j) with i's bit j clear.
+fn tile_step(t: u32, base: u32, k: u32, j: u32) {
+    let i = 2u * j * (t / j) + (t % j);
+    let partner = i + j;
+    if (base + partner < params.len) {
+        let a = tile[i];
+        let b = tile[partner];
+        if (out_of_order(a, b, ((base + i) & k) == 0u)) {
+            tile[i] = b;
+            tile[partner] = a;
+        }
+    }
+}
+
+fn load_tile(t: u32, base: u32) {
+    if (base + t < params.len) {
+        tile[t] = data[base + t];
+    }
+    if (base + t + SORT_THREADS < params.len) {
+        tile[t + SORT_THREADS] = data[base + t + SORT_THREADS];
+    }
+    workgroupBarrier();
+}
+
+fn store_tile(t: u32, base: u32) {
+    if (base + t < params.len) {
+        data[base + t] = tile[t];
+    }
+    if (base + t + SORT_THREADS < params.len) {
+        data[base + t + SORT_THREADS] = tile[t + SORT_THREADS];
+    }
+}
+
+// Every stage with k <= SORT_BLOCK, entirely in workgroup memory.
+@compute @workgroup_size(256)
+fn sort_local(
+    @builtin(local_invocation_id) lid: vec3<u32>,
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let t = lid.x;
+    let base = (wid.x + wid.y * groups.x) * SORT_BLOCK;
+    load_tile(t, base);
+    let limit = min(SORT_BLOCK, params.len);
+    for (var k = 2u; k <= limit; k = k * 2u) {
+        for (var j = k / 2u; j > 0u; j = j / 2u) {
+            tile_step(t, base, k, j);
+            workgroupBarrier();
+        }
+    }
+    store_tile(t, base);
+}
+
+// Stages j < SORT_BLOCK of merge size params.k, in workgroup memory.
+@compute @workgroup_size(256)
+fn sort_merge(
+    @builtin(local_invocation_id) lid: vec3<u32>,
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let t = lid.x;
+    let base = (wid.x + wid.y * groups.x) * SORT_BLOCK;
+    load_tile(t, base);
+    for (var j = params.j; j > 0u; j = j / 2u) {
+        tile_step(t, base, params.k, j);
+        workgroupBarrier();
+    }
+    store_tile(t, base);
+}
+
+// One (k, j) stage with j >= SORT_BLOCK across the whole buffer.
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn sort_global(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let i = gid.x + gid.y * groups.x * WORKGROUP_SIZE;
     if (i >= params.len) {
         return;
     }
@@ -447,17 +636,9 @@ fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
     if (ixj > i && ixj < params.len) {
         let a = data[i];
         let b = data[ixj];
-        let ascending = (i & params.k) == 0u;
-        if (ascending) {
-            if (a.key > b.key) {
-                data[i] = b;
-                data[ixj] = a;
-            }
-        } else {
-            if (a.key < b.key) {
-                data[i] = b;
-                data[ixj] = a;
-            }
+        if (out_of_order(a, b, (i & params.k) == 0u)) {
+            data[i] = b;
+            data[ixj] = a;
         }
     }
 }
@@ -504,6 +685,11 @@ fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
     var i: u32 = 0u;
     while (i < len) {
         let entry = data[i];
+        i = i + 1u;
+        // Sort padding carries the tombstone value; real entries never do.
+        if (entry.value == 0xffffffffu) {
+            continue;
+        }
         if (write_idx == 0u) {
             data[write_idx] = entry;
             prev_key = entry.key;
@@ -517,26 +703,15 @@ fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
                 write_idx = write_idx + 1u;
             }
         }
-        i = i + 1u;
     }
 
     dedup_meta.len = write_idx;
 }
 "#;
 
-const BULK_MERGE_WGSL: &str = r#"
-struct KvEntry {
-    key: u32,
-    value: u32,
-};
-
-struct SlabMeta {
-    len: u32,
-    capacity: u32,
-    _pad0: u32,
-    _pad1: u32,
-};
-
+const BULK_MERGE_WGSL: &str = concat!(
+    slab_wgsl!(),
+    r#"
 struct InputMeta {
     len: u32,
     _pad0: u32,
@@ -558,38 +733,6 @@ struct MergeMeta {
 @group(0) @binding(4) var<uniform> input_meta: InputMeta;
 @group(0) @binding(5) var<storage, read_write> merge_meta: MergeMeta;
 
-fn merge_partition(k: u32, slab_len: u32, input_len: u32) -> vec2<u32> {
-    var i_low: u32 = 0u;
-    if (k > input_len) {
-        i_low = k - input_len;
-    }
-    var i_high: u32 = k;
-    if (i_high > slab_len) {
-        i_high = slab_len;
-    }
-
-    var i: u32 = i_high;
-    var j: u32 = k - i;
-    loop {
-        let move_left = i > 0u && j < input_len && slab[i - 1u].key >= input[j].key;
-        let move_right = j > 0u && i < slab_len && input[j - 1u].key > slab[i].key;
-        if (move_left) {
-            i_high = i - 1u;
-            i = (i_low + i_high) / 2u;
-            j = k - i;
-            continue;
-        }
-        if (move_right) {
-            i_low = i + 1u;
-            i = (i_low + i_high + 1u) / 2u;
-            j = k - i;
-            continue;
-        }
-        break;
-    }
-    return vec2<u32>(i, j);
-}
-
 @compute @workgroup_size(1)
 fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
     if (gid.x > 0u) {
@@ -612,7 +755,7 @@ fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
         let b = input[j];
         if (a.key < b.key) {
             // Compaction: skip dead slab slots rather than copying them forward.
-            if (a.value != 0xffffffffu) {
+            if (slab_is_live(a)) {
                 output[k] = a;
                 k = k + 1u;
             }
@@ -631,7 +774,7 @@ fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
 
     while (i < slab_len) {
         let a = slab[i];
-        if (a.value != 0xffffffffu) {
+        if (slab_is_live(a)) {
             output[k] = a;
             k = k + 1u;
         }
@@ -646,4 +789,5 @@ fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
 
     merge_meta.len = k;
 }
-"#;
+"#
+);
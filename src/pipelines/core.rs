@@ -1,10 +1,25 @@
 use std::sync::Arc;
 use wgpu::Device;
 
+use crate::pipelines::pool::BufferPool;
+
 pub struct ComputeStep {
     device: Arc<Device>,
     pipeline: wgpu::ComputePipeline,
     bind_group_layout: wgpu::BindGroupLayout,
+    max_workgroups_per_dimension: u32,
+}
+
+/// Grid for `count` workgroups. Counts above `max_per_dimension` fold into
+/// `y`, and shaders recover the linear workgroup index as
+/// `workgroup_id.x + workgroup_id.y * num_workgroups.x`. The grid may hold a
+/// few more workgroups than `count`, so shaders bounds-check their index.
+pub fn workgroup_grid(count: u32, max_per_dimension: u32) -> (u32, u32, u32) {
+    if count <= max_per_dimension {
+        return (count, 1, 1);
+    }
+    let rows = count.div_ceil(max_per_dimension);
+    (count.div_ceil(rows), rows, 1)
 }
 
 impl ComputeStep {
@@ -38,10 +53,12 @@ impl ComputeStep {
             compilation_options: wgpu::PipelineCompilationOptions::default(),
         });
 
+        let max_workgroups_per_dimension = device.limits().max_compute_workgroups_per_dimension;
         Self {
             device,
             pipeline,
             bind_group_layout,
+            max_workgroups_per_dimension,
         }
     }
 
@@ -57,19 +74,65 @@ impl ComputeStep {
         })
     }
 
+    /// Bind group for `entries` from `pool`'s cache, created on a miss.
+    pub fn cached_bind_group(
+        &self,
+        pool: &BufferPool,
+        label: &str,
+        entries: &[wgpu::BindGroupEntry],
+    ) -> Arc<wgpu::BindGroup> {
+        pool.bind_group(&self.bind_group_layout, entries, || {
+            self.create_bind_group(label, entries)
+        })
+    }
+
+    /// Record a pass running `workgroups` workgroups, laid out with
+    /// [`workgroup_grid`] for the device's per-dimension limit.
     pub fn dispatch(
         &self,
         encoder: &mut wgpu::CommandEncoder,
         pass_label: &str,
         bind_group: &wgpu::BindGroup,
-        workgroups: (u32, u32, u32),
+        workgroups: u32,
+    ) {
+        self.dispatch_with_offsets(encoder, pass_label, bind_group, &[], workgroups);
+    }
+
+    /// Like [`dispatch`](Self::dispatch), for layouts with dynamic offsets.
+    pub fn dispatch_with_offsets(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pass_label: &str,
+        bind_group: &wgpu::BindGroup,
+        offsets: &[wgpu::DynamicOffset],
+        workgroups: u32,
     ) {
         let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
             label: Some(pass_label),
             timestamp_writes: None,
         });
         cpass.set_pipeline(&self.pipeline);
-        cpass.set_bind_group(0, bind_group, &[]);
-        cpass.dispatch_workgroups(workgroups.0, workgroups.1, workgroups.2);
+        cpass.set_bind_group(0, bind_group, offsets);
+        let (x, y, z) = workgroup_grid(workgroups, self.max_workgroups_per_dimension);
+        cpass.dispatch_workgroups(x, y, z);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::workgroup_grid;
+
+    #[test]
+    fn workgroup_grid_folds_counts_over_the_dimension_limit() {
+        assert_eq!(workgroup_grid(0, 4), (0, 1, 1));
+        assert_eq!(workgroup_grid(4, 4), (4, 1, 1));
+        assert_eq!(workgroup_grid(5, 4), (3, 2, 1));
+        assert_eq!(workgroup_grid(16, 4), (4, 4, 1));
+        assert_eq!(workgroup_grid(65_536, 65_535), (32_768, 2, 1));
+        for count in 1..200 {
+            let (x, y, _) = workgroup_grid(count, 7);
+            assert!(x <= 7 && y <= 7 * 7);
+            assert!(x * y >= count && x * (y - 1) < count);
+        }
     }
 }
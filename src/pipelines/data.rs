@@ -38,6 +38,12 @@ pub struct SortParams {
     pub _pad: u32,
 }
 
+impl SortParams {
+    pub fn new(k: u32, j: u32, len: u32) -> Self {
+        Self { k, j, len, _pad: 0 }
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
 pub struct DedupParams {
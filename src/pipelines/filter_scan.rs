@@ -0,0 +1,427 @@
+//! Filtered range pipeline.
+//!
+//! Compacts the live entries in slab slots `[start, end)` whose value matches
+//! a `ValuePredicate`, so only matches are read back. The pass is an ordered
+//! stream compaction in three dispatches recorded in one encoder: each thread
+//! counts matches in a fixed-size chunk, one thread prefix-sums the chunk
+//! counts, then each thread rescans its chunk and writes matches at its offset.
+
+use std::sync::{Arc, Mutex};
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::gpu_array::GpuArray;
+use crate::pipelines::core::ComputeStep;
+use crate::pipelines::pool::BufferPool;
+use crate::pipelines::utils::{map_read, map_read_single, ErrorScope};
+use crate::pipelines::wgsl::{slab_wgsl, with_workgroup_size};
+use crate::predicate::ValuePredicate;
+use crate::{GpuMapError, KvEntry};
+
+const FILTER_BIND_SLAB: u32 = 0;
+const FILTER_BIND_PARAMS: u32 = 1;
+const FILTER_BIND_VALUE_SET: u32 = 2;
+const FILTER_BIND_CHUNK_COUNTS: u32 = 3;
+const FILTER_BIND_OUTPUT: u32 = 4;
+const FILTER_BIND_OUTPUT_META: u32 = 5;
+
+/// Slab slots scanned by one thread.
+const FILTER_CHUNK: u32 = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
+struct FilterParams {
+    start: u32,
+    end: u32,
+    chunks: u32,
+    op: u32,
+    a: u32,
+    b: u32,
+    set_len: u32,
+    _pad: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
+struct FilterMeta {
+    count: u32,
+    _pad: [u32; 3],
+}
+
+pub struct FilterScanPipeline {
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
+    pool: Arc<BufferPool>,
+    scopes: Arc<Mutex<()>>,
+    workgroup_size: u32,
+    count_step: ComputeStep,
+    scan_step: ComputeStep,
+    scatter_step: ComputeStep,
+}
+
+impl FilterScanPipeline {
+    pub fn new(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        pool: Arc<BufferPool>,
+        scopes: Arc<Mutex<()>>,
+        workgroup_size: u32,
+    ) -> Self {
+        let layout = [
+            wgpu::BindGroupLayoutEntry {
+                binding: FILTER_BIND_SLAB,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: FILTER_BIND_PARAMS,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: FILTER_BIND_VALUE_SET,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: FILTER_BIND_CHUNK_COUNTS,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: FILTER_BIND_OUTPUT,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: FILTER_BIND_OUTPUT_META,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ];
+        let source = with_workgroup_size(FILTER_WGSL, workgroup_size);
+        let count_step = ComputeStep::new(Arc::clone(&device), &source, "count", &layout);
+        let scan_step = ComputeStep::new(Arc::clone(&device), &source, "scan", &layout);
+        let scatter_step = ComputeStep::new(Arc::clone(&device), &source, "scatter", &layout);
+
+        Self {
+            device,
+            queue,
+            pool,
+            scopes,
+            workgroup_size,
+            count_step,
+            scan_step,
+            scatter_step,
+        }
+    }
+
+    /// Live entries in slab slots `[start, end)` whose value matches `predicate`.
+    pub async fn execute(
+        &self,
+        slab: &GpuArray<KvEntry>,
+        start: u32,
+        end: u32,
+        predicate: &ValuePredicate,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        if end <= start {
+            return Ok(Vec::new());
+        }
+
+        let scope = ErrorScope::push(&self.device, &self.scopes);
+        let (op, a, b, set) = predicate.encode();
+        let chunks = (end - start).div_ceil(FILTER_CHUNK);
+        let params = FilterParams {
+            start,
+            end,
+            chunks,
+            op,
+            a,
+            b,
+            set_len: set.len() as u32,
+            _pad: 0,
+        };
+        let params_buffer = self.pool.acquire_with_data(
+            &self.queue,
+            "filter-params",
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            &[params],
+        );
+        // Storage bindings must be non-empty even when the predicate has no set.
+        let set = if set.is_empty() { vec![0] } else { set };
+        let set_buffer = self.pool.acquire_with_data(
+            &self.queue,
+            "filter-value-set",
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            &set,
+        );
+        let chunk_counts_buffer = self.pool.acquire(
+            "filter-chunk-counts",
+            wgpu::BufferUsages::STORAGE,
+            (chunks as u64) * std::mem::size_of::<u32>() as u64,
+        );
+        let output_size = ((end - start) as u64) * std::mem::size_of::<KvEntry>() as u64;
+        let output_buffer = self.pool.acquire(
+            "filter-output",
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            output_size,
+        );
+        let output_meta_buffer = self.pool.acquire_with_data(
+            &self.queue,
+            "filter-meta",
+            wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            &[FilterMeta::default()],
+        );
+
+        let entries = [
+            wgpu::BindGroupEntry {
+                binding: FILTER_BIND_SLAB,
+                resource: slab.buffer().as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: FILTER_BIND_PARAMS,
+                resource: params_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: FILTER_BIND_VALUE_SET,
+                resource: set_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: FILTER_BIND_CHUNK_COUNTS,
+                resource: chunk_counts_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: FILTER_BIND_OUTPUT,
+                resource: output_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: FILTER_BIND_OUTPUT_META,
+                resource: output_meta_buffer.as_entire_binding(),
+            },
+        ];
+        let count_bind_group =
+            self.count_step
+                .cached_bind_group(&self.pool, "filter-count-bind-group", &entries);
+        let scan_bind_group =
+            self.scan_step
+                .cached_bind_group(&self.pool, "filter-scan-bind-group", &entries);
+        let scatter_bind_group =
+            self.scatter_step
+                .cached_bind_group(&self.pool, "filter-scatter-bind-group", &entries);
+
+        let meta_readback = self.pool.acquire(
+            "filter-meta-readback",
+            wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            std::mem::size_of::<FilterMeta>() as u64,
+        );
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("filter-encoder"),
+            });
+        let workgroups = chunks.div_ceil(self.workgroup_size);
+        self.count_step.dispatch(
+            &mut encoder,
+            "filter-count-pass",
+            &count_bind_group,
+            workgroups,
+        );
+        self.scan_step
+            .dispatch(&mut encoder, "filter-scan-pass", &scan_bind_group, 1);
+        self.scatter_step.dispatch(
+            &mut encoder,
+            "filter-scatter-pass",
+            &scatter_bind_group,
+            workgroups,
+        );
+        encoder.copy_buffer_to_buffer(
+            &output_meta_buffer,
+            0,
+            &meta_readback,
+            0,
+            std::mem::size_of::<FilterMeta>() as u64,
+        );
+        self.queue.submit(Some(encoder.finish()));
+        scope.pop().await?;
+
+        let meta = map_read_single::<FilterMeta>(&meta_readback).await?;
+        if meta.count == 0 {
+            return Ok(Vec::new());
+        }
+
+        let scope = ErrorScope::push(&self.device, &self.scopes);
+        let byte_len = (meta.count as u64) * std::mem::size_of::<KvEntry>() as u64;
+        let readback = self.pool.acquire(
+            "filter-readback",
+            wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            byte_len,
+        );
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("filter-copy-encoder"),
+            });
+        encoder.copy_buffer_to_buffer(&output_buffer, 0, &readback, 0, byte_len);
+        self.queue.submit(Some(encoder.finish()));
+        scope.pop().await?;
+
+        map_read::<KvEntry>(&readback, meta.count as usize).await
+    }
+}
+
+const FILTER_WGSL: &str = concat!(
+    slab_wgsl!(),
+    r#"
+struct FilterParams {
+    start: u32,
+    end: u32,
+    chunks: u32,
+    op: u32,
+    a: u32,
+    b: u32,
+    set_len: u32,
+    _pad0: u32,
+};
+
+struct FilterMeta {
+    count: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+};
+
+const FILTER_CHUNK: u32 = 64u;
+
+@group(0) @binding(0) var<storage, read> slab: array<KvEntry>;
+@group(0) @binding(1) var<uniform> params: FilterParams;
+@group(0) @binding(2) var<storage, read> value_set: array<u32>;
+@group(0) @binding(3) var<storage, read_write> chunk_counts: array<u32>;
+@group(0) @binding(4) var<storage, read_write> output: array<KvEntry>;
+@group(0) @binding(5) var<storage, read_write> out_meta: FilterMeta;
+
+fn in_value_set(x: u32) -> bool {
+    var lo: u32 = 0u;
+    var hi: u32 = params.set_len;
+    while (lo < hi) {
+        let mid = (lo + hi) / 2u;
+        if (value_set[mid] < x) {
+            lo = mid + 1u;
+        } else {
+            hi = mid;
+        }
+    }
+    return lo < params.set_len && value_set[lo] == x;
+}
+
+fn predicate_matches(x: u32) -> bool {
+    switch params.op {
+        case 0u: { return x == params.a; }
+        case 1u: { return x != params.a; }
+        case 2u: { return x < params.a; }
+        case 3u: { return x <= params.a; }
+        case 4u: { return x > params.a; }
+        case 5u: { return x >= params.a; }
+        case 6u: { return (x & params.a) != 0u; }
+        case 7u: { return (x & params.a) == params.a; }
+        case 8u: { return params.a <= x && x < params.b; }
+        case 9u: { return in_value_set(x); }
+        default: { return false; }
+    }
+}
+
+fn entry_matches(idx: u32) -> bool {
+    let entry = slab[idx];
+    return slab_is_live(entry) && predicate_matches(entry.value);
+}
+
+fn chunk_range(chunk: u32) -> vec2<u32> {
+    let lo = params.start + chunk * FILTER_CHUNK;
+    return vec2<u32>(lo, min(lo + FILTER_CHUNK, params.end));
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn count(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let chunk = gid.x + gid.y * groups.x * WORKGROUP_SIZE;
+    if (chunk >= params.chunks) {
+        return;
+    }
+    let bounds = chunk_range(chunk);
+    var matches: u32 = 0u;
+    for (var i: u32 = bounds.x; i < bounds.y; i = i + 1u) {
+        if (entry_matches(i)) {
+            matches = matches + 1u;
+        }
+    }
+    chunk_counts[chunk] = matches;
+}
+
+@compute @workgroup_size(1)
+fn scan(@builtin(global_invocation_id) gid: vec3<u32>) {
+    if (gid.x > 0u) {
+        return;
+    }
+    var total: u32 = 0u;
+    for (var chunk: u32 = 0u; chunk < params.chunks; chunk = chunk + 1u) {
+        let matches = chunk_counts[chunk];
+        chunk_counts[chunk] = total;
+        total = total + matches;
+    }
+    out_meta.count = total;
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn scatter(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let chunk = gid.x + gid.y * groups.x * WORKGROUP_SIZE;
+    if (chunk >= params.chunks) {
+        return;
+    }
+    let bounds = chunk_range(chunk);
+    var write_idx = chunk_counts[chunk];
+    for (var i: u32 = bounds.x; i < bounds.y; i = i + 1u) {
+        if (entry_matches(i)) {
+            output[write_idx] = slab[i];
+            write_idx = write_idx + 1u;
+        }
+    }
+}
+"#
+);
@@ -0,0 +1,292 @@
+//! In-place value transform pipeline.
+//!
+//! Splices a user-supplied WGSL function `fn f(key: u32, value: u32) -> u32`
+//! into a kernel that rewrites every live slab entry whose key lies in
+//! `[lo, hi]`. One GPU thread handles one slab slot. Compiled pipelines are
+//! cached per source string, up to [`MAX_CACHED_STEPS`] of them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::gpu_array::GpuArray;
+use crate::pipelines::core::ComputeStep;
+use crate::pipelines::pool::BufferPool;
+use crate::pipelines::wgsl::{slab_wgsl, with_workgroup_size};
+use crate::{GpuMapError, KvEntry};
+
+/// Compiled transforms kept before the least recently used one is dropped.
+pub const MAX_CACHED_STEPS: usize = 32;
+
+const MAP_VALUES_BIND_SLAB: u32 = 0;
+const MAP_VALUES_BIND_SLAB_META: u32 = 1;
+const MAP_VALUES_BIND_PARAMS: u32 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
+struct MapValuesParams {
+    lo: u32,
+    hi: u32,
+    _pad: [u32; 2],
+}
+
+pub struct MapValuesPipeline {
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
+    pool: Arc<BufferPool>,
+    scopes: Arc<Mutex<()>>,
+    workgroup_size: u32,
+    steps: Mutex<StepCache>,
+}
+
+/// Compiled transforms by source, with the tick each was last used at.
+#[derive(Default)]
+struct StepCache {
+    steps: HashMap<String, (Arc<ComputeStep>, u64)>,
+    tick: u64,
+}
+
+impl MapValuesPipeline {
+    pub fn new(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        pool: Arc<BufferPool>,
+        scopes: Arc<Mutex<()>>,
+        workgroup_size: u32,
+    ) -> Self {
+        Self {
+            device,
+            queue,
+            pool,
+            scopes,
+            workgroup_size,
+            steps: Mutex::new(StepCache::default()),
+        }
+    }
+
+    /// Apply `wgsl_fn` to live entries with keys in the inclusive range `[lo, hi]`.
+    pub fn execute(
+        &self,
+        slab: &GpuArray<KvEntry>,
+        wgsl_fn: &str,
+        lo: u32,
+        hi: u32,
+    ) -> Result<(), GpuMapError> {
+        let step = self.step_for(wgsl_fn)?;
+        let len = slab.len().0;
+        if len == 0 || lo > hi {
+            return Ok(());
+        }
+
+        let params = MapValuesParams {
+            lo,
+            hi,
+            _pad: [0; 2],
+        };
+        let params_buffer = self.pool.acquire_with_data(
+            &self.queue,
+            "map-values-params",
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            &[params],
+        );
+
+        let bind_group = step.cached_bind_group(
+            &self.pool,
+            "map-values-bind-group",
+            &[
+                wgpu::BindGroupEntry {
+                    binding: MAP_VALUES_BIND_SLAB,
+                    resource: slab.buffer().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: MAP_VALUES_BIND_SLAB_META,
+                    resource: slab.meta_buffer().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: MAP_VALUES_BIND_PARAMS,
+                    resource: params_buffer.as_entire_binding(),
+                },
+            ],
+        );
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("map-values-encoder"),
+            });
+        step.dispatch(
+            &mut encoder,
+            "map-values-pass",
+            &bind_group,
+            len.div_ceil(self.workgroup_size),
+        );
+        self.queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
+
+    fn step_for(&self, wgsl_fn: &str) -> Result<Arc<ComputeStep>, GpuMapError> {
+        let mut cache = self.steps.lock().expect("map_values cache poisoned");
+        cache.tick += 1;
+        let tick = cache.tick;
+        if let Some((step, last_used)) = cache.steps.get_mut(wgsl_fn) {
+            *last_used = tick;
+            return Ok(Arc::clone(step));
+        }
+
+        let source = with_workgroup_size(
+            &format!(
+                "{}{}\n{}",
+                MAP_VALUES_PRELUDE_WGSL, wgsl_fn, MAP_VALUES_MAIN_WGSL
+            ),
+            self.workgroup_size,
+        );
+        let _serial = self.scopes.lock().unwrap_or_else(PoisonError::into_inner);
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let step = ComputeStep::new(
+            Arc::clone(&self.device),
+            &source,
+            "main",
+            &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: MAP_VALUES_BIND_SLAB,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: MAP_VALUES_BIND_SLAB_META,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: MAP_VALUES_BIND_PARAMS,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        );
+        if let Some(err) = pollster::block_on(self.device.pop_error_scope()) {
+            return Err(GpuMapError::InvalidShader {
+                message: err.to_string(),
+            });
+        }
+
+        if cache.steps.len() >= MAX_CACHED_STEPS {
+            let oldest = cache
+                .steps
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(source, _)| source.clone());
+            if let Some(oldest) = oldest {
+                cache.steps.remove(&oldest);
+            }
+        }
+        let step = Arc::new(step);
+        cache
+            .steps
+            .insert(wgsl_fn.to_string(), (Arc::clone(&step), tick));
+        Ok(step)
+    }
+
+    /// Number of compiled transforms currently cached.
+    #[cfg(test)]
+    pub(crate) fn cached_steps(&self) -> usize {
+        self.steps
+            .lock()
+            .expect("map_values cache poisoned")
+            .steps
+            .len()
+    }
+}
+
+const MAP_VALUES_PRELUDE_WGSL: &str = concat!(
+    slab_wgsl!(),
+    r#"
+struct MapValuesParams {
+    lo: u32,
+    hi: u32,
+    _pad0: u32,
+    _pad1: u32,
+};
+
+@group(0) @binding(0) var<storage, read_write> slab: array<KvEntry>;
+@group(0) @binding(1) var<uniform> slab_meta: SlabMeta;
+@group(0) @binding(2) var<uniform> params: MapValuesParams;
+
+"#
+);
+
+const MAP_VALUES_MAIN_WGSL: &str = r#"
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn main(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let idx = gid.x + gid.y * groups.x * WORKGROUP_SIZE;
+    if (idx >= slab_meta.len) {
+        return;
+    }
+
+    let entry = slab[idx];
+    if (!slab_is_live(entry) || entry.key < params.lo || entry.key > params.hi) {
+        return;
+    }
+
+    // The tombstone is reserved; a transform that produces it leaves the entry unchanged.
+    let mapped = f(entry.key, entry.value);
+    if (mapped != SLAB_TOMBSTONE) {
+        slab[idx].value = mapped;
+    }
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::MAX_CACHED_STEPS;
+    use crate::backend::test_map;
+    use crate::{Capacity, Key, Value};
+
+    #[test]
+    fn compiled_transforms_are_bounded_by_the_cache() {
+        let mut map = test_map(Capacity::new(16));
+        map.put(Key::new(1), Value::new(0)).unwrap();
+        let Some(gpu) = map.store.gpu_backend().cloned() else {
+            return;
+        };
+
+        // A constant formatted into each source compiles a new transform.
+        let sources: Vec<String> = (1..=MAX_CACHED_STEPS as u32 + 8)
+            .map(|i| {
+                format!(
+                    "fn f(key: u32, value: u32) -> u32 {{ return value + {}u; }}",
+                    i
+                )
+            })
+            .collect();
+        for source in &sources {
+            map.map_values(.., source).unwrap();
+        }
+        assert_eq!(gpu.map_values.cached_steps(), MAX_CACHED_STEPS);
+
+        // The evicted first transform compiles again on demand.
+        map.map_values(.., &sources[0]).unwrap();
+        let total: u32 = (1..=MAX_CACHED_STEPS as u32 + 8).sum::<u32>() + 1;
+        assert_eq!(map.get(Key::new(1)).unwrap(), Some(Value::new(total)));
+        assert_eq!(gpu.map_values.cached_steps(), MAX_CACHED_STEPS);
+    }
+}
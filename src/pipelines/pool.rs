@@ -0,0 +1,467 @@
+//! Size-bucketed buffer pool and bind-group cache shared by all pipelines.
+//!
+//! Per-call scratch buffers (key uploads, parameter uniforms, results and
+//! readbacks) are taken from the pool and returned when the [`PooledBuffer`]
+//! is dropped. Sizes are rounded up to a power of two so batches of similar
+//! size reuse the same allocations. Bind groups are cached by layout and
+//! bound buffers, which hits whenever the same pooled buffers come back.
+//! A cached bind group keeps its buffers alive, so buffers freed outside the
+//! pool, such as the arrays of dropped slab versions, are passed to
+//! [`BufferPool::forget_buffer`] to evict the bind groups that reference them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU64;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// Smallest bucket handed out; keeps tiny uniforms in one shared bucket.
+const MIN_BUCKET_BYTES: u64 = 256;
+/// Bind groups kept before the least recently used one is evicted.
+const MAX_CACHED_BIND_GROUPS: usize = 256;
+/// Default cap on memory held by idle pooled buffers.
+pub const DEFAULT_MAX_RETAINED_BYTES: u64 = 64 << 20;
+
+type BucketKey = (u32, u64);
+type BindGroupKey = (
+    wgpu::Id<wgpu::BindGroupLayout>,
+    Vec<(u32, wgpu::Id<wgpu::Buffer>, u64, Option<NonZeroU64>)>,
+);
+
+/// Counters describing what the pool currently holds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Bytes held by idle buffers waiting for reuse.
+    pub retained_bytes: u64,
+    /// Number of idle buffers waiting for reuse.
+    pub retained_buffers: usize,
+    /// Cached bind groups.
+    pub cached_bind_groups: usize,
+    /// Acquisitions served from an idle buffer.
+    pub buffer_hits: u64,
+    /// Acquisitions that had to allocate.
+    pub buffer_misses: u64,
+    /// Bind-group lookups served from the cache.
+    pub bind_group_hits: u64,
+    /// Bind-group lookups that had to create one.
+    pub bind_group_misses: u64,
+}
+
+/// Buffer pool and bind-group cache for one device.
+pub struct BufferPool {
+    device: Arc<wgpu::Device>,
+    inner: Mutex<PoolInner>,
+}
+
+struct PoolInner {
+    /// Idle buffers by usage bits, then bucket size.
+    free: HashMap<u32, BTreeMap<u64, Vec<wgpu::Buffer>>>,
+    bind_groups: HashMap<BindGroupKey, (Arc<wgpu::BindGroup>, u64)>,
+    max_retained_bytes: u64,
+    tick: u64,
+    stats: PoolStats,
+}
+
+impl BufferPool {
+    pub fn new(device: Arc<wgpu::Device>, max_retained_bytes: u64) -> Self {
+        Self {
+            device,
+            inner: Mutex::new(PoolInner {
+                free: HashMap::new(),
+                bind_groups: HashMap::new(),
+                max_retained_bytes,
+                tick: 0,
+                stats: PoolStats::default(),
+            }),
+        }
+    }
+
+    /// Take a buffer of at least `size` bytes with exactly `usage`, reusing
+    /// the smallest idle one that fits.
+    pub fn acquire(
+        self: &Arc<Self>,
+        label: &str,
+        usage: wgpu::BufferUsages,
+        size: u64,
+    ) -> PooledBuffer {
+        let bucket = bucket_size(size);
+        let reused = {
+            let mut inner = self.lock();
+            let reused = inner.take(usage.bits(), bucket);
+            if reused.is_some() {
+                inner.stats.buffer_hits += 1;
+            } else {
+                inner.stats.buffer_misses += 1;
+            }
+            reused
+        };
+        let (buffer, bucket) = reused.unwrap_or_else(|| {
+            let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: bucket,
+                usage,
+                mapped_at_creation: false,
+            });
+            (buffer, bucket)
+        });
+        PooledBuffer {
+            buffer: Some(buffer),
+            pool: Arc::clone(self),
+            key: (usage.bits(), bucket),
+        }
+    }
+
+    /// Take a buffer and schedule `data` to be written to its start before the
+    /// next submission. `usage` must include `COPY_DST`.
+    pub fn acquire_with_data<T: bytemuck::Pod>(
+        self: &Arc<Self>,
+        queue: &wgpu::Queue,
+        label: &str,
+        usage: wgpu::BufferUsages,
+        data: &[T],
+    ) -> PooledBuffer {
+        let buffer = self.acquire(label, usage, std::mem::size_of_val(data) as u64);
+        queue.write_buffer(&buffer, 0, bytemuck::cast_slice(data));
+        buffer
+    }
+
+    /// Allocate `count` idle buffers of `size` bytes with `usage`, up to the
+    /// retained-memory cap.
+    pub fn prewarm(self: &Arc<Self>, usage: wgpu::BufferUsages, size: u64, count: usize) {
+        let buffers: Vec<PooledBuffer> = (0..count)
+            .map(|_| self.acquire("prewarmed-buffer", usage, size))
+            .collect();
+        drop(buffers);
+    }
+
+    /// Look up the bind group for `layout` and `entries`, calling `create` on
+    /// a miss.
+    ///
+    /// Only buffer bindings are cached; anything else is created fresh.
+    pub fn bind_group(
+        &self,
+        layout: &wgpu::BindGroupLayout,
+        entries: &[wgpu::BindGroupEntry],
+        create: impl FnOnce() -> wgpu::BindGroup,
+    ) -> Arc<wgpu::BindGroup> {
+        let Some(key) = bind_group_key(layout, entries) else {
+            return Arc::new(create());
+        };
+
+        let mut inner = self.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((bind_group, last_used)) = inner.bind_groups.get_mut(&key) {
+            *last_used = tick;
+            let bind_group = Arc::clone(bind_group);
+            inner.stats.bind_group_hits += 1;
+            return bind_group;
+        }
+
+        inner.stats.bind_group_misses += 1;
+        if inner.bind_groups.len() >= MAX_CACHED_BIND_GROUPS {
+            let oldest = inner
+                .bind_groups
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                inner.bind_groups.remove(&oldest);
+            }
+        }
+        let bind_group = Arc::new(create());
+        inner
+            .bind_groups
+            .insert(key, (Arc::clone(&bind_group), tick));
+        bind_group
+    }
+
+    /// Current pool contents and hit counters.
+    pub fn stats(&self) -> PoolStats {
+        let inner = self.lock();
+        PoolStats {
+            cached_bind_groups: inner.bind_groups.len(),
+            ..inner.stats
+        }
+    }
+
+    /// Cap on bytes held by idle buffers; lowering it frees buffers at once.
+    pub fn set_max_retained_bytes(&self, max_retained_bytes: u64) {
+        let mut inner = self.lock();
+        inner.max_retained_bytes = max_retained_bytes;
+        inner.trim();
+    }
+
+    pub fn max_retained_bytes(&self) -> u64 {
+        self.lock().max_retained_bytes
+    }
+
+    /// Release every idle buffer and cached bind group.
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        inner.free.clear();
+        inner.bind_groups.clear();
+        inner.stats.retained_bytes = 0;
+        inner.stats.retained_buffers = 0;
+    }
+
+    /// Drop cached bind groups that reference `buffer`, so it can be freed.
+    pub fn forget_buffer(&self, buffer: &wgpu::Buffer) {
+        self.lock().forget(buffer.global_id());
+    }
+
+    fn release(&self, key: BucketKey, buffer: wgpu::Buffer) {
+        let mut inner = self.lock();
+        if inner.stats.retained_bytes + key.1 > inner.max_retained_bytes {
+            inner.forget(buffer.global_id());
+            return;
+        }
+        inner.stats.retained_bytes += key.1;
+        inner.stats.retained_buffers += 1;
+        inner
+            .free
+            .entry(key.0)
+            .or_default()
+            .entry(key.1)
+            .or_default()
+            .push(buffer);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolInner> {
+        self.inner.lock().expect("buffer pool poisoned")
+    }
+}
+
+impl PoolInner {
+    /// Pop the smallest idle buffer with `usage` and at least `bucket` bytes.
+    fn take(&mut self, usage: u32, bucket: u64) -> Option<(wgpu::Buffer, u64)> {
+        let buckets = self.free.get_mut(&usage)?;
+        let (&size, buffers) = buckets
+            .range_mut(bucket..)
+            .find(|(_, buffers)| !buffers.is_empty())?;
+        let buffer = buffers.pop()?;
+        self.stats.retained_bytes -= size;
+        self.stats.retained_buffers -= 1;
+        Some((buffer, size))
+    }
+
+    /// Free the largest idle buffers until retained memory fits the cap.
+    fn trim(&mut self) {
+        while self.stats.retained_bytes > self.max_retained_bytes {
+            let largest = self
+                .free
+                .iter()
+                .flat_map(|(usage, buckets)| {
+                    buckets
+                        .iter()
+                        .filter(|(_, buffers)| !buffers.is_empty())
+                        .map(move |(size, _)| (*usage, *size))
+                })
+                .max_by_key(|(_, size)| *size);
+            let Some((usage, size)) = largest else {
+                break;
+            };
+            if let Some((buffer, _)) = self.take(usage, size) {
+                self.forget(buffer.global_id());
+            }
+        }
+    }
+
+    fn forget(&mut self, id: wgpu::Id<wgpu::Buffer>) {
+        self.bind_groups
+            .retain(|(_, bindings), _| bindings.iter().all(|binding| binding.1 != id));
+    }
+}
+
+/// Buffer on loan from a [`BufferPool`]; returned to the pool on drop.
+///
+/// The buffer may be larger than requested. It must not be left mapped.
+pub struct PooledBuffer {
+    buffer: Option<wgpu::Buffer>,
+    pool: Arc<BufferPool>,
+    key: BucketKey,
+}
+
+impl Deref for PooledBuffer {
+    type Target = wgpu::Buffer;
+
+    fn deref(&self) -> &wgpu::Buffer {
+        self.buffer
+            .as_ref()
+            .expect("pooled buffer already released")
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.release(self.key, buffer);
+        }
+    }
+}
+
+fn bucket_size(size: u64) -> u64 {
+    size.max(MIN_BUCKET_BYTES).next_power_of_two()
+}
+
+fn bind_group_key(
+    layout: &wgpu::BindGroupLayout,
+    entries: &[wgpu::BindGroupEntry],
+) -> Option<BindGroupKey> {
+    let bindings = entries
+        .iter()
+        .map(|entry| match &entry.resource {
+            wgpu::BindingResource::Buffer(binding) => Some((
+                entry.binding,
+                binding.buffer.global_id(),
+                binding.offset,
+                binding.size,
+            )),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some((layout.global_id(), bindings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bucket_size;
+    use crate::{Capacity, GpuSortedMap, Key, KvEntry, Value};
+
+    fn try_create_map(capacity: Capacity) -> Option<GpuSortedMap> {
+        match pollster::block_on(GpuSortedMap::new(capacity)) {
+            Ok(map) => Some(map),
+            Err(_) => {
+                eprintln!("Skipping test: GPU not available in this environment");
+                None
+            }
+        }
+    }
+
+    fn entries(count: u32) -> Vec<KvEntry> {
+        (0..count)
+            .map(|i| KvEntry {
+                key: Key::new(i),
+                value: Value::new(i + 100),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn buckets_round_up_to_powers_of_two() {
+        assert_eq!(bucket_size(0), 256);
+        assert_eq!(bucket_size(16), 256);
+        assert_eq!(bucket_size(257), 512);
+        assert_eq!(bucket_size(4096), 4096);
+    }
+
+    #[test]
+    fn repeated_batches_reuse_buffers_and_bind_groups() {
+        let Some(mut map) = try_create_map(Capacity::new(256)) else {
+            return;
+        };
+        map.bulk_put(&entries(64)).unwrap();
+        let keys: Vec<Key> = (0..32).map(Key::new).collect();
+        map.bulk_get(&keys).unwrap();
+        map.range(Key::new(0), Key::new(10)).unwrap();
+
+        let before = map.buffer_pool().unwrap().stats();
+        for _ in 0..4 {
+            assert_eq!(map.bulk_get(&keys).unwrap()[3], Some(Value::new(103)));
+            map.range(Key::new(0), Key::new(10)).unwrap();
+        }
+        let after = map.buffer_pool().unwrap().stats();
+        assert_eq!(after.buffer_misses, before.buffer_misses);
+        assert!(after.buffer_hits > before.buffer_hits);
+        assert!(after.bind_group_hits > before.bind_group_hits);
+        assert!(after.retained_buffers > 0);
+    }
+
+    #[test]
+    fn freed_slab_versions_leave_the_bind_group_cache() {
+        let Some(mut map) = try_create_map(Capacity::new(256)) else {
+            return;
+        };
+        map.bulk_put(&entries(64)).unwrap();
+        let snapshot = map.snapshot();
+        map.bulk_put(&entries(128)).unwrap();
+        let keys: Vec<Key> = (0..32).map(Key::new).collect();
+        snapshot.bulk_get(&keys).unwrap();
+        snapshot.range(Key::new(0), Key::new(10)).unwrap();
+
+        let pinned = map.buffer_pool().unwrap().stats().cached_bind_groups;
+        drop(snapshot);
+        let freed = map.buffer_pool().unwrap().stats().cached_bind_groups;
+        assert!(freed < pinned, "{freed} >= {pinned}");
+    }
+
+    #[test]
+    fn prewarm_serves_first_batch_from_pool() {
+        let Some(mut map) = try_create_map(Capacity::new(256)) else {
+            return;
+        };
+        map.prewarm(128);
+        let warmed = map.buffer_pool().unwrap().stats();
+        assert!(warmed.retained_bytes > 0);
+
+        map.bulk_put(&entries(100)).unwrap();
+        map.bulk_delete(&[Key::new(1), Key::new(2)]).unwrap();
+        let keys: Vec<Key> = (0..128).map(Key::new).collect();
+        let values = map.bulk_get(&keys).unwrap();
+        assert_eq!(values[1], None);
+        assert_eq!(values[99], Some(Value::new(199)));
+        assert_eq!(map.range(Key::new(0), Key::new(128)).unwrap().len(), 98);
+        assert_eq!(
+            map.buffer_pool().unwrap().stats().buffer_misses,
+            warmed.buffer_misses
+        );
+    }
+
+    #[test]
+    fn retained_memory_respects_cap() {
+        let Some(mut map) = try_create_map(Capacity::new(256)) else {
+            return;
+        };
+        map.prewarm(256);
+        assert!(map.buffer_pool().unwrap().stats().retained_bytes > 1024);
+
+        map.buffer_pool().unwrap().set_max_retained_bytes(1024);
+        assert!(map.buffer_pool().unwrap().stats().retained_bytes <= 1024);
+        map.bulk_put(&entries(200)).unwrap();
+        let keys: Vec<Key> = (0..200).map(Key::new).collect();
+        assert_eq!(map.bulk_get(&keys).unwrap()[150], Some(Value::new(250)));
+        assert!(map.buffer_pool().unwrap().stats().retained_bytes <= 1024);
+
+        map.buffer_pool().unwrap().clear();
+        let cleared = map.buffer_pool().unwrap().stats();
+        assert_eq!(cleared.retained_bytes, 0);
+        assert_eq!(cleared.cached_bind_groups, 0);
+        assert_eq!(map.get(Key::new(7)).unwrap(), Some(Value::new(107)));
+    }
+
+    #[test]
+    fn abandoned_readback_returns_unmapped_buffer() {
+        use std::future::Future;
+        use std::sync::Arc;
+        use std::task::{Context, Wake, Waker};
+
+        struct NoopWaker;
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let Some(mut map) = try_create_map(Capacity::new(64)) else {
+            return;
+        };
+        map.bulk_put(&entries(8)).unwrap();
+        let keys = [Key::new(1)];
+        {
+            let future = map.bulk_get_async(&keys);
+            let mut future = std::pin::pin!(future);
+            let waker = Waker::from(Arc::new(NoopWaker));
+            let _ = future.as_mut().poll(&mut Context::from_waker(&waker));
+        }
+        for _ in 0..3 {
+            assert_eq!(map.get(Key::new(1)).unwrap(), Some(Value::new(101)));
+        }
+    }
+}
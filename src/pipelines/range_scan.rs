@@ -4,14 +4,16 @@
 //! lower-bound binary searches over sorted keys. Tombstone filtering happens in
 //! host code after readback.
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use bytemuck::{Pod, Zeroable};
 
 use crate::gpu_array::GpuArray;
 use crate::pipelines::core::ComputeStep;
-use crate::pipelines::utils::{create_buffer_with_data, readback_single, readback_vec};
-use crate::{Key, KvEntry};
+use crate::pipelines::pool::{BufferPool, PooledBuffer};
+use crate::pipelines::utils::{map_read, map_read_single, ErrorScope};
+use crate::pipelines::wgsl::slab_wgsl;
+use crate::{GpuMapError, Key, KvEntry};
 
 const RANGE_BIND_SLAB: u32 = 0;
 const RANGE_BIND_SLAB_META: u32 = 1;
@@ -37,11 +39,18 @@ struct RangeMeta {
 pub struct RangeScanPipeline {
     device: Arc<wgpu::Device>,
     queue: Arc<wgpu::Queue>,
+    pool: Arc<BufferPool>,
+    scopes: Arc<Mutex<()>>,
     step: ComputeStep,
 }
 
 impl RangeScanPipeline {
-    pub fn new(device: Arc<wgpu::Device>, queue: Arc<wgpu::Queue>) -> Self {
+    pub fn new(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        pool: Arc<BufferPool>,
+        scopes: Arc<Mutex<()>>,
+    ) -> Self {
         let step = ComputeStep::new(
             Arc::clone(&device),
             RANGE_WGSL,
@@ -93,38 +102,104 @@ impl RangeScanPipeline {
         Self {
             device,
             queue,
+            pool,
+            scopes,
             step,
         }
     }
 
-    pub fn execute(&self, slab: &GpuArray<KvEntry>, from_key: Key, to_key: Key) -> Vec<KvEntry> {
+    pub async fn execute(
+        &self,
+        slab: &GpuArray<KvEntry>,
+        from_key: Key,
+        to_key: Key,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        let Some((start, end)) = self.bounds(slab, from_key, to_key).await? else {
+            return Ok(Vec::new());
+        };
+        self.read_slots(slab, start, end).await
+    }
+
+    /// Copy of slab slots `[start, end)`, tombstones included.
+    pub async fn read_slots(
+        &self,
+        slab: &GpuArray<KvEntry>,
+        start: u32,
+        end: u32,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        if end <= start {
+            return Ok(Vec::new());
+        }
+
+        let scope = ErrorScope::push(&self.device, &self.scopes);
+        let count = end - start;
+        let byte_len = (count as u64) * std::mem::size_of::<KvEntry>() as u64;
+        let readback = self.pool.acquire(
+            "range-readback",
+            wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            byte_len,
+        );
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("range-copy-encoder"),
+            });
+        let offset = (start as u64) * std::mem::size_of::<KvEntry>() as u64;
+        encoder.copy_buffer_to_buffer(slab.buffer(), offset, &readback, 0, byte_len);
+        self.queue.submit(Some(encoder.finish()));
+        scope.pop().await?;
+
+        map_read::<KvEntry>(&readback, count as usize).await
+    }
+
+    /// Slab index bounds `[start, end)` covering keys in `[from_key, to_key)`,
+    /// or `None` if the range is empty.
+    pub async fn bounds(
+        &self,
+        slab: &GpuArray<KvEntry>,
+        from_key: Key,
+        to_key: Key,
+    ) -> Result<Option<(u32, u32)>, GpuMapError> {
         if from_key >= to_key || slab.len().0 == 0 {
-            return Vec::new();
+            return Ok(None);
         }
 
+        let scope = ErrorScope::push(&self.device, &self.scopes);
+        let staging = RangeStaging::acquire(&self.pool);
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("range-encoder"),
+            });
+        self.encode_bounds(&mut encoder, slab, &staging, from_key, to_key);
+        self.queue.submit(Some(encoder.finish()));
+        scope.pop().await?;
+
+        let meta = map_read_single::<RangeMeta>(&staging.meta_readback).await?;
+        Ok(bounds_from_meta(meta))
+    }
+
+    /// Record the bound search for `[from_key, to_key)` and the copy of its
+    /// result into `staging`'s meta readback buffer.
+    pub fn encode_bounds(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        slab: &GpuArray<KvEntry>,
+        staging: &RangeStaging,
+        from_key: Key,
+        to_key: Key,
+    ) {
         let params = RangeParams {
             from_key: from_key.0,
             to_key: to_key.0,
             _pad: [0; 2],
         };
-        let params_buffer = create_buffer_with_data(
-            &self.device,
-            "range-params",
-            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            &[params],
-        );
-
-        let output_meta = RangeMeta::default();
-        let output_meta_buffer = create_buffer_with_data(
-            &self.device,
-            "range-meta",
-            wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::COPY_SRC
-                | wgpu::BufferUsages::COPY_DST,
-            &[output_meta],
-        );
+        self.queue
+            .write_buffer(&staging.params, 0, bytemuck::bytes_of(&params));
 
-        let bind_group = self.step.create_bind_group(
+        let bind_group = self.step.cached_bind_group(
+            &self.pool,
             "range-bind-group",
             &[
                 wgpu::BindGroupEntry {
@@ -137,78 +212,113 @@ impl RangeScanPipeline {
                 },
                 wgpu::BindGroupEntry {
                     binding: RANGE_BIND_PARAMS,
-                    resource: params_buffer.as_entire_binding(),
+                    resource: staging.params.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: RANGE_BIND_OUTPUT_META,
-                    resource: output_meta_buffer.as_entire_binding(),
+                    resource: staging.meta.as_entire_binding(),
                 },
             ],
         );
-
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("range-encoder"),
-            });
-        self.step
-            .dispatch(&mut encoder, "range-pass", &bind_group, (1, 1, 1));
-
-        let output_readback = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("range-meta-readback"),
-            size: std::mem::size_of::<RangeMeta>() as u64,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-            mapped_at_creation: false,
-        });
+        self.step.dispatch(encoder, "range-pass", &bind_group, 1);
         encoder.copy_buffer_to_buffer(
-            &output_meta_buffer,
+            &staging.meta,
             0,
-            &output_readback,
+            &staging.meta_readback,
             0,
             std::mem::size_of::<RangeMeta>() as u64,
         );
-        self.queue.submit(Some(encoder.finish()));
+    }
+
+    /// Fill the pool with staging buffers for ranges of up to `batch` entries.
+    pub fn prewarm(&self, batch: u32) {
+        let mut staging = RangeStaging::acquire(&self.pool);
+        staging.reserve(batch);
+    }
+}
+
+/// Parameter, bound and readback buffers for one range batch, on loan from
+/// the buffer pool.
+///
+/// The entries readback buffer is only taken once [`reserve`](Self::reserve)
+/// knows how many entries the range holds.
+pub struct RangeStaging {
+    pool: Arc<BufferPool>,
+    params: PooledBuffer,
+    meta: PooledBuffer,
+    meta_readback: PooledBuffer,
+    entries_readback: Option<PooledBuffer>,
+    capacity: u32,
+}
 
-        let meta = readback_single::<RangeMeta>(&self.device, &output_readback);
-        if meta.end <= meta.start {
-            return Vec::new();
+impl RangeStaging {
+    pub fn acquire(pool: &Arc<BufferPool>) -> Self {
+        Self {
+            pool: Arc::clone(pool),
+            params: pool.acquire(
+                "range-params",
+                wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                std::mem::size_of::<RangeParams>() as u64,
+            ),
+            meta: pool.acquire(
+                "range-meta",
+                wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_SRC
+                    | wgpu::BufferUsages::COPY_DST,
+                std::mem::size_of::<RangeMeta>() as u64,
+            ),
+            meta_readback: pool.acquire(
+                "range-meta-readback",
+                wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                std::mem::size_of::<RangeMeta>() as u64,
+            ),
+            entries_readback: None,
+            capacity: 0,
         }
+    }
 
-        let count = meta.end - meta.start;
-        let byte_len = (count as u64) * std::mem::size_of::<KvEntry>() as u64;
-        let readback = self.device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("range-readback"),
-            size: byte_len,
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+    /// Make sure the entries readback buffer holds at least `count` entries.
+    pub fn reserve(&mut self, count: u32) {
+        if self.entries_readback.is_none() || count > self.capacity {
+            let capacity = count.max(1);
+            self.entries_readback = Some(self.pool.acquire(
+                "range-readback",
+                wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                capacity as u64 * std::mem::size_of::<KvEntry>() as u64,
+            ));
+            self.capacity = capacity;
+        }
+    }
 
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("range-copy-encoder"),
-            });
-        let offset = (meta.start as u64) * std::mem::size_of::<KvEntry>() as u64;
-        encoder.copy_buffer_to_buffer(slab.buffer(), offset, &readback, 0, byte_len);
-        self.queue.submit(Some(encoder.finish()));
+    pub fn meta_readback(&self) -> &wgpu::Buffer {
+        &self.meta_readback
+    }
 
-        readback_vec::<KvEntry>(&self.device, &readback)
+    /// Entries readback buffer; call [`reserve`](Self::reserve) first.
+    pub fn entries_readback(&self) -> &wgpu::Buffer {
+        self.entries_readback
+            .as_ref()
+            .expect("range staging has no entries buffer; call reserve first")
     }
 }
 
-const RANGE_WGSL: &str = r#"
-struct KvEntry {
-    key: u32,
-    value: u32,
-};
+/// Size of the bound-search result read back per range batch.
+pub const RANGE_META_BYTES: u64 = std::mem::size_of::<RangeMeta>() as u64;
 
-struct SlabMeta {
-    len: u32,
-    capacity: u32,
-    _pad0: u32,
-    _pad1: u32,
-};
+/// Decode a bound-search readback into `[start, end)`, or `None` if empty.
+pub fn decode_bounds(bytes: &[u32]) -> Option<(u32, u32)> {
+    bounds_from_meta(*bytemuck::from_bytes::<RangeMeta>(bytemuck::cast_slice(
+        bytes,
+    )))
+}
+
+fn bounds_from_meta(meta: RangeMeta) -> Option<(u32, u32)> {
+    (meta.end > meta.start).then_some((meta.start, meta.end))
+}
 
+const RANGE_WGSL: &str = concat!(
+    slab_wgsl!(),
+    r#"
 struct RangeParams {
     from_key: u32,
     to_key: u32,
@@ -228,21 +338,6 @@ struct RangeMeta {
 @group(0) @binding(2) var<uniform> params: RangeParams;
 @group(0) @binding(3) var<storage, read_write> out_meta: RangeMeta;
 
-fn lower_bound(tgt: u32, len: u32) -> u32 {
-    var lo: u32 = 0u;
-    var hi: u32 = len;
-    while (lo < hi) {
-        let mid = (lo + hi) / 2u;
-        let mid_key = slab[mid].key;
-        if (mid_key < tgt) {
-            lo = mid + 1u;
-        } else {
-            hi = mid;
-        }
-    }
-    return lo;
-}
-
 @compute @workgroup_size(1)
 fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
     if (gid.x > 0u) {
@@ -256,10 +351,11 @@ fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
         return;
     }
 
-    let start = lower_bound(params.from_key, len);
-    let end = lower_bound(params.to_key, len);
+    let start = slab_lower_bound(params.from_key, len);
+    let end = slab_lower_bound(params.to_key, len);
 
     out_meta.start = start;
     out_meta.end = end;
 }
-"#;
+"#
+);
@@ -0,0 +1,342 @@
+//! Search index pipeline.
+//!
+//! A [`SearchIndex`] holds fence keys for one slab version: the key of
+//! every `stride`-th slot, at most [`INDEX_FENCES`] of them. Lookup and
+//! delete kernels copy the fences into workgroup memory, binary-search them
+//! there, and then only search the `stride` slots between two fences in the
+//! slab, instead of every thread wandering over the whole array.
+//!
+//! The fences depend only on the slab's keys, so an index stays valid while
+//! deletes and value rewrites change the slab, and is rebuilt when a merge
+//! publishes a new version.
+
+use std::sync::{Arc, Mutex};
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::gpu_array::GpuArray;
+use crate::pipelines::core::ComputeStep;
+use crate::pipelines::pool::{BufferPool, PooledBuffer};
+use crate::pipelines::utils::ErrorScope;
+use crate::pipelines::wgsl::{slab_wgsl, with_workgroup_size};
+use crate::{GpuMapError, KvEntry};
+
+/// Most fences one index holds: 8 KiB of workgroup memory, half the
+/// smallest `max_compute_workgroup_storage_size` wgpu allows.
+pub const INDEX_FENCES: u32 = 2048;
+
+const SEARCH_INDEX_BIND_SLAB: u32 = 0;
+const SEARCH_INDEX_BIND_META: u32 = 1;
+const SEARCH_INDEX_BIND_FENCES: u32 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
+pub struct IndexMeta {
+    pub count: u32,
+    pub stride: u32,
+    pub _pad: [u32; 2],
+}
+
+impl IndexMeta {
+    /// Fences for a slab of `len` entries: as many as fit, each covering
+    /// `stride` slots, with the last one inside the slab.
+    pub fn for_len(len: u32) -> Self {
+        let stride = len.div_ceil(INDEX_FENCES).max(1);
+        Self {
+            count: len.div_ceil(stride),
+            stride,
+            _pad: [0; 2],
+        }
+    }
+}
+
+/// Fence keys of one slab version, bound by the lookup and delete kernels.
+/// An index with no fences makes them search the whole slab.
+pub struct SearchIndex {
+    fences: PooledBuffer,
+    meta: PooledBuffer,
+}
+
+impl SearchIndex {
+    /// An index with no fences, for slabs that are not indexed.
+    pub fn empty(pool: &Arc<BufferPool>, queue: &wgpu::Queue) -> Self {
+        Self::with_meta(pool, queue, IndexMeta::default())
+    }
+
+    fn with_meta(pool: &Arc<BufferPool>, queue: &wgpu::Queue, meta: IndexMeta) -> Self {
+        Self {
+            fences: pool.acquire(
+                "search-index-fences-buffer",
+                wgpu::BufferUsages::STORAGE,
+                u64::from(meta.count.max(1)) * std::mem::size_of::<u32>() as u64,
+            ),
+            meta: pool.acquire_with_data(
+                queue,
+                "search-index-meta-buffer",
+                wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                &[meta],
+            ),
+        }
+    }
+
+    pub fn fences(&self) -> &wgpu::Buffer {
+        &self.fences
+    }
+
+    pub fn meta(&self) -> &wgpu::Buffer {
+        &self.meta
+    }
+}
+
+pub struct SearchIndexPipeline {
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
+    pool: Arc<BufferPool>,
+    scopes: Arc<Mutex<()>>,
+    workgroup_size: u32,
+    step: ComputeStep,
+}
+
+impl SearchIndexPipeline {
+    pub fn new(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        pool: Arc<BufferPool>,
+        scopes: Arc<Mutex<()>>,
+        workgroup_size: u32,
+    ) -> Self {
+        let step = ComputeStep::new(
+            Arc::clone(&device),
+            &with_workgroup_size(SEARCH_INDEX_WGSL, workgroup_size),
+            "main",
+            &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: SEARCH_INDEX_BIND_SLAB,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: SEARCH_INDEX_BIND_META,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: SEARCH_INDEX_BIND_FENCES,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        );
+        Self {
+            device,
+            queue,
+            pool,
+            scopes,
+            workgroup_size,
+            step,
+        }
+    }
+
+    /// Gather the fence keys of `slab` into a new index.
+    pub async fn execute(&self, slab: &GpuArray<KvEntry>) -> Result<SearchIndex, GpuMapError> {
+        let meta = IndexMeta::for_len(slab.len().0);
+        let index = SearchIndex::with_meta(&self.pool, &self.queue, meta);
+        if meta.count == 0 {
+            return Ok(index);
+        }
+
+        let scope = ErrorScope::push(&self.device, &self.scopes);
+        let bind_group = self.step.cached_bind_group(
+            &self.pool,
+            "search-index-bind-group",
+            &[
+                wgpu::BindGroupEntry {
+                    binding: SEARCH_INDEX_BIND_SLAB,
+                    resource: slab.buffer().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: SEARCH_INDEX_BIND_META,
+                    resource: index.meta.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: SEARCH_INDEX_BIND_FENCES,
+                    resource: index.fences.as_entire_binding(),
+                },
+            ],
+        );
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("search-index-encoder"),
+            });
+        let workgroups = meta.count.div_ceil(self.workgroup_size);
+        self.step
+            .dispatch(&mut encoder, "search-index-pass", &bind_group, workgroups);
+        self.queue.submit(Some(encoder.finish()));
+        scope.pop().await?;
+        Ok(index)
+    }
+}
+
+const SEARCH_INDEX_WGSL: &str = concat!(
+    slab_wgsl!(),
+    r#"
+struct IndexMeta {
+    count: u32,
+    stride: u32,
+    _pad0: u32,
+    _pad1: u32,
+};
+
+@group(0) @binding(0) var<storage, read> slab: array<KvEntry>;
+@group(0) @binding(1) var<uniform> index_meta: IndexMeta;
+@group(0) @binding(2) var<storage, read_write> fences: array<u32>;
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn main(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let idx = gid.x + gid.y * groups.x * WORKGROUP_SIZE;
+    if (idx >= index_meta.count) {
+        return;
+    }
+    fences[idx] = slab[idx * index_meta.stride].key;
+}
+"#
+);
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{IndexMeta, INDEX_FENCES};
+    use crate::backend::test_map;
+    use crate::pipelines::wgsl::search_index_wgsl;
+    use crate::{Backend, BatchResult, Capacity, Key, KvEntry, Value};
+
+    #[test]
+    fn fences_cover_the_slab_within_the_limit() {
+        for len in [
+            0,
+            1,
+            2,
+            100,
+            2047,
+            2048,
+            2049,
+            4096,
+            4097,
+            1 << 20,
+            1_000_003,
+        ] {
+            let meta = IndexMeta::for_len(len);
+            assert!(meta.count <= INDEX_FENCES, "len {}", len);
+            assert!(meta.count * meta.stride >= len, "len {}", len);
+            if len > 0 {
+                // The last fence is a slot inside the slab.
+                assert!((meta.count - 1) * meta.stride < len, "len {}", len);
+            }
+        }
+        assert_eq!(IndexMeta::for_len(0).count, 0);
+        assert_eq!(IndexMeta::for_len(100).stride, 1);
+    }
+
+    #[test]
+    fn lookup_snippet_matches_the_fence_limit() {
+        let declaration = format!("const INDEX_FENCES: u32 = {}u;", INDEX_FENCES);
+        assert!(search_index_wgsl!().contains(&declaration));
+    }
+
+    #[test]
+    fn indexed_lookups_and_deletes_match_the_contents() {
+        let mut map = test_map(Capacity::new(8192));
+        map.set_search_index(true);
+        assert_eq!(map.search_index(), map.backend() == Backend::Gpu);
+
+        // 5000 entries: more than one fence each, and a stride that does
+        // not divide the slab.
+        let mut expected = BTreeMap::new();
+        let entries: Vec<KvEntry> = (0..5000u32)
+            .map(|i| {
+                let key = 100 + i * 3;
+                expected.insert(key, key / 2);
+                KvEntry {
+                    key: Key::new(key),
+                    value: Value::new(key / 2),
+                }
+            })
+            .collect();
+        map.bulk_put(&entries).unwrap();
+        let probes: Vec<Key> = (0..16_000u32).map(Key::new).collect();
+        let check = |map: &crate::GpuSortedMap, expected: &BTreeMap<u32, u32>| {
+            let values = map.bulk_get(&probes).unwrap();
+            for (key, value) in probes.iter().zip(values) {
+                assert_eq!(
+                    value,
+                    expected.get(&key.0).copied().map(Value::new),
+                    "{:?}",
+                    key
+                );
+            }
+        };
+        check(&map, &expected);
+
+        // Deletes keep the index; a snapshot makes the delete copy the slab.
+        let snapshot = map.snapshot();
+        let deleted: Vec<Key> = (0..16_000u32).step_by(7).map(Key::new).collect();
+        map.bulk_delete(&deleted).unwrap();
+        let before = expected.clone();
+        for key in &deleted {
+            expected.remove(&key.0);
+        }
+        check(&map, &expected);
+        assert_eq!(
+            snapshot.bulk_get(&probes[100..200]).unwrap(),
+            probes[100..200]
+                .iter()
+                .map(|key| before.get(&key.0).copied().map(Value::new))
+                .collect::<Vec<_>>()
+        );
+
+        // A put rebuilds the index around new keys at both ends.
+        map.bulk_put(&[
+            KvEntry {
+                key: Key::new(1),
+                value: Value::new(11),
+            },
+            KvEntry {
+                key: Key::new(15_999),
+                value: Value::new(12),
+            },
+        ])
+        .unwrap();
+        expected.insert(1, 11);
+        expected.insert(15_999, 12);
+        check(&map, &expected);
+
+        let mut queue = map.submission_queue(2);
+        let id = queue.enqueue_get(&probes[..50]);
+        let (done, result) = queue.next_completed().unwrap();
+        assert_eq!(done, id);
+        let BatchResult::Get(values) = result else {
+            panic!("expected a lookup result, got {:?}", result);
+        };
+        assert_eq!(values[1], Some(Value::new(11)));
+    }
+}
@@ -0,0 +1,347 @@
+//! Sorted bulk lookup pipeline.
+//!
+//! Instead of one binary search per key, the batch is sorted on the GPU
+//! with the bulk-put sort, each query carrying its position in the batch,
+//! and then merge-joined with the slab. Every thread takes
+//! [`JOIN_STEPS`] steps of the merge: it finds where its share starts with
+//! `merge_partition`, walks slab and queries together from there, and
+//! writes each query's result back to the query's original position.
+
+use std::sync::{Arc, Mutex};
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::gpu_array::GpuArray;
+use crate::pipelines::bulk_get::{decode_results, result_bytes};
+use crate::pipelines::bulk_put::BulkPutPipeline;
+use crate::pipelines::core::ComputeStep;
+use crate::pipelines::data::ResultEntry;
+use crate::pipelines::pool::BufferPool;
+use crate::pipelines::utils::{map_read, ErrorScope};
+use crate::pipelines::wgsl::{merge_path_wgsl, slab_wgsl, with_workgroup_size};
+use crate::{GpuMapError, Key, KvEntry, Value, TOMBSTONE_VALUE};
+
+/// Merge steps, slab entries plus queries, each join thread takes.
+pub const JOIN_STEPS: u32 = 32;
+
+const SORTED_GET_BIND_SLAB: u32 = 0;
+const SORTED_GET_BIND_SLAB_META: u32 = 1;
+const SORTED_GET_BIND_QUERIES: u32 = 2;
+const SORTED_GET_BIND_PARAMS: u32 = 3;
+const SORTED_GET_BIND_RESULTS: u32 = 4;
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
+struct JoinParams {
+    len: u32,
+    steps: u32,
+    _pad: [u32; 2],
+}
+
+pub struct SortedGetPipeline {
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
+    pool: Arc<BufferPool>,
+    scopes: Arc<Mutex<()>>,
+    workgroup_size: u32,
+    step: ComputeStep,
+}
+
+impl SortedGetPipeline {
+    pub fn new(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        pool: Arc<BufferPool>,
+        scopes: Arc<Mutex<()>>,
+        workgroup_size: u32,
+    ) -> Self {
+        let step = ComputeStep::new(
+            Arc::clone(&device),
+            &with_workgroup_size(SORTED_GET_WGSL, workgroup_size),
+            "main",
+            &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: SORTED_GET_BIND_SLAB,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: SORTED_GET_BIND_SLAB_META,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: SORTED_GET_BIND_QUERIES,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: SORTED_GET_BIND_PARAMS,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: SORTED_GET_BIND_RESULTS,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        );
+        Self {
+            device,
+            queue,
+            pool,
+            scopes,
+            workgroup_size,
+            step,
+        }
+    }
+
+    /// Values of the entries in `slab` for `keys`, in the order of `keys`;
+    /// tombstones come back as `None`. `sort` orders the queries.
+    pub async fn execute(
+        &self,
+        sort: &BulkPutPipeline,
+        slab: &GpuArray<KvEntry>,
+        keys: &[Key],
+    ) -> Result<Vec<Option<Value>>, GpuMapError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Queries carry their position as the value. The sort needs a power
+        // of two; padding sorts last and is marked with the tombstone value,
+        // which no position reaches.
+        let len = keys.len() as u32;
+        let padded_len = len.next_power_of_two();
+        let mut queries: Vec<KvEntry> = keys
+            .iter()
+            .enumerate()
+            .map(|(position, &key)| KvEntry {
+                key,
+                value: Value::new(position as u32),
+            })
+            .collect();
+        queries.resize(
+            padded_len as usize,
+            KvEntry {
+                key: Key::new(u32::MAX),
+                value: TOMBSTONE_VALUE,
+            },
+        );
+
+        let scope = ErrorScope::push(&self.device, &self.scopes);
+        let queries_buffer = self.pool.acquire_with_data(
+            &self.queue,
+            "sorted-get-queries-buffer",
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            &queries,
+        );
+        let params = JoinParams {
+            len: padded_len,
+            steps: JOIN_STEPS,
+            _pad: [0; 2],
+        };
+        let params_buffer = self.pool.acquire_with_data(
+            &self.queue,
+            "sorted-get-params-buffer",
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            &[params],
+        );
+        let results = self.pool.acquire(
+            "sorted-get-results-buffer",
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            result_bytes(len),
+        );
+        let readback = self.pool.acquire(
+            "sorted-get-readback-buffer",
+            wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            result_bytes(len),
+        );
+
+        let bind_group = self.step.cached_bind_group(
+            &self.pool,
+            "sorted-get-bind-group",
+            &[
+                wgpu::BindGroupEntry {
+                    binding: SORTED_GET_BIND_SLAB,
+                    resource: slab.buffer().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: SORTED_GET_BIND_SLAB_META,
+                    resource: slab.meta_buffer().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: SORTED_GET_BIND_QUERIES,
+                    resource: queries_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: SORTED_GET_BIND_PARAMS,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: SORTED_GET_BIND_RESULTS,
+                    resource: results.as_entire_binding(),
+                },
+            ],
+        );
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("sorted-get-encoder"),
+            });
+        let _sort_params =
+            (padded_len > 1).then(|| sort.encode_sort(&mut encoder, &queries_buffer, padded_len));
+        let threads = (slab.len().0 + padded_len).div_ceil(JOIN_STEPS);
+        let workgroups = threads.div_ceil(self.workgroup_size);
+        self.step
+            .dispatch(&mut encoder, "sorted-get-pass", &bind_group, workgroups);
+        encoder.copy_buffer_to_buffer(&results, 0, &readback, 0, result_bytes(len));
+        self.queue.submit(Some(encoder.finish()));
+        scope.pop().await?;
+
+        let result_entries = map_read::<ResultEntry>(&readback, keys.len()).await?;
+        Ok(decode_results(&result_entries))
+    }
+}
+
+const SORTED_GET_WGSL: &str = concat!(
+    slab_wgsl!(),
+    merge_path_wgsl!(),
+    r#"
+struct JoinParams {
+    len: u32,
+    steps: u32,
+    _pad0: u32,
+    _pad1: u32,
+};
+
+struct ResultEntry {
+    value: u32,
+    found: u32,
+    _pad0: u32,
+    _pad1: u32,
+};
+
+@group(0) @binding(0) var<storage, read> slab: array<KvEntry>;
+@group(0) @binding(1) var<uniform> slab_meta: SlabMeta;
+@group(0) @binding(2) var<storage, read> input: array<KvEntry>;
+@group(0) @binding(3) var<uniform> params: JoinParams;
+@group(0) @binding(4) var<storage, read_write> results: array<ResultEntry>;
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn main(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let idx = gid.x + gid.y * groups.x * WORKGROUP_SIZE;
+    let slab_len = slab_meta.len;
+    let input_len = params.len;
+    let total = slab_len + input_len;
+    let start = idx * params.steps;
+    if (start >= total) {
+        return;
+    }
+    let end = min(start + params.steps, total);
+
+    // Every slab entry before `i` has a smaller key than `input[j]`, so
+    // `slab[i]` is the only entry a query can match.
+    let split = merge_partition(start, slab_len, input_len);
+    var i = split.x;
+    var j = split.y;
+    for (var k = start; k < end; k = k + 1u) {
+        if (j < input_len && (i >= slab_len || input[j].key <= slab[i].key)) {
+            let query = input[j];
+            // Padding carries the tombstone value instead of a position.
+            if (query.value != SLAB_TOMBSTONE) {
+                if (i < slab_len && slab[i].key == query.key) {
+                    results[query.value].value = slab[i].value;
+                    results[query.value].found = 1u;
+                } else {
+                    results[query.value].value = 0u;
+                    results[query.value].found = 0u;
+                }
+            }
+            j = j + 1u;
+        } else {
+            i = i + 1u;
+        }
+    }
+}
+"#
+);
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_map;
+    use crate::{Capacity, Key, KvEntry, Value};
+
+    #[test]
+    fn sorted_lookups_match_per_key_lookups_in_caller_order() {
+        let mut map = test_map(Capacity::new(4096));
+        assert_eq!(map.bulk_get_sorted(&[Key::new(3)]).unwrap(), vec![None]);
+
+        let entries: Vec<KvEntry> = (0..1500u32)
+            .map(|i| KvEntry {
+                key: Key::new(i * 2),
+                value: Value::new(i),
+            })
+            .chain([KvEntry {
+                key: Key::new(u32::MAX),
+                value: Value::new(7),
+            }])
+            .collect();
+        map.bulk_put(&entries).unwrap();
+        map.bulk_delete(&[Key::new(10), Key::new(12)]).unwrap();
+        map.put_with_ttl(Key::new(14), Value::new(1), 1).unwrap();
+        map.advance_clock(1);
+
+        // Unsorted, with duplicates, misses, tombstones, an expired entry,
+        // `u32::MAX` and a length that is not a power of two: more than one
+        // sort block, so the sort runs its global passes too.
+        let keys: Vec<Key> = (0..2999u32)
+            .map(|i| Key::new(i.wrapping_mul(2_654_435_761) % 3200))
+            .chain([10, 12, 14, 16, 16, u32::MAX, u32::MAX - 1].map(Key::new))
+            .collect();
+        let sorted = map.bulk_get_sorted(&keys).unwrap();
+        assert_eq!(sorted, map.bulk_get(&keys).unwrap());
+        assert_eq!(
+            sorted[sorted.len() - 7..],
+            [
+                None,
+                None,
+                None,
+                Some(Value::new(8)),
+                Some(Value::new(8)),
+                Some(Value::new(7)),
+                None
+            ]
+        );
+    }
+}
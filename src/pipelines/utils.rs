@@ -1,5 +1,13 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
+
 use bytemuck::Pod;
 
+use crate::GpuMapError;
+
 pub fn create_buffer_with_data<T: Pod>(
     device: &wgpu::Device,
     label: &str,
@@ -21,24 +29,308 @@ pub fn create_buffer_with_data<T: Pod>(
     buffer
 }
 
-pub fn readback_vec<T: Pod>(device: &wgpu::Device, buffer: &wgpu::Buffer) -> Vec<T> {
-    let slice = buffer.slice(..);
-    let (sender, receiver) = std::sync::mpsc::channel();
-    slice.map_async(wgpu::MapMode::Read, move |res| {
-        let _ = sender.send(res);
-    });
-    device.poll(wgpu::Maintain::Wait);
-    receiver.recv().expect("readback channel closed").unwrap();
-    let data = slice.get_mapped_range();
-    let results = bytemuck::cast_slice(&data).to_vec();
-    drop(data);
-    buffer.unmap();
-    results
+/// Pending `map_async` request on a readback buffer.
+///
+/// The callback only fires while the device is polled: by [`block_on_device`],
+/// an explicit [`GpuSortedMap::poll`](crate::GpuSortedMap::poll), or a
+/// [`DevicePoller`](crate::DevicePoller) thread.
+pub(crate) struct MapRequest {
+    state: Arc<Mutex<MapReadState>>,
 }
 
-pub(crate) fn readback_single<T: Pod>(device: &wgpu::Device, buffer: &wgpu::Buffer) -> T {
-    readback_vec::<T>(device, buffer)
+#[derive(Default)]
+struct MapReadState {
+    result: Option<Result<(), wgpu::BufferAsyncError>>,
+    waker: Option<Waker>,
+}
+
+impl MapRequest {
+    /// Start mapping the first `size` bytes of `buffer` for reading.
+    pub(crate) fn new(buffer: &wgpu::Buffer, size: u64) -> Self {
+        let state = Arc::new(Mutex::new(MapReadState::default()));
+        let callback_state = Arc::clone(&state);
+        buffer
+            .slice(..size)
+            .map_async(wgpu::MapMode::Read, move |res| {
+                let mut state = callback_state.lock().expect("readback state poisoned");
+                state.result = Some(res);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            });
+        Self { state }
+    }
+
+    /// Whether the map callback has fired.
+    pub(crate) fn is_ready(&self) -> bool {
+        self.state
+            .lock()
+            .expect("readback state poisoned")
+            .result
+            .is_some()
+    }
+
+    fn poll_ready(&self, waker: &Waker) -> bool {
+        let mut state = self.state.lock().expect("readback state poisoned");
+        if state.result.is_some() {
+            return true;
+        }
+        state.waker = Some(waker.clone());
+        false
+    }
+
+    /// Copy out the mapped bytes and unmap. Only call once [`is_ready`](Self::is_ready).
+    ///
+    /// Returns [`GpuMapError::BufferMapFailed`] if the buffer could not be
+    /// mapped, for example because the device was lost.
+    pub(crate) fn read<T: Pod>(
+        self,
+        buffer: &wgpu::Buffer,
+        size: u64,
+    ) -> Result<Vec<T>, GpuMapError> {
+        let result = self
+            .state
+            .lock()
+            .expect("readback state poisoned")
+            .result
+            .take()
+            .expect("readback buffer is not mapped yet");
+        result.map_err(|err| GpuMapError::BufferMapFailed {
+            message: err.to_string(),
+        })?;
+        let data = buffer.slice(..size).get_mapped_range();
+        let results = bytemuck::cast_slice(&data).to_vec();
+        drop(data);
+        buffer.unmap();
+        Ok(results)
+    }
+
+    /// Abandon the request, leaving `buffer` unmapped.
+    pub(crate) fn cancel(self, buffer: &wgpu::Buffer) {
+        let failed = matches!(
+            self.state.lock().expect("readback state poisoned").result,
+            Some(Err(_))
+        );
+        // A pending map is aborted by `unmap`; a failed one never mapped.
+        if !failed {
+            buffer.unmap();
+        }
+    }
+}
+
+/// Future resolving to the first `count` elements of a `MAP_READ` buffer once
+/// the GPU work that fills it has completed.
+///
+/// Dropping the future before it resolves cancels the mapping, so the buffer
+/// can go back to the pool unmapped.
+pub(crate) struct MapRead<'a, T: Pod> {
+    buffer: &'a wgpu::Buffer,
+    size: u64,
+    request: Option<MapRequest>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+pub(crate) fn map_read<T: Pod>(buffer: &wgpu::Buffer, count: usize) -> MapRead<'_, T> {
+    let size = (count * std::mem::size_of::<T>()) as u64;
+    MapRead {
+        buffer,
+        size,
+        request: Some(MapRequest::new(buffer, size)),
+        _marker: PhantomData,
+    }
+}
+
+impl<T: Pod> Future for MapRead<'_, T> {
+    type Output = Result<Vec<T>, GpuMapError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let request = self
+            .request
+            .as_ref()
+            .expect("MapRead polled after completion");
+        if !request.poll_ready(cx.waker()) {
+            return Poll::Pending;
+        }
+        let request = self
+            .request
+            .take()
+            .expect("MapRead polled after completion");
+        Poll::Ready(request.read(self.buffer, self.size))
+    }
+}
+
+impl<T: Pod> Drop for MapRead<'_, T> {
+    fn drop(&mut self) {
+        if let Some(request) = self.request.take() {
+            request.cancel(self.buffer);
+        }
+    }
+}
+
+pub(crate) async fn map_read_single<T: Pod>(buffer: &wgpu::Buffer) -> Result<T, GpuMapError> {
+    map_read::<T>(buffer, 1)
+        .await?
         .first()
         .copied()
-        .expect("readback failed to return data")
+        .ok_or_else(|| GpuMapError::BufferMapFailed {
+            message: "readback returned no data".to_string(),
+        })
+}
+
+/// Validation and out-of-memory error scopes on a device.
+///
+/// Errors raised by wgpu calls made while the scope is open are captured
+/// instead of reaching the device's uncaptured-error handler, which panics
+/// by default. [`pop`](Self::pop) closes the scope before its future is
+/// awaited, so a dropped future cannot leave it open; dropping the scope
+/// without popping discards its errors.
+///
+/// Scopes form one stack per device rather than per thread, so a scope
+/// holds the device's scope lock from push to pop. Another thread's call
+/// waits for it instead of popping this scope's errors as its own.
+pub(crate) struct ErrorScope<'a> {
+    device: Option<&'a wgpu::Device>,
+    _serial: MutexGuard<'a, ()>,
+}
+
+impl<'a> ErrorScope<'a> {
+    pub(crate) fn push(device: &'a wgpu::Device, scopes: &'a Mutex<()>) -> Self {
+        // The lock guards no data, so a panic while it was held leaves
+        // nothing to repair.
+        let serial = scopes.lock().unwrap_or_else(PoisonError::into_inner);
+        device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        Self {
+            device: Some(device),
+            _serial: serial,
+        }
+    }
+
+    /// Close the scope, resolving to the first error captured in it.
+    pub(crate) fn pop(mut self) -> impl Future<Output = Result<(), GpuMapError>> {
+        let device = self.device.take().expect("error scope popped twice");
+        let validation = device.pop_error_scope();
+        let out_of_memory = device.pop_error_scope();
+        async move {
+            let out_of_memory = out_of_memory.await;
+            match validation.await.or(out_of_memory) {
+                Some(error) => Err(scope_error(error)),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+impl Drop for ErrorScope<'_> {
+    fn drop(&mut self) {
+        if let Some(device) = self.device.take() {
+            drop(device.pop_error_scope());
+            drop(device.pop_error_scope());
+        }
+    }
+}
+
+fn scope_error(error: wgpu::Error) -> GpuMapError {
+    match error {
+        wgpu::Error::OutOfMemory { .. } => GpuMapError::OutOfMemory,
+        wgpu::Error::Validation { description, .. } => GpuMapError::ValidationFailed {
+            message: description,
+        },
+        other => GpuMapError::ValidationFailed {
+            message: other.to_string(),
+        },
+    }
+}
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Run `future` to completion on the calling thread, blocking on
+/// `device.poll(Maintain::Wait)` whenever it is waiting on the GPU.
+pub(crate) fn block_on_device<F: Future>(device: &wgpu::Device, future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        device.poll(wgpu::Maintain::Wait);
+    }
+}
+
+#[cfg(test)]
+pub fn readback_vec<T: Pod>(device: &wgpu::Device, buffer: &wgpu::Buffer) -> Vec<T> {
+    let count = buffer.size() as usize / std::mem::size_of::<T>();
+    block_on_device(device, map_read::<T>(buffer, count)).expect("readback failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::{block_on_device, map_read, ErrorScope};
+    use crate::{Capacity, GpuMapError, GpuSortedMap};
+
+    fn try_create_map() -> Option<GpuSortedMap> {
+        match pollster::block_on(GpuSortedMap::new(Capacity::new(16))) {
+            Ok(map) => Some(map),
+            Err(_) => {
+                eprintln!("Skipping test: GPU not available in this environment");
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn error_scope_captures_validation_errors() {
+        let Some(map) = try_create_map() else {
+            return;
+        };
+        let device = map.device().unwrap();
+        let scopes = Mutex::new(());
+
+        let scope = ErrorScope::push(device, &scopes);
+        // Mappable storage buffers need a feature the map never requests.
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("invalid-buffer"),
+            size: 16,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let err = pollster::block_on(scope.pop()).unwrap_err();
+        assert!(
+            matches!(err, GpuMapError::ValidationFailed { .. }),
+            "{err:?}"
+        );
+
+        let scope = ErrorScope::push(device, &scopes);
+        assert_eq!(pollster::block_on(scope.pop()), Ok(()));
+    }
+
+    #[test]
+    fn failed_map_returns_buffer_map_error() {
+        let Some(map) = try_create_map() else {
+            return;
+        };
+        let device = map.device().unwrap();
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("unmappable-buffer"),
+            size: 16,
+            usage: wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let scopes = Mutex::new(());
+        let scope = ErrorScope::push(device, &scopes);
+        let result = block_on_device(device, map_read::<u32>(&buffer, 4));
+        drop(scope);
+        assert!(
+            matches!(result, Err(GpuMapError::BufferMapFailed { .. })),
+            "{result:?}"
+        );
+    }
 }
@@ -0,0 +1,184 @@
+//! Shared WGSL declarations for shaders that read the sorted slab.
+//!
+//! The snippet is a macro so internal shaders can splice it in with
+//! `concat!`; [`SLAB_WGSL`] exposes the same text to user kernels.
+//!
+//! Kernels that run one thread per key or slot are templates over a
+//! `WORKGROUP_SIZE` constant, which [`with_workgroup_size`] declares, so
+//! each map compiles the variant its [`TuningProfile`](crate::TuningProfile)
+//! picked.
+
+/// Expands to the shared slab WGSL snippet as a string literal.
+macro_rules! slab_wgsl {
+    () => {
+        r#"
+struct KvEntry {
+    key: u32,
+    value: u32,
+};
+
+struct SlabMeta {
+    len: u32,
+    capacity: u32,
+    _pad0: u32,
+    _pad1: u32,
+};
+
+const SLAB_TOMBSTONE: u32 = 0xffffffffu;
+
+fn slab_is_live(entry: KvEntry) -> bool {
+    return entry.value != SLAB_TOMBSTONE;
+}
+
+// First index in `slab[0..len)` whose key is >= `tgt`.
+// Expects the including shader to declare `slab: array<KvEntry>`.
+fn slab_lower_bound(tgt: u32, len: u32) -> u32 {
+    var lo: u32 = 0u;
+    var hi: u32 = len;
+    while (lo < hi) {
+        let mid = (lo + hi) / 2u;
+        if (slab[mid].key < tgt) {
+            lo = mid + 1u;
+        } else {
+            hi = mid;
+        }
+    }
+    return lo;
+}
+"#
+    };
+}
+
+pub(crate) use slab_wgsl;
+
+/// Expands to the WGSL that searches the slab through a search index (see
+/// `pipelines::search_index`). Follows [`slab_wgsl!`] in the including
+/// shader, which declares the `fences` and `index_meta` bindings.
+macro_rules! search_index_wgsl {
+    () => {
+        r#"
+struct IndexMeta {
+    count: u32,
+    stride: u32,
+    _pad0: u32,
+    _pad1: u32,
+};
+
+const INDEX_FENCES: u32 = 2048u;
+
+var<workgroup> index_cache: array<u32, INDEX_FENCES>;
+
+// Copy the fences into workgroup memory. Every invocation of the workgroup
+// must call this before any of them returns.
+fn index_load(local_index: u32) {
+    for (var i = local_index; i < index_meta.count; i += WORKGROUP_SIZE) {
+        index_cache[i] = fences[i];
+    }
+    workgroupBarrier();
+}
+
+// `slab_lower_bound` that finds the two fences around `tgt` first and only
+// searches the slots between them. Without fences, searches the whole slab.
+fn index_lower_bound(tgt: u32, len: u32) -> u32 {
+    if (index_meta.count == 0u) {
+        return slab_lower_bound(tgt, len);
+    }
+    // Fences with keys <= tgt.
+    var above: u32 = 0u;
+    var hi: u32 = index_meta.count;
+    while (above < hi) {
+        let mid = (above + hi) / 2u;
+        if (index_cache[mid] <= tgt) {
+            above = mid + 1u;
+        } else {
+            hi = mid;
+        }
+    }
+    if (above == 0u) {
+        return 0u;
+    }
+    var lo: u32 = (above - 1u) * index_meta.stride;
+    hi = min(above * index_meta.stride, len);
+    while (lo < hi) {
+        let mid = (lo + hi) / 2u;
+        if (slab[mid].key < tgt) {
+            lo = mid + 1u;
+        } else {
+            hi = mid;
+        }
+    }
+    return lo;
+}
+"#
+    };
+}
+
+pub(crate) use search_index_wgsl;
+
+/// Expands to `merge_partition(k, slab_len, input_len)`, the merge-path
+/// split of the sorted `slab` and `input` arrays, which the including
+/// shader declares. Returns how many entries of each the first `k` merged
+/// entries take; on equal keys the `input` entry comes first.
+macro_rules! merge_path_wgsl {
+    () => {
+        r#"
+fn merge_partition(k: u32, slab_len: u32, input_len: u32) -> vec2<u32> {
+    var i_low: u32 = 0u;
+    if (k > input_len) {
+        i_low = k - input_len;
+    }
+    var i_high: u32 = k;
+    if (i_high > slab_len) {
+        i_high = slab_len;
+    }
+
+    var i: u32 = i_high;
+    var j: u32 = k - i;
+    loop {
+        let move_left = i > 0u && j < input_len && slab[i - 1u].key >= input[j].key;
+        let move_right = j > 0u && i < slab_len && input[j - 1u].key > slab[i].key;
+        if (move_left) {
+            i_high = i - 1u;
+            i = (i_low + i_high) / 2u;
+            j = k - i;
+            continue;
+        }
+        if (move_right) {
+            i_low = i + 1u;
+            i = (i_low + i_high + 1u) / 2u;
+            j = k - i;
+            continue;
+        }
+        break;
+    }
+    return vec2<u32>(i, j);
+}
+"#
+    };
+}
+
+pub(crate) use merge_path_wgsl;
+
+/// WGSL declarations for kernels that read the slab exposed by
+/// [`GpuSortedMap::slab_binding`](crate::GpuSortedMap::slab_binding).
+///
+/// WGSL has no `#include`; prepend this snippet to your shader source. It
+/// declares the `KvEntry` and `SlabMeta` structs, the `SLAB_TOMBSTONE`
+/// constant, `slab_is_live(entry)`, and `slab_lower_bound(key, len)`. The
+/// helper reads a module-scope `slab: array<KvEntry>` binding, which the
+/// including shader must declare.
+pub const SLAB_WGSL: &str = slab_wgsl!();
+
+/// Threads per workgroup of the per-key and per-slot kernels when no
+/// [`TuningProfile`](crate::TuningProfile) picks another size.
+pub const DEFAULT_WORKGROUP_SIZE: u32 = 64;
+
+/// Source of the kernel `template` with `WORKGROUP_SIZE` set to
+/// `workgroup_size`. Templates use it in `@workgroup_size` and to turn
+/// invocation ids into linear indices.
+pub fn with_workgroup_size(template: &str, workgroup_size: u32) -> String {
+    format!(
+        "const WORKGROUP_SIZE: u32 = {}u;\n{}",
+        workgroup_size, template
+    )
+}
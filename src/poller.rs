@@ -0,0 +1,52 @@
+//! Background device polling for the `*_async` map operations.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How long the poller sleeps when the device has no outstanding work.
+const IDLE_INTERVAL: Duration = Duration::from_micros(500);
+
+/// Thread that keeps polling a device so pending `map_async` callbacks fire
+/// and `*_async` futures complete on any executor.
+///
+/// Created by [`GpuSortedMap::spawn_poller`](crate::GpuSortedMap::spawn_poller).
+/// The thread stops when the handle is dropped.
+pub struct DevicePoller {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DevicePoller {
+    pub(crate) fn spawn(device: Arc<wgpu::Device>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let thread = std::thread::Builder::new()
+            .name("gpusorted-map-poller".into())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Acquire) {
+                    if device.poll(wgpu::Maintain::Poll).is_queue_empty() {
+                        std::thread::park_timeout(IDLE_INTERVAL);
+                    } else {
+                        device.poll(wgpu::Maintain::Wait);
+                    }
+                }
+            })
+            .expect("failed to spawn device poller thread");
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for DevicePoller {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Value predicates evaluated on the GPU by filtered range scans.
+
+use crate::Value;
+
+/// Condition on an entry's value, used by
+/// [`GpuSortedMap::range_filtered`](crate::GpuSortedMap::range_filtered) and
+/// [`GpuSortedMap::filter`](crate::GpuSortedMap::filter).
+///
+/// Tombstoned entries never match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValuePredicate {
+    /// `value == v`
+    Eq(Value),
+    /// `value != v`
+    Ne(Value),
+    /// `value < v`
+    Lt(Value),
+    /// `value <= v`
+    Le(Value),
+    /// `value > v`
+    Gt(Value),
+    /// `value >= v`
+    Ge(Value),
+    /// `value & mask != 0`
+    MaskAny(u32),
+    /// `value & mask == mask`
+    MaskAll(u32),
+    /// `lo <= value < hi`, half-open like key ranges.
+    Between(Value, Value),
+    /// `value` is one of the listed values.
+    OneOf(Vec<Value>),
+}
+
+impl ValuePredicate {
+    /// Host-side evaluation with the same semantics as the GPU kernel.
+    pub fn matches(&self, value: Value) -> bool {
+        let x = value.0;
+        match self {
+            ValuePredicate::Eq(v) => x == v.0,
+            ValuePredicate::Ne(v) => x != v.0,
+            ValuePredicate::Lt(v) => x < v.0,
+            ValuePredicate::Le(v) => x <= v.0,
+            ValuePredicate::Gt(v) => x > v.0,
+            ValuePredicate::Ge(v) => x >= v.0,
+            ValuePredicate::MaskAny(mask) => x & mask != 0,
+            ValuePredicate::MaskAll(mask) => x & mask == *mask,
+            ValuePredicate::Between(lo, hi) => lo.0 <= x && x < hi.0,
+            ValuePredicate::OneOf(values) => values.iter().any(|v| v.0 == x),
+        }
+    }
+
+    /// Opcode and operands for the GPU kernel; `OneOf` values come back sorted
+    /// and de-duplicated so the kernel can binary-search them.
+    pub(crate) fn encode(&self) -> (u32, u32, u32, Vec<u32>) {
+        match self {
+            ValuePredicate::Eq(v) => (0, v.0, 0, Vec::new()),
+            ValuePredicate::Ne(v) => (1, v.0, 0, Vec::new()),
+            ValuePredicate::Lt(v) => (2, v.0, 0, Vec::new()),
+            ValuePredicate::Le(v) => (3, v.0, 0, Vec::new()),
+            ValuePredicate::Gt(v) => (4, v.0, 0, Vec::new()),
+            ValuePredicate::Ge(v) => (5, v.0, 0, Vec::new()),
+            ValuePredicate::MaskAny(mask) => (6, *mask, 0, Vec::new()),
+            ValuePredicate::MaskAll(mask) => (7, *mask, 0, Vec::new()),
+            ValuePredicate::Between(lo, hi) => (8, lo.0, hi.0, Vec::new()),
+            ValuePredicate::OneOf(values) => {
+                let mut set: Vec<u32> = values.iter().map(|v| v.0).collect();
+                set.sort_unstable();
+                set.dedup();
+                (9, 0, 0, set)
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Host copies for device-loss recovery.
+//!
+//! A lost device takes the slab with it, so
+//! [`GpuSortedMap::recover`](crate::GpuSortedMap::recover) restores the map
+//! from a copy in host memory. A checkpoint is a copy taken at one point in
+//! time; a shadow is a copy that every write is also applied to, using the
+//! same [`CpuSlab`] steps as [`Backend::Cpu`](crate::Backend::Cpu).
+
+use std::sync::Arc;
+
+use crate::cpu_slab::CpuSlab;
+use crate::{Capacity, Key, KvEntry, Length};
+
+/// Host copy of one map's slab.
+#[derive(Clone)]
+pub(crate) struct HostCopy {
+    slab: Arc<CpuSlab>,
+    live_len: Length,
+    /// Whether writes are applied to the copy as they happen.
+    shadow: bool,
+}
+
+impl HostCopy {
+    pub(crate) fn new(slab: CpuSlab, live_len: Length, shadow: bool) -> Self {
+        Self {
+            slab: Arc::new(slab),
+            live_len,
+            shadow,
+        }
+    }
+
+    /// Shadow of a map that is still empty.
+    pub(crate) fn empty_shadow(capacity: Capacity) -> Self {
+        Self::new(CpuSlab::new(capacity), Length::new(0), true)
+    }
+
+    pub(crate) fn slab(&self) -> &CpuSlab {
+        &self.slab
+    }
+
+    /// The slab, shared with any [`HostMirror`](crate::dispatch::HostMirror)
+    /// reading it.
+    pub(crate) fn shared_slab(&self) -> Arc<CpuSlab> {
+        Arc::clone(&self.slab)
+    }
+
+    pub(crate) fn live_len(&self) -> Length {
+        self.live_len
+    }
+
+    pub(crate) fn is_shadow(&self) -> bool {
+        self.shadow
+    }
+
+    /// Apply a merge that left the map with `live_len` live entries.
+    pub(crate) fn merge(&mut self, entries: &[KvEntry], live_len: Length) {
+        self.slab = Arc::new(self.slab.merged(entries));
+        self.live_len = live_len;
+    }
+
+    /// Apply a delete that left the map with `live_len` live entries.
+    pub(crate) fn delete(&mut self, keys: &[Key], live_len: Length) {
+        Arc::make_mut(&mut self.slab).delete(keys);
+        self.live_len = live_len;
+    }
+
+    pub(crate) fn set_len(&mut self, len: Length) {
+        Arc::make_mut(&mut self.slab).set_len(len);
+    }
+}
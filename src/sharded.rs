@@ -0,0 +1,461 @@
+//! Slabs larger than one storage binding.
+//!
+//! A shader can bind at most `max_storage_buffer_binding_size` bytes of the
+//! slab, so a map whose capacity exceeds that keeps its entries in several
+//! segments, each a [`SlabVersion`] that fits one binding. Segments own
+//! disjoint key ranges: segment `i` holds keys from its fence key up to the
+//! next segment's fence, and the first fence is 0. Lookups and deletes route
+//! each key to its segment; scans walk the segments in key order and count
+//! slot positions across them as if they were one slab.
+//!
+//! Segments are added as the map fills: a merge that would overflow a
+//! segment first splits it in two. Deletes never join segments again.
+
+use std::sync::Arc;
+
+use crate::backend::GpuBackend;
+use crate::slab::{self, SlabVersion};
+use crate::{Capacity, GpuMapError, Key, KvEntry, Length, Value, ValuePredicate};
+
+/// Smallest segment a device's limits may leave room for.
+pub(crate) const MIN_SEGMENT_CAPACITY: u32 = 64;
+
+/// Entries per segment on a device with `limits`: the largest power of two
+/// whose slab fits one storage binding and one buffer. A power of two keeps
+/// the pooled scratch buffers of a full segment within the binding too.
+pub(crate) fn segment_capacity(limits: &wgpu::Limits) -> Capacity {
+    let bytes = u64::from(limits.max_storage_buffer_binding_size).min(limits.max_buffer_size);
+    let entries = (bytes / std::mem::size_of::<KvEntry>() as u64).min(u64::from(u32::MAX)) as u32;
+    Capacity::new(entries.checked_ilog2().map_or(0, |log| 1 << log))
+}
+
+/// One segment and the smallest key it may hold.
+#[derive(Clone)]
+struct Segment {
+    fence: Key,
+    slab: Arc<SlabVersion>,
+}
+
+/// A slab split into segments of at most `segment_capacity` slots.
+#[derive(Clone)]
+pub(crate) struct ShardedSlab {
+    segments: Vec<Segment>,
+    segment_capacity: Capacity,
+    capacity: Capacity,
+}
+
+impl ShardedSlab {
+    /// An empty slab of `capacity` entries with a single segment.
+    pub(crate) fn new(gpu: &GpuBackend, capacity: Capacity, segment_capacity: Capacity) -> Self {
+        Self {
+            segments: vec![Segment {
+                fence: Key::new(0),
+                slab: gpu.empty_slab(segment_capacity),
+            }],
+            segment_capacity,
+            capacity,
+        }
+    }
+
+    /// An empty slab with the same capacities.
+    pub(crate) fn sibling(&self, gpu: &GpuBackend) -> Self {
+        Self::new(gpu, self.capacity, self.segment_capacity)
+    }
+
+    /// Occupied slots across all segments, including tombstones.
+    pub(crate) fn len(&self) -> Length {
+        Length::new(
+            self.segments
+                .iter()
+                .map(|segment| segment.slab.len().0)
+                .sum(),
+        )
+    }
+
+    pub(crate) fn capacity(&self) -> Capacity {
+        self.capacity
+    }
+
+    pub(crate) fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Index of the segment whose key range holds `key`.
+    fn segment_of(&self, key: Key) -> usize {
+        self.segments
+            .partition_point(|segment| segment.fence <= key)
+            - 1
+    }
+
+    /// Positions in `keys` grouped by the segment each key routes to.
+    fn route(&self, keys: impl Iterator<Item = Key>) -> Vec<Vec<usize>> {
+        let mut routes = vec![Vec::new(); self.segments.len()];
+        for (position, key) in keys.enumerate() {
+            routes[self.segment_of(key)].push(position);
+        }
+        routes
+    }
+
+    /// Non-empty segments whose key range meets `[from_key, to_key)`, with
+    /// the slot offset of each.
+    fn overlapping(&self, from_key: Key, to_key: Key) -> impl Iterator<Item = (u32, &Segment)> {
+        let mut offset = 0;
+        self.segments
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, segment)| {
+                let start = offset;
+                offset += segment.slab.len().0;
+                let below_end = segment.fence < to_key;
+                let above_start = self
+                    .segments
+                    .get(index + 1)
+                    .map_or(true, |next| next.fence > from_key);
+                let meets = from_key < to_key && below_end && above_start;
+                (meets && segment.slab.len().0 > 0).then_some((start, segment))
+            })
+    }
+
+    /// Values of live entries for `keys`, looked up in their segments.
+    pub(crate) async fn get(
+        &self,
+        gpu: &GpuBackend,
+        keys: &[Key],
+    ) -> Result<Vec<Option<Value>>, GpuMapError> {
+        let mut values = vec![None; keys.len()];
+        let routes = self.route(keys.iter().copied());
+        for (segment, positions) in self.segments.iter().zip(routes) {
+            if segment.slab.len().0 == 0 || positions.is_empty() {
+                continue;
+            }
+            let segment_keys: Vec<Key> = positions.iter().map(|&position| keys[position]).collect();
+            let found = gpu.lookup(&segment.slab, &segment_keys).await?;
+            for (position, value) in positions.into_iter().zip(found) {
+                values[position] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Occupied slots with keys in `[from_key, to_key)`, in key order.
+    pub(crate) async fn range(
+        &self,
+        gpu: &GpuBackend,
+        from_key: Key,
+        to_key: Key,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        let mut entries = Vec::new();
+        for (_, segment) in self.overlapping(from_key, to_key) {
+            entries.extend(
+                gpu.range_scan
+                    .execute(&segment.slab, from_key, to_key)
+                    .await?,
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Slot range, counted across segments, holding keys in
+    /// `[from_key, to_key)`.
+    async fn bounds(
+        &self,
+        gpu: &GpuBackend,
+        from_key: Key,
+        to_key: Key,
+    ) -> Result<Option<(u32, u32)>, GpuMapError> {
+        let mut bounds: Option<(u32, u32)> = None;
+        for (offset, segment) in self.overlapping(from_key, to_key) {
+            if let Some((start, end)) = gpu
+                .range_scan
+                .bounds(&segment.slab, from_key, to_key)
+                .await?
+            {
+                let start = bounds.map_or(offset + start, |(first, _)| first);
+                bounds = Some((start, offset + end));
+            }
+        }
+        Ok(bounds)
+    }
+
+    /// Live entries with keys from `from_key` up to `to_key`, or to the end
+    /// without one, whose value matches `predicate`.
+    pub(crate) async fn filter(
+        &self,
+        gpu: &GpuBackend,
+        from_key: Key,
+        to_key: Option<Key>,
+        predicate: &ValuePredicate,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        let (start, end) = match to_key {
+            Some(to_key) => match self.bounds(gpu, from_key, to_key).await? {
+                Some(bounds) => bounds,
+                None => return Ok(Vec::new()),
+            },
+            None => (0, self.len().0),
+        };
+        self.filter_slots(gpu, start, end, predicate).await
+    }
+
+    /// Live entries in slots `[start, end)`, counted across segments, whose
+    /// value matches `predicate`.
+    async fn filter_slots(
+        &self,
+        gpu: &GpuBackend,
+        start: u32,
+        end: u32,
+        predicate: &ValuePredicate,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        for segment in &self.segments {
+            let len = segment.slab.len().0;
+            let (lo, hi) = (start.max(offset), end.min(offset + len));
+            if lo < hi {
+                entries.extend(
+                    gpu.filter_scan
+                        .execute(&segment.slab, lo - offset, hi - offset, predicate)
+                        .await?,
+                );
+            }
+            offset += len;
+        }
+        Ok(entries)
+    }
+
+    /// Merge `entries` into the segments their keys route to, splitting
+    /// segments that cannot take their part first. Splits keep the contents
+    /// as they are; on error no segment has taken its part of the merge.
+    pub(crate) async fn merge(
+        &mut self,
+        gpu: &GpuBackend,
+        entries: &[KvEntry],
+    ) -> Result<(), GpuMapError> {
+        let routes = loop {
+            let routes = self.route(entries.iter().map(|entry| entry.key));
+            match self.overflowing(gpu, entries, &routes).await? {
+                Some(index) => self.split(gpu, index, entries, &routes[index]).await?,
+                None => break routes,
+            }
+        };
+
+        let mut segments = self.segments.clone();
+        for (segment, positions) in segments.iter_mut().zip(&routes) {
+            if positions.is_empty() {
+                continue;
+            }
+            let part: Vec<KvEntry> = positions
+                .iter()
+                .map(|&position| entries[position])
+                .collect();
+            gpu.merge_into(&mut segment.slab, &part).await?;
+        }
+        self.segments = segments;
+        Ok(())
+    }
+
+    /// First segment that cannot take the entries routed to it.
+    async fn overflowing(
+        &self,
+        gpu: &GpuBackend,
+        entries: &[KvEntry],
+        routes: &[Vec<usize>],
+    ) -> Result<Option<usize>, GpuMapError> {
+        let capacity = self.segment_capacity.0;
+        for (index, (segment, positions)) in self.segments.iter().zip(routes).enumerate() {
+            let incoming = positions.len() as u32;
+            if incoming == 0 {
+                continue;
+            }
+            // The merge pads its input to a power of two.
+            if incoming.next_power_of_two() > capacity {
+                return Ok(Some(index));
+            }
+            let len = segment.slab.len().0;
+            if len + incoming <= capacity {
+                continue;
+            }
+            // Updates of live keys reuse their slot.
+            let keys: Vec<Key> = positions
+                .iter()
+                .map(|&position| entries[position].key)
+                .collect();
+            let values = gpu.lookup(&segment.slab, &keys).await?;
+            let updates = values.iter().filter(|value| value.is_some()).count() as u32;
+            if len + incoming - updates > capacity {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Split segment `index`, which cannot take the entries at `positions`.
+    ///
+    /// A segment at least as large as the batch splits at its middle slot.
+    /// Otherwise the batch splits at its median key, so each half of the
+    /// segment takes about half of it.
+    async fn split(
+        &mut self,
+        gpu: &GpuBackend,
+        index: usize,
+        entries: &[KvEntry],
+        positions: &[usize],
+    ) -> Result<(), GpuMapError> {
+        let lower = &self.segments[index];
+        let len = lower.slab.len().0;
+        let incoming = positions.len() as u32;
+        let (at, fence) = if len >= 2 && len >= incoming {
+            let at = len / 2;
+            let first = gpu.range_scan.read_slots(&lower.slab, at, at + 1).await?;
+            (at, first[0].key)
+        } else {
+            let mut keys: Vec<Key> = positions
+                .iter()
+                .map(|&position| entries[position].key)
+                .collect();
+            let middle = keys.len() / 2;
+            let (_, &mut median, _) = keys.select_nth_unstable(middle);
+            let below = gpu
+                .range_scan
+                .bounds(&lower.slab, Key::new(0), median)
+                .await?;
+            (below.map_or(0, |(_, end)| end), median)
+        };
+        if fence <= lower.fence {
+            // Only a batch of one repeated key lands here; the caller
+            // rejects duplicates, so the segment is simply too small.
+            return Err(GpuMapError::CapacityExceeded {
+                capacity: self.segment_capacity,
+                requested: Length::new(len + incoming),
+            });
+        }
+
+        let mut upper = gpu.empty_slab(self.segment_capacity);
+        let moved = len - at;
+        let upper_array = slab::make_unique(&mut upper, &gpu.queue, &gpu.device);
+        if moved > 0 {
+            let entry = std::mem::size_of::<KvEntry>() as u64;
+            let mut encoder = gpu
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("segment-split-encoder"),
+                });
+            encoder.copy_buffer_to_buffer(
+                lower.slab.buffer(),
+                u64::from(at) * entry,
+                upper_array.buffer(),
+                0,
+                u64::from(moved) * entry,
+            );
+            gpu.queue.submit(Some(encoder.finish()));
+        }
+        upper_array.update_len(&gpu.queue, Length::new(moved));
+
+        let lower = &mut self.segments[index].slab;
+        slab::make_unique(lower, &gpu.queue, &gpu.device).update_len(&gpu.queue, Length::new(at));
+        self.segments
+            .insert(index + 1, Segment { fence, slab: upper });
+        Ok(())
+    }
+
+    /// Tombstone `keys` in the segments they route to.
+    pub(crate) async fn delete(
+        &mut self,
+        gpu: &GpuBackend,
+        keys: &[Key],
+    ) -> Result<(), GpuMapError> {
+        let routes = self.route(keys.iter().copied());
+        for (segment, positions) in self.segments.iter_mut().zip(routes) {
+            if segment.slab.len().0 == 0 || positions.is_empty() {
+                continue;
+            }
+            let segment_keys: Vec<Key> = positions.iter().map(|&position| keys[position]).collect();
+            gpu.delete_from(&mut segment.slab, &segment_keys).await?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite values of live entries with keys in `[lo, hi]` in every
+    /// segment that may hold them.
+    pub(crate) fn map_values(
+        &mut self,
+        gpu: &GpuBackend,
+        wgsl_fn: &str,
+        lo: u32,
+        hi: u32,
+    ) -> Result<(), GpuMapError> {
+        // Compiles the function even when no segment is touched.
+        gpu.map_values
+            .execute(&self.segments[0].slab, wgsl_fn, 1, 0)?;
+        for index in 0..self.segments.len() {
+            let next = self.segments.get(index + 1).map(|next| next.fence.0);
+            let segment = &mut self.segments[index];
+            let meets = segment.fence.0 <= hi && next.map_or(true, |next| next > lo);
+            if meets && segment.slab.len().0 > 0 {
+                let slab = slab::make_values_unique(&mut segment.slab, &gpu.queue, &gpu.device);
+                gpu.map_values.execute(slab, wgsl_fn, lo, hi)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the occupied slots to the first `len` across segments. Growing
+    /// extends the last segment, up to its capacity.
+    pub(crate) fn update_len(&mut self, gpu: &GpuBackend, len: Length) {
+        let segment_capacity = self.segment_capacity.0;
+        let last = self.segments.len() - 1;
+        let mut remaining = len.0.min(self.capacity.0);
+        for (index, segment) in self.segments.iter_mut().enumerate() {
+            let current = segment.slab.len().0;
+            let limit = if index == last {
+                segment_capacity
+            } else {
+                current
+            };
+            let target = remaining.min(limit);
+            if target != current {
+                slab::make_unique(&mut segment.slab, &gpu.queue, &gpu.device)
+                    .update_len(&gpu.queue, Length::new(target));
+            }
+            remaining -= target;
+        }
+    }
+
+    /// Every occupied slot in key order, tombstones included.
+    pub(crate) async fn read_all(&self, gpu: &GpuBackend) -> Result<Vec<KvEntry>, GpuMapError> {
+        let mut entries = Vec::with_capacity(self.len().0 as usize);
+        for segment in &self.segments {
+            let len = segment.slab.len().0;
+            entries.extend(gpu.range_scan.read_slots(&segment.slab, 0, len).await?);
+        }
+        Ok(entries)
+    }
+
+    /// Replace the contents with sorted `entries`, filling each segment to
+    /// half its capacity so merges have room before the next split.
+    pub(crate) fn load(&mut self, gpu: &GpuBackend, entries: &[KvEntry]) {
+        let fill = (self.segment_capacity.0 / 2).max(1) as usize;
+        let mut segments: Vec<Segment> = entries
+            .chunks(fill)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut slab = gpu.empty_slab(self.segment_capacity);
+                let array = slab::make_unique(&mut slab, &gpu.queue, &gpu.device);
+                array.write(&gpu.queue, chunk);
+                array.update_len(&gpu.queue, Length::new(chunk.len() as u32));
+                let fence = if index == 0 {
+                    Key::new(0)
+                } else {
+                    chunk[0].key
+                };
+                Segment { fence, slab }
+            })
+            .collect();
+        if segments.is_empty() {
+            segments.push(Segment {
+                fence: Key::new(0),
+                slab: gpu.empty_slab(self.segment_capacity),
+            });
+        }
+        self.segments = segments;
+    }
+}
@@ -0,0 +1,296 @@
+//! Thread-safe map handle.
+//!
+//! [`SharedGpuSortedMap`] wraps a [`GpuSortedMap`] for use from many threads.
+//! Writers take a mutex and run one at a time. Each write finishes into a new
+//! slab version, which is then published as a [`Snapshot`]. Readers clone the
+//! published snapshot under a short read lock and run their lookups against
+//! it without holding any lock, so they proceed in parallel with each other
+//! and with a writer, and never observe a half-merged slab.
+
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use crate::{
+    Capacity, GpuMapError, GpuSortedMap, Key, KvEntry, Length, Snapshot, Value, WriteBatch,
+};
+
+/// [`GpuSortedMap`] that can be shared between threads.
+///
+/// `bulk_get` and `range` run concurrently. `bulk_put` and `bulk_delete` are
+/// serialized and become visible all at once when they return. Because
+/// readers may still hold the previous version, a delete copies the slab
+/// before tombstoning entries instead of editing it in place.
+pub struct SharedGpuSortedMap {
+    /// `None` on [`Backend::Cpu`](crate::Backend::Cpu).
+    device: Option<Arc<wgpu::Device>>,
+    published: RwLock<Snapshot>,
+    writer: Mutex<GpuSortedMap>,
+}
+
+impl SharedGpuSortedMap {
+    /// Share `map` between threads.
+    pub fn new(map: GpuSortedMap) -> Self {
+        Self {
+            device: map.store.gpu_backend().map(|gpu| Arc::clone(&gpu.device)),
+            published: RwLock::new(map.snapshot()),
+            writer: Mutex::new(map),
+        }
+    }
+
+    /// Read-only view of the latest published version, unaffected by later
+    /// writes.
+    pub fn snapshot(&self) -> Snapshot {
+        self.published
+            .read()
+            .expect("published slab poisoned")
+            .clone()
+    }
+
+    /// Batch lookup of keys against the latest published version.
+    pub fn bulk_get(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, GpuMapError> {
+        self.snapshot().bulk_get(keys)
+    }
+
+    /// Non-blocking [`bulk_get`](Self::bulk_get); see
+    /// [`GpuSortedMap::bulk_get_async`] for polling requirements.
+    pub async fn bulk_get_async(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, GpuMapError> {
+        self.snapshot().bulk_get_async(keys).await
+    }
+
+    /// Single-key lookup convenience wrapper over `bulk_get`.
+    pub fn get(&self, key: Key) -> Result<Option<Value>, GpuMapError> {
+        self.snapshot().get(key)
+    }
+
+    /// Returns entries with keys in `[from_key, to_key)` from the latest
+    /// published version.
+    pub fn range(&self, from_key: Key, to_key: Key) -> Result<Vec<KvEntry>, GpuMapError> {
+        self.snapshot().range(from_key, to_key)
+    }
+
+    /// Non-blocking [`range`](Self::range); see
+    /// [`GpuSortedMap::bulk_get_async`] for polling requirements.
+    pub async fn range_async(
+        &self,
+        from_key: Key,
+        to_key: Key,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        self.snapshot().range_async(from_key, to_key).await
+    }
+
+    /// Batch insert/update of entries, published when it returns.
+    pub fn bulk_put(&self, entries: &[KvEntry]) -> Result<(), GpuMapError> {
+        self.write(|map| map.bulk_put(entries))
+    }
+
+    /// Single-key insert/update convenience wrapper over `bulk_put`.
+    pub fn put(&self, key: Key, value: Value) -> Result<(), GpuMapError> {
+        self.bulk_put(&[KvEntry { key, value }])
+    }
+
+    /// Batch insert/update of entries that expire `ttl` ticks after the
+    /// current clock; see [`GpuSortedMap::bulk_put_with_ttl`].
+    pub fn bulk_put_with_ttl(&self, entries: &[KvEntry], ttl: u32) -> Result<(), GpuMapError> {
+        self.write(|map| map.bulk_put_with_ttl(entries, ttl))
+    }
+
+    /// Single-key convenience wrapper over
+    /// [`bulk_put_with_ttl`](Self::bulk_put_with_ttl).
+    pub fn put_with_ttl(&self, key: Key, value: Value, ttl: u32) -> Result<(), GpuMapError> {
+        self.bulk_put_with_ttl(&[KvEntry { key, value }], ttl)
+    }
+
+    /// Move the logical clock forward to `now`; see
+    /// [`GpuSortedMap::advance_clock`].
+    pub fn advance_clock(&self, now: u32) {
+        self.write(|map| map.advance_clock(now))
+    }
+
+    /// Advance the clock to `now` and remove expired entries; see
+    /// [`GpuSortedMap::expire`].
+    pub fn expire(&self, now: u32) -> Result<usize, GpuMapError> {
+        self.write(|map| map.expire(now))
+    }
+
+    /// Batch delete of keys, published when it returns.
+    pub fn bulk_delete(&self, keys: &[Key]) -> Result<(), GpuMapError> {
+        self.write(|map| map.bulk_delete(keys))
+    }
+
+    /// Apply a [`WriteBatch`], published as one version when it returns.
+    pub fn apply_batch(&self, batch: &WriteBatch) -> Result<(), GpuMapError> {
+        self.write(|map| map.apply_batch(batch))
+    }
+
+    /// Single-key delete convenience wrapper over `bulk_delete`.
+    pub fn delete(&self, key: Key) -> Result<(), GpuMapError> {
+        self.bulk_delete(&[key])
+    }
+
+    /// Run `f` with exclusive access to the underlying map, then publish the
+    /// resulting slab. Readers keep seeing the previous version until `f`
+    /// returns.
+    pub fn write<R>(&self, f: impl FnOnce(&mut GpuSortedMap) -> R) -> R {
+        let mut map = self.lock_writer();
+        let result = f(&mut map);
+        let next = map.snapshot();
+        // Drop the old version outside the lock so its release does not
+        // stall readers.
+        let _previous = std::mem::replace(
+            &mut *self.published.write().expect("published slab poisoned"),
+            next,
+        );
+        result
+    }
+
+    /// Live entries in the latest published version.
+    pub fn len(&self) -> Length {
+        self.published
+            .read()
+            .expect("published slab poisoned")
+            .len()
+    }
+
+    /// Returns true if the latest published version has no live entries.
+    pub fn is_empty(&self) -> bool {
+        self.len().0 == 0
+    }
+
+    /// Total slab capacity.
+    pub fn capacity(&self) -> Capacity {
+        self.snapshot().capacity()
+    }
+
+    /// Device the map's buffers and pipelines live on. `None` on
+    /// [`Backend::Cpu`](crate::Backend::Cpu).
+    pub fn device(&self) -> Option<&Arc<wgpu::Device>> {
+        self.device.as_ref()
+    }
+
+    /// Unwrap the underlying map.
+    pub fn into_inner(self) -> GpuSortedMap {
+        self.writer
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_writer(&self) -> MutexGuard<'_, GpuSortedMap> {
+        self.writer.lock().expect("map writer poisoned")
+    }
+}
+
+impl From<GpuSortedMap> for SharedGpuSortedMap {
+    fn from(map: GpuSortedMap) -> Self {
+        Self::new(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_map(capacity: Capacity) -> SharedGpuSortedMap {
+        SharedGpuSortedMap::new(crate::backend::test_map(capacity))
+    }
+
+    fn generation(keys: u32, value: u32) -> Vec<KvEntry> {
+        (0..keys)
+            .map(|key| KvEntry {
+                key: Key::new(key),
+                value: Value::new(value),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shared_map_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedGpuSortedMap>();
+    }
+
+    #[test]
+    fn readers_never_observe_a_half_merged_slab() {
+        let map = create_map(Capacity::new(2048));
+        const KEYS: u32 = 512;
+        const GENERATIONS: u32 = 12;
+        map.bulk_put(&generation(KEYS, 0)).unwrap();
+        let keys: Vec<Key> = (0..KEYS).map(Key::new).collect();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for value in 1..=GENERATIONS {
+                    map.bulk_put(&generation(KEYS, value)).unwrap();
+                }
+            });
+            for _ in 0..3 {
+                scope.spawn(|| {
+                    let mut last = 0;
+                    while last < GENERATIONS {
+                        let values = map.bulk_get(&keys).unwrap();
+                        let first = values[0].expect("key present").0;
+                        assert!(values.iter().all(|v| *v == Some(Value::new(first))));
+                        assert!(first >= last, "versions went backwards");
+                        last = first;
+
+                        let entries = map.range(Key::new(0), Key::new(KEYS)).unwrap();
+                        assert_eq!(entries.len(), KEYS as usize);
+                        assert!(entries.iter().all(|e| e.value == entries[0].value));
+                    }
+                });
+            }
+        });
+        assert_eq!(map.len(), Length::new(KEYS));
+    }
+
+    #[test]
+    fn concurrent_calls_only_see_their_own_gpu_errors() {
+        let map = create_map(Capacity::new(1024));
+        if map.write(|map| map.backend()) == crate::Backend::Cpu {
+            return;
+        }
+        map.bulk_put(&generation(256, 1)).unwrap();
+        let keys: Vec<Key> = (0..256).map(Key::new).collect();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..16 {
+                    // A new source each time, so every call compiles.
+                    let source = format!("fn f(key: u32, value: u32) -> u32 {{ return v{i}; }}");
+                    let result = map.write(|map| map.map_values(.., &source));
+                    assert!(
+                        matches!(result, Err(GpuMapError::InvalidShader { .. })),
+                        "{result:?}"
+                    );
+                }
+            });
+            for _ in 0..3 {
+                scope.spawn(|| {
+                    for _ in 0..16 {
+                        assert_eq!(map.bulk_get(&keys).unwrap()[255], Some(Value::new(1)));
+                        map.range(Key::new(0), Key::new(256)).unwrap();
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn delete_publishes_copy_and_keeps_readers_consistent() {
+        let map = create_map(Capacity::new(256));
+        map.bulk_put(&generation(64, 7)).unwrap();
+        let evens: Vec<Key> = (0..64).step_by(2).map(Key::new).collect();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| map.bulk_delete(&evens).unwrap());
+            scope.spawn(|| {
+                let live = map.range(Key::new(0), Key::new(64)).unwrap().len();
+                assert!(live == 64 || live == 32, "saw {live} live entries");
+            });
+        });
+
+        assert_eq!(map.len(), Length::new(32));
+        assert_eq!(map.get(Key::new(2)).unwrap(), None);
+        assert_eq!(map.get(Key::new(3)).unwrap(), Some(Value::new(7)));
+        let inner = map.into_inner();
+        assert_eq!(inner.range(Key::new(0), Key::new(64)).unwrap().len(), 32);
+    }
+}
@@ -0,0 +1,195 @@
+//! Versioned slab storage.
+//!
+//! Writes never modify a slab that someone else can still read. A put merges
+//! into a spare array and publishes it as a new [`SlabVersion`]; a delete or
+//! value rewrite on a shared version copies it first. Each version holds the
+//! slab buffer and its meta buffer, so a reader that cloned the `Arc` always
+//! sees a whole slab of one generation.
+//!
+//! When the last handle to a version drops, its array goes back to
+//! [`SlabSpares`] so the next write can reuse it instead of allocating.
+//!
+//! A version may also hold the [`SearchIndex`] built over its keys. Writes
+//! through [`make_unique`] drop it; [`make_values_unique`] is for writes that
+//! leave the keys as they are, and keeps it.
+
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::gpu_array::GpuArray;
+use crate::pipelines::{BufferPool, SearchIndex};
+use crate::{Capacity, KvEntry};
+
+/// Spare slab arrays kept for reuse by the next write.
+pub(crate) struct SlabSpares {
+    device: Arc<wgpu::Device>,
+    pool: Arc<BufferPool>,
+    capacity: Capacity,
+    free: Mutex<Option<GpuArray<KvEntry>>>,
+}
+
+impl SlabSpares {
+    pub(crate) fn new(
+        device: Arc<wgpu::Device>,
+        pool: Arc<BufferPool>,
+        capacity: Capacity,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            device,
+            pool,
+            capacity,
+            free: Mutex::new(None),
+        })
+    }
+
+    /// Allocate a spare now if none is free, so the next write does not.
+    pub(crate) fn reserve(&self) {
+        let mut free = self.free.lock().expect("slab spares poisoned");
+        if free.is_none() {
+            *free = Some(self.allocate());
+        }
+    }
+
+    /// A spare array, allocating one if none is free.
+    pub(crate) fn take(&self) -> GpuArray<KvEntry> {
+        let spare = self.free.lock().expect("slab spares poisoned").take();
+        spare.unwrap_or_else(|| self.allocate())
+    }
+
+    /// Keep `array` for reuse. Only one spare is kept; any other array is
+    /// freed. Bind groups that reference `array` are dropped either way, so
+    /// the pool's cache never holds a spare, or a dropped one, alive.
+    pub(crate) fn give(&self, array: GpuArray<KvEntry>) {
+        self.pool.forget_buffer(array.buffer());
+        self.pool.forget_buffer(array.meta_buffer());
+        let mut free = self.free.lock().expect("slab spares poisoned");
+        if free.is_none() {
+            *free = Some(array);
+        }
+    }
+
+    fn allocate(&self) -> GpuArray<KvEntry> {
+        GpuArray::new(
+            &self.device,
+            self.capacity,
+            wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            "slab-buffer",
+        )
+    }
+}
+
+/// One published generation of the slab.
+pub(crate) struct SlabVersion {
+    array: Option<GpuArray<KvEntry>>,
+    spares: Arc<SlabSpares>,
+    /// Search index over the array's keys, once built.
+    index: OnceLock<Arc<SearchIndex>>,
+}
+
+impl SlabVersion {
+    pub(crate) fn new(array: GpuArray<KvEntry>, spares: Arc<SlabSpares>) -> Arc<Self> {
+        Arc::new(Self {
+            array: Some(array),
+            spares,
+            index: OnceLock::new(),
+        })
+    }
+
+    /// The search index over this version's keys, if one has been built.
+    pub(crate) fn index(&self) -> Option<&Arc<SearchIndex>> {
+        self.index.get()
+    }
+
+    /// Keep `index` as this version's search index, unless another caller
+    /// built one first.
+    pub(crate) fn set_index(&self, index: Arc<SearchIndex>) {
+        let _ = self.index.set(index);
+    }
+
+    fn array_mut(&mut self) -> &mut GpuArray<KvEntry> {
+        self.array.as_mut().expect("slab version already released")
+    }
+}
+
+impl Deref for SlabVersion {
+    type Target = GpuArray<KvEntry>;
+
+    fn deref(&self) -> &GpuArray<KvEntry> {
+        self.array.as_ref().expect("slab version already released")
+    }
+}
+
+impl Drop for SlabVersion {
+    fn drop(&mut self) {
+        if let Some(array) = self.array.take() {
+            self.spares.give(array);
+        }
+    }
+}
+
+/// Writable access to the current slab, copying it first if another handle
+/// still reads it. Drops the slab's search index.
+pub(crate) fn make_unique<'a>(
+    slab: &'a mut Arc<SlabVersion>,
+    queue: &wgpu::Queue,
+    device: &wgpu::Device,
+) -> &'a mut GpuArray<KvEntry> {
+    let version = unique(slab, queue, device);
+    version.index.take();
+    version.array_mut()
+}
+
+/// [`make_unique`] for writes that change values only, such as tombstoning
+/// or rewriting them. The slab keeps its search index, and a copy shares it.
+pub(crate) fn make_values_unique<'a>(
+    slab: &'a mut Arc<SlabVersion>,
+    queue: &wgpu::Queue,
+    device: &wgpu::Device,
+) -> &'a mut GpuArray<KvEntry> {
+    unique(slab, queue, device).array_mut()
+}
+
+fn unique<'a>(
+    slab: &'a mut Arc<SlabVersion>,
+    queue: &wgpu::Queue,
+    device: &wgpu::Device,
+) -> &'a mut SlabVersion {
+    if Arc::get_mut(slab).is_none() {
+        let spares = Arc::clone(&slab.spares);
+        let mut copy = spares.take();
+        let bytes = slab.len().0 as u64 * std::mem::size_of::<KvEntry>() as u64;
+        if bytes > 0 {
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("slab-copy-encoder"),
+            });
+            encoder.copy_buffer_to_buffer(slab.buffer(), 0, copy.buffer(), 0, bytes);
+            queue.submit(Some(encoder.finish()));
+        }
+        copy.update_len(queue, slab.len());
+        let index = slab.index().cloned();
+        *slab = SlabVersion::new(copy, spares);
+        if let Some(index) = index {
+            slab.set_index(index);
+        }
+    }
+    Arc::get_mut(slab).expect("freshly copied slab is unique")
+}
+
+/// Publish `next` as the current slab, returning the previous version's
+/// array to the spares once its last reader lets go.
+pub(crate) fn publish(slab: &mut Arc<SlabVersion>, next: GpuArray<KvEntry>) {
+    let spares = Arc::clone(&slab.spares);
+    *slab = SlabVersion::new(next, spares);
+}
+
+/// Take a spare array to build the next version in.
+pub(crate) fn take_spare(slab: &Arc<SlabVersion>) -> GpuArray<KvEntry> {
+    slab.spares.take()
+}
+
+/// Give back a spare taken for a write that failed.
+pub(crate) fn return_spare(slab: &Arc<SlabVersion>, array: GpuArray<KvEntry>) {
+    slab.spares.give(array);
+}
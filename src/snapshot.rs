@@ -0,0 +1,215 @@
+//! Read-only views pinned to one slab version.
+
+use crate::backend::Store;
+use crate::dispatch::HostMirror;
+use crate::ttl::ExpiryView;
+use crate::{Capacity, GpuMapError, Key, KvEntry, Length, Value, TOMBSTONE_VALUE};
+
+/// Read-only handle on the slab generation that was current when it was
+/// taken.
+///
+/// Returned by [`GpuSortedMap::snapshot`](crate::GpuSortedMap::snapshot).
+/// Later writes to the map publish new versions and leave this one alone, so
+/// lookups and range scans keep returning the same results until the
+/// snapshot is dropped. Holding a snapshot keeps its slab buffer alive, and a
+/// delete on the map copies the slab rather than editing it in place while
+/// any snapshot shares it.
+#[derive(Clone)]
+pub struct Snapshot {
+    store: Store,
+    live_len: Length,
+    /// Expiry slab version, if any entry had a TTL.
+    expiries: Option<Store>,
+    clock: u32,
+    /// Host copy of the same version for small reads, if the map had one.
+    mirror: Option<HostMirror>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(store: Store, live_len: Length, expiries: Option<Store>, clock: u32) -> Self {
+        Self {
+            store,
+            live_len,
+            expiries,
+            clock,
+            mirror: None,
+        }
+    }
+
+    /// Serve reads below the mirror's threshold from `mirror`.
+    pub(crate) fn with_mirror(mut self, mirror: Option<HostMirror>) -> Self {
+        self.mirror = mirror;
+        self
+    }
+
+    /// Batch lookup of keys.
+    pub fn bulk_get(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, GpuMapError> {
+        self.store.blocker().block_on(self.bulk_get_async(keys))
+    }
+
+    /// Non-blocking [`bulk_get`](Self::bulk_get); see
+    /// [`GpuSortedMap::bulk_get_async`](crate::GpuSortedMap::bulk_get_async)
+    /// for polling requirements.
+    pub async fn bulk_get_async(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, GpuMapError> {
+        match self.mirror().and_then(|m| m.for_batch(keys.len())) {
+            Some(mirror) => mirror.lookup(keys).await,
+            None => self.lookup(keys).await,
+        }
+    }
+
+    /// The host mirror, unless the device has been lost since the snapshot
+    /// was taken.
+    fn mirror(&self) -> Option<&HostMirror> {
+        self.mirror.as_ref().filter(|_| !self.store.is_lost())
+    }
+
+    async fn lookup(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, GpuMapError> {
+        let mut values = self.store.get(keys).await?;
+        if let Some(expiries) = self.expiry_view() {
+            expiries.hide_values(keys, &mut values).await?;
+        }
+        Ok(values)
+    }
+
+    /// Single-key lookup convenience wrapper over `bulk_get`.
+    pub fn get(&self, key: Key) -> Result<Option<Value>, GpuMapError> {
+        Ok(self.bulk_get(&[key])?.into_iter().next().unwrap_or(None))
+    }
+
+    /// Returns entries with keys in `[from_key, to_key)`.
+    pub fn range(&self, from_key: Key, to_key: Key) -> Result<Vec<KvEntry>, GpuMapError> {
+        self.store
+            .blocker()
+            .block_on(self.range_async(from_key, to_key))
+    }
+
+    /// Non-blocking [`range`](Self::range).
+    pub async fn range_async(
+        &self,
+        from_key: Key,
+        to_key: Key,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        match self.mirror().and_then(|m| m.for_range(from_key, to_key)) {
+            Some(mirror) => mirror.scan(from_key, to_key).await,
+            None => self.scan(from_key, to_key).await,
+        }
+    }
+
+    async fn scan(&self, from_key: Key, to_key: Key) -> Result<Vec<KvEntry>, GpuMapError> {
+        let entries = self
+            .store
+            .range(from_key, to_key)
+            .await?
+            .into_iter()
+            .filter(|entry| entry.value != TOMBSTONE_VALUE)
+            .collect();
+        match self.expiry_view() {
+            Some(expiries) => expiries.hide_entries(entries).await,
+            None => Ok(entries),
+        }
+    }
+
+    /// Logical clock reading the snapshot judges TTLs against.
+    pub fn clock(&self) -> u32 {
+        self.clock
+    }
+
+    /// Live entries in this version.
+    pub fn len(&self) -> Length {
+        self.live_len
+    }
+
+    /// Returns true if this version has no live entries.
+    pub fn is_empty(&self) -> bool {
+        self.live_len.0 == 0
+    }
+
+    /// Total slab capacity.
+    pub fn capacity(&self) -> Capacity {
+        self.store.capacity()
+    }
+
+    fn expiry_view(&self) -> Option<ExpiryView<'_>> {
+        self.expiries.as_ref().map(|store| ExpiryView {
+            store,
+            now: self.clock,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_map;
+    use crate::{Capacity, GpuSortedMap, Key, KvEntry, Length, Value};
+
+    fn try_create_map(capacity: Capacity) -> Option<GpuSortedMap> {
+        match pollster::block_on(GpuSortedMap::new(capacity)) {
+            Ok(map) => Some(map),
+            Err(_) => {
+                eprintln!("Skipping test: GPU not available in this environment");
+                None
+            }
+        }
+    }
+
+    fn entries(keys: std::ops::Range<u32>, value: u32) -> Vec<KvEntry> {
+        keys.map(|key| KvEntry {
+            key: Key::new(key),
+            value: Value::new(value),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn snapshot_is_unaffected_by_later_writes() {
+        let Some(mut map) = try_create_map(Capacity::new(256)) else {
+            return;
+        };
+        map.bulk_put(&entries(0..32, 1)).unwrap();
+        let before = map.snapshot();
+
+        map.bulk_put(&entries(16..48, 2)).unwrap();
+        map.bulk_delete(&[Key::new(0), Key::new(1)]).unwrap();
+        map.map_values(
+            ..,
+            "fn f(key: u32, value: u32) -> u32 { return value + 10u; }",
+        )
+        .unwrap();
+        let after = map.snapshot();
+        map.bulk_put(&entries(100..101, 3)).unwrap();
+
+        assert_eq!(before.len(), Length::new(32));
+        assert_eq!(
+            before.range(Key::new(0), Key::new(64)).unwrap(),
+            entries(0..32, 1),
+            "old snapshot changed"
+        );
+        assert_eq!(
+            before
+                .bulk_get(&[Key::new(0), Key::new(20), Key::new(40)])
+                .unwrap(),
+            vec![Some(Value::new(1)), Some(Value::new(1)), None]
+        );
+
+        assert_eq!(after.len(), Length::new(46));
+        assert_eq!(after.get(Key::new(0)).unwrap(), None);
+        assert_eq!(after.get(Key::new(2)).unwrap(), Some(Value::new(11)));
+        assert_eq!(after.get(Key::new(40)).unwrap(), Some(Value::new(12)));
+        assert_eq!(after.get(Key::new(100)).unwrap(), None);
+        assert_eq!(map.get(Key::new(100)).unwrap(), Some(Value::new(3)));
+    }
+
+    #[test]
+    fn writes_stay_correct_as_snapshots_come_and_go() {
+        let mut map = test_map(Capacity::new(64));
+        for round in 0..4u32 {
+            let snapshot = map.snapshot();
+            map.bulk_put(&entries(round * 8..round * 8 + 8, round))
+                .unwrap();
+            map.bulk_delete(&[Key::new(round * 8)]).unwrap();
+            assert_eq!(snapshot.len(), Length::new(round * 7));
+        }
+        assert_eq!(map.len(), Length::new(28));
+        assert_eq!(map.range(Key::new(0), Key::new(64)).unwrap().len(), 28);
+    }
+}
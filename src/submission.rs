@@ -0,0 +1,574 @@
+//! Pipelined submission of lookup and range batches.
+//!
+//! [`SubmissionQueue`] keeps up to `depth` batches on the GPU at once. Each
+//! in-flight batch holds its own upload and readback staging buffers, so the
+//! next batch can be uploaded and dispatched while earlier results are still
+//! being copied back. Staging buffers return to the map's buffer pool as
+//! batches complete.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::backend::GpuBackend;
+use crate::pipelines::bulk_get::{decode_results, result_bytes, GetStaging};
+use crate::pipelines::data::ResultEntry;
+use crate::pipelines::range_scan::{decode_bounds, RangeStaging, RANGE_META_BYTES};
+use crate::pipelines::utils::{ErrorScope, MapRequest};
+use crate::pipelines::SearchIndex;
+use crate::slab::SlabVersion;
+use crate::{GpuMapError, GpuSortedMap, Key, KvEntry, Value, TOMBSTONE_VALUE};
+
+/// Identifies a batch enqueued on a [`SubmissionQueue`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BatchId(pub u64);
+
+/// Result of a completed batch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchResult {
+    /// Values for an [`enqueue_get`](SubmissionQueue::enqueue_get) batch, in key order.
+    Get(Vec<Option<Value>>),
+    /// Live entries for an [`enqueue_range`](SubmissionQueue::enqueue_range) batch.
+    Range(Vec<KvEntry>),
+    /// The batch could not be run or read back.
+    Failed(GpuMapError),
+}
+
+impl BatchResult {
+    fn from_result<T>(result: Result<T, GpuMapError>, ok: impl FnOnce(T) -> Self) -> Self {
+        result.map_or_else(BatchResult::Failed, ok)
+    }
+}
+
+/// Streaming front-end that overlaps upload, compute and readback across
+/// batches.
+///
+/// Created by [`GpuSortedMap::submission_queue`]; it borrows the map, so no
+/// writes can happen while batches are in flight. Enqueueing blocks only when
+/// `depth` batches are already outstanding. Results are returned in
+/// completion order, tagged with the [`BatchId`] returned at enqueue time.
+pub struct SubmissionQueue<'a> {
+    map: &'a GpuSortedMap,
+    depth: usize,
+    next_id: u64,
+    in_flight: VecDeque<InFlight>,
+    completed: VecDeque<(BatchId, BatchResult)>,
+}
+
+struct InFlight {
+    id: BatchId,
+    submission: wgpu::SubmissionIndex,
+    request: MapRequest,
+    stage: Stage,
+}
+
+enum Stage {
+    /// `keys` is kept only when results need an expiry check.
+    Get {
+        staging: GetStaging,
+        len: u32,
+        keys: Option<Vec<Key>>,
+    },
+    RangeBounds {
+        staging: RangeStaging,
+    },
+    RangeEntries {
+        staging: RangeStaging,
+        count: u32,
+    },
+    /// Expiry lookup for the keys of a finished batch.
+    Expiry {
+        result: BatchResult,
+        staging: GetStaging,
+        len: u32,
+    },
+}
+
+/// What a batch moves on to once a stage's readback completes.
+enum Step {
+    Done(BatchResult),
+    /// A follow-up submission for the same batch.
+    Next(Box<InFlight>),
+}
+
+impl<'a> SubmissionQueue<'a> {
+    /// Default number of batches in flight: one computing while the previous
+    /// one reads back.
+    pub const DEFAULT_DEPTH: usize = 2;
+
+    pub(crate) fn new(map: &'a GpuSortedMap, depth: usize) -> Self {
+        Self {
+            map,
+            depth: depth.max(1),
+            next_id: 0,
+            in_flight: VecDeque::new(),
+            completed: VecDeque::new(),
+        }
+    }
+
+    /// Maximum number of batches kept on the GPU at once.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Number of batches submitted but not yet completed.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Returns true when no batch is in flight or waiting to be collected.
+    pub fn is_empty(&self) -> bool {
+        self.in_flight.is_empty() && self.completed.is_empty()
+    }
+
+    /// Enqueue a batch lookup of `keys`.
+    pub fn enqueue_get(&mut self, keys: &[Key]) -> BatchId {
+        let id = self.allocate_id();
+        if keys.is_empty() {
+            self.completed.push_back((id, BatchResult::Get(Vec::new())));
+            return id;
+        }
+        // Slabs that are not a single buffer, and batches too large for one
+        // dispatch, take the blocking path.
+        let store = &self.map.store;
+        if store.gpu_slab().is_none() || keys.len() > store.batch_limit() as usize {
+            let result = BatchResult::from_result(self.map.bulk_get(keys), BatchResult::Get);
+            self.completed.push_back((id, result));
+            return id;
+        }
+
+        self.wait_for_slot();
+        let (gpu, slab) = self.gpu();
+        let index = match gpu.check().and_then(|()| search_index(gpu, slab)) {
+            Ok(index) => index,
+            Err(err) => {
+                self.completed.push_back((id, BatchResult::Failed(err)));
+                return id;
+            }
+        };
+        let scope = ErrorScope::push(&gpu.device, &gpu.scopes);
+        let len = keys.len() as u32;
+        let staging = GetStaging::acquire(&gpu.pool, len);
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("queued-bulk-get-encoder"),
+            });
+        gpu.bulk_get
+            .encode(&mut encoder, slab, &index, &staging, keys);
+        let submission = gpu.queue.submit(Some(encoder.finish()));
+        if let Err(err) = check(gpu, scope) {
+            self.completed.push_back((id, BatchResult::Failed(err)));
+            return id;
+        }
+        let request = MapRequest::new(staging.readback(), result_bytes(len));
+        self.in_flight.push_back(InFlight {
+            id,
+            submission,
+            request,
+            stage: Stage::Get {
+                staging,
+                len,
+                keys: self.map.expiry_view().map(|_| keys.to_vec()),
+            },
+        });
+        id
+    }
+
+    /// Enqueue a scan of entries with keys in `[from_key, to_key)`.
+    ///
+    /// The range runs in two GPU steps (bound search, then copy); the copy is
+    /// issued as soon as the bounds come back, without blocking the caller.
+    pub fn enqueue_range(&mut self, from_key: Key, to_key: Key) -> BatchId {
+        let id = self.allocate_id();
+        if from_key >= to_key || self.map.store.len().0 == 0 {
+            self.completed
+                .push_back((id, BatchResult::Range(Vec::new())));
+            return id;
+        }
+        if self.map.store.gpu_slab().is_none() {
+            let result =
+                BatchResult::from_result(self.map.range(from_key, to_key), BatchResult::Range);
+            self.completed.push_back((id, result));
+            return id;
+        }
+
+        self.wait_for_slot();
+        let (gpu, slab) = self.gpu();
+        if let Err(err) = gpu.check() {
+            self.completed.push_back((id, BatchResult::Failed(err)));
+            return id;
+        }
+        let scope = ErrorScope::push(&gpu.device, &gpu.scopes);
+        let staging = RangeStaging::acquire(&gpu.pool);
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("queued-range-encoder"),
+            });
+        gpu.range_scan
+            .encode_bounds(&mut encoder, slab, &staging, from_key, to_key);
+        let submission = gpu.queue.submit(Some(encoder.finish()));
+        if let Err(err) = check(gpu, scope) {
+            self.completed.push_back((id, BatchResult::Failed(err)));
+            return id;
+        }
+        let request = MapRequest::new(staging.meta_readback(), RANGE_META_BYTES);
+        self.in_flight.push_back(InFlight {
+            id,
+            submission,
+            request,
+            stage: Stage::RangeBounds { staging },
+        });
+        id
+    }
+
+    /// Return a completed batch without blocking, or `None` if none is ready.
+    pub fn try_next(&mut self) -> Option<(BatchId, BatchResult)> {
+        if !self.in_flight.is_empty() {
+            self.gpu().0.device.poll(wgpu::Maintain::Poll);
+            self.collect();
+        }
+        self.completed.pop_front()
+    }
+
+    /// Block until a batch completes and return it, or `None` once every
+    /// enqueued batch has been returned.
+    pub fn next_completed(&mut self) -> Option<(BatchId, BatchResult)> {
+        loop {
+            self.collect();
+            if let Some(done) = self.completed.pop_front() {
+                return Some(done);
+            }
+            let oldest = self.in_flight.front()?.submission.clone();
+            self.gpu()
+                .0
+                .device
+                .poll(wgpu::Maintain::WaitForSubmissionIndex(oldest));
+        }
+    }
+
+    /// Block until every enqueued batch completes and return the results in
+    /// completion order.
+    pub fn drain(&mut self) -> Vec<(BatchId, BatchResult)> {
+        std::iter::from_fn(|| self.next_completed()).collect()
+    }
+
+    /// The map's GPU backend and slab. Batches are only ever in flight on
+    /// [`Backend::Gpu`](crate::Backend::Gpu) maps with a single slab segment.
+    fn gpu(&self) -> (&'a GpuBackend, &'a SlabVersion) {
+        let map: &'a GpuSortedMap = self.map;
+        let (gpu, slab) = map
+            .store
+            .gpu_slab()
+            .expect("batches are only submitted to single-segment GPU slabs");
+        (gpu, slab)
+    }
+
+    fn allocate_id(&mut self) -> BatchId {
+        let id = BatchId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn wait_for_slot(&mut self) {
+        while self.in_flight.len() >= self.depth {
+            let oldest = self.in_flight[0].submission.clone();
+            self.gpu()
+                .0
+                .device
+                .poll(wgpu::Maintain::WaitForSubmissionIndex(oldest));
+            self.collect();
+        }
+    }
+
+    /// Move every finished batch out of `in_flight`, issuing range copies for
+    /// bound searches that have come back.
+    fn collect(&mut self) {
+        let mut pending = VecDeque::with_capacity(self.in_flight.len());
+        while let Some(batch) = self.in_flight.pop_front() {
+            if !batch.request.is_ready() {
+                pending.push_back(batch);
+                continue;
+            }
+            let id = batch.id;
+            let next = match batch.stage {
+                Stage::Get { staging, len, keys } => batch
+                    .request
+                    .read::<ResultEntry>(staging.readback(), result_bytes(len))
+                    .and_then(|entries| {
+                        let result = BatchResult::Get(decode_results(&entries));
+                        match keys {
+                            Some(keys) => self
+                                .submit_expiry_check(id, result, &keys)
+                                .map(|next| Step::Next(Box::new(next))),
+                            None => Ok(Step::Done(result)),
+                        }
+                    }),
+                Stage::RangeBounds { staging } => batch
+                    .request
+                    .read::<u32>(staging.meta_readback(), RANGE_META_BYTES)
+                    .and_then(|meta| match decode_bounds(&meta) {
+                        Some((start, end)) => self
+                            .submit_range_copy(id, staging, start, end)
+                            .map(|next| Step::Next(Box::new(next))),
+                        None => Ok(Step::Done(BatchResult::Range(Vec::new()))),
+                    }),
+                Stage::RangeEntries { staging, count } => {
+                    let bytes = count as u64 * std::mem::size_of::<KvEntry>() as u64;
+                    batch
+                        .request
+                        .read::<KvEntry>(staging.entries_readback(), bytes)
+                        .and_then(|entries| {
+                            let entries: Vec<KvEntry> = entries
+                                .into_iter()
+                                .filter(|entry| entry.value != TOMBSTONE_VALUE)
+                                .collect();
+                            if self.map.expiry_view().is_some() && !entries.is_empty() {
+                                let keys: Vec<Key> =
+                                    entries.iter().map(|entry| entry.key).collect();
+                                let result = BatchResult::Range(entries);
+                                self.submit_expiry_check(id, result, &keys)
+                                    .map(|next| Step::Next(Box::new(next)))
+                            } else {
+                                Ok(Step::Done(BatchResult::Range(entries)))
+                            }
+                        })
+                }
+                Stage::Expiry {
+                    result,
+                    staging,
+                    len,
+                } => batch
+                    .request
+                    .read::<ResultEntry>(staging.readback(), result_bytes(len))
+                    .map(|entries| {
+                        let expiries = decode_results(&entries);
+                        let view = self
+                            .map
+                            .expiry_view()
+                            .expect("expiry check without expiries");
+                        Step::Done(match result {
+                            BatchResult::Get(mut values) => {
+                                view.hide_looked_up_values(&expiries, &mut values);
+                                BatchResult::Get(values)
+                            }
+                            BatchResult::Range(entries) => {
+                                BatchResult::Range(view.keep_unexpired(entries, &expiries))
+                            }
+                            failed @ BatchResult::Failed(_) => failed,
+                        })
+                    }),
+            };
+            match next {
+                Ok(Step::Done(result)) => self.completed.push_back((id, result)),
+                Ok(Step::Next(follow_up)) => pending.push_back(*follow_up),
+                Err(err) => {
+                    let err = self.gpu().0.lost_or(err);
+                    self.completed.push_back((id, BatchResult::Failed(err)));
+                }
+            }
+        }
+        self.in_flight = pending;
+    }
+
+    /// Copy slab entries `[start, end)` into the batch's readback buffer.
+    fn submit_range_copy(
+        &self,
+        id: BatchId,
+        mut staging: RangeStaging,
+        start: u32,
+        end: u32,
+    ) -> Result<InFlight, GpuMapError> {
+        let (gpu, slab) = self.gpu();
+        gpu.check()?;
+        let scope = ErrorScope::push(&gpu.device, &gpu.scopes);
+        let count = end - start;
+        staging.reserve(count);
+        let entry_size = std::mem::size_of::<KvEntry>() as u64;
+        let bytes = count as u64 * entry_size;
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("queued-range-copy-encoder"),
+            });
+        encoder.copy_buffer_to_buffer(
+            slab.buffer(),
+            start as u64 * entry_size,
+            staging.entries_readback(),
+            0,
+            bytes,
+        );
+        let submission = gpu.queue.submit(Some(encoder.finish()));
+        check(gpu, scope)?;
+        let request = MapRequest::new(staging.entries_readback(), bytes);
+        Ok(InFlight {
+            id,
+            submission,
+            request,
+            stage: Stage::RangeEntries { staging, count },
+        })
+    }
+
+    /// Look up the expiry times of a finished batch's keys, so expired
+    /// entries can be dropped when the lookup completes.
+    fn submit_expiry_check(
+        &self,
+        id: BatchId,
+        result: BatchResult,
+        keys: &[Key],
+    ) -> Result<InFlight, GpuMapError> {
+        let view = self
+            .map
+            .expiry_view()
+            .expect("expiry check without expiries");
+        let (_, expiry_slab) = view
+            .store
+            .gpu_slab()
+            .expect("batches are only submitted to single-segment GPU slabs");
+        let gpu = self.gpu().0;
+        gpu.check()?;
+        let index = search_index(gpu, expiry_slab)?;
+        let scope = ErrorScope::push(&gpu.device, &gpu.scopes);
+        let len = keys.len() as u32;
+        let staging = GetStaging::acquire(&gpu.pool, len);
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("queued-expiry-encoder"),
+            });
+        gpu.bulk_get
+            .encode(&mut encoder, expiry_slab, &index, &staging, keys);
+        let submission = gpu.queue.submit(Some(encoder.finish()));
+        check(gpu, scope)?;
+        let request = MapRequest::new(staging.readback(), result_bytes(len));
+        Ok(InFlight {
+            id,
+            submission,
+            request,
+            stage: Stage::Expiry {
+                result,
+                staging,
+                len,
+            },
+        })
+    }
+}
+
+/// Close `scope` and return what it captured. Native error scopes resolve
+/// as soon as they are popped, so this does not wait on the GPU.
+fn check(gpu: &GpuBackend, scope: ErrorScope<'_>) -> Result<(), GpuMapError> {
+    pollster::block_on(scope.pop()).map_err(|err| gpu.lost_or(err))
+}
+
+/// `slab`'s search index, built now if it has none. Building waits on an
+/// error scope only, not on the device.
+fn search_index(gpu: &GpuBackend, slab: &SlabVersion) -> Result<Arc<SearchIndex>, GpuMapError> {
+    pollster::block_on(gpu.index_for(slab)).map_err(|err| gpu.lost_or(err))
+}
+
+impl Drop for SubmissionQueue<'_> {
+    /// Abandon outstanding batches, unmapping their readback buffers before
+    /// the staging goes back to the pool.
+    fn drop(&mut self) {
+        for batch in self.in_flight.drain(..) {
+            let buffer = match &batch.stage {
+                Stage::Get { staging, .. } | Stage::Expiry { staging, .. } => staging.readback(),
+                Stage::RangeBounds { staging } => staging.meta_readback(),
+                Stage::RangeEntries { staging, .. } => staging.entries_readback(),
+            };
+            batch.request.cancel(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatchId, BatchResult, SubmissionQueue};
+    use crate::backend::test_map;
+    use crate::{Capacity, GpuSortedMap, Key, KvEntry, Value};
+
+    fn populated_map() -> GpuSortedMap {
+        let mut map = test_map(Capacity::new(1024));
+        let entries: Vec<KvEntry> = (0..512)
+            .map(|i| KvEntry {
+                key: Key::new(i * 2),
+                value: Value::new(i * 10),
+            })
+            .collect();
+        map.bulk_put(&entries).unwrap();
+        map.bulk_delete(&[Key::new(4), Key::new(100)]).unwrap();
+        map
+    }
+
+    #[test]
+    fn queued_batches_match_blocking_calls() {
+        let map = populated_map();
+        let get_batches: Vec<Vec<Key>> = (0..6)
+            .map(|b| (0..(50 + b * 37)).map(|i| Key::new(i * 3 + b)).collect())
+            .collect();
+        let ranges = [(0, 20), (90, 110), (1000, 1100), (5, 5), (2000, 3000)];
+
+        let mut queue = map.submission_queue(SubmissionQueue::DEFAULT_DEPTH);
+        let mut expected = Vec::new();
+        for (keys, &(from, to)) in get_batches.iter().zip(ranges.iter().cycle()) {
+            let id = queue.enqueue_get(keys);
+            expected.push((id, BatchResult::Get(map.bulk_get(keys).unwrap())));
+            let id = queue.enqueue_range(Key::new(from), Key::new(to));
+            expected.push((
+                id,
+                BatchResult::Range(map.range(Key::new(from), Key::new(to)).unwrap()),
+            ));
+            assert!(queue.in_flight() <= queue.depth());
+        }
+
+        let mut results = queue.drain();
+        assert!(queue.is_empty());
+        results.sort_by_key(|(id, _)| *id);
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn try_next_eventually_returns_every_batch() {
+        let map = populated_map();
+        let mut queue = map.submission_queue(4);
+        let a = queue.enqueue_get(&[Key::new(2), Key::new(4), Key::new(3)]);
+        let b = queue.enqueue_range(Key::new(0), Key::new(8));
+        let c = queue.enqueue_get(&[]);
+
+        let mut seen = Vec::new();
+        while seen.len() < 3 {
+            if let Some(done) = queue.try_next() {
+                seen.push(done);
+            }
+        }
+        assert!(queue.try_next().is_none());
+        assert!(queue.next_completed().is_none());
+
+        seen.sort_by_key(|(id, _)| *id);
+        assert_eq!(seen[0].0, a);
+        assert_eq!(
+            seen[0].1,
+            BatchResult::Get(vec![Some(Value::new(10)), None, None])
+        );
+        assert_eq!(seen[1].0, b);
+        assert_eq!(
+            seen[1].1,
+            BatchResult::Range(vec![
+                KvEntry {
+                    key: Key::new(0),
+                    value: Value::new(0),
+                },
+                KvEntry {
+                    key: Key::new(2),
+                    value: Value::new(10),
+                },
+                KvEntry {
+                    key: Key::new(6),
+                    value: Value::new(30),
+                },
+            ])
+        );
+        assert_eq!(seen[2], (c, BatchResult::Get(Vec::new())));
+        assert_eq!(c, BatchId(2));
+    }
+}
@@ -0,0 +1,81 @@
+//! Per-entry expiry.
+//!
+//! Expiry times live in a second sorted slab that maps each key with a TTL to
+//! the logical time at which it expires, so the existing pipelines sort,
+//! merge and search them. Keys without a TTL have no expiry entry. Reads look
+//! up the keys they return in the expiry slab and drop those whose time has
+//! passed; [`GpuSortedMap::expire`](crate::GpuSortedMap::expire) finds expired
+//! keys with a filter pass and tombstones them in both slabs.
+
+use crate::backend::Store;
+use crate::{GpuMapError, Key, KvEntry, Value};
+
+/// Latest expiry time that can be stored; later times saturate to it.
+/// `u32::MAX` is the tombstone, so it cannot be an expiry value.
+pub(crate) const MAX_EXPIRY: u32 = u32::MAX - 1;
+
+/// An entry with expiry time `at` is expired once the clock reaches `at`.
+pub(crate) fn is_expired(at: Option<Value>, now: u32) -> bool {
+    at.is_some_and(|at| at.0 <= now)
+}
+
+/// Expiry time for an entry written at `now` with a lifetime of `ttl` ticks.
+pub(crate) fn expiry_at(now: u32, ttl: u32) -> Value {
+    Value::new(now.saturating_add(ttl).min(MAX_EXPIRY))
+}
+
+/// Expiry slab lookups at a fixed clock reading.
+pub(crate) struct ExpiryView<'a> {
+    pub(crate) store: &'a Store,
+    pub(crate) now: u32,
+}
+
+impl ExpiryView<'_> {
+    /// Clear the values of expired keys.
+    pub(crate) async fn hide_values(
+        &self,
+        keys: &[Key],
+        values: &mut [Option<Value>],
+    ) -> Result<(), GpuMapError> {
+        let expiries = self.store.get(keys).await?;
+        self.hide_looked_up_values(&expiries, values);
+        Ok(())
+    }
+
+    /// Clear the values of expired keys, given their looked-up expiry times.
+    pub(crate) fn hide_looked_up_values(
+        &self,
+        expiries: &[Option<Value>],
+        values: &mut [Option<Value>],
+    ) {
+        for (value, at) in values.iter_mut().zip(expiries) {
+            if is_expired(*at, self.now) {
+                *value = None;
+            }
+        }
+    }
+
+    /// Drop expired entries.
+    pub(crate) async fn hide_entries(
+        &self,
+        entries: Vec<KvEntry>,
+    ) -> Result<Vec<KvEntry>, GpuMapError> {
+        let keys: Vec<Key> = entries.iter().map(|entry| entry.key).collect();
+        let expiries = self.store.get(&keys).await?;
+        Ok(self.keep_unexpired(entries, &expiries))
+    }
+
+    /// Drop expired entries, given their looked-up expiry times.
+    pub(crate) fn keep_unexpired(
+        &self,
+        entries: Vec<KvEntry>,
+        expiries: &[Option<Value>],
+    ) -> Vec<KvEntry> {
+        entries
+            .into_iter()
+            .zip(expiries)
+            .filter(|(_, at)| !is_expired(**at, self.now))
+            .map(|(entry, _)| entry)
+            .collect()
+    }
+}
@@ -0,0 +1,406 @@
+//! Startup tuning for the adapter a map runs on.
+//!
+//! The per-key and per-slot kernels are templates over their workgroup size
+//! (see `pipelines::wgsl`). [`measure`] builds the pipelines for each
+//! candidate size on the map's device, times a lookup batch and a bulk put
+//! with each, and then finds the CPU/GPU crossover for
+//! [`DispatchPolicy::Calibrated`](crate::DispatchPolicy::Calibrated) with the
+//! fastest. The result is a [`TuningProfile`], which has a short text form
+//! so later launches can load it instead of measuring again.
+//!
+//! The dedup, merge and range-bound kernels run as a single invocation, so
+//! they have no workgroup size to tune.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::backend::Store;
+use crate::cpu_slab::CpuSlab;
+use crate::dispatch;
+use crate::pipelines::DEFAULT_MAX_RETAINED_BYTES;
+use crate::sharded::segment_capacity;
+use crate::{BufferPool, Capacity, GpuMapError, Key, KvEntry, Value};
+
+/// First line of the text form, naming its version.
+const PROFILE_HEADER: &str = "gpusorted_map tuning profile v1";
+/// Smallest and largest workgroup sizes tried.
+const MIN_CANDIDATE: u32 = 32;
+const MAX_CANDIDATE: u32 = 256;
+/// Entries in the slab the candidates are timed against.
+const TUNING_ENTRIES: u32 = 1 << 14;
+/// Timed runs per candidate; the fastest one counts.
+const TUNING_RUNS: usize = 3;
+
+/// Workgroup size and CPU/GPU crossover measured on one adapter.
+///
+/// Returned by [`GpuSortedMap::tuning_profile`](crate::GpuSortedMap::tuning_profile)
+/// for maps built with
+/// [`auto_tune`](crate::GpuSortedMapBuilder::auto_tune) or
+/// [`tuning_cache`](crate::GpuSortedMapBuilder::tuning_cache). `to_string()`
+/// and `parse()` convert it to and from a line-based text form for storing
+/// between runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TuningProfile {
+    /// Adapter name, as in [`wgpu::AdapterInfo::name`].
+    pub adapter: String,
+    /// wgpu backend of the adapter, such as `Vulkan`.
+    pub backend: String,
+    /// PCI vendor and device ids of the adapter.
+    pub vendor: u32,
+    pub device: u32,
+    /// Threads per workgroup of the per-key and per-slot kernels.
+    pub workgroup_size: u32,
+    /// Smallest lookup batch the GPU answered faster than a host binary
+    /// search; used as the threshold for
+    /// [`DispatchPolicy::Calibrated`](crate::DispatchPolicy::Calibrated).
+    pub dispatch_threshold: usize,
+}
+
+impl TuningProfile {
+    /// Returns true if the profile was measured on `adapter`.
+    pub fn matches(&self, adapter: &wgpu::AdapterInfo) -> bool {
+        self.adapter == adapter.name
+            && self.backend == format!("{:?}", adapter.backend)
+            && self.vendor == adapter.vendor
+            && self.device == adapter.device
+    }
+
+    /// Fails unless `limits` allow the profile's workgroup size.
+    pub(crate) fn validate(&self, limits: &wgpu::Limits) -> Result<(), GpuMapError> {
+        let checks = [
+            (
+                "max_compute_workgroup_size_x",
+                limits.max_compute_workgroup_size_x,
+            ),
+            (
+                "max_compute_invocations_per_workgroup",
+                limits.max_compute_invocations_per_workgroup,
+            ),
+        ];
+        for (limit, available) in checks {
+            if self.workgroup_size > available {
+                return Err(GpuMapError::DeviceLimitsInsufficient {
+                    limit,
+                    required: self.workgroup_size as u64,
+                    available: available as u64,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TuningProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", PROFILE_HEADER)?;
+        writeln!(f, "adapter={}", self.adapter)?;
+        writeln!(f, "backend={}", self.backend)?;
+        writeln!(f, "vendor={}", self.vendor)?;
+        writeln!(f, "device={}", self.device)?;
+        writeln!(f, "workgroup_size={}", self.workgroup_size)?;
+        writeln!(f, "dispatch_threshold={}", self.dispatch_threshold)
+    }
+}
+
+impl FromStr for TuningProfile {
+    type Err = GpuMapError;
+
+    fn from_str(text: &str) -> Result<Self, GpuMapError> {
+        let invalid = |message: String| GpuMapError::InvalidTuningProfile { message };
+        let mut lines = text.lines();
+        if lines.next().map(str::trim) != Some(PROFILE_HEADER) {
+            return Err(invalid(format!("expected {:?} header", PROFILE_HEADER)));
+        }
+        let mut fields = std::collections::HashMap::new();
+        for line in lines.filter(|line| !line.trim().is_empty()) {
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected name=value, got {:?}", line)))?;
+            fields.insert(name.trim(), value.trim());
+        }
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .copied()
+                .ok_or_else(|| invalid(format!("missing {}", name)))
+        };
+        fn number<T>(name: &str, value: &str) -> Result<T, GpuMapError>
+        where
+            T: FromStr,
+            T::Err: fmt::Display,
+        {
+            value
+                .parse()
+                .map_err(|err| GpuMapError::InvalidTuningProfile {
+                    message: format!("{}: {}", name, err),
+                })
+        }
+        let profile = TuningProfile {
+            adapter: field("adapter")?.to_string(),
+            backend: field("backend")?.to_string(),
+            vendor: number("vendor", field("vendor")?)?,
+            device: number("device", field("device")?)?,
+            workgroup_size: number("workgroup_size", field("workgroup_size")?)?,
+            dispatch_threshold: number("dispatch_threshold", field("dispatch_threshold")?)?,
+        };
+        if profile.workgroup_size == 0 {
+            return Err(invalid("workgroup_size must be at least 1".to_string()));
+        }
+        Ok(profile)
+    }
+}
+
+/// The profile stored at `path` if it was measured on `adapter` and fits
+/// `limits`; otherwise a fresh measurement, written back to `path`.
+pub(crate) fn cached_or_measure(
+    path: &Path,
+    device: &Arc<wgpu::Device>,
+    queue: &Arc<wgpu::Queue>,
+    adapter: &wgpu::AdapterInfo,
+) -> Result<TuningProfile, GpuMapError> {
+    let cached = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|text| text.parse::<TuningProfile>().ok())
+        .filter(|profile| profile.matches(adapter) && profile.validate(&device.limits()).is_ok());
+    if let Some(profile) = cached {
+        return Ok(profile);
+    }
+    let profile = measure(device, queue, adapter)?;
+    std::fs::write(path, profile.to_string()).map_err(|err| {
+        GpuMapError::GpuInitializationFailed {
+            message: format!("failed to write tuning profile {}: {}", path.display(), err),
+        }
+    })?;
+    Ok(profile)
+}
+
+/// Time each candidate workgroup size on `device` and measure the CPU/GPU
+/// crossover with the fastest.
+pub(crate) fn measure(
+    device: &Arc<wgpu::Device>,
+    queue: &Arc<wgpu::Queue>,
+    adapter: &wgpu::AdapterInfo,
+) -> Result<TuningProfile, GpuMapError> {
+    let limits = device.limits();
+    let capacity = Capacity::new(TUNING_ENTRIES.min(segment_capacity(&limits).0));
+    let entries = tuning_entries(capacity.0 / 2);
+    let pool = Arc::new(BufferPool::new(
+        Arc::clone(device),
+        DEFAULT_MAX_RETAINED_BYTES,
+    ));
+
+    let mut fastest: Option<(u32, Duration, Store)> = None;
+    for workgroup_size in candidate_sizes(&limits) {
+        let store = Store::gpu(
+            Arc::clone(device),
+            Arc::clone(queue),
+            capacity,
+            Arc::clone(&pool),
+            workgroup_size,
+        );
+        let (elapsed, filled) = time_kernels(&store, &entries)?;
+        if fastest
+            .as_ref()
+            .map_or(true, |(_, best, _)| elapsed < *best)
+        {
+            fastest = Some((workgroup_size, elapsed, filled));
+        }
+    }
+    let (workgroup_size, _, filled) = fastest.expect("at least one candidate size");
+    let host = CpuSlab::from_entries(entries, capacity);
+    let dispatch_threshold = dispatch::calibrate(&filled, &host)?;
+
+    Ok(TuningProfile {
+        adapter: adapter.name.clone(),
+        backend: format!("{:?}", adapter.backend),
+        vendor: adapter.vendor,
+        device: adapter.device,
+        workgroup_size,
+        dispatch_threshold,
+    })
+}
+
+/// Powers of two the device allows, from the subgroup width (or
+/// [`MIN_CANDIDATE`]) up to [`MAX_CANDIDATE`].
+fn candidate_sizes(limits: &wgpu::Limits) -> Vec<u32> {
+    let largest = MAX_CANDIDATE
+        .min(limits.max_compute_workgroup_size_x)
+        .min(limits.max_compute_invocations_per_workgroup);
+    let mut size = limits
+        .min_subgroup_size
+        .max(MIN_CANDIDATE)
+        .next_power_of_two()
+        .min(largest);
+    let mut sizes = Vec::new();
+    while size <= largest {
+        sizes.push(size);
+        size *= 2;
+    }
+    sizes
+}
+
+/// Best time for a lookup of every entry plus a bulk put of all of them,
+/// and a store holding the entries.
+fn time_kernels(store: &Store, entries: &[KvEntry]) -> Result<(Duration, Store), GpuMapError> {
+    let blocker = store.blocker();
+    let mut filled = store.sibling();
+    blocker.block_on(filled.merge(entries))?;
+    let batch = entries.len().min(store.batch_limit() as usize);
+    let keys: Vec<Key> = entries[..batch].iter().map(|entry| entry.key).collect();
+
+    let mut lookups = Duration::MAX;
+    let mut puts = Duration::MAX;
+    for _ in 0..TUNING_RUNS {
+        let start = Instant::now();
+        blocker.block_on(filled.get(&keys))?;
+        lookups = lookups.min(start.elapsed());
+
+        let mut scratch = store.sibling();
+        let start = Instant::now();
+        blocker.block_on(scratch.merge(entries))?;
+        puts = puts.min(start.elapsed());
+    }
+    Ok((lookups + puts, filled))
+}
+
+/// `len` distinct entries spread over the key space, in no particular
+/// order.
+fn tuning_entries(len: u32) -> Vec<KvEntry> {
+    (0..len)
+        .map(|i| KvEntry {
+            key: Key::new(i.wrapping_mul(0x9E37_79B9)),
+            value: Value::new(i),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{candidate_sizes, TuningProfile};
+    use crate::{Capacity, GpuMapError, GpuSortedMap, Key, KvEntry, Value, ValuePredicate};
+
+    fn profile(workgroup_size: u32) -> TuningProfile {
+        TuningProfile {
+            adapter: "Example GPU (rev = 2)".to_string(),
+            backend: "Vulkan".to_string(),
+            vendor: 0x10de,
+            device: 0x2684,
+            workgroup_size,
+            dispatch_threshold: 4096,
+        }
+    }
+
+    #[test]
+    fn profile_round_trips_through_text() {
+        let profile = profile(128);
+        let text = profile.to_string();
+        assert_eq!(text.parse::<TuningProfile>().unwrap(), profile);
+
+        for bad in [
+            "",
+            "adapter=x\n",
+            &text.replace("workgroup_size=128", "workgroup_size=0"),
+            &text.replace("vendor=4318", "vendor=ten"),
+            &text.replace("vendor=4318", "vendor=4294967296"),
+            &text.replace("workgroup_size=128", "workgroup_size=4294967360"),
+            &text.replace("dispatch_threshold=4096\n", ""),
+        ] {
+            assert!(matches!(
+                bad.parse::<TuningProfile>(),
+                Err(GpuMapError::InvalidTuningProfile { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn candidates_respect_device_limits() {
+        let limits = wgpu::Limits::default();
+        assert_eq!(candidate_sizes(&limits), [32, 64, 128, 256]);
+        let limits = wgpu::Limits {
+            min_subgroup_size: 64,
+            max_compute_invocations_per_workgroup: 128,
+            ..wgpu::Limits::default()
+        };
+        assert_eq!(candidate_sizes(&limits), [64, 128]);
+    }
+
+    #[test]
+    fn kernels_give_the_same_results_at_every_workgroup_size() {
+        let entries: Vec<KvEntry> = (0..2000u32)
+            .map(|i| KvEntry {
+                key: Key::new(i * 7),
+                value: Value::new(i),
+            })
+            .collect();
+        let keys: Vec<Key> = (0..3000u32).map(|i| Key::new(i * 5)).collect();
+        let mut results = Vec::new();
+        for workgroup_size in [1, 32, 64, 256] {
+            let mut builder = GpuSortedMap::builder(Capacity::new(4096));
+            builder.tuning(profile(workgroup_size));
+            let Ok(mut map) = pollster::block_on(builder.build()) else {
+                eprintln!("Skipping test: GPU not available in this environment");
+                return;
+            };
+            assert_eq!(map.tuning_profile().unwrap().workgroup_size, workgroup_size);
+            map.bulk_put(&entries).unwrap();
+            map.bulk_delete(&keys[..100]).unwrap();
+            map.map_values(
+                Key::new(0)..Key::new(5000),
+                "fn f(key: u32, value: u32) -> u32 { return value + key; }",
+            )
+            .unwrap();
+            results.push((
+                map.bulk_get(&keys).unwrap(),
+                map.filter(&ValuePredicate::Lt(Value::new(3000))).unwrap(),
+            ));
+        }
+        assert!(results.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[test]
+    fn explicit_profile_must_fit_the_device() {
+        let mut builder = GpuSortedMap::builder(Capacity::new(64));
+        builder.tuning(profile(1 << 20));
+        match pollster::block_on(builder.build()) {
+            Err(GpuMapError::DeviceLimitsInsufficient { required, .. }) => {
+                assert_eq!(required, 1 << 20)
+            }
+            Err(GpuMapError::GpuInitializationFailed { .. }) => {
+                eprintln!("Skipping test: GPU not available in this environment");
+            }
+            other => panic!("expected insufficient limits, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn tuning_cache_is_measured_once_and_reused() {
+        let path =
+            std::env::temp_dir().join(format!("gpusorted_map-tuning-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut builder = GpuSortedMap::builder(Capacity::new(64));
+        builder.tuning_cache(&path);
+        let Ok(map) = pollster::block_on(builder.build()) else {
+            eprintln!("Skipping test: GPU not available in this environment");
+            return;
+        };
+        let measured = map.tuning_profile().unwrap().clone();
+        assert!(measured.matches(map.adapter_info().unwrap()));
+        assert!(candidate_sizes(&map.device().unwrap().limits()).contains(&measured.workgroup_size));
+        assert!(measured.dispatch_threshold >= 1);
+        let stored: TuningProfile = std::fs::read_to_string(&path).unwrap().parse().unwrap();
+        assert_eq!(stored, measured);
+
+        // A second build loads the file rather than measuring again.
+        let edited = TuningProfile {
+            workgroup_size: 16,
+            ..measured
+        };
+        std::fs::write(&path, edited.to_string()).unwrap();
+        let map = pollster::block_on(builder.build()).unwrap();
+        assert_eq!(map.tuning_profile(), Some(&edited));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! Mixed put/delete batches.
+
+use std::collections::HashMap;
+
+use crate::{Key, KvEntry, Value, TOMBSTONE_VALUE};
+
+/// Ordered list of puts and deletes applied together by
+/// [`GpuSortedMap::apply_batch`](crate::GpuSortedMap::apply_batch).
+///
+/// Operations on the same key collapse to the last one, so a batch may
+/// mention a key any number of times.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<(Key, Option<Value>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert or update `key`.
+    pub fn put(&mut self, key: Key, value: Value) -> &mut Self {
+        self.ops.push((key, Some(value)));
+        self
+    }
+
+    /// Delete `key`.
+    pub fn delete(&mut self, key: Key) -> &mut Self {
+        self.ops.push((key, None));
+        self
+    }
+
+    /// Number of operations recorded, before collapsing repeated keys.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    /// A put value, if any, that uses the reserved tombstone.
+    pub(crate) fn reserved_value(&self) -> Option<Value> {
+        self.ops
+            .iter()
+            .filter_map(|(_, value)| *value)
+            .find(|value| *value == TOMBSTONE_VALUE)
+    }
+
+    /// Final puts and deletes after collapsing repeated keys, in the order of
+    /// each key's last operation.
+    pub(crate) fn resolve(&self) -> (Vec<KvEntry>, Vec<Key>) {
+        let mut last: HashMap<Key, usize> = HashMap::with_capacity(self.ops.len());
+        for (index, (key, _)) in self.ops.iter().enumerate() {
+            last.insert(*key, index);
+        }
+        let mut puts = Vec::new();
+        let mut deletes = Vec::new();
+        for (index, (key, value)) in self.ops.iter().enumerate() {
+            if last.get(key) != Some(&index) {
+                continue;
+            }
+            match value {
+                Some(value) => puts.push(KvEntry {
+                    key: *key,
+                    value: *value,
+                }),
+                None => deletes.push(*key),
+            }
+        }
+        (puts, deletes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_keeps_the_last_op_per_key() {
+        let mut batch = WriteBatch::new();
+        batch
+            .put(Key::new(1), Value::new(10))
+            .delete(Key::new(2))
+            .put(Key::new(2), Value::new(20))
+            .delete(Key::new(1))
+            .put(Key::new(3), Value::new(30));
+        assert_eq!(batch.len(), 5);
+
+        let (puts, deletes) = batch.resolve();
+        assert_eq!(
+            puts,
+            vec![
+                KvEntry {
+                    key: Key::new(2),
+                    value: Value::new(20)
+                },
+                KvEntry {
+                    key: Key::new(3),
+                    value: Value::new(30)
+                },
+            ]
+        );
+        assert_eq!(deletes, vec![Key::new(1)]);
+        assert_eq!(batch.reserved_value(), None);
+    }
+}
@@ -0,0 +1,593 @@
+//! Differential tests: seeded random operation sequences run against
+//! `GpuSortedMap` and a `BTreeMap` model, with every result compared.
+//!
+//! A failing sequence is shrunk to a minimal reproduction before the test
+//! panics, and the report includes the seed needed to replay it.
+//!
+//! - `cargo test --test differential` runs a fixed set of seeds on the CPU
+//!   backend and, when an adapter is available, on the GPU: as is, with a
+//!   binding limit small enough to split larger slabs into segments, in
+//!   leveled mode with small runs, and with small reads served from the host
+//!   mirror.
+//! - `GPUSORTED_MAP_DIFF_SEED=<n>` replays a single seed.
+//! - `GPUSORTED_MAP_DIFF_OPS=<n>` sets the sequence length (default 150).
+//! - `cargo test --release --test differential -- --ignored soak` keeps
+//!   generating fresh seeds for `GPUSORTED_MAP_DIFF_SOAK` seconds (default
+//!   600).
+//!
+//! `GPUSORTED_MAP_TEST_BACKEND=cpu` skips the GPU backend, as for the unit
+//! tests.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use gpusorted_map::{
+    Backend, Capacity, DispatchPolicy, GpuMapError, GpuSortedMap, Key, KvEntry, LeveledConfig,
+    Value,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const TOMBSTONE: u32 = u32::MAX;
+const DEFAULT_SEEDS: u64 = 8;
+const DEFAULT_OPS: usize = 150;
+const DEFAULT_SOAK_SECS: u64 = 600;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Op {
+    Put(Vec<(u32, u32)>),
+    Delete(Vec<u32>),
+    Get(Vec<u32>),
+    Range(u32, u32),
+    Len,
+}
+
+/// Result of a put, with `CapacityExceeded` reduced to its variant: the
+/// `requested` count of the padding check depends on slab slots the model
+/// does not track.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PutOutcome {
+    Ok,
+    CapacityExceeded,
+    TombstoneValueReserved,
+    DuplicateKeys(u32),
+    Other(String),
+}
+
+impl From<Result<(), GpuMapError>> for PutOutcome {
+    fn from(result: Result<(), GpuMapError>) -> Self {
+        match result {
+            Ok(()) => PutOutcome::Ok,
+            Err(GpuMapError::CapacityExceeded { .. }) => PutOutcome::CapacityExceeded,
+            Err(GpuMapError::TombstoneValueReserved { .. }) => PutOutcome::TombstoneValueReserved,
+            Err(GpuMapError::DuplicateKeys { key }) => PutOutcome::DuplicateKeys(key.0),
+            Err(other) => PutOutcome::Other(other.to_string()),
+        }
+    }
+}
+
+/// Reference semantics of the map.
+struct Model {
+    entries: BTreeMap<u32, u32>,
+    capacity: u32,
+}
+
+impl Model {
+    fn new(capacity: u32) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            capacity,
+        }
+    }
+
+    fn put(&mut self, batch: &[(u32, u32)]) -> PutOutcome {
+        if batch.is_empty() {
+            return PutOutcome::Ok;
+        }
+        if batch.iter().any(|&(_, value)| value == TOMBSTONE) {
+            return PutOutcome::TombstoneValueReserved;
+        }
+        let mut seen = std::collections::HashSet::new();
+        if let Some(&(key, _)) = batch.iter().find(|(key, _)| !seen.insert(*key)) {
+            return PutOutcome::DuplicateKeys(key);
+        }
+        let net_new = batch
+            .iter()
+            .filter(|(key, _)| !self.entries.contains_key(key))
+            .count();
+        let padded = (batch.len() as u32).next_power_of_two();
+        if self.entries.len() + net_new > self.capacity as usize || padded > self.capacity {
+            return PutOutcome::CapacityExceeded;
+        }
+        self.entries.extend(batch.iter().copied());
+        PutOutcome::Ok
+    }
+
+    fn delete(&mut self, keys: &[u32]) {
+        for key in keys {
+            self.entries.remove(key);
+        }
+    }
+
+    fn get(&self, keys: &[u32]) -> Vec<Option<Value>> {
+        keys.iter()
+            .map(|key| self.entries.get(key).map(|&value| Value::new(value)))
+            .collect()
+    }
+
+    fn range(&self, from: u32, to: u32) -> Vec<KvEntry> {
+        if from >= to {
+            return Vec::new();
+        }
+        self.entries
+            .range(from..to)
+            .map(|(&key, &value)| KvEntry {
+                key: Key::new(key),
+                value: Value::new(value),
+            })
+            .collect()
+    }
+}
+
+/// Makes fresh maps on one backend. GPU maps share a device so shrinking
+/// does not request a new adapter per attempt.
+#[derive(Clone)]
+enum Target {
+    Gpu(Arc<wgpu::Device>, Arc<wgpu::Queue>),
+    /// A device whose bindings hold 64 entries, so slabs of 128 or more are
+    /// split into segments.
+    Sharded(Arc<wgpu::Device>, Arc<wgpu::Queue>),
+    /// Leveled mode with an L0 of 4 entries and runs growing by 2.
+    Leveled(Arc<wgpu::Device>, Arc<wgpu::Queue>),
+    /// Reads of fewer than 8 keys or entries served from the host mirror.
+    Hybrid(Arc<wgpu::Device>, Arc<wgpu::Queue>),
+    /// Lookups and deletes through a search index.
+    Indexed(Arc<wgpu::Device>, Arc<wgpu::Queue>),
+    Cpu,
+}
+
+impl Target {
+    fn available() -> Vec<Target> {
+        let mut targets = vec![Target::Cpu];
+        let force_cpu = std::env::var("GPUSORTED_MAP_TEST_BACKEND")
+            .is_ok_and(|backend| backend.eq_ignore_ascii_case("cpu"));
+        if force_cpu {
+            return targets;
+        }
+        match pollster::block_on(GpuSortedMap::new(Capacity::new(1))) {
+            Ok(map) => {
+                let (device, queue) = (map.device().unwrap(), map.queue().unwrap());
+                targets.push(Target::Gpu(Arc::clone(device), Arc::clone(queue)));
+                targets.push(Target::Leveled(Arc::clone(device), Arc::clone(queue)));
+                targets.push(Target::Hybrid(Arc::clone(device), Arc::clone(queue)));
+                targets.push(Target::Indexed(Arc::clone(device), Arc::clone(queue)));
+            }
+            Err(_) => eprintln!("Skipping GPU backend: GPU not available in this environment"),
+        }
+        if let Some((device, queue)) = sharding_device() {
+            targets.push(Target::Sharded(device, queue));
+        }
+        targets
+    }
+
+    fn map(&self, capacity: u32) -> GpuSortedMap {
+        match self {
+            Target::Gpu(device, queue) | Target::Sharded(device, queue) => {
+                GpuSortedMap::with_device(
+                    Arc::clone(device),
+                    Arc::clone(queue),
+                    Capacity::new(capacity),
+                )
+                .expect("test capacities fit the device limits")
+            }
+            Target::Leveled(device, queue) => {
+                let mut map = GpuSortedMap::with_device(
+                    Arc::clone(device),
+                    Arc::clone(queue),
+                    Capacity::new(capacity),
+                )
+                .expect("test capacities fit the device limits");
+                map.set_leveled(Some(LeveledConfig {
+                    l0_capacity: Capacity::new(4),
+                    size_ratio: 2,
+                }))
+                .expect("single slabs can be leveled");
+                map
+            }
+            Target::Hybrid(device, queue) => {
+                let mut map = GpuSortedMap::with_device(
+                    Arc::clone(device),
+                    Arc::clone(queue),
+                    Capacity::new(capacity),
+                )
+                .expect("test capacities fit the device limits");
+                map.set_dispatch(DispatchPolicy::Threshold(8))
+                    .expect("an empty map can be mirrored");
+                map
+            }
+            Target::Indexed(device, queue) => {
+                let mut map = GpuSortedMap::with_device(
+                    Arc::clone(device),
+                    Arc::clone(queue),
+                    Capacity::new(capacity),
+                )
+                .expect("test capacities fit the device limits");
+                map.set_search_index(true);
+                map
+            }
+            Target::Cpu => pollster::block_on(GpuSortedMap::with_backend(
+                Capacity::new(capacity),
+                Backend::Cpu,
+            ))
+            .expect("CPU backend is always available"),
+        }
+    }
+}
+
+impl fmt::Debug for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Gpu(..) => f.write_str("Gpu"),
+            Target::Sharded(..) => f.write_str("Sharded"),
+            Target::Leveled(..) => f.write_str("Leveled"),
+            Target::Hybrid(..) => f.write_str("Hybrid"),
+            Target::Indexed(..) => f.write_str("Indexed"),
+            Target::Cpu => f.write_str("Cpu"),
+        }
+    }
+}
+
+/// Device limited to 512-byte storage bindings.
+fn sharding_device() -> Option<(Arc<wgpu::Device>, Arc<wgpu::Queue>)> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::all(),
+        ..Default::default()
+    });
+    let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::LowPower,
+        compatible_surface: None,
+        force_fallback_adapter: false,
+    }))?;
+    let (device, queue) = pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            label: Some("differential-sharded-device"),
+            required_features: wgpu::Features::empty(),
+            required_limits: wgpu::Limits {
+                max_storage_buffer_binding_size: 512,
+                ..wgpu::Limits::default()
+            },
+        },
+        None,
+    ))
+    .ok()?;
+    Some((Arc::new(device), Arc::new(queue)))
+}
+
+/// A random sequence and the map capacity it runs with.
+#[derive(Clone, Debug)]
+struct Case {
+    capacity: u32,
+    ops: Vec<Op>,
+}
+
+/// First disagreement between the map and the model.
+#[derive(Debug)]
+struct Mismatch {
+    step: usize,
+    message: String,
+}
+
+fn generate(seed: u64, len: usize) -> Case {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let capacity = [8, 32, 128, 512][rng.gen_range(0..4)];
+    // A key space about the size of the slab keeps batches overlapping, so
+    // updates, revivals and compaction all happen often.
+    let key_space = capacity + capacity / 2;
+    let key = |rng: &mut StdRng| match rng.gen_range(0..32) {
+        0 => 0,
+        1 => u32::MAX,
+        _ => rng.gen_range(0..key_space),
+    };
+    let keys = |rng: &mut StdRng, max: u32| -> Vec<u32> {
+        let count = rng.gen_range(0..=max);
+        (0..count).map(|_| key(rng)).collect()
+    };
+
+    let ops = (0..len)
+        .map(|_| match rng.gen_range(0..100) {
+            0..=39 => {
+                // Mostly batches that fit, sometimes ones that cannot.
+                let max = if rng.gen_range(0..20) == 0 {
+                    capacity + 2
+                } else {
+                    capacity / 2
+                };
+                let mut batch_keys = keys(&mut rng, max);
+                if rng.gen_range(0..10) != 0 {
+                    batch_keys.sort_unstable();
+                    batch_keys.dedup();
+                    shuffle(&mut rng, &mut batch_keys);
+                }
+                let batch = batch_keys
+                    .into_iter()
+                    .map(|key| {
+                        let value = match rng.gen_range(0..200) {
+                            0 => TOMBSTONE,
+                            1 => TOMBSTONE - 1,
+                            _ => rng.gen_range(0..1000),
+                        };
+                        (key, value)
+                    })
+                    .collect();
+                Op::Put(batch)
+            }
+            40..=59 => Op::Delete(keys(&mut rng, capacity / 2)),
+            60..=79 => Op::Get(keys(&mut rng, 64)),
+            80..=94 => {
+                let (a, b) = (key(&mut rng), key(&mut rng));
+                if rng.gen_bool(0.8) {
+                    Op::Range(a.min(b), a.max(b))
+                } else {
+                    Op::Range(a, b)
+                }
+            }
+            _ => Op::Len,
+        })
+        .collect();
+    Case { capacity, ops }
+}
+
+fn shuffle(rng: &mut StdRng, keys: &mut [u32]) {
+    use rand::seq::SliceRandom;
+    keys.shuffle(rng);
+}
+
+/// Run `ops` on a fresh map and model, comparing every result.
+fn run(target: &Target, capacity: u32, ops: &[Op]) -> Result<(), Mismatch> {
+    let mut map = target.map(capacity);
+    let mut model = Model::new(capacity);
+    let fail = |step: usize, message: String| Err(Mismatch { step, message });
+
+    for (step, op) in ops.iter().enumerate() {
+        match op {
+            Op::Put(batch) => {
+                let entries: Vec<KvEntry> = batch
+                    .iter()
+                    .map(|&(key, value)| KvEntry {
+                        key: Key::new(key),
+                        value: Value::new(value),
+                    })
+                    .collect();
+                let actual = PutOutcome::from(map.bulk_put(&entries));
+                let expected = model.put(batch);
+                if actual != expected {
+                    return fail(step, format!("put: map {actual:?}, model {expected:?}"));
+                }
+            }
+            Op::Delete(keys) => {
+                let map_keys: Vec<Key> = keys.iter().map(|&key| Key::new(key)).collect();
+                if let Err(err) = map.bulk_delete(&map_keys) {
+                    return fail(step, format!("delete: map {err:?}"));
+                }
+                model.delete(keys);
+            }
+            Op::Get(keys) => {
+                let map_keys: Vec<Key> = keys.iter().map(|&key| Key::new(key)).collect();
+                let (actual, expected) = (map.bulk_get(&map_keys), Ok(model.get(keys)));
+                if actual != expected {
+                    return fail(step, format!("get: map {actual:?}, model {expected:?}"));
+                }
+                let sorted = map.bulk_get_sorted(&map_keys);
+                if sorted != expected {
+                    return fail(
+                        step,
+                        format!("sorted get: map {sorted:?}, model {expected:?}"),
+                    );
+                }
+            }
+            Op::Range(from, to) => {
+                let actual = map.range(Key::new(*from), Key::new(*to));
+                let expected = Ok(model.range(*from, *to));
+                if actual != expected {
+                    return fail(step, format!("range: map {actual:?}, model {expected:?}"));
+                }
+            }
+            Op::Len => {}
+        }
+        // Every op checks the live count, so `Len` only pads sequences.
+        let (actual, expected) = (map.len().0 as usize, model.entries.len());
+        if actual != expected {
+            return fail(step, format!("len: map {actual}, model {expected}"));
+        }
+    }
+    Ok(())
+}
+
+/// Smallest sequence found that still makes `fails` return true: drop the
+/// tail after the first failure, then chunks of ops, then batch elements,
+/// until nothing more can go.
+fn shrink(ops: Vec<Op>, mut fails: impl FnMut(&[Op]) -> Option<usize>) -> Vec<Op> {
+    let mut best = ops;
+    let Some(step) = fails(&best) else {
+        return best;
+    };
+    best.truncate(step + 1);
+
+    loop {
+        let mut progress = false;
+
+        let mut chunk = best.len().div_ceil(2);
+        while chunk > 0 {
+            let mut start = 0;
+            while start < best.len() {
+                let mut candidate = best.clone();
+                candidate.drain(start..(start + chunk).min(best.len()));
+                if let Some(step) = fails(&candidate) {
+                    candidate.truncate(step + 1);
+                    best = candidate;
+                    progress = true;
+                } else {
+                    start += chunk;
+                }
+            }
+            chunk /= 2;
+        }
+
+        for index in 0..best.len() {
+            while let Some(candidate) = smaller_ops(&best[index])
+                .into_iter()
+                .map(|op| {
+                    let mut candidate = best.clone();
+                    candidate[index] = op;
+                    candidate
+                })
+                .find(|candidate| fails(candidate).is_some())
+            {
+                best = candidate;
+                progress = true;
+            }
+        }
+
+        if !progress {
+            return best;
+        }
+    }
+}
+
+/// Simpler variants of `op`: the first or second half of its batch, or the
+/// batch with one element removed.
+fn smaller_ops(op: &Op) -> Vec<Op> {
+    fn smaller<T: Clone>(items: &[T]) -> Vec<Vec<T>> {
+        let mut out = Vec::new();
+        if items.len() > 1 {
+            let mid = items.len() / 2;
+            out.push(items[..mid].to_vec());
+            out.push(items[mid..].to_vec());
+        }
+        for index in 0..items.len() {
+            let mut fewer = items.to_vec();
+            fewer.remove(index);
+            out.push(fewer);
+        }
+        out
+    }
+    match op {
+        Op::Put(batch) => smaller(batch).into_iter().map(Op::Put).collect(),
+        Op::Delete(keys) => smaller(keys).into_iter().map(Op::Delete).collect(),
+        Op::Get(keys) => smaller(keys).into_iter().map(Op::Get).collect(),
+        Op::Range(..) | Op::Len => Vec::new(),
+    }
+}
+
+/// Run the sequence for `seed` and panic with a shrunk reproduction if the
+/// map disagrees with the model.
+fn check_seed(target: &Target, seed: u64, len: usize) {
+    let case = generate(seed, len);
+    let Err(mismatch) = run(target, case.capacity, &case.ops) else {
+        return;
+    };
+    let minimal = shrink(case.ops.clone(), |ops| {
+        run(target, case.capacity, ops).err().map(|m| m.step)
+    });
+    let last = run(target, case.capacity, &minimal)
+        .err()
+        .map_or(mismatch.message.clone(), |m| m.message);
+    let steps: String = minimal
+        .iter()
+        .enumerate()
+        .map(|(step, op)| format!("\n  {step}: {op:?}"))
+        .collect();
+    panic!(
+        "{target:?} backend disagrees with the model for seed {seed} \
+         (GPUSORTED_MAP_DIFF_SEED={seed}), capacity {}.\n\
+         Original failure at step {}: {}\n\
+         Minimal sequence ({} of {} ops):{steps}\n\
+         Last step: {last}",
+        case.capacity,
+        mismatch.step,
+        mismatch.message,
+        minimal.len(),
+        case.ops.len(),
+    );
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    let value = std::env::var(name).ok()?;
+    Some(
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} must be an integer, got {value:?}")),
+    )
+}
+
+fn ops_per_sequence() -> usize {
+    env_u64("GPUSORTED_MAP_DIFF_OPS").map_or(DEFAULT_OPS, |ops| ops as usize)
+}
+
+#[test]
+fn random_sequences_match_btreemap() {
+    let seeds: Vec<u64> = match env_u64("GPUSORTED_MAP_DIFF_SEED") {
+        Some(seed) => vec![seed],
+        None => (0..DEFAULT_SEEDS).collect(),
+    };
+    let len = ops_per_sequence();
+    for target in Target::available() {
+        for &seed in &seeds {
+            check_seed(&target, seed, len);
+        }
+    }
+}
+
+#[test]
+#[ignore = "long-running; run with --ignored"]
+fn soak() {
+    let duration =
+        Duration::from_secs(env_u64("GPUSORTED_MAP_DIFF_SOAK").unwrap_or(DEFAULT_SOAK_SECS));
+    let len = ops_per_sequence();
+    let start_seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let targets = Target::available();
+    let started = Instant::now();
+    let mut seed = start_seed;
+    while started.elapsed() < duration {
+        for target in &targets {
+            check_seed(target, seed, len);
+        }
+        seed += 1;
+        if (seed - start_seed) % 100 == 0 {
+            eprintln!("soak: {} seeds passed from {start_seed}", seed - start_seed);
+        }
+    }
+    eprintln!("soak: {} seeds passed from {start_seed}", seed - start_seed);
+}
+
+#[test]
+fn shrink_finds_minimal_failing_sequence() {
+    // Fails once key 7 is put and later read, however much else happens.
+    let fails = |ops: &[Op]| {
+        let mut put = false;
+        for (step, op) in ops.iter().enumerate() {
+            match op {
+                Op::Put(batch) if batch.iter().any(|&(key, _)| key == 7) => put = true,
+                Op::Get(keys) if put && keys.contains(&7) => return Some(step),
+                _ => {}
+            }
+        }
+        None
+    };
+    let ops = vec![
+        Op::Len,
+        Op::Put(vec![(1, 1), (7, 70), (9, 90)]),
+        Op::Delete(vec![1, 2]),
+        Op::Range(0, 10),
+        Op::Get(vec![3, 7, 8]),
+        Op::Put(vec![(4, 4)]),
+    ];
+    assert_eq!(
+        shrink(ops, fails),
+        vec![Op::Put(vec![(7, 70)]), Op::Get(vec![7])]
+    );
+}